@@ -1,8 +1,43 @@
+/// Messages which the receiver HTTP server transcodes from JSON for
+/// `grpc::web`, and those which it transcodes back. Those nested in them
+/// are included.
+const JSON_REQUESTS: &[&str] = &[
+    ".pager.PageRequest",
+    ".pager.PageBatchRequest",
+    ".pager.AckRequest",
+    ".pager.ListReceivedMessagesRequest",
+];
+const JSON_RESPONSES: &[&str] = &[
+    ".pager.PageResponse",
+    ".pager.PageResult",
+    ".pager.PageBatchResponse",
+    ".pager.ReceivedMessage",
+    ".pager.ListReceivedMessagesResponse",
+    ".pager.OnCallShift",
+    ".pager.GetOnCallResponse",
+    ".pager.CommandOutcome",
+    ".pager.GetStatusResponse",
+];
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let fds_path =
-        std::path::PathBuf::from(std::env::var("OUT_DIR").expect("$OUT_DIR")).join("fdset.bin");
-    tonic_prost_build::configure()
-        .file_descriptor_set_path(fds_path)
+    let out_dir = std::path::PathBuf::from(std::env::var("OUT_DIR").expect("$OUT_DIR"));
+    let mut pager =
+        tonic_prost_build::configure().file_descriptor_set_path(out_dir.join("fdset.bin"));
+    for message in JSON_REQUESTS {
+        pager = pager.type_attribute(message, "#[derive(serde::Deserialize)] #[serde(default)]");
+    }
+    for message in JSON_RESPONSES {
+        pager = pager.type_attribute(message, "#[derive(serde::Serialize)]");
+    }
+    pager
+        // Attachments over JSON are given as data: URLs in attachment_url.
+        .field_attribute(".pager.PageRequest.attachment", "#[serde(skip)]")
         .compile_protos(&["proto/pager.proto"], &["proto"])?;
+    tonic_prost_build::configure()
+        .file_descriptor_set_path(out_dir.join("admin_fdset.bin"))
+        .compile_protos(&["proto/admin.proto"], &["proto"])?;
+    tonic_prost_build::configure()
+        .file_descriptor_set_path(out_dir.join("health_fdset.bin"))
+        .compile_protos(&["proto/health.proto"], &["proto"])?;
     Ok(())
 }
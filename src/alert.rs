@@ -0,0 +1,363 @@
+//! Alerts as Alertmanager and Grafana send them, and rendering them into
+//! messages. Both binaries take alerts in through here, and the pager
+//! renders those forwarded by the relay here too, so that templates see
+//! the same thing however an alert arrives.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::page::{AlertFields, Attachment, PageMeta};
+use crate::render::Renderer;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AlertInput {
+    pub status: String,
+    pub labels: HashMap<String, String>,
+    pub annotations: HashMap<String, String>,
+    #[serde(rename = "startsAt", default)]
+    pub starts_at: Option<String>,
+    #[serde(rename = "endsAt", default)]
+    pub ends_at: Option<String>,
+    #[serde(rename = "generatorURL", default)]
+    pub generator_url: Option<String>,
+    #[serde(default)]
+    pub fingerprint: Option<String>,
+}
+
+/// What a webhook says about all of its alerts together.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct GroupContext {
+    #[serde(default)]
+    pub receiver: Option<String>,
+    #[serde(rename = "externalURL", default)]
+    pub external_url: Option<String>,
+    #[serde(rename = "groupKey", default)]
+    pub group_key: Option<String>,
+    #[serde(rename = "groupLabels", default)]
+    pub group_labels: HashMap<String, String>,
+    #[serde(rename = "commonLabels", default)]
+    pub common_labels: HashMap<String, String>,
+    #[serde(rename = "commonAnnotations", default)]
+    pub common_annotations: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+pub struct AlertsInput {
+    pub alerts: Vec<AlertInput>,
+    #[serde(flatten)]
+    pub group: GroupContext,
+}
+
+/// Grafana's webhook alerts are a superset of Alertmanager's.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct GrafanaAlertInput {
+    #[serde(flatten)]
+    pub alert: AlertInput,
+    #[serde(rename = "silenceURL", default)]
+    pub silence_url: Option<String>,
+    #[serde(rename = "dashboardURL", default)]
+    pub dashboard_url: Option<String>,
+    #[serde(rename = "panelURL", default)]
+    pub panel_url: Option<String>,
+    #[serde(rename = "valueString", default)]
+    pub value_string: Option<String>,
+    #[serde(rename = "imageURL", default)]
+    pub image_url: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct GrafanaAlertsInput {
+    pub alerts: Vec<GrafanaAlertInput>,
+    #[serde(flatten)]
+    pub group: GroupContext,
+}
+
+pub trait Alert: Serialize {
+    fn status(&self) -> &str;
+    fn labels(&self) -> &HashMap<String, String>;
+    fn annotations(&self) -> &HashMap<String, String>;
+    fn fingerprint(&self) -> Option<&str>;
+    /// An image or other file to send along with the page.
+    fn attachment_url(&self) -> Option<&str>;
+    fn starts_at(&self) -> Option<&str>;
+    fn ends_at(&self) -> Option<&str>;
+    fn generator_url(&self) -> Option<&str>;
+
+    /// Where to silence the alert, if the alert does not say already.
+    fn silence_url(&self, external_url: Option<&str>) -> Option<String> {
+        let mut labels = self.labels().iter().collect::<Vec<_>>();
+        labels.sort();
+        let filter = labels
+            .iter()
+            .map(|(k, v)| format!("{k}=\"{v}\""))
+            .collect::<Vec<_>>()
+            .join(",");
+        Some(format!(
+            "{}/#/silences/new?filter={}",
+            external_url?.trim_end_matches('/'),
+            percent_encode(&format!("{{{filter}}}"))
+        ))
+    }
+
+    /// How long the alert has been firing, or fired for if it is
+    /// resolved, to the minute, like `1h 25m`.
+    fn firing_for(&self) -> Option<String> {
+        crate::render::firing_for(self.status(), self.starts_at(), self.ends_at())
+    }
+
+    fn meta(&self) -> PageMeta {
+        PageMeta {
+            labels: self.labels().clone(),
+            resolved: self.status() == "resolved",
+            fingerprint: self.fingerprint().map(String::from),
+            attachment: self.attachment_url().map(Attachment::from_url),
+            ..Default::default()
+        }
+    }
+}
+
+impl<A: Alert> Alert for &A {
+    fn status(&self) -> &str {
+        (*self).status()
+    }
+
+    fn labels(&self) -> &HashMap<String, String> {
+        (*self).labels()
+    }
+
+    fn annotations(&self) -> &HashMap<String, String> {
+        (*self).annotations()
+    }
+
+    fn fingerprint(&self) -> Option<&str> {
+        (*self).fingerprint()
+    }
+
+    fn attachment_url(&self) -> Option<&str> {
+        (*self).attachment_url()
+    }
+
+    fn starts_at(&self) -> Option<&str> {
+        (*self).starts_at()
+    }
+
+    fn ends_at(&self) -> Option<&str> {
+        (*self).ends_at()
+    }
+
+    fn generator_url(&self) -> Option<&str> {
+        (*self).generator_url()
+    }
+
+    fn silence_url(&self, external_url: Option<&str>) -> Option<String> {
+        (*self).silence_url(external_url)
+    }
+}
+
+impl Alert for AlertInput {
+    fn status(&self) -> &str {
+        &self.status
+    }
+
+    fn labels(&self) -> &HashMap<String, String> {
+        &self.labels
+    }
+
+    fn annotations(&self) -> &HashMap<String, String> {
+        &self.annotations
+    }
+
+    fn fingerprint(&self) -> Option<&str> {
+        self.fingerprint.as_deref()
+    }
+
+    fn attachment_url(&self) -> Option<&str> {
+        self.annotations.get("attachment_url").map(String::as_str)
+    }
+
+    fn starts_at(&self) -> Option<&str> {
+        self.starts_at.as_deref()
+    }
+
+    fn ends_at(&self) -> Option<&str> {
+        self.ends_at.as_deref()
+    }
+
+    fn generator_url(&self) -> Option<&str> {
+        self.generator_url.as_deref()
+    }
+}
+
+impl Alert for GrafanaAlertInput {
+    fn status(&self) -> &str {
+        &self.alert.status
+    }
+
+    fn labels(&self) -> &HashMap<String, String> {
+        &self.alert.labels
+    }
+
+    fn annotations(&self) -> &HashMap<String, String> {
+        &self.alert.annotations
+    }
+
+    fn fingerprint(&self) -> Option<&str> {
+        self.alert.fingerprint.as_deref()
+    }
+
+    fn attachment_url(&self) -> Option<&str> {
+        self.image_url
+            .as_deref()
+            .filter(|u| !u.is_empty())
+            .or_else(|| self.alert.attachment_url())
+    }
+
+    fn starts_at(&self) -> Option<&str> {
+        self.alert.starts_at()
+    }
+
+    fn ends_at(&self) -> Option<&str> {
+        self.alert.ends_at()
+    }
+
+    fn generator_url(&self) -> Option<&str> {
+        self.alert.generator_url()
+    }
+
+    /// Grafana gives its own `silenceURL`.
+    fn silence_url(&self, _: Option<&str>) -> Option<String> {
+        None
+    }
+}
+
+/// Percent-encodes everything but unreserved characters, for a URL's
+/// query.
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                char::from(b).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+/// What the message template sees for an alert: the alert itself, what
+/// the webhook says about its group and what is worked out from them.
+#[derive(Serialize)]
+struct TemplateInput<'a, A> {
+    #[serde(flatten)]
+    group: &'a GroupContext,
+    #[serde(flatten)]
+    alert: &'a A,
+    #[serde(rename = "firingFor")]
+    firing_for: Option<String>,
+    #[serde(rename = "silenceURL", skip_serializing_if = "Option::is_none")]
+    silence_url: Option<String>,
+}
+
+pub fn render_alert<A: Alert>(
+    renderer: &Renderer,
+    clean_link: &dyn Fn(&str) -> String,
+    group: &GroupContext,
+    alert: &A,
+) -> Result<String, tera::Error> {
+    renderer.render(&TemplateInput {
+        group,
+        alert,
+        firing_for: alert.firing_for(),
+        silence_url: alert
+            .silence_url(group.external_url.as_deref())
+            .map(|u| clean_link(&u)),
+    })
+}
+
+/// The labels that all of the alerts have in common.
+pub fn common_labels<A: Alert>(alerts: &[A]) -> HashMap<String, String> {
+    let Some((first, rest)) = alerts.split_first() else {
+        return HashMap::new();
+    };
+    first
+        .labels()
+        .iter()
+        .filter(|(k, v)| rest.iter().all(|a| a.labels().get(*k) == Some(*v)))
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect()
+}
+
+/// All of the alerts from one webhook rendered as a single message.
+pub fn render_grouped<A: Alert>(
+    renderer: &Renderer,
+    clean_link: &dyn Fn(&str) -> String,
+    group: &GroupContext,
+    alerts: &[A],
+) -> Result<String, tera::Error> {
+    let firing = alerts.iter().filter(|a| a.status() == "firing").count();
+    let mut msg = format!("{} alerts", alerts.len());
+    if firing > 0 && firing < alerts.len() {
+        msg += &format!(" ({firing} firing, {} resolved)", alerts.len() - firing);
+    }
+    msg.push('\n');
+    for alert in alerts {
+        msg.push('\n');
+        msg += &render_alert(renderer, clean_link, group, alert)?;
+    }
+    Ok(msg)
+}
+
+/// An alert rendered with the default template can be rendered again
+/// wherever it is forwarded to, so that the template used is that one's.
+pub fn unrendered<A: Alert>(renderer: &Renderer, alert: &A) -> Option<AlertFields> {
+    (!renderer.custom()).then(|| AlertFields {
+        annotations: alert.annotations().clone(),
+        starts_at: alert.starts_at().map(String::from),
+        ends_at: alert.ends_at().map(String::from),
+        generator_url: alert.generator_url().map(String::from),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alert(status: &str, labels: &[(&str, &str)]) -> AlertInput {
+        AlertInput {
+            status: String::from(status),
+            labels: labels
+                .iter()
+                .map(|(k, v)| (String::from(*k), String::from(*v)))
+                .collect(),
+            annotations: HashMap::new(),
+            starts_at: None,
+            ends_at: None,
+            generator_url: None,
+            fingerprint: None,
+        }
+    }
+
+    #[test]
+    fn labels_in_common() {
+        let alerts = [
+            alert("firing", &[("team", "db"), ("host", "a")]),
+            alert("resolved", &[("team", "db"), ("host", "b")]),
+        ];
+        assert_eq!(
+            common_labels(&alerts),
+            HashMap::from([(String::from("team"), String::from("db"))])
+        );
+        assert!(common_labels::<AlertInput>(&[]).is_empty());
+    }
+
+    #[test]
+    fn silence_url() {
+        let a = alert("firing", &[("team", "db"), ("alertname", "Disk Full")]);
+        assert_eq!(a.silence_url(None), None);
+        assert_eq!(
+            a.silence_url(Some("https://am.example.com/")).as_deref(),
+            Some(
+                "https://am.example.com/#/silences/new?filter=%7Balertname%3D%22Disk%20Full%22%2Cteam%3D%22db%22%7D"
+            )
+        );
+    }
+}
@@ -0,0 +1,536 @@
+use comprehensive::ResourceDependencies;
+use comprehensive::v1::{AssemblyRuntime, Resource, resource};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::page::PageMeta;
+use crate::signal::Target;
+
+mod html;
+
+pub use html::html;
+
+const APP_DATA_NAME: &str = "alerts.json";
+
+mod rfc3339 {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::SystemTime;
+
+    pub fn serialize<S: Serializer>(t: &SystemTime, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(&humantime::format_rfc3339_seconds(*t))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<SystemTime, D::Error> {
+        humantime::parse_rfc3339(&String::deserialize(d)?).map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Ack {
+    pub by: String,
+    #[serde(with = "rfc3339")]
+    pub at: SystemTime,
+}
+
+/// What became of the page about an alert.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    /// Not sent yet, for example because it is in the send queue.
+    Pending,
+    /// Alerts saved before their status was kept had all been sent.
+    #[default]
+    Sent,
+    /// Sending failed and was not retried, or was given up on.
+    Failed,
+    /// Not sent because of `--resolved-action=suppress`.
+    Suppressed,
+    Acked,
+}
+
+impl Status {
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Sent => "sent",
+            Self::Failed => "failed",
+            Self::Suppressed => "suppressed",
+            Self::Acked => "acked",
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct AlertRecord {
+    pub id: u64,
+    pub source: String,
+    pub message: String,
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    #[serde(default)]
+    pub fingerprint: Option<String>,
+    /// Where the alert was sent. Empty means the default group.
+    #[serde(default)]
+    pub targets: Vec<Target>,
+    #[serde(with = "rfc3339")]
+    pub created: SystemTime,
+    /// When the alert was last sent to anybody, including repeats.
+    #[serde(with = "rfc3339")]
+    pub last_notified: SystemTime,
+    pub sent_timestamps: Vec<u64>,
+    /// The first message sent for the alert to each target.
+    #[serde(default)]
+    pub sent_to: Vec<(Target, u64)>,
+    pub ack: Option<Ack>,
+    /// How many times the alert was repeated for lack of acknowledgement.
+    #[serde(default)]
+    pub repeats: u32,
+    #[serde(default)]
+    pub escalated: bool,
+    /// Either this page said that the alert was over, or a later one did.
+    #[serde(default)]
+    pub resolved: bool,
+    #[serde(default)]
+    pub status: Status,
+    /// Why sending failed, if it did.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl AlertRecord {
+    /// Identifies pages about the same alert: the fingerprint if there
+    /// is one, or else the labels.
+    pub fn key(&self) -> Option<String> {
+        if let Some(ref f) = self.fingerprint {
+            return Some(f.clone());
+        }
+        if self.labels.is_empty() {
+            return None;
+        }
+        let mut labels = self.labels.iter().collect::<Vec<_>>();
+        labels.sort();
+        Some(format!("{labels:?}"))
+    }
+
+    /// Whether a page is about the same alert.
+    fn same_alert(&self, meta: &PageMeta) -> bool {
+        match (&self.fingerprint, &meta.fingerprint) {
+            (Some(a), Some(b)) if a == b => true,
+            _ => !meta.labels.is_empty() && self.labels == meta.labels,
+        }
+    }
+}
+
+#[derive(Default, Deserialize, Serialize)]
+struct Inner {
+    next_id: u64,
+    alerts: BTreeMap<u64, AlertRecord>,
+    #[serde(skip)]
+    by_timestamp: HashMap<u64, u64>,
+}
+
+impl Inner {
+    fn index(&mut self) {
+        self.by_timestamp = self
+            .alerts
+            .values()
+            .flat_map(|a| a.sent_timestamps.iter().map(|ts| (*ts, a.id)))
+            .collect();
+    }
+}
+
+#[derive(ResourceDependencies)]
+pub struct AlertsDependencies(Arc<crate::state::SignalState>);
+
+#[derive(clap::Args)]
+pub struct AlertsArgs {
+    /// How many of the latest alerts to remember, for `/alerts` and for
+    /// acknowledgements.
+    #[arg(long, default_value_t = 1000)]
+    max_alerts: usize,
+}
+
+/// Every page we have sent recently, so that they can be referred to
+/// later, for example to acknowledge them. This is kept in the state so
+/// that it survives restarts.
+pub struct Alerts {
+    inner: Mutex<Inner>,
+    changed: tokio::sync::Notify,
+    /// Set once the saved alerts have been loaded, or found not to be
+    /// there, after which IDs can be given out without colliding.
+    loaded: tokio::sync::watch::Sender<bool>,
+    max_alerts: usize,
+}
+
+#[resource]
+impl Resource for Alerts {
+    fn new(
+        d: AlertsDependencies,
+        a: AlertsArgs,
+        api: &mut AssemblyRuntime<'_>,
+    ) -> Result<Arc<Self>, std::convert::Infallible> {
+        let shared = Arc::new(Self {
+            inner: Mutex::new(Inner {
+                next_id: 1,
+                ..Default::default()
+            }),
+            changed: tokio::sync::Notify::new(),
+            loaded: tokio::sync::watch::Sender::new(false),
+            max_alerts: a.max_alerts,
+        });
+        let shared2 = Arc::clone(&shared);
+        let state = d.0;
+        api.set_task(async move {
+            state.wait_available().await;
+            match state.get().await.read_app_data::<Inner>(APP_DATA_NAME) {
+                Ok(Some(saved)) => shared2.merge(saved),
+                Ok(None) => (),
+                Err(e) => log::error!("Loading alerts from state: {e}"),
+            }
+            shared2.loaded.send_replace(true);
+            loop {
+                shared2.changed.notified().await;
+                let guard = state.get().await;
+                let r = {
+                    let inner = shared2.inner.lock().unwrap();
+                    guard.write_app_data(APP_DATA_NAME, &*inner)
+                };
+                if let Err(e) = r {
+                    log::error!("Saving alerts to state: {e}");
+                }
+            }
+        });
+        Ok(shared)
+    }
+}
+
+impl Alerts {
+    /// No alerts are created until this has run, so the saved ones are
+    /// all there are.
+    fn merge(&self, saved: Inner) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_id = inner.next_id.max(saved.next_id);
+        inner.alerts = saved.alerts;
+        inner.index();
+        log::info!("Loaded {} alerts from state", inner.alerts.len());
+    }
+
+    fn changed(&self) {
+        self.changed.notify_one();
+    }
+
+    /// Waits for the saved alerts to be loaded first, so that the new
+    /// alert's ID is not one that they already use.
+    pub async fn create(
+        &self,
+        source: &str,
+        message: &str,
+        meta: &PageMeta,
+        targets: &[Target],
+    ) -> u64 {
+        let _ = self.loaded.subscribe().wait_for(|l| *l).await;
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        let now = SystemTime::now();
+        if meta.resolved {
+            for alert in inner.alerts.values_mut() {
+                if alert.same_alert(meta) {
+                    alert.resolved = true;
+                }
+            }
+        }
+        inner.alerts.insert(
+            id,
+            AlertRecord {
+                id,
+                source: String::from(source),
+                message: String::from(message),
+                labels: meta.labels.clone(),
+                fingerprint: meta.fingerprint.clone(),
+                targets: targets.to_vec(),
+                created: now,
+                last_notified: now,
+                sent_timestamps: Vec::new(),
+                sent_to: Vec::new(),
+                ack: None,
+                repeats: 0,
+                escalated: false,
+                resolved: meta.resolved,
+                status: Status::Pending,
+                error: None,
+            },
+        );
+        while inner.alerts.len() > self.max_alerts {
+            if let Some((_, old)) = inner.alerts.pop_first() {
+                for ts in old.sent_timestamps {
+                    inner.by_timestamp.remove(&ts);
+                }
+            }
+        }
+        drop(inner);
+        self.changed();
+        id
+    }
+
+    /// Remember the Signal timestamp of a message sent for an alert so
+    /// that reactions to that message can be attributed to it.
+    pub fn record_sent(&self, id: u64, target: &Target, timestamp: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(alert) = inner.alerts.get_mut(&id) {
+            alert.sent_timestamps.push(timestamp);
+            if !alert.sent_to.iter().any(|(t, _)| t == target) {
+                alert.sent_to.push((target.clone(), timestamp));
+            }
+            inner.by_timestamp.insert(timestamp, id);
+        }
+        drop(inner);
+        self.changed();
+    }
+
+    /// Record what became of the page about an alert, unless it has
+    /// been acknowledged already.
+    pub fn record_status(&self, id: u64, status: Status, error: Option<String>) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(alert) = inner.alerts.get_mut(&id)
+            && alert.status != Status::Acked
+        {
+            alert.status = status;
+            alert.error = error;
+        }
+        drop(inner);
+        self.changed();
+    }
+
+    /// Record that an unacknowledged alert was sent again, either as a
+    /// repeat or to escalate it.
+    pub fn record_renotified(&self, id: u64, escalated: bool, timestamp: Option<u64>) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(alert) = inner.alerts.get_mut(&id) {
+            alert.last_notified = SystemTime::now();
+            if escalated {
+                alert.escalated = true;
+            } else {
+                alert.repeats += 1;
+            }
+            if let Some(ts) = timestamp {
+                alert.sent_timestamps.push(ts);
+                inner.by_timestamp.insert(ts, id);
+            }
+        }
+        drop(inner);
+        self.changed();
+    }
+
+    /// The latest page about an alert which has not resolved yet, for
+    /// a page saying that it has.
+    pub fn firing(&self, meta: &PageMeta) -> Option<u64> {
+        self.inner
+            .lock()
+            .unwrap()
+            .alerts
+            .values()
+            .rev()
+            .find(|a| !a.resolved && a.same_alert(meta))
+            .map(|a| a.id)
+    }
+
+    pub fn get(&self, id: u64) -> Option<AlertRecord> {
+        self.inner.lock().unwrap().alerts.get(&id).cloned()
+    }
+
+    pub fn id_for_timestamp(&self, timestamp: u64) -> Option<u64> {
+        self.inner
+            .lock()
+            .unwrap()
+            .by_timestamp
+            .get(&timestamp)
+            .copied()
+    }
+
+    /// Returns false if there is no such alert. Acknowledging an alert
+    /// that is already acknowledged keeps the original acknowledgement.
+    pub fn ack(&self, id: u64, by: &str) -> bool {
+        let found = match self.inner.lock().unwrap().alerts.get_mut(&id) {
+            None => false,
+            Some(alert) => {
+                if alert.ack.is_none() {
+                    log::info!("Alert {id} acknowledged by {by}");
+                    alert.ack = Some(Ack {
+                        by: String::from(by),
+                        at: SystemTime::now(),
+                    });
+                    alert.status = Status::Acked;
+                }
+                true
+            }
+        };
+        if found {
+            self.changed();
+        }
+        found
+    }
+
+    pub fn unacked(&self) -> Vec<AlertRecord> {
+        self.inner
+            .lock()
+            .unwrap()
+            .alerts
+            .values()
+            .filter(|a| a.ack.is_none())
+            .cloned()
+            .collect()
+    }
+
+    /// Most recent first.
+    pub fn list(&self) -> Vec<AlertRecord> {
+        self.inner
+            .lock()
+            .unwrap()
+            .alerts
+            .values()
+            .rev()
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alerts() -> Alerts {
+        Alerts {
+            inner: Mutex::new(Inner {
+                next_id: 1,
+                ..Default::default()
+            }),
+            changed: tokio::sync::Notify::new(),
+            loaded: tokio::sync::watch::Sender::new(true),
+            max_alerts: 10,
+        }
+    }
+
+    #[tokio::test]
+    async fn ids_follow_saved_alerts() {
+        let earlier = alerts();
+        earlier
+            .create("test", "one", &PageMeta::default(), &[])
+            .await;
+        earlier
+            .create("test", "two", &PageMeta::default(), &[])
+            .await;
+        let saved = std::mem::take(&mut *earlier.inner.lock().unwrap());
+
+        let alerts = Alerts {
+            loaded: tokio::sync::watch::Sender::new(false),
+            ..self::alerts()
+        };
+        let meta = PageMeta::default();
+        let (id, ()) = tokio::join!(alerts.create("test", "three", &meta, &[]), async {
+            tokio::task::yield_now().await;
+            alerts.merge(saved);
+            alerts.loaded.send_replace(true);
+        });
+        assert_eq!(id, 3);
+        let messages: Vec<_> = alerts.list().into_iter().map(|a| a.message).collect();
+        assert_eq!(messages, ["three", "two", "one"]);
+    }
+
+    #[tokio::test]
+    async fn ack_by_timestamp() {
+        let alerts = alerts();
+        let id = alerts
+            .create("test", "disk full", &PageMeta::default(), &[])
+            .await;
+        alerts.record_sent(id, &Target::Group(String::from("g")), 1000);
+        assert_eq!(alerts.id_for_timestamp(1000), Some(id));
+        assert_eq!(alerts.id_for_timestamp(1001), None);
+        assert!(alerts.ack(id, "alice"));
+        assert!(alerts.ack(id, "bob"));
+        assert_eq!(alerts.list()[0].ack.as_ref().unwrap().by, "alice");
+        assert!(!alerts.ack(id + 1, "alice"));
+    }
+
+    #[tokio::test]
+    async fn forgets_the_oldest() {
+        let alerts = alerts();
+        let first = alerts
+            .create("test", "first", &PageMeta::default(), &[])
+            .await;
+        alerts.record_sent(first, &Target::Group(String::from("g")), 1);
+        for _ in 0..10 {
+            alerts
+                .create("test", "more", &PageMeta::default(), &[])
+                .await;
+        }
+        assert_eq!(alerts.list().len(), 10);
+        assert_eq!(alerts.id_for_timestamp(1), None);
+        assert!(!alerts.ack(first, "alice"));
+    }
+
+    #[tokio::test]
+    async fn resolved_by_labels() {
+        let alerts = alerts();
+        let meta = PageMeta {
+            labels: HashMap::from([(String::from("alertname"), String::from("DiskFull"))]),
+            ..Default::default()
+        };
+        let firing = alerts.create("test", "disk full", &meta, &[]).await;
+        let other = alerts
+            .create("test", "unrelated", &PageMeta::default(), &[])
+            .await;
+        let resolved = PageMeta {
+            resolved: true,
+            ..meta
+        };
+        alerts.create("test", "disk fine", &resolved, &[]).await;
+        let list = alerts.list();
+        assert!(list.iter().find(|a| a.id == firing).unwrap().resolved);
+        assert!(!list.iter().find(|a| a.id == other).unwrap().resolved);
+    }
+
+    #[tokio::test]
+    async fn firing() {
+        let alerts = alerts();
+        let meta = |fingerprint: &str| PageMeta {
+            fingerprint: Some(String::from(fingerprint)),
+            ..Default::default()
+        };
+        assert_eq!(alerts.firing(&meta("a")), None);
+        alerts.create("test", "first", &meta("a"), &[]).await;
+        let latest = alerts.create("test", "again", &meta("a"), &[]).await;
+        alerts.create("test", "other", &meta("b"), &[]).await;
+        assert_eq!(alerts.firing(&meta("a")), Some(latest));
+        // Pages without labels or a fingerprint are never the same alert.
+        alerts
+            .create("test", "bare", &PageMeta::default(), &[])
+            .await;
+        assert_eq!(alerts.firing(&PageMeta::default()), None);
+
+        let group = Target::Group(String::from("g"));
+        let person = Target::Recipient(String::from("+15550001"));
+        alerts.record_sent(latest, &group, 1);
+        alerts.record_sent(latest, &person, 2);
+        alerts.record_sent(latest, &group, 3);
+        let sent_to = alerts.get(latest).unwrap().sent_to;
+        assert_eq!(sent_to, [(group, 1), (person, 2)]);
+    }
+
+    #[tokio::test]
+    async fn status() {
+        let alerts = alerts();
+        let id = alerts
+            .create("test", "disk full", &PageMeta::default(), &[])
+            .await;
+        assert_eq!(alerts.list()[0].status, Status::Pending);
+        alerts.record_status(id, Status::Failed, Some(String::from("no route")));
+        assert_eq!(alerts.list()[0].status, Status::Failed);
+        assert_eq!(alerts.list()[0].error.as_deref(), Some("no route"));
+        alerts.ack(id, "alice");
+        alerts.record_status(id, Status::Sent, None);
+        assert_eq!(alerts.list()[0].status, Status::Acked);
+    }
+}
@@ -0,0 +1,59 @@
+//! A plain page listing recent alerts, for looking at in a browser
+//! instead of scrolling back through Signal.
+
+use std::fmt::Write;
+
+use super::AlertRecord;
+
+const HEAD: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta http-equiv="refresh" content="60">
+<title>Alerts</title>
+<style>
+body { font-family: sans-serif; }
+table { border-collapse: collapse; }
+th, td { border: 1px solid #ccc; padding: 0.3em 0.6em; text-align: left; vertical-align: top; }
+td pre { margin: 0; white-space: pre-wrap; }
+.failed { background: #fdd; }
+.pending { background: #ffd; }
+.acked, .suppressed { color: #777; }
+</style>
+</head>
+<body>
+<table>
+<tr><th>ID</th><th>Created</th><th>Status</th><th>Source</th><th>Message</th><th>Acknowledged by</th></tr>
+"#;
+
+const TAIL: &str = "</table>\n</body>\n</html>\n";
+
+/// The alerts in a table, in the order given.
+pub fn html(alerts: &[AlertRecord]) -> String {
+    let mut page = String::from(HEAD);
+    for a in alerts {
+        let status = a.status.label();
+        let message = a.message.replace(
+            [crate::render::MENTION_START, crate::render::MENTION_END],
+            "",
+        );
+        let mut detail = tera::escape_html(&message);
+        if let Some(ref e) = a.error {
+            detail = format!("{detail}\n\n{}", tera::escape_html(e));
+        }
+        let ack = a
+            .ack
+            .as_ref()
+            .map(|ack| tera::escape_html(&ack.by))
+            .unwrap_or_default();
+        let _ = writeln!(
+            page,
+            "<tr class=\"{status}\"><td>{}</td><td>{}</td><td>{status}</td><td>{}</td><td><pre>{detail}</pre></td><td>{ack}</td></tr>",
+            a.id,
+            humantime::format_rfc3339_seconds(a.created),
+            tera::escape_html(&a.source),
+        );
+    }
+    page.push_str(TAIL);
+    page
+}
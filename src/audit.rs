@@ -0,0 +1,296 @@
+//! A record of every page that we were asked to send and what became
+//! of it, for answering "did we get paged, and when?" after an
+//! incident.
+
+use comprehensive::v1::{AssemblyRuntime, Resource, resource};
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::page::PageMeta;
+
+#[derive(Debug, thiserror::Error)]
+pub enum AuditError {
+    #[error("Audit log {0}: {1}")]
+    IOError(PathBuf, std::io::Error),
+}
+
+impl From<AuditError> for (http::StatusCode, String) {
+    fn from(e: AuditError) -> (http::StatusCode, String) {
+        (http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    }
+}
+
+/// One attempt to page.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Entry {
+    pub time: Timestamp,
+    /// The API that the page came in on.
+    pub source: String,
+    /// Who sent it, if we know: the SPIFFE ID of a gRPC caller or the
+    /// user or token that authenticated an HTTP request.
+    #[serde(default)]
+    pub caller: Option<String>,
+    /// SHA-256 of the message, so that the log does not hold what pages
+    /// said but a page can still be matched to its entry.
+    pub message_sha256: String,
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    #[serde(default)]
+    pub resolved: bool,
+    /// Where the page was routed.
+    #[serde(default)]
+    pub route: Vec<String>,
+    #[serde(default)]
+    pub alert_id: Option<u64>,
+    /// How the page was delivered, like `sent`, `queued` or
+    /// `maintenance`, or `failed`.
+    pub outcome: String,
+    #[serde(default)]
+    pub error: Option<String>,
+    /// How long we took to deliver the page or fail to.
+    pub latency_ms: u64,
+}
+
+impl Entry {
+    /// Starts an entry for a page. What became of it is filled in by
+    /// `finish`.
+    pub fn new(source: &str, caller: Option<&str>, msg: &str, meta: &PageMeta) -> Self {
+        Self {
+            time: Timestamp::now(),
+            source: String::from(source),
+            caller: caller.map(String::from),
+            message_sha256: hex::encode(Sha256::digest(msg.as_bytes())),
+            labels: meta.labels.clone(),
+            resolved: meta.resolved,
+            route: Vec::new(),
+            alert_id: None,
+            outcome: String::new(),
+            error: None,
+            latency_ms: 0,
+        }
+    }
+
+    pub fn finish<E: std::fmt::Display>(
+        mut self,
+        started: Instant,
+        outcome: Result<&str, &E>,
+    ) -> Self {
+        self.latency_ms = started.elapsed().as_millis() as u64;
+        match outcome {
+            Ok(outcome) => self.outcome = String::from(outcome),
+            Err(e) => {
+                self.outcome = String::from("failed");
+                self.error = Some(e.to_string());
+            }
+        }
+        self
+    }
+}
+
+/// Which entries to return from `/audit`.
+#[derive(Default, Deserialize)]
+pub struct Query {
+    /// Only entries at or after this time.
+    pub since: Option<Timestamp>,
+    /// Only entries before this time.
+    pub until: Option<Timestamp>,
+    pub source: Option<String>,
+    pub caller: Option<String>,
+    pub alert_id: Option<u64>,
+    /// At most this many of the latest matching entries.
+    pub limit: Option<usize>,
+}
+
+impl Query {
+    fn matches(&self, e: &Entry) -> bool {
+        self.since.is_none_or(|t| e.time >= t)
+            && self.until.is_none_or(|t| e.time < t)
+            && self.source.as_ref().is_none_or(|s| *s == e.source)
+            && self
+                .caller
+                .as_ref()
+                .is_none_or(|c| e.caller.as_ref() == Some(c))
+            && self.alert_id.is_none_or(|id| e.alert_id == Some(id))
+    }
+}
+
+#[derive(clap::Args)]
+pub struct AuditArgs {
+    /// Append a JSON line to this file for every page received, saying
+    /// where it came from, where it was routed and what became of it.
+    /// `/audit` searches it.
+    #[arg(long)]
+    audit_log_file: Option<PathBuf>,
+    /// How many of the latest entries to keep in memory for `/audit`
+    /// when there is no `--audit-log-file`.
+    #[arg(long, default_value_t = 1000)]
+    audit_max_entries: usize,
+}
+
+pub struct Audit {
+    file: Option<(PathBuf, Mutex<std::fs::File>)>,
+    recent: Mutex<VecDeque<Entry>>,
+    max_entries: usize,
+}
+
+#[resource]
+impl Resource for Audit {
+    fn new(
+        _: comprehensive::NoDependencies,
+        a: AuditArgs,
+        _: &mut AssemblyRuntime<'_>,
+    ) -> Result<Arc<Self>, AuditError> {
+        let file = match a.audit_log_file {
+            Some(path) => {
+                let f = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .map_err(|e| AuditError::IOError(path.clone(), e))?;
+                Some((path, Mutex::new(f)))
+            }
+            None => None,
+        };
+        Ok(Arc::new(Self {
+            file,
+            recent: Mutex::new(VecDeque::new()),
+            max_entries: a.audit_max_entries,
+        }))
+    }
+}
+
+impl Audit {
+    pub fn record(&self, entry: Entry) {
+        if let Some((ref path, ref f)) = self.file {
+            let mut line = serde_json::to_vec(&entry).expect("audit entries serialize");
+            line.push(b'\n');
+            // One write per entry so that lines are never interleaved.
+            if let Err(e) = f.lock().unwrap().write_all(&line) {
+                log::error!("Writing to audit log {}: {e}", path.display());
+            }
+        }
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() >= self.max_entries {
+            recent.pop_front();
+        }
+        recent.push_back(entry);
+    }
+
+    /// The latest entries matching the query, oldest first.
+    pub fn query(&self, q: &Query) -> Result<Vec<Entry>, AuditError> {
+        let mut entries = match self.file {
+            Some((ref path, _)) => {
+                let f =
+                    std::fs::File::open(path).map_err(|e| AuditError::IOError(path.clone(), e))?;
+                let mut entries = Vec::new();
+                for line in std::io::BufReader::new(f).lines() {
+                    let line = line.map_err(|e| AuditError::IOError(path.clone(), e))?;
+                    match serde_json::from_str::<Entry>(&line) {
+                        Ok(e) if q.matches(&e) => entries.push(e),
+                        Ok(_) => (),
+                        // A line cut short by a crash.
+                        Err(e) => log::warn!("Skipping audit log line: {e}"),
+                    }
+                }
+                entries
+            }
+            None => self
+                .recent
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|e| q.matches(e))
+                .cloned()
+                .collect(),
+        };
+        if let Some(limit) = q.limit {
+            entries.drain(..entries.len().saturating_sub(limit));
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(source: &str, alert_id: u64) -> Entry {
+        let mut e = Entry::new(source, Some("alice"), "disk full", &PageMeta::default());
+        e.alert_id = Some(alert_id);
+        e.finish::<std::io::Error>(Instant::now(), Ok("sent"))
+    }
+
+    fn ids(entries: Vec<Entry>) -> Vec<u64> {
+        entries.into_iter().filter_map(|e| e.alert_id).collect()
+    }
+
+    #[test]
+    fn hashes_message() {
+        let e = entry("grpc", 1);
+        assert_eq!(e.message_sha256.len(), 64);
+        let other = Entry::new("grpc", None, "disk fine", &PageMeta::default());
+        assert_ne!(e.message_sha256, other.message_sha256);
+        let failed = Entry::new("http", None, "", &PageMeta::default())
+            .finish(Instant::now(), Err(&"no route"));
+        assert_eq!(failed.outcome, "failed");
+        assert_eq!(failed.error.as_deref(), Some("no route"));
+    }
+
+    #[test]
+    fn in_memory() {
+        let audit = Audit {
+            file: None,
+            recent: Mutex::new(VecDeque::new()),
+            max_entries: 3,
+        };
+        for id in 1..=4 {
+            audit.record(entry(if id % 2 == 0 { "http" } else { "grpc" }, id));
+        }
+        assert_eq!(ids(audit.query(&Query::default()).unwrap()), [2, 3, 4]);
+        let q = Query {
+            source: Some(String::from("http")),
+            ..Default::default()
+        };
+        assert_eq!(ids(audit.query(&q).unwrap()), [2, 4]);
+        let q = Query {
+            limit: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(ids(audit.query(&q).unwrap()), [4]);
+    }
+
+    #[test]
+    fn file_survives_truncated_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let f = std::fs::File::create(&path).unwrap();
+        let audit = Audit {
+            file: Some((path.clone(), Mutex::new(f))),
+            recent: Mutex::new(VecDeque::new()),
+            max_entries: 1,
+        };
+        audit.record(entry("grpc", 1));
+        audit
+            .file
+            .as_ref()
+            .unwrap()
+            .1
+            .lock()
+            .unwrap()
+            .write_all(b"{\"time\"\n")
+            .unwrap();
+        audit.record(entry("grpc", 2));
+        let q = Query {
+            alert_id: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(ids(audit.query(&q).unwrap()), [2]);
+        assert_eq!(ids(audit.query(&Query::default()).unwrap()), [1, 2]);
+    }
+}
@@ -0,0 +1,295 @@
+//! `--config FILE`: a TOML file of flag values, for when there are too
+//! many flags to give comfortably on the command line. Each key is a
+//! flag's name and each value is what would be given to the flag, or an
+//! array for a flag that may be repeated. Structured values like routes
+//! and rotations may be written as tables instead of in their flag
+//! syntax. Flags given on the command line win over the file.
+//!
+//! On SIGHUP the file is read again, and the parts which can change
+//! without a restart pick up their new values.
+
+use comprehensive::v1::{AssemblyRuntime, Resource, resource};
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock, RwLock};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("Reading {0}: {1}")]
+    IOError(PathBuf, std::io::Error),
+    #[error("Parsing {0}: {1}")]
+    ParseError(PathBuf, toml::de::Error),
+    #[error("{0}: {1}: {2}")]
+    InvalidValue(PathBuf, String, String),
+    #[error("--config needs a file name")]
+    MissingPath,
+}
+
+struct Loaded {
+    path: PathBuf,
+    /// Flags given on the command line, which the file does not
+    /// override even when it is reloaded.
+    given: HashSet<String>,
+    /// What the file gives each flag, as it would be on the command
+    /// line.
+    values: RwLock<HashMap<String, Vec<String>>>,
+}
+
+static LOADED: OnceLock<Loaded> = OnceLock::new();
+
+/// One flag value written as a table: `match` holds label matchers to
+/// go before `=>`, `to` holds targets, and any other key is `key=value`
+/// or repeated for each element of an array.
+fn compound(table: &toml::Table) -> Result<String, String> {
+    let mut matchers = None;
+    let mut items = Vec::new();
+    for (k, v) in table {
+        match (k.as_str(), v) {
+            ("match", toml::Value::Table(m)) => {
+                let m = m
+                    .iter()
+                    .map(|(k, v)| Ok(format!("{k}={}", scalar(v)?)))
+                    .collect::<Result<Vec<_>, String>>()?;
+                matchers = Some(m.join(","));
+            }
+            ("match", _) => return Err(String::from("match must be a table of labels")),
+            ("to", v) => items.extend(list(v)?),
+            (k, v) => items.extend(list(v)?.into_iter().map(|v| format!("{k}={v}"))),
+        }
+    }
+    let items = items.join(",");
+    Ok(match matchers {
+        Some(m) => format!("{m}=>{items}"),
+        None => items,
+    })
+}
+
+fn scalar(v: &toml::Value) -> Result<String, String> {
+    match v {
+        toml::Value::String(s) => Ok(s.clone()),
+        toml::Value::Integer(i) => Ok(i.to_string()),
+        toml::Value::Float(f) => Ok(f.to_string()),
+        toml::Value::Boolean(b) => Ok(b.to_string()),
+        toml::Value::Datetime(d) => Ok(d.to_string()),
+        toml::Value::Table(t) => compound(t),
+        toml::Value::Array(_) => Err(String::from("arrays cannot be nested here")),
+    }
+}
+
+fn list(v: &toml::Value) -> Result<Vec<String>, String> {
+    match v {
+        toml::Value::Array(a) => a.iter().map(scalar).collect(),
+        v => Ok(vec![scalar(v)?]),
+    }
+}
+
+/// The values of each flag, and which of the flags that take no value
+/// are switched on.
+type Flags = (HashMap<String, Vec<String>>, Vec<String>);
+
+/// Reads the file into the flags it sets.
+fn read(path: &Path) -> Result<Flags, ConfigError> {
+    let contents =
+        std::fs::read_to_string(path).map_err(|e| ConfigError::IOError(path.to_owned(), e))?;
+    let table = toml::from_str::<toml::Table>(&contents)
+        .map_err(|e| ConfigError::ParseError(path.to_owned(), e))?;
+    let mut values = HashMap::new();
+    let mut switches = Vec::new();
+    for (k, v) in &table {
+        let flag = k.replace('_', "-");
+        match v {
+            toml::Value::Boolean(true) => switches.push(flag),
+            toml::Value::Boolean(false) => (),
+            v => {
+                let v = list(v)
+                    .map_err(|e| ConfigError::InvalidValue(path.to_owned(), k.clone(), e))?;
+                values.insert(flag, v);
+            }
+        }
+    }
+    Ok((values, switches))
+}
+
+/// The command line with the flags from `--config`, if it is given,
+/// put in front of the ones given.
+pub fn argv() -> Result<Vec<OsString>, ConfigError> {
+    let mut args = std::env::args_os();
+    let argv0 = args.next().unwrap_or_default();
+    let given_args = args.collect::<Vec<_>>();
+    let mut path = None;
+    let mut given = HashSet::new();
+    let mut it = given_args.iter();
+    while let Some(arg) = it.next() {
+        let Some(flag) = arg.to_str().and_then(|a| a.strip_prefix("--")) else {
+            continue;
+        };
+        let (name, value) = match flag.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (flag, None),
+        };
+        if name == "config" {
+            path = Some(PathBuf::from(match value {
+                Some(v) => OsString::from(v),
+                None => it.next().ok_or(ConfigError::MissingPath)?.clone(),
+            }));
+        }
+        given.insert(String::from(name));
+    }
+    let mut argv = vec![argv0];
+    if let Some(path) = path {
+        let (values, switches) = read(&path)?;
+        for flag in switches.iter().filter(|f| !given.contains(*f)) {
+            argv.push(format!("--{flag}").into());
+        }
+        for (flag, values) in values.iter().filter(|(f, _)| !given.contains(*f)) {
+            argv.extend(values.iter().map(|v| format!("--{flag}={v}").into()));
+        }
+        let _ = LOADED.set(Loaded {
+            path,
+            given,
+            values: RwLock::new(values),
+        });
+    }
+    argv.extend(given_args);
+    Ok(argv)
+}
+
+/// The values that `--config` currently gives a flag, or `None` if
+/// there is no config file or the flag was given on the command line.
+pub fn values(flag: &str) -> Option<Vec<String>> {
+    let loaded = LOADED.get()?;
+    if loaded.given.contains(flag) {
+        return None;
+    }
+    Some(
+        loaded
+            .values
+            .read()
+            .unwrap()
+            .get(flag)
+            .cloned()
+            .unwrap_or_default(),
+    )
+}
+
+/// Like `values`, parsed.
+pub fn parsed<T: std::str::FromStr<Err = String>>(flag: &str) -> Option<Result<Vec<T>, String>> {
+    values(flag).map(|values| {
+        values
+            .iter()
+            .map(|v| v.parse().map_err(|e| format!("--{flag}={v}: {e}")))
+            .collect()
+    })
+}
+
+#[derive(clap::Args)]
+pub struct ConfigArgs {
+    /// TOML file of flag values, keyed by flag name. Flags given on the
+    /// command line take precedence. It is read again on SIGHUP.
+    #[arg(long)]
+    config: Option<PathBuf>,
+}
+
+/// Tells the parts which can be reconfigured without a restart when to
+/// reload, which is on SIGHUP. They reload their own files then too.
+pub struct Reloader(tokio::sync::watch::Sender<()>);
+
+#[resource]
+impl Resource for Reloader {
+    fn new(
+        _: comprehensive::NoDependencies,
+        _: ConfigArgs,
+        api: &mut AssemblyRuntime<'_>,
+    ) -> Result<Arc<Self>, std::io::Error> {
+        let mut hangups = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+        let shared = Arc::new(Self(tokio::sync::watch::Sender::new(())));
+        let shared2 = Arc::clone(&shared);
+        api.set_task(async move {
+            while hangups.recv().await.is_some() {
+                if let Some(loaded) = LOADED.get() {
+                    match read(&loaded.path) {
+                        Ok((values, _)) => {
+                            *loaded.values.write().unwrap() = values;
+                            log::info!("Reloaded {}", loaded.path.display());
+                        }
+                        Err(e) => {
+                            log::error!("Reloading config: {e}");
+                            continue;
+                        }
+                    }
+                }
+                shared2.0.send_replace(());
+            }
+            Ok(())
+        });
+        Ok(shared)
+    }
+}
+
+impl Reloader {
+    /// Changes whenever it is time to reload.
+    pub fn subscribe(&self) -> tokio::sync::watch::Receiver<()> {
+        self.0.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::file;
+
+    fn parse(toml: &str) -> Result<Flags, ConfigError> {
+        read(file(toml).path())
+    }
+
+    #[test]
+    fn flags() {
+        let (values, switches) = parse(
+            r#"
+            http_port = 8080
+            allow_spiffe = ["spiffe://example.com/a", "spiffe://example.com/b"]
+            state_read_only = true
+            verbose = false
+            "#,
+        )
+        .unwrap();
+        assert_eq!(values["http-port"], ["8080"]);
+        assert_eq!(
+            values["allow-spiffe"],
+            ["spiffe://example.com/a", "spiffe://example.com/b"]
+        );
+        assert_eq!(switches, ["state-read-only"]);
+        assert_eq!(values.len(), 2);
+    }
+
+    #[test]
+    fn tables() {
+        let (values, _) = parse(
+            r#"
+            [[route]]
+            match = { team = "db", severity = "critical" }
+            to = ["group:abc", "+15551234567"]
+            resolved = "reply"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            values["route"],
+            ["severity=critical,team=db=>resolved=reply,group:abc,+15551234567"]
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(matches!(
+            parse("route = [[1]]"),
+            Err(ConfigError::InvalidValue(_, _, _))
+        ));
+        assert!(matches!(
+            parse("route = { match = 1 }"),
+            Err(ConfigError::InvalidValue(_, _, _))
+        ));
+        assert!(matches!(parse("= 1"), Err(ConfigError::ParseError(_, _))));
+    }
+}
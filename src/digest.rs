@@ -0,0 +1,223 @@
+//! Alerts which are not worth a page of their own, like those with
+//! `severity=info`, held back and sent together in one summary message
+//! every so often.
+
+use comprehensive::ResourceDependencies;
+use comprehensive::v1::{AssemblyRuntime, Resource, resource};
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::http::filter::Matchers;
+use crate::page::PageMeta;
+
+const MAX_HELD: usize = 1000;
+const APP_DATA_NAME: &str = "digest.json";
+
+/// An alert waiting for the next digest.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct HeldPage {
+    pub id: u64,
+    pub message: String,
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    #[serde(default)]
+    pub recipients: Vec<String>,
+    #[serde(default)]
+    pub group: Option<String>,
+    #[serde(default)]
+    pub target: Option<String>,
+    pub received: Timestamp,
+}
+
+impl HeldPage {
+    pub fn meta(&self) -> PageMeta {
+        PageMeta {
+            labels: self.labels.clone(),
+            recipients: self.recipients.clone(),
+            group: self.group.clone(),
+            target: self.target.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct Inner {
+    next_id: u64,
+    /// Oldest first.
+    held: VecDeque<HeldPage>,
+    /// When the last digest was sent, from which the next is due.
+    last_sent: Timestamp,
+}
+
+#[derive(ResourceDependencies)]
+pub struct DigestDependencies(Arc<crate::state::SignalState>);
+
+#[derive(clap::Args)]
+pub struct DigestArgs {
+    /// Instead of paging for alerts whose labels match, like
+    /// `severity=info`, send them summarised in one message every
+    /// `--digest-interval`. May be repeated.
+    #[arg(long)]
+    digest_matching: Vec<Matchers>,
+    /// How often to send the digest of alerts held back by
+    /// `--digest-matching`, like `1h` or `24h`.
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "1h")]
+    digest_interval: Duration,
+}
+
+/// The alerts held back for the digest, which are kept in the state so
+/// that they survive restarts.
+pub struct Digest {
+    matchers: Vec<Matchers>,
+    interval: Duration,
+    inner: Mutex<Inner>,
+    changed: tokio::sync::Notify,
+}
+
+#[resource]
+impl Resource for Digest {
+    fn new(
+        d: DigestDependencies,
+        a: DigestArgs,
+        api: &mut AssemblyRuntime<'_>,
+    ) -> Result<Arc<Self>, std::convert::Infallible> {
+        let shared = Arc::new(Self {
+            matchers: a.digest_matching,
+            interval: a.digest_interval,
+            inner: Mutex::new(Inner {
+                next_id: 1,
+                held: VecDeque::new(),
+                last_sent: Timestamp::now(),
+            }),
+            changed: tokio::sync::Notify::new(),
+        });
+        if shared.matchers.is_empty() {
+            return Ok(shared);
+        }
+        let shared2 = Arc::clone(&shared);
+        let state = d.0;
+        api.set_task(async move {
+            state.wait_available().await;
+            match state.get().await.read_app_data::<Inner>(APP_DATA_NAME) {
+                Ok(Some(saved)) => shared2.merge(saved),
+                Ok(None) => (),
+                Err(e) => log::error!("Loading digest from state: {e}"),
+            }
+            loop {
+                shared2.changed.notified().await;
+                let guard = state.get().await;
+                let r = {
+                    let inner = shared2.inner.lock().unwrap();
+                    guard.write_app_data(APP_DATA_NAME, &*inner)
+                };
+                if let Err(e) = r {
+                    log::error!("Saving digest to state: {e}");
+                }
+            }
+        });
+        Ok(shared)
+    }
+}
+
+impl Digest {
+    /// Alerts held back before the state was loaded are kept, after the
+    /// saved ones, and the schedule carries on from the saved one.
+    fn merge(&self, saved: Inner) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_id = inner.next_id.max(saved.next_id);
+        let mut held = saved.held;
+        held.extend(inner.held.drain(..));
+        let excess = held.len().saturating_sub(MAX_HELD);
+        held.drain(..excess);
+        inner.held = held;
+        inner.last_sent = saved.last_sent;
+        log::info!(
+            "Loaded {} alerts for the digest from state",
+            inner.held.len()
+        );
+    }
+
+    /// If the page is for the digest, holds it back and returns true.
+    pub fn hold(&self, msg: &str, meta: &PageMeta) -> bool {
+        if !self.matchers.iter().any(|m| m.matches(&meta.labels)) {
+            return false;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.held.push_back(HeldPage {
+            id,
+            message: String::from(msg),
+            labels: meta.labels.clone(),
+            recipients: meta.recipients.clone(),
+            group: meta.group.clone(),
+            target: meta.target.clone(),
+            received: Timestamp::now(),
+        });
+        if inner.held.len() > MAX_HELD {
+            inner.held.pop_front();
+        }
+        drop(inner);
+        self.changed.notify_one();
+        true
+    }
+
+    /// Resolves once the next digest is due, and never if there is no
+    /// `--digest-matching`.
+    pub async fn wait_due(&self) {
+        if self.matchers.is_empty() {
+            return std::future::pending().await;
+        }
+        loop {
+            // Loading the state may move the schedule, so look again.
+            let last_sent = self.inner.lock().unwrap().last_sent;
+            let since =
+                Duration::try_from(Timestamp::now().duration_since(last_sent)).unwrap_or_default();
+            match self.interval.checked_sub(since) {
+                Some(wait) if !wait.is_zero() => tokio::time::sleep(wait).await,
+                _ => return,
+            }
+        }
+    }
+
+    /// The alerts for the digest which is due, oldest first, and when
+    /// the previous digest was sent.
+    pub fn pending(&self) -> (Vec<HeldPage>, Timestamp) {
+        let inner = self.inner.lock().unwrap();
+        (inner.held.iter().cloned().collect(), inner.last_sent)
+    }
+
+    /// Forgets the alerts which were sent and starts the next interval.
+    pub fn record_sent(&self, ids: &[u64]) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.held.retain(|p| !ids.contains(&p.id));
+        inner.last_sent = Timestamp::now();
+        drop(inner);
+        self.changed.notify_one();
+    }
+}
+
+/// One line for each different alert, with how many times it came.
+pub fn summarise(pages: &[&HeldPage]) -> String {
+    let mut lines: Vec<(&str, usize)> = Vec::new();
+    for page in pages {
+        let line = page.message.lines().next().unwrap_or_default();
+        match lines.iter_mut().find(|(l, _)| *l == line) {
+            Some((_, n)) => *n += 1,
+            None => lines.push((line, 1)),
+        }
+    }
+    let mut summary = String::new();
+    for (line, n) in lines {
+        if n > 1 {
+            summary += &format!("\n{n}x {line}");
+        } else {
+            summary += &format!("\n{line}");
+        }
+    }
+    summary
+}
@@ -0,0 +1,334 @@
+use comprehensive::ResourceDependencies;
+use comprehensive::v1::{AssemblyRuntime, Resource, resource};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::alerts::{AlertRecord, Alerts};
+use crate::signal::{SignalRunner, Target};
+
+const CHECK_INTERVAL: Duration = Duration::new(30, 0);
+
+#[derive(Debug, thiserror::Error)]
+pub enum EscalatorError {
+    #[error("--escalation-policy requires --enable-acks")]
+    AcksDisabled,
+    #[error("More than one --escalation-policy for severity {0:?}")]
+    DuplicatePolicy(Option<String>),
+}
+
+/// What to do about a page that nobody acknowledges. Parsed from
+/// `severity=critical,repeat=5m,repeats=2,escalate=group:ID,escalate=+1555...`
+/// where all of the keys are optional and `escalate` may be repeated.
+#[derive(Clone, Debug)]
+pub struct Policy {
+    severity: Option<String>,
+    repeat_after: Duration,
+    repeats: u32,
+    escalate: Vec<Target>,
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut policy = Self {
+            severity: None,
+            repeat_after: Duration::new(300, 0),
+            repeats: 0,
+            escalate: Vec::new(),
+        };
+        for item in s.split(',') {
+            let Some((k, v)) = item.split_once('=') else {
+                return Err(format!("Expected key=value, got {item:?}"));
+            };
+            match k {
+                "severity" => policy.severity = Some(String::from(v)),
+                "repeat" => {
+                    policy.repeat_after = humantime::parse_duration(v).map_err(|e| e.to_string())?
+                }
+                "repeats" => policy.repeats = v.parse().map_err(|e| format!("repeats: {e}"))?,
+                "escalate" => policy.escalate.push(v.parse().map_err(|e| format!("{e}"))?),
+                _ => return Err(format!("Unknown escalation policy key {k:?}")),
+            }
+        }
+        Ok(policy)
+    }
+}
+
+enum Action<'a> {
+    Repeat,
+    Escalate(&'a [Target]),
+    /// The policy, if any, is done with the alert but it is still firing.
+    Remind,
+}
+
+impl Policy {
+    fn action(&self, alert: &AlertRecord, now: SystemTime) -> Option<Action<'_>> {
+        if alert.escalated || alert.resolved {
+            return None;
+        }
+        if now.duration_since(alert.last_notified).unwrap_or_default() < self.repeat_after {
+            return None;
+        }
+        if alert.repeats < self.repeats {
+            Some(Action::Repeat)
+        } else if !self.escalate.is_empty() {
+            Some(Action::Escalate(&self.escalate))
+        } else {
+            None
+        }
+    }
+
+    /// Whether the policy has nothing more to do about the alert.
+    fn exhausted(&self, alert: &AlertRecord) -> bool {
+        alert.escalated || (alert.repeats >= self.repeats && self.escalate.is_empty())
+    }
+}
+
+#[derive(ResourceDependencies)]
+pub struct EscalatorDependencies {
+    signal: Arc<SignalRunner>,
+    alerts: Arc<Alerts>,
+}
+
+#[derive(clap::Args)]
+pub struct EscalatorArgs {
+    /// Repeat unacknowledged pages and then send them to other targets.
+    /// May be given once per severity label value, plus once without a
+    /// severity for pages that match none of the others.
+    #[arg(long)]
+    escalation_policy: Vec<Policy>,
+    /// Once any escalation policy is done with a page, send it again
+    /// this often until its alert resolves or it is acknowledged.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    reminder_interval: Option<Duration>,
+}
+
+pub struct Escalator {
+    signal: Arc<SignalRunner>,
+    alerts: Arc<Alerts>,
+    default_policy: Option<Policy>,
+    policies: HashMap<String, Policy>,
+    reminder_interval: Option<Duration>,
+}
+
+#[resource]
+impl Resource for Escalator {
+    fn new(
+        d: EscalatorDependencies,
+        a: EscalatorArgs,
+        api: &mut AssemblyRuntime<'_>,
+    ) -> Result<Arc<Self>, EscalatorError> {
+        if !a.escalation_policy.is_empty() && !d.signal.acks_enabled() {
+            return Err(EscalatorError::AcksDisabled);
+        }
+        let mut default_policy = None;
+        let mut policies = HashMap::new();
+        for policy in a.escalation_policy {
+            let severity = policy.severity.clone();
+            let previous = match severity {
+                None => default_policy.replace(policy),
+                Some(ref s) => policies.insert(s.clone(), policy),
+            };
+            if previous.is_some() {
+                return Err(EscalatorError::DuplicatePolicy(severity));
+            }
+        }
+        let enabled =
+            default_policy.is_some() || !policies.is_empty() || a.reminder_interval.is_some();
+        let shared = Arc::new(Self {
+            signal: d.signal,
+            alerts: d.alerts,
+            default_policy,
+            policies,
+            reminder_interval: a.reminder_interval,
+        });
+        if enabled {
+            let shared2 = Arc::clone(&shared);
+            api.set_task(async move {
+                loop {
+                    tokio::time::sleep(CHECK_INTERVAL).await;
+                    shared2.check().await;
+                }
+            });
+        }
+        Ok(shared)
+    }
+}
+
+impl Escalator {
+    fn policy_for(&self, alert: &AlertRecord) -> Option<&Policy> {
+        alert
+            .labels
+            .get("severity")
+            .and_then(|s| self.policies.get(s))
+            .or(self.default_policy.as_ref())
+    }
+
+    /// Whether to remind everyone about an alert that its policy, if any,
+    /// is done with. Only the latest page about each alert is repeated,
+    /// since Alertmanager also sends firing alerts again from time to
+    /// time.
+    fn reminder_due(
+        &self,
+        alert: &AlertRecord,
+        policy: Option<&Policy>,
+        latest: &HashMap<String, u64>,
+        now: SystemTime,
+    ) -> bool {
+        let Some(interval) = self.reminder_interval else {
+            return false;
+        };
+        !alert.resolved
+            && policy.is_none_or(|p| p.exhausted(alert))
+            && alert
+                .key()
+                .is_none_or(|k| latest.get(&k) == Some(&alert.id))
+            && now.duration_since(alert.last_notified).unwrap_or_default() >= interval
+    }
+
+    async fn check(&self) {
+        let now = SystemTime::now();
+        let mut latest = HashMap::new();
+        for alert in self.alerts.list() {
+            if let Some(key) = alert.key() {
+                latest.entry(key).or_insert(alert.id);
+            }
+        }
+        for alert in self.alerts.unacked() {
+            let policy = self.policy_for(&alert);
+            let action = match policy.and_then(|p| p.action(&alert, now)) {
+                Some(action) => action,
+                None if self.reminder_due(&alert, policy, &latest, now) => Action::Remind,
+                None => continue,
+            };
+            match action {
+                Action::Repeat => {
+                    let msg = format!("#{} Unacknowledged: {}", alert.id, alert.message);
+                    self.repeat(&alert, &msg).await;
+                }
+                Action::Remind => {
+                    let msg = if self.signal.acks_enabled() {
+                        format!("#{} Still firing: {}", alert.id, alert.message)
+                    } else {
+                        format!("Still firing: {}", alert.message)
+                    };
+                    self.repeat(&alert, &msg).await;
+                }
+                Action::Escalate(targets) => {
+                    let msg = format!("#{} Escalated: {}", alert.id, alert.message);
+                    let mut sent = None;
+                    for target in targets {
+                        match self.signal.send(target, &msg, None).await {
+                            Ok(ts) => sent = Some(ts),
+                            Err(e) => log::error!("Escalating alert {} to {target}: {e}", alert.id),
+                        }
+                    }
+                    if let Some(ts) = sent {
+                        self.alerts.record_renotified(alert.id, true, ts);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends an alert again to wherever it was sent.
+    async fn repeat(&self, alert: &AlertRecord, msg: &str) {
+        let targets = if alert.targets.is_empty() {
+            vec![self.signal.default_target()]
+        } else {
+            alert.targets.clone()
+        };
+        let mut sent = None;
+        for target in &targets {
+            match self.signal.send(target, msg, None).await {
+                Ok(ts) => sent = Some(ts),
+                Err(e) => log::error!("Repeating alert {} to {target}: {e}", alert.id),
+            }
+        }
+        if let Some(ts) = sent {
+            self.alerts.record_renotified(alert.id, false, ts);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alerts::Status;
+
+    fn alert(last_notified: SystemTime, repeats: u32) -> AlertRecord {
+        AlertRecord {
+            id: 1,
+            source: String::from("test"),
+            message: String::from("disk full"),
+            labels: HashMap::new(),
+            fingerprint: None,
+            targets: Vec::new(),
+            created: last_notified,
+            last_notified,
+            sent_timestamps: Vec::new(),
+            sent_to: Vec::new(),
+            ack: None,
+            repeats,
+            escalated: false,
+            resolved: false,
+            status: Status::Sent,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn parse() {
+        let p: Policy =
+            "severity=critical,repeat=1m,repeats=2,escalate=group:abc,escalate=+15550001"
+                .parse()
+                .unwrap();
+        assert_eq!(p.severity.as_deref(), Some("critical"));
+        assert_eq!(p.repeat_after, Duration::new(60, 0));
+        assert_eq!(p.repeats, 2);
+        assert_eq!(
+            p.escalate,
+            vec![
+                Target::Group(String::from("abc")),
+                Target::Recipient(String::from("+15550001"))
+            ]
+        );
+        assert!("repeats".parse::<Policy>().is_err());
+        assert!("colour=red".parse::<Policy>().is_err());
+        assert!("repeat=soon".parse::<Policy>().is_err());
+    }
+
+    #[test]
+    fn repeats_then_escalates() {
+        let p: Policy = "repeat=5m,repeats=1,escalate=+15550001".parse().unwrap();
+        let now = SystemTime::now();
+        let recent = now - Duration::new(60, 0);
+        let old = now - Duration::new(600, 0);
+        assert!(p.action(&alert(recent, 0), now).is_none());
+        assert!(matches!(
+            p.action(&alert(old, 0), now),
+            Some(Action::Repeat)
+        ));
+        assert!(matches!(
+            p.action(&alert(old, 1), now),
+            Some(Action::Escalate([Target::Recipient(_)]))
+        ));
+        let mut done = alert(old, 1);
+        done.escalated = true;
+        assert!(p.action(&done, now).is_none());
+        let mut resolved = alert(old, 0);
+        resolved.resolved = true;
+        assert!(p.action(&resolved, now).is_none());
+    }
+
+    #[test]
+    fn nothing_to_escalate_to() {
+        let p: Policy = "repeats=1".parse().unwrap();
+        let now = SystemTime::now();
+        let old = now - Duration::new(600, 0);
+        assert!(p.action(&alert(old, 1), now).is_none());
+    }
+}
@@ -0,0 +1,269 @@
+//! Other ways to reach people when Signal cannot deliver a page, so that
+//! a Signal outage does not silence paging altogether.
+
+use comprehensive::v1::{AssemblyRuntime, Resource, resource};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+#[derive(Debug, thiserror::Error)]
+pub enum FallbackError {
+    #[error("More than one --fallback for severity {0:?}")]
+    DuplicateChain(Option<String>),
+    #[error("--fallback to {0} requires --smtp-url and --smtp-from")]
+    NoSmtp(String),
+    #[error("--smtp-url: {0}")]
+    SmtpUrl(#[from] lettre::transport::smtp::Error),
+    #[error("--smtp-from: {0}")]
+    InvalidFrom(#[from] lettre::address::AddressError),
+    #[error("--smtp-password-file: {0}")]
+    IOError(#[from] std::io::Error),
+    #[error("--smtp-password-file requires --smtp-username")]
+    NoUsername,
+}
+
+/// Somewhere other than Signal to send a page.
+#[derive(Clone, Debug)]
+pub enum Transport {
+    Email(String),
+    Webhook(String),
+}
+
+impl FromStr for Transport {
+    type Err = String;
+
+    /// `mailto:ADDRESS` or an `http://` or `https://` URL.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(address) = s.strip_prefix("mailto:") {
+            Ok(Self::Email(String::from(address)))
+        } else if s.starts_with("http://") || s.starts_with("https://") {
+            Ok(Self::Webhook(String::from(s)))
+        } else {
+            Err(format!("Expected mailto:ADDRESS or a URL, got {s:?}"))
+        }
+    }
+}
+
+impl std::fmt::Display for Transport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Self::Email(address) => write!(f, "mailto:{address}"),
+            Self::Webhook(url) => f.write_str(url),
+        }
+    }
+}
+
+impl Transport {
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Email(_) => "email",
+            Self::Webhook(_) => "webhook",
+        }
+    }
+}
+
+/// The transports to try in turn for pages of a severity. Parsed from
+/// `severity=critical,mailto:oncall@example.com,https://hooks.example.com/x`
+/// where `severity` is optional and the transports may also be given as
+/// `to=TRANSPORT`.
+#[derive(Clone, Debug)]
+pub struct Chain {
+    severity: Option<String>,
+    transports: Vec<Transport>,
+}
+
+impl FromStr for Chain {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut chain = Self {
+            severity: None,
+            transports: Vec::new(),
+        };
+        for item in s.split(',') {
+            // URLs may have an `=` in them, so look for transports first.
+            if let Ok(t) = item.parse() {
+                chain.transports.push(t);
+                continue;
+            }
+            match item.split_once('=') {
+                Some(("severity", v)) => chain.severity = Some(String::from(v)),
+                Some(("to", v)) => chain.transports.push(v.parse()?),
+                Some((k, _)) => return Err(format!("Unknown fallback key {k:?}")),
+                None => return Err(format!("Expected key=value or a transport, got {item:?}")),
+            }
+        }
+        if chain.transports.is_empty() {
+            return Err(String::from(
+                "A fallback chain needs at least one transport",
+            ));
+        }
+        Ok(chain)
+    }
+}
+
+#[derive(clap::Args)]
+pub struct FallbackArgs {
+    /// Where to send pages which Signal fails to deliver, tried in
+    /// order until one works, like
+    /// `severity=critical,mailto:oncall@example.com,https://hooks.example.com/x`.
+    /// A chain without a severity is for pages whose severity has no
+    /// chain of its own. May be repeated.
+    #[arg(long)]
+    fallback: Vec<Chain>,
+    /// Fall back once a queued page has failed to send this many times.
+    /// Pages sent synchronously fall back on their first failure.
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    fallback_after: u32,
+    /// SMTP server for `mailto:` fallbacks, like `smtps://smtp.example.com`
+    /// or `smtp://localhost:25`.
+    #[arg(long)]
+    smtp_url: Option<String>,
+    #[arg(long)]
+    smtp_username: Option<String>,
+    /// File whose first line is the password for `--smtp-username`.
+    #[arg(long)]
+    smtp_password_file: Option<PathBuf>,
+    /// Sender address of fallback email.
+    #[arg(long)]
+    smtp_from: Option<String>,
+}
+
+pub struct Fallback {
+    chains: Vec<Chain>,
+    after: u32,
+    smtp: Option<(AsyncSmtpTransport<Tokio1Executor>, lettre::message::Mailbox)>,
+    http: reqwest::Client,
+}
+
+#[resource]
+impl Resource for Fallback {
+    fn new(
+        _: comprehensive::NoDependencies,
+        a: FallbackArgs,
+        _: &mut AssemblyRuntime<'_>,
+    ) -> Result<Arc<Self>, FallbackError> {
+        let mut severities = HashSet::new();
+        for chain in &a.fallback {
+            if !severities.insert(chain.severity.clone()) {
+                return Err(FallbackError::DuplicateChain(chain.severity.clone()));
+            }
+        }
+        let smtp = match (a.smtp_url, a.smtp_from) {
+            (Some(url), Some(from)) => {
+                let mut builder = AsyncSmtpTransport::<Tokio1Executor>::from_url(&url)?;
+                match (a.smtp_username, a.smtp_password_file) {
+                    (Some(username), Some(path)) => {
+                        let contents = std::fs::read_to_string(path)?;
+                        let password = contents.lines().next().unwrap_or_default();
+                        builder =
+                            builder.credentials(Credentials::new(username, String::from(password)));
+                    }
+                    (None, Some(_)) => return Err(FallbackError::NoUsername),
+                    (_, None) => (),
+                }
+                Some((builder.build(), from.parse()?))
+            }
+            _ => None,
+        };
+        if smtp.is_none() {
+            let email = a
+                .fallback
+                .iter()
+                .flat_map(|c| &c.transports)
+                .find(|t| matches!(t, Transport::Email(_)));
+            if let Some(t) = email {
+                return Err(FallbackError::NoSmtp(t.to_string()));
+            }
+        }
+        Ok(Arc::new(Self {
+            chains: a.fallback,
+            after: a.fallback_after,
+            smtp,
+            http: reqwest::Client::new(),
+        }))
+    }
+}
+
+impl Fallback {
+    /// How many times a queued page fails before it falls back.
+    pub fn after(&self) -> u32 {
+        self.after
+    }
+
+    fn chain(&self, labels: &HashMap<String, String>) -> Option<&Chain> {
+        let severity = labels.get("severity");
+        self.chains
+            .iter()
+            .find(|c| c.severity.is_some() && c.severity.as_ref() == severity)
+            .or_else(|| self.chains.iter().find(|c| c.severity.is_none()))
+    }
+
+    /// Sends a page which Signal could not deliver along the chain for
+    /// its severity, stopping at the first transport which works.
+    pub async fn send(&self, id: u64, labels: &HashMap<String, String>, msg: &str, error: &str) {
+        let Some(chain) = self.chain(labels) else {
+            return;
+        };
+        let msg = msg.replace(
+            [crate::render::MENTION_START, crate::render::MENTION_END],
+            "",
+        );
+        let text = format!("{msg}\n\n-- \nPage {id} could not be sent over Signal: {error}");
+        for t in &chain.transports {
+            let r = match t {
+                Transport::Email(address) => self.email(address, id, &text).await,
+                Transport::Webhook(url) => self.webhook(url, id, labels, &text).await,
+            };
+            crate::metrics::FALLBACKS
+                .with_label_values(&[t.label(), crate::metrics::result_label(&r)])
+                .inc();
+            match r {
+                Ok(()) => {
+                    log::warn!("Page {id} fell back to {t}");
+                    return;
+                }
+                Err(e) => log::error!("Falling back to {t} for page {id}: {e}"),
+            }
+        }
+        log::error!("Page {id} could not be sent any other way either");
+    }
+
+    async fn email(&self, address: &str, id: u64, text: &str) -> Result<(), String> {
+        let Some((ref mailer, ref from)) = self.smtp else {
+            return Err(String::from("no SMTP server"));
+        };
+        let message = Message::builder()
+            .from(from.clone())
+            .to(address.parse().map_err(|e| format!("{e}"))?)
+            .subject(format!("Page {id} could not be sent over Signal"))
+            .body(String::from(text))
+            .map_err(|e| e.to_string())?;
+        mailer.send(message).await.map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    async fn webhook(
+        &self,
+        url: &str,
+        id: u64,
+        labels: &HashMap<String, String>,
+        text: &str,
+    ) -> Result<(), String> {
+        self.http
+            .post(url)
+            .json(&serde_json::json!({
+                "text": text,
+                "alert_id": id,
+                "labels": labels,
+            }))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+}
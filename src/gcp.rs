@@ -0,0 +1,49 @@
+//! Access tokens from the GCE metadata server, which is also what serves
+//! GKE workload identity.
+
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+
+const METADATA_TOKEN_URL: &str =
+    "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token";
+const TOKEN_REFRESH_MARGIN: Duration = Duration::new(60, 0);
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+struct Token {
+    value: String,
+    expires: Instant,
+}
+
+/// Caches the token until shortly before it expires.
+#[derive(Default)]
+pub struct MetadataToken(tokio::sync::Mutex<Option<Token>>);
+
+impl MetadataToken {
+    pub async fn get(&self, client: &reqwest::Client) -> Result<String, reqwest::Error> {
+        let mut token = self.0.lock().await;
+        if let Some(ref t) = *token
+            && Instant::now() < t.expires
+        {
+            return Ok(t.value.clone());
+        }
+        let r: TokenResponse = client
+            .get(METADATA_TOKEN_URL)
+            .header("Metadata-Flavor", "Google")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let lifetime = Duration::new(r.expires_in, 0).saturating_sub(TOKEN_REFRESH_MARGIN);
+        *token = Some(Token {
+            value: r.access_token.clone(),
+            expires: Instant::now() + lifetime,
+        });
+        Ok(r.access_token)
+    }
+}
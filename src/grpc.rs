@@ -1,25 +1,82 @@
 use comprehensive::v1::{AssemblyRuntime, Resource, resource};
 use itertools::Itertools;
-use std::collections::HashSet;
-use std::sync::Arc;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tonic::{Code, Status};
+use tracing::Instrument;
 use x509_parser::certificate::X509Certificate;
 use x509_parser::prelude::FromDer;
 
-mod pb {
+use crate::alert::{AlertInput, GroupContext, render_alert};
+use crate::page::{Attachment, AttachmentData, PageMeta};
+use crate::ratelimit;
+use crate::render::Renderer;
+use crate::signal::{Delivery, PageOutcome};
+
+mod acl;
+pub mod admin;
+pub mod health;
+mod idempotency;
+mod token;
+pub mod unix;
+pub mod web;
+
+pub mod pb {
     tonic::include_proto!("pager");
     pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("fdset");
 }
 
+/// Answer this long before the caller's deadline, so that the answer
+/// gets back in time.
+const DEADLINE_MARGIN: Duration = Duration::from_millis(100);
+
 pub struct PagerService {
     signal: Arc<crate::signal::SignalRunner>,
-    acl: HashSet<String>,
+    alerts: Arc<crate::alerts::Alerts>,
+    oncall: Arc<crate::oncall::OnCall>,
+    acl: Arc<RwLock<acl::Acl>>,
+    tokens: token::Tokens,
+    paging: Arc<Paging>,
+}
+
+/// What sending a page needs, apart from the RPC it came in on.
+struct Paging {
+    signal: Arc<crate::signal::SignalRunner>,
+    renderer: Arc<Renderer>,
+    limiter: ratelimit::RateLimiter,
+    idempotency: idempotency::Idempotency,
 }
 
 #[derive(clap::Args)]
 pub struct PagerServiceArgs {
+    /// SPIFFE ID allowed to call us. May be repeated. `*` matches any
+    /// one path segment, or any number of them at the end, and a bare
+    /// trust domain like `spiffe://example.com` allows all of it. May end
+    /// with `=` and a target like `group:GROUP_ID`, where the pages of
+    /// callers it matches are sent instead of wherever they are routed.
     #[arg(long)]
     allow_spiffe: Vec<String>,
+    /// File of more `--allow-spiffe` patterns, one per line. It is
+    /// reloaded whenever it changes, and on SIGHUP.
+    #[arg(long)]
+    acl_file: Option<PathBuf>,
+    /// Accept at most this many pages per caller over this long, like
+    /// `30/1h`. Up to the whole number can come at once.
+    #[arg(long)]
+    page_rate_limit: Option<ratelimit::Rate>,
+    /// A different rate limit for one caller, like
+    /// `spiffe://example.com/ns/monitoring/sa/alertmanager=100/1h`. May
+    /// be repeated.
+    #[arg(long)]
+    page_rate_limit_override: Vec<ratelimit::Override>,
+    /// Remember the response to a page with an `idempotency_key` for
+    /// this long, and give it again instead of paging again if the same
+    /// caller retries with the same key.
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "1h")]
+    idempotency_window: Duration,
+    #[command(flatten)]
+    tokens: token::TokenArgs,
 }
 
 #[resource]
@@ -27,55 +84,474 @@ pub struct PagerServiceArgs {
 #[proto_descriptor(pb::FILE_DESCRIPTOR_SET)]
 impl Resource for PagerService {
     fn new(
-        d: (Arc<crate::signal::SignalRunner>,),
+        d: (
+            Arc<crate::signal::SignalRunner>,
+            Arc<crate::alerts::Alerts>,
+            Arc<crate::oncall::OnCall>,
+            Arc<crate::config::Reloader>,
+            Arc<Renderer>,
+        ),
         args: PagerServiceArgs,
-        _: &mut AssemblyRuntime<'_>,
-    ) -> Result<Arc<Self>, std::convert::Infallible> {
+        api: &mut AssemblyRuntime<'_>,
+    ) -> Result<Arc<Self>, std::io::Error> {
+        let source = acl::AclSource {
+            flag: "allow-spiffe",
+            flags: args.allow_spiffe,
+            file: args.acl_file,
+        };
         Ok(Arc::new(Self {
-            signal: d.0,
-            acl: args.allow_spiffe.into_iter().collect(),
+            signal: Arc::clone(&d.0),
+            alerts: d.1,
+            oncall: d.2,
+            acl: load_acl(source, &d.3, api)?,
+            tokens: token::Tokens::new(args.tokens).map_err(std::io::Error::other)?,
+            paging: Arc::new(Paging {
+                signal: d.0,
+                renderer: d.4,
+                limiter: ratelimit::RateLimiter::new(
+                    args.page_rate_limit,
+                    args.page_rate_limit_override,
+                ),
+                idempotency: idempotency::Idempotency::new(args.idempotency_window),
+            }),
         }))
     }
 }
 
+/// Loads the ACL and keeps reloading it when its file changes and on
+/// SIGHUP.
+fn load_acl(
+    source: acl::AclSource,
+    reloader: &crate::config::Reloader,
+    api: &mut AssemblyRuntime<'_>,
+) -> Result<Arc<RwLock<acl::Acl>>, std::io::Error> {
+    let shared = Arc::new(RwLock::new(source.load()?));
+    let shared2 = Arc::clone(&shared);
+    let reloads = reloader.subscribe();
+    api.set_task(async move {
+        let path = source.file.clone();
+        crate::watch::watch_and_reload(path.as_deref(), reloads, || match source.load() {
+            Ok(acl) => {
+                *shared2.write().unwrap() = acl;
+                log::info!("Reloaded --{}", source.flag);
+            }
+            Err(e) => log::error!("Reloading --{}: {e}", source.flag),
+        })
+        .await
+        .map_err(|e| Box::new(e) as Box<dyn std::error::Error>)
+    });
+    Ok(shared)
+}
+
+/// Returns the caller's SPIFFE ID if it is in the ACL.
+fn authorize<T>(acl: &RwLock<acl::Acl>, req: &tonic::Request<T>) -> Result<String, Status> {
+    authorize_with_tokens(acl, None, req)
+}
+
+/// Like `authorize`, but callers may identify themselves with a bearer
+/// token from `tokens` instead of a client certificate. Calls through
+/// `web` have no client certificate either, so the identity that
+/// `--auth-route` gave them is checked instead. Those through `unix` were
+/// already checked by their UID.
+fn authorize_with_tokens<T>(
+    acl: &RwLock<acl::Acl>,
+    tokens: Option<&token::Tokens>,
+    req: &tonic::Request<T>,
+) -> Result<String, Status> {
+    if let Some(peer) = req.extensions().get::<unix::Peer>() {
+        return Ok(peer.0.clone());
+    }
+    let id = match req.extensions().get::<crate::http::auth::Caller>() {
+        Some(caller) => caller.0.clone(),
+        None => match tokens
+            .map(|t| t.identify(req.metadata()))
+            .transpose()?
+            .flatten()
+        {
+            Some(id) => id,
+            None => spiffe_id(req)?,
+        },
+    };
+    if !acl.read().unwrap().allows(&id) {
+        return Err(Status::new(Code::PermissionDenied, "not in ACL"));
+    }
+    Ok(id)
+}
+
+fn spiffe_id<T>(req: &tonic::Request<T>) -> Result<String, Status> {
+    let certs = req
+        .peer_certs()
+        .ok_or_else(|| Status::new(Code::PermissionDenied, "no client certificate"))?;
+    let cert = certs
+        .iter()
+        .next()
+        .ok_or_else(|| Status::new(Code::PermissionDenied, "no client certificate"))?;
+    let x509 = X509Certificate::from_der(cert)
+        .map_err(|e| {
+            Status::new(
+                Code::PermissionDenied,
+                format!("error reading client certificate: {}", e),
+            )
+        })?
+        .1;
+    x509.subject_alternative_name()
+        .ok()
+        .flatten()
+        .and_then(|ext| ext.value.general_names.iter().exactly_one().ok())
+        .and_then(|gn| match gn {
+            x509_parser::extensions::GeneralName::URI(s) => Some(String::from(*s)),
+            _ => None,
+        })
+        .ok_or_else(|| Status::new(Code::PermissionDenied, "no URI SAN in certificate"))
+}
+
+fn page_response(outcome: &PageOutcome) -> pb::PageResponse {
+    let delivery = match outcome.delivery {
+        Delivery::Sent => pb::page_response::Delivery::Sent,
+        Delivery::Queued => pb::page_response::Delivery::Queued,
+        Delivery::Suppressed => pb::page_response::Delivery::Suppressed,
+        Delivery::Heartbeat => pb::page_response::Delivery::Heartbeat,
+        Delivery::Maintenance => pb::page_response::Delivery::Maintenance,
+        Delivery::Digest => pb::page_response::Delivery::Digest,
+    };
+    pb::PageResponse {
+        id: outcome.id,
+        timestamp_ms: millis(outcome.received),
+        delivery: Some(delivery.into()),
+    }
+}
+
+/// When the caller stops waiting, from the `grpc-timeout` header, less
+/// `DEADLINE_MARGIN`.
+fn deadline<T>(req: &tonic::Request<T>) -> Option<Instant> {
+    let timeout = req.metadata().get("grpc-timeout")?.to_str().ok()?;
+    let (n, unit) = timeout.split_at(timeout.len().checked_sub(1)?);
+    let n = n.parse::<u64>().ok()?;
+    let timeout = match unit {
+        "H" => Duration::from_secs(n.saturating_mul(3600)),
+        "M" => Duration::from_secs(n.saturating_mul(60)),
+        "S" => Duration::from_secs(n),
+        "m" => Duration::from_millis(n),
+        "u" => Duration::from_micros(n),
+        "n" => Duration::from_nanos(n),
+        _ => return None,
+    };
+    Instant::now().checked_add(timeout.saturating_sub(DEADLINE_MARGIN))
+}
+
+fn millis(t: SystemTime) -> Option<u64> {
+    t.duration_since(UNIX_EPOCH)
+        .ok()
+        .map(|d| d.as_millis() as u64)
+}
+
+fn command_outcome(o: crate::signal::LastOutcome) -> pb::CommandOutcome {
+    pb::CommandOutcome {
+        timestamp_ms: millis(o.time),
+        error: o.error,
+    }
+}
+
+fn on_call_shift(s: crate::oncall::Shift) -> pb::OnCallShift {
+    pb::OnCallShift {
+        rotation: Some(s.rotation),
+        recipient: Some(s.recipient),
+        until_ms: Some(s.until.as_millisecond()),
+    }
+}
+
+fn received_message(m: crate::signal::IncomingMessage) -> pb::ReceivedMessage {
+    pb::ReceivedMessage {
+        sender: Some(m.sender),
+        timestamp_ms: Some(m.timestamp),
+        group_id: m.group_id,
+        body: m.text,
+        reaction: m.reaction.map(|r| pb::received_message::Reaction {
+            emoji: Some(r.emoji),
+            target_timestamp_ms: Some(r.target_sent_timestamp),
+            remove: Some(r.is_remove),
+        }),
+    }
+}
+
+impl PagerService {
+    fn authorize<T>(&self, req: &tonic::Request<T>) -> Result<String, Status> {
+        authorize_with_tokens(&self.acl, Some(&self.tokens), req)
+    }
+
+    /// Pages in a task of its own, so that the RPC being cancelled or
+    /// running out of time cannot kill signal-cli halfway through
+    /// sending. If the deadline comes first, the caller is told so and
+    /// the page carries on, to be queued for retry if it times out.
+    #[tracing::instrument(skip(self, req, deadline))]
+    async fn page_one(
+        &self,
+        caller: &str,
+        req: pb::PageRequest,
+        deadline: Option<Instant>,
+    ) -> Result<pb::PageResponse, Status> {
+        let paging = Arc::clone(&self.paging);
+        let target = self.acl.read().unwrap().target(caller).map(String::from);
+        let caller = String::from(caller);
+        let task =
+            tokio::spawn(async move { paging.page(&caller, target, req).await }.in_current_span());
+        let r = match deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline.into(), task).await {
+                Ok(r) => r,
+                Err(_) => {
+                    return Err(Status::deadline_exceeded(
+                        "still sending the page, which will be queued for retry if it times out",
+                    ));
+                }
+            },
+            None => task.await,
+        };
+        r.map_err(|e| Status::internal(format!("paging: {e}")))?
+    }
+
+    /// For batches, where one page failing does not fail the others.
+    async fn page_result(
+        &self,
+        caller: &str,
+        req: pb::PageRequest,
+        deadline: Option<Instant>,
+    ) -> pb::PageResult {
+        match self.page_one(caller, req, deadline).await {
+            Ok(response) => pb::PageResult {
+                response: Some(response),
+                error: None,
+                code: None,
+            },
+            Err(status) => pb::PageResult {
+                response: None,
+                error: Some(String::from(status.message())),
+                code: Some(status.code().into()),
+            },
+        }
+    }
+}
+
+impl Paging {
+    /// `target` is where the caller's ACL entry sends its pages, if it
+    /// says.
+    async fn page(
+        &self,
+        caller: &str,
+        target: Option<String>,
+        mut req: pb::PageRequest,
+    ) -> Result<pb::PageResponse, Status> {
+        match req.idempotency_key.take().filter(|k| !k.is_empty()) {
+            Some(key) => {
+                self.idempotency
+                    .once(caller, key, self.page_new(caller, target, req))
+                    .await
+            }
+            None => self.page_new(caller, target, req).await,
+        }
+    }
+
+    /// Pages unless the caller is over its rate limit.
+    async fn page_new(
+        &self,
+        caller: &str,
+        target: Option<String>,
+        req: pb::PageRequest,
+    ) -> Result<pb::PageResponse, Status> {
+        if let Err(rate) = self.limiter.take(caller) {
+            log::warn!("Rejecting page from {caller} over its rate limit of {rate}");
+            return Err(Status::resource_exhausted(format!(
+                "rate limit of {rate} pages exceeded"
+            )));
+        }
+        let attachment = match (req.attachment, req.attachment_url) {
+            (Some(data), _) => Some(Attachment::Data(AttachmentData {
+                content_type: req
+                    .attachment_content_type
+                    .unwrap_or_else(|| String::from("application/octet-stream")),
+                data,
+            })),
+            (None, Some(url)) => Some(Attachment::from_url(&url)),
+            (None, None) => None,
+        };
+        let status = req.status.as_deref().unwrap_or("firing");
+        if status != "firing" && status != "resolved" {
+            return Err(Status::invalid_argument(format!(
+                "status must be firing or resolved, not {status:?}"
+            )));
+        }
+        let mut labels = req.labels;
+        if let Some(severity) = req.severity {
+            labels.insert(String::from("severity"), severity);
+        }
+        if let Some(source) = req.source {
+            labels.entry(String::from("source")).or_insert(source);
+        }
+        // Rendered as if it had come from Alertmanager, since it often
+        // did by way of a relay.
+        let message = match req.message {
+            Some(message) => message,
+            None => render_alert(
+                &self.renderer,
+                &str::to_owned,
+                &GroupContext::default(),
+                &AlertInput {
+                    status: String::from(status),
+                    labels: labels.clone(),
+                    annotations: req.annotations,
+                    starts_at: req.starts_at,
+                    ends_at: req.ends_at,
+                    generator_url: req.generator_url,
+                    fingerprint: req.fingerprint.clone(),
+                },
+            )
+            .map_err(|e| Status::invalid_argument(format!("rendering page: {e}")))?,
+        };
+        let meta = PageMeta {
+            labels,
+            resolved: status == "resolved",
+            fingerprint: req.fingerprint,
+            recipients: req.recipients,
+            group: req.group_id,
+            target,
+            attachment,
+            ..Default::default()
+        };
+        let outcome = self
+            .signal
+            .page("grpc", Some(caller), message, &meta)
+            .await?;
+        Ok(page_response(&outcome))
+    }
+}
+
 #[tonic::async_trait]
 impl pb::pager_server::Pager for PagerService {
     async fn page(
         &self,
         req: tonic::Request<pb::PageRequest>,
-    ) -> Result<tonic::Response<()>, Status> {
-        let certs = req
-            .peer_certs()
-            .ok_or_else(|| Status::new(Code::PermissionDenied, "no client certificate"))?;
-        let cert = certs
-            .iter()
-            .next()
-            .ok_or_else(|| Status::new(Code::PermissionDenied, "no client certificate"))?;
-        let x509 = X509Certificate::from_der(cert)
-            .map_err(|e| {
-                Status::new(
-                    Code::PermissionDenied,
-                    format!("error reading client certificate: {}", e),
-                )
-            })?
-            .1;
-        let cn = x509
-            .subject_alternative_name()
-            .ok()
-            .flatten()
-            .and_then(|ext| ext.value.general_names.iter().exactly_one().ok())
-            .and_then(|gn| match gn {
-                x509_parser::extensions::GeneralName::URI(s) => Some(*s),
-                _ => None,
-            })
-            .ok_or_else(|| Status::new(Code::PermissionDenied, "no URI SAN in certificate"))?;
-        if self.acl.get(cn).is_none() {
-            return Err(Status::new(Code::PermissionDenied, "not in ACL"));
+    ) -> Result<tonic::Response<pb::PageResponse>, Status> {
+        let caller = self.authorize(&req)?;
+        let span = tracing::info_span!("grpc Page");
+        crate::telemetry::set_parent(&span, req.metadata());
+        let deadline = deadline(&req);
+        Ok(tonic::Response::new(
+            self.page_one(&caller, req.into_inner(), deadline)
+                .instrument(span)
+                .await?,
+        ))
+    }
+
+    async fn page_batch(
+        &self,
+        req: tonic::Request<pb::PageBatchRequest>,
+    ) -> Result<tonic::Response<pb::PageBatchResponse>, Status> {
+        let caller = self.authorize(&req)?;
+        let span = tracing::info_span!("grpc PageBatch");
+        crate::telemetry::set_parent(&span, req.metadata());
+        let deadline = deadline(&req);
+        let mut results = Vec::new();
+        for page in req.into_inner().pages {
+            results.push(
+                self.page_result(&caller, page, deadline)
+                    .instrument(span.clone())
+                    .await,
+            );
+        }
+        Ok(tonic::Response::new(pb::PageBatchResponse { results }))
+    }
+
+    async fn page_stream(
+        &self,
+        req: tonic::Request<tonic::Streaming<pb::PageRequest>>,
+    ) -> Result<tonic::Response<pb::PageBatchResponse>, Status> {
+        let caller = self.authorize(&req)?;
+        let span = tracing::info_span!("grpc PageStream");
+        crate::telemetry::set_parent(&span, req.metadata());
+        let deadline = deadline(&req);
+        let mut stream = req.into_inner();
+        let mut results = Vec::new();
+        while let Some(page) = stream.message().await? {
+            results.push(
+                self.page_result(&caller, page, deadline)
+                    .instrument(span.clone())
+                    .await,
+            );
         }
+        Ok(tonic::Response::new(pb::PageBatchResponse { results }))
+    }
 
-        self.signal
-            .send(req.into_inner().message.unwrap_or_default())
-            .await?;
+    async fn heartbeat(&self, req: tonic::Request<()>) -> Result<tonic::Response<()>, Status> {
+        self.authorize(&req)?;
+        self.signal.heartbeat().await?;
+        Ok(tonic::Response::new(()))
+    }
+
+    async fn ack(
+        &self,
+        req: tonic::Request<pb::AckRequest>,
+    ) -> Result<tonic::Response<()>, Status> {
+        let caller = self.authorize(&req)?;
+        let id = req
+            .into_inner()
+            .id
+            .ok_or_else(|| Status::invalid_argument("id is required"))?;
+        if !self.alerts.ack(id, &caller) {
+            return Err(Status::not_found(format!("no alert {id}")));
+        }
         Ok(tonic::Response::new(()))
     }
+
+    async fn list_received_messages(
+        &self,
+        req: tonic::Request<pb::ListReceivedMessagesRequest>,
+    ) -> Result<tonic::Response<pb::ListReceivedMessagesResponse>, Status> {
+        self.authorize(&req)?;
+        let since_ms = req.into_inner().since_ms.unwrap_or(0);
+        let messages = self
+            .signal
+            .received_messages(since_ms)
+            .into_iter()
+            .map(received_message)
+            .collect();
+        Ok(tonic::Response::new(pb::ListReceivedMessagesResponse {
+            messages,
+        }))
+    }
+
+    async fn get_on_call(
+        &self,
+        req: tonic::Request<()>,
+    ) -> Result<tonic::Response<pb::GetOnCallResponse>, Status> {
+        self.authorize(&req)?;
+        let shifts = self
+            .oncall
+            .current()
+            .into_iter()
+            .map(on_call_shift)
+            .collect();
+        Ok(tonic::Response::new(pb::GetOnCallResponse { shifts }))
+    }
+
+    async fn get_status(
+        &self,
+        req: tonic::Request<()>,
+    ) -> Result<tonic::Response<pb::GetStatusResponse>, Status> {
+        self.authorize(&req)?;
+        let status = self.signal.status().await;
+        Ok(tonic::Response::new(pb::GetStatusResponse {
+            state_version: status.state.version,
+            state_dirty: Some(status.state.dirty),
+            state_loaded_ms: status.state.last_loaded.and_then(millis),
+            state_saved_ms: status.state.last_saved.and_then(millis),
+            last_send: status.last_send.map(command_outcome),
+            last_receive: status.last_receive.map(command_outcome),
+            send_queue_depth: Some(status.queue_depth as u32),
+            on_call: self
+                .oncall
+                .current()
+                .into_iter()
+                .map(on_call_shift)
+                .collect(),
+        }))
+    }
 }
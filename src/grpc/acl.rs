@@ -0,0 +1,184 @@
+use std::path::PathBuf;
+
+/// SPIFFE IDs allowed to call us. Each pattern is either a SPIFFE ID, a
+/// SPIFFE ID with `*` path segments which match any one segment, or a
+/// bare trust domain like `spiffe://example.com` which matches every ID
+/// in it. A `*` as the last segment matches one or more segments.
+///
+/// A pattern may be followed by `=` and a target, like `=group:GROUP_ID`,
+/// to send the pages of the callers it matches there instead of wherever
+/// their labels route them.
+pub struct Acl(Vec<Entry>);
+
+struct Entry {
+    segments: Vec<String>,
+    target: Option<String>,
+}
+
+fn segment_matches(pattern: &str, segment: &str) -> bool {
+    pattern == "*" || pattern == segment
+}
+
+impl Entry {
+    fn matches(&self, id: &[&str]) -> bool {
+        match self.segments.split_last() {
+            Some((last, init)) if last == "*" => {
+                id.len() > init.len() && init.iter().zip(id).all(|(p, s)| segment_matches(p, s))
+            }
+            _ => {
+                self.segments.len() == id.len()
+                    && self
+                        .segments
+                        .iter()
+                        .zip(id)
+                        .all(|(p, s)| segment_matches(p, s))
+            }
+        }
+    }
+}
+
+impl Acl {
+    pub fn new<I: IntoIterator<Item = String>>(patterns: I) -> Self {
+        Self(
+            patterns
+                .into_iter()
+                .map(|p| {
+                    let (p, target) = match p.split_once('=') {
+                        Some((p, target)) => (p, Some(String::from(target))),
+                        None => (p.as_str(), None),
+                    };
+                    let mut segments = p.split('/').map(String::from).collect::<Vec<_>>();
+                    if p.starts_with("spiffe://") && segments.len() == 3 {
+                        segments.push(String::from("*"));
+                    }
+                    Entry { segments, target }
+                })
+                .collect(),
+        )
+    }
+
+    pub fn allows(&self, id: &str) -> bool {
+        let id = id.split('/').collect::<Vec<_>>();
+        self.0.iter().any(|entry| entry.matches(&id))
+    }
+
+    /// The target of the first pattern with one that matches `id`.
+    pub fn target(&self, id: &str) -> Option<&str> {
+        let id = id.split('/').collect::<Vec<_>>();
+        self.0
+            .iter()
+            .filter(|entry| entry.matches(&id))
+            .find_map(|entry| entry.target.as_deref())
+    }
+}
+
+/// Where the ACL comes from: the flags, plus the patterns in a file, one
+/// per line, if there is one. Blank lines and `#` comments are ignored.
+pub struct AclSource {
+    /// The name of the flag, for picking up its new value from
+    /// `--config` on reload.
+    pub flag: &'static str,
+    pub flags: Vec<String>,
+    pub file: Option<PathBuf>,
+}
+
+impl AclSource {
+    pub fn load(&self) -> Result<Acl, std::io::Error> {
+        let mut patterns = crate::config::values(self.flag).unwrap_or_else(|| self.flags.clone());
+        if let Some(ref path) = self.file {
+            patterns.extend(
+                std::fs::read_to_string(path)?
+                    .lines()
+                    .map(str::trim)
+                    .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                    .map(String::from),
+            );
+        }
+        Ok(Acl::new(patterns))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    fn acl(patterns: &[&str]) -> Acl {
+        Acl::new(patterns.iter().map(|p| String::from(*p)))
+    }
+
+    #[test]
+    fn exact() {
+        let acl = acl(&["spiffe://example.com/ns/prod/sa/alertmanager"]);
+        assert!(acl.allows("spiffe://example.com/ns/prod/sa/alertmanager"));
+        assert!(!acl.allows("spiffe://example.com/ns/prod/sa/grafana"));
+        assert!(!acl.allows("spiffe://example.com/ns/prod/sa"));
+        assert!(!acl.allows("spiffe://example.com/ns/prod/sa/alertmanager/x"));
+    }
+
+    #[test]
+    fn wildcard_segment() {
+        let acl = acl(&["spiffe://example.com/ns/*/sa/alertmanager"]);
+        assert!(acl.allows("spiffe://example.com/ns/prod/sa/alertmanager"));
+        assert!(acl.allows("spiffe://example.com/ns/dev/sa/alertmanager"));
+        assert!(!acl.allows("spiffe://example.com/ns/dev/sa/grafana"));
+        assert!(!acl.allows("spiffe://example.com/ns/a/b/sa/alertmanager"));
+    }
+
+    #[test]
+    fn trailing_wildcard() {
+        let acl = acl(&["spiffe://example.com/ns/prod/*"]);
+        assert!(acl.allows("spiffe://example.com/ns/prod/sa"));
+        assert!(acl.allows("spiffe://example.com/ns/prod/sa/alertmanager"));
+        assert!(!acl.allows("spiffe://example.com/ns/prod"));
+        assert!(!acl.allows("spiffe://example.com/ns/dev/sa"));
+    }
+
+    #[test]
+    fn trust_domain() {
+        let acl = acl(&["spiffe://example.com"]);
+        assert!(acl.allows("spiffe://example.com/anything"));
+        assert!(acl.allows("spiffe://example.com/ns/prod/sa/alertmanager"));
+        assert!(!acl.allows("spiffe://example.com"));
+        assert!(!acl.allows("spiffe://example.org/anything"));
+    }
+
+    #[test]
+    fn file() {
+        let f = testutil::file("# comment\n\n  spiffe://example.com/a  \nspiffe://example.com/b\n");
+        let source = AclSource {
+            flag: "allow-spiffe",
+            flags: vec![String::from("spiffe://example.com/c")],
+            file: Some(f.path().to_path_buf()),
+        };
+        let acl = source.load().unwrap();
+        assert!(acl.allows("spiffe://example.com/a"));
+        assert!(acl.allows("spiffe://example.com/b"));
+        assert!(acl.allows("spiffe://example.com/c"));
+        assert!(!acl.allows("spiffe://example.com/d"));
+        assert_eq!(acl.0.len(), 3);
+    }
+
+    #[test]
+    fn targets() {
+        let acl = acl(&[
+            "spiffe://example.com/ns/prod/sa/ci",
+            "spiffe://example.com/ns/prod/*=group:prod",
+            "spiffe://example.com/ns/dev/*=+15550001",
+        ]);
+        assert!(acl.allows("spiffe://example.com/ns/prod/sa/db"));
+        assert_eq!(
+            acl.target("spiffe://example.com/ns/prod/sa/db"),
+            Some("group:prod")
+        );
+        assert_eq!(
+            acl.target("spiffe://example.com/ns/prod/sa/ci"),
+            Some("group:prod")
+        );
+        assert_eq!(
+            acl.target("spiffe://example.com/ns/dev/sa/db"),
+            Some("+15550001")
+        );
+        assert_eq!(acl.target("spiffe://example.com/ns/test/sa/db"), None);
+    }
+}
@@ -0,0 +1,249 @@
+use comprehensive::v1::{AssemblyRuntime, Resource, resource};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use tonic::Status;
+
+use super::acl;
+use crate::maintenance::{self, Maintenance};
+use crate::signal::SignalRunner;
+use crate::state::{SignalState, StateStatus};
+
+mod pb {
+    tonic::include_proto!("pager.admin");
+    pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("admin_fdset");
+}
+
+pub struct AdminService {
+    state: Arc<SignalState>,
+    signal: Arc<SignalRunner>,
+    maintenance: Arc<Maintenance>,
+    acl: Arc<RwLock<acl::Acl>>,
+}
+
+#[derive(clap::Args)]
+pub struct AdminServiceArgs {
+    /// SPIFFE ID allowed to call the Admin service, in the same form as
+    /// `--allow-spiffe`. May be repeated.
+    #[arg(long)]
+    admin_allow_spiffe: Vec<String>,
+    /// File of more `--admin-allow-spiffe` patterns, one per line. It is
+    /// reloaded whenever it changes, and on SIGHUP.
+    #[arg(long)]
+    admin_acl_file: Option<PathBuf>,
+}
+
+#[resource]
+#[export_grpc(pb::admin_server::AdminServer)]
+#[proto_descriptor(pb::FILE_DESCRIPTOR_SET)]
+impl Resource for AdminService {
+    fn new(
+        d: (
+            Arc<SignalState>,
+            Arc<SignalRunner>,
+            Arc<Maintenance>,
+            Arc<crate::config::Reloader>,
+        ),
+        args: AdminServiceArgs,
+        api: &mut AssemblyRuntime<'_>,
+    ) -> Result<Arc<Self>, std::io::Error> {
+        let source = acl::AclSource {
+            flag: "admin-allow-spiffe",
+            flags: args.admin_allow_spiffe,
+            file: args.admin_acl_file,
+        };
+        Ok(Arc::new(Self {
+            state: d.0,
+            signal: d.1,
+            maintenance: d.2,
+            acl: super::load_acl(source, &d.3, api)?,
+        }))
+    }
+}
+
+fn state_status(status: StateStatus) -> tonic::Response<pb::StateStatus> {
+    tonic::Response::new(pb::StateStatus {
+        version: status.version,
+        dirty: Some(status.dirty),
+        read_only: Some(status.read_only),
+    })
+}
+
+fn action(action: maintenance::Action) -> pb::maintenance_window::Action {
+    match action {
+        maintenance::Action::Suppress => pb::maintenance_window::Action::Suppress,
+        maintenance::Action::Digest => pb::maintenance_window::Action::Digest,
+    }
+}
+
+fn timestamp_ms(ms: i64) -> Result<jiff::Timestamp, Status> {
+    jiff::Timestamp::from_millisecond(ms).map_err(|e| Status::invalid_argument(e.to_string()))
+}
+
+fn maintenance_window(w: maintenance::Window) -> pb::MaintenanceWindow {
+    pb::MaintenanceWindow {
+        id: w.id,
+        matchers: w.matchers,
+        from_ms: w.from.map(|t| t.as_millisecond()),
+        until_ms: Some(w.until.as_millisecond()),
+        action: Some(action(w.action).into()),
+        comment: Some(w.comment),
+        created_by: Some(w.created_by),
+    }
+}
+
+fn suppressed_page(p: maintenance::SuppressedPage) -> pb::SuppressedPage {
+    pb::SuppressedPage {
+        id: Some(p.id),
+        window_id: p.window,
+        action: Some(action(p.action).into()),
+        source: Some(p.source),
+        message: Some(p.message),
+        labels: p.labels,
+        recipients: p.recipients,
+        received_ms: Some(p.received.as_millisecond()),
+        digested: Some(p.digested),
+    }
+}
+
+#[tonic::async_trait]
+impl pb::admin_server::Admin for AdminService {
+    async fn flush(
+        &self,
+        req: tonic::Request<()>,
+    ) -> Result<tonic::Response<pb::StateStatus>, Status> {
+        let caller = super::authorize(&self.acl, &req)?;
+        log::info!("{caller} requested a state flush");
+        self.state.flush().await?;
+        Ok(state_status(self.state.status().await))
+    }
+
+    async fn reload(
+        &self,
+        req: tonic::Request<pb::ReloadRequest>,
+    ) -> Result<tonic::Response<pb::StateStatus>, Status> {
+        let caller = super::authorize(&self.acl, &req)?;
+        let version = req
+            .into_inner()
+            .version
+            .ok_or_else(|| Status::invalid_argument("version is required"))?;
+        log::info!("{caller} requested a reload of state version {version}");
+        self.state.reload(version).await?;
+        Ok(state_status(self.state.status().await))
+    }
+
+    async fn get_state_status(
+        &self,
+        req: tonic::Request<()>,
+    ) -> Result<tonic::Response<pb::StateStatus>, Status> {
+        super::authorize(&self.acl, &req)?;
+        Ok(state_status(self.state.status().await))
+    }
+
+    async fn receive(&self, req: tonic::Request<()>) -> Result<tonic::Response<()>, Status> {
+        let caller = super::authorize(&self.acl, &req)?;
+        log::info!("{caller} requested a Signal receive");
+        self.signal.receive_soon()?;
+        Ok(tonic::Response::new(()))
+    }
+
+    async fn add_maintenance_window(
+        &self,
+        req: tonic::Request<pb::MaintenanceWindow>,
+    ) -> Result<tonic::Response<pb::MaintenanceWindow>, Status> {
+        let caller = super::authorize(&self.acl, &req)?;
+        let req = req.into_inner();
+        let until_ms = req
+            .until_ms
+            .ok_or_else(|| Status::invalid_argument("until_ms is required"))?;
+        let window = maintenance::Window {
+            id: None,
+            action: match req.action() {
+                pb::maintenance_window::Action::Suppress => maintenance::Action::Suppress,
+                pb::maintenance_window::Action::Digest => maintenance::Action::Digest,
+            },
+            matchers: req.matchers,
+            from: req.from_ms.map(timestamp_ms).transpose()?,
+            until: timestamp_ms(until_ms)?,
+            comment: req.comment.unwrap_or_default(),
+            created_by: caller.clone(),
+        };
+        let window = self.maintenance.add(window);
+        log::info!(
+            "{caller} added maintenance window {} for {:?} until {}",
+            window.id.unwrap_or_default(),
+            window.matchers,
+            window.until
+        );
+        Ok(tonic::Response::new(maintenance_window(window)))
+    }
+
+    async fn delete_maintenance_window(
+        &self,
+        req: tonic::Request<pb::DeleteMaintenanceWindowRequest>,
+    ) -> Result<tonic::Response<()>, Status> {
+        let caller = super::authorize(&self.acl, &req)?;
+        let id = req
+            .into_inner()
+            .id
+            .ok_or_else(|| Status::invalid_argument("id is required"))?;
+        if !self.maintenance.remove(id) {
+            return Err(Status::not_found(format!("no maintenance window {id}")));
+        }
+        log::info!("{caller} deleted maintenance window {id}");
+        Ok(tonic::Response::new(()))
+    }
+
+    async fn list_maintenance_windows(
+        &self,
+        req: tonic::Request<()>,
+    ) -> Result<tonic::Response<pb::ListMaintenanceWindowsResponse>, Status> {
+        super::authorize(&self.acl, &req)?;
+        let windows = self
+            .maintenance
+            .windows()
+            .into_iter()
+            .map(maintenance_window)
+            .collect();
+        Ok(tonic::Response::new(pb::ListMaintenanceWindowsResponse {
+            windows,
+        }))
+    }
+
+    async fn list_suppressed_pages(
+        &self,
+        req: tonic::Request<()>,
+    ) -> Result<tonic::Response<pb::ListSuppressedPagesResponse>, Status> {
+        super::authorize(&self.acl, &req)?;
+        let pages = self
+            .maintenance
+            .suppressed()
+            .into_iter()
+            .map(suppressed_page)
+            .collect();
+        Ok(tonic::Response::new(pb::ListSuppressedPagesResponse {
+            pages,
+        }))
+    }
+
+    async fn replay_suppressed_pages(
+        &self,
+        req: tonic::Request<pb::ReplaySuppressedPagesRequest>,
+    ) -> Result<tonic::Response<pb::ReplaySuppressedPagesResponse>, Status> {
+        let caller = super::authorize(&self.acl, &req)?;
+        let pages = self.maintenance.take_suppressed(req.into_inner().window_id);
+        log::info!(
+            "{caller} requested a replay of {} suppressed pages",
+            pages.len()
+        );
+        let mut replayed = 0;
+        for page in &pages {
+            match self.signal.replay(&caller, page).await {
+                Ok(_) => replayed += 1,
+                Err(e) => log::error!("Replaying suppressed page {}: {e}", page.id),
+            }
+        }
+        Ok(tonic::Response::new(pb::ReplaySuppressedPagesResponse {
+            replayed: Some(replayed),
+        }))
+    }
+}
@@ -0,0 +1,51 @@
+use comprehensive::v1::{AssemblyRuntime, Resource, resource};
+use std::sync::Arc;
+use tonic::Status;
+
+use crate::signal::SignalRunner;
+
+mod pb {
+    tonic::include_proto!("pager.health");
+    pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("health_fdset");
+}
+
+/// Serves the same report as `/healthz`. It is open to any caller, like
+/// the standard health service.
+pub struct HealthService {
+    signal: Arc<SignalRunner>,
+}
+
+#[resource]
+#[export_grpc(pb::health_server::HealthServer)]
+#[proto_descriptor(pb::FILE_DESCRIPTOR_SET)]
+impl Resource for HealthService {
+    fn new(
+        d: (Arc<SignalRunner>,),
+        _: comprehensive::NoArgs,
+        _: &mut AssemblyRuntime<'_>,
+    ) -> Result<Arc<Self>, std::convert::Infallible> {
+        Ok(Arc::new(Self { signal: d.0 }))
+    }
+}
+
+#[tonic::async_trait]
+impl pb::health_server::Health for HealthService {
+    async fn get_health(
+        &self,
+        _: tonic::Request<()>,
+    ) -> Result<tonic::Response<pb::HealthReport>, Status> {
+        let report = self.signal.health().await;
+        Ok(tonic::Response::new(pb::HealthReport {
+            healthy: Some(report.healthy),
+            checks: report
+                .checks
+                .into_iter()
+                .map(|c| pb::Check {
+                    name: Some(String::from(c.name)),
+                    ok: Some(c.ok),
+                    detail: c.detail,
+                })
+                .collect(),
+        }))
+    }
+}
@@ -0,0 +1,62 @@
+//! Idempotency keys, so that a client which retries a `Page` because it
+//! did not hear back, like the relay, does not page twice.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::OnceCell;
+use tonic::Status;
+
+use super::pb;
+
+struct Entry {
+    created: Instant,
+    response: Arc<OnceCell<pb::PageResponse>>,
+}
+
+/// The response to each page sent with an idempotency key, kept in
+/// memory for `window`. Keys are per caller.
+pub struct Idempotency {
+    window: Duration,
+    entries: Mutex<HashMap<(String, String), Entry>>,
+}
+
+impl Idempotency {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Runs `page` unless a page with the same key has already
+    /// succeeded, in which case its response is returned instead. While
+    /// one is in progress, others with the same key wait for it. Failed
+    /// pages are not remembered, so that they can be retried.
+    pub async fn once<F>(
+        &self,
+        caller: &str,
+        key: String,
+        page: F,
+    ) -> Result<pb::PageResponse, Status>
+    where
+        F: Future<Output = Result<pb::PageResponse, Status>>,
+    {
+        let response = {
+            let mut entries = self.entries.lock().unwrap();
+            entries.retain(|_, e| e.created.elapsed() < self.window);
+            let entry = entries
+                .entry((String::from(caller), key))
+                .or_insert_with(|| Entry {
+                    created: Instant::now(),
+                    response: Arc::new(OnceCell::new()),
+                });
+            Arc::clone(&entry.response)
+        };
+        if let Some(r) = response.get() {
+            log::info!("Not paging again for a retried request from {caller}");
+            return Ok(*r);
+        }
+        response.get_or_try_init(|| page).await.cloned()
+    }
+}
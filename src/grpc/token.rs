@@ -0,0 +1,222 @@
+//! Bearer tokens which callers of the Pager service may give in their
+//! `authorization` metadata instead of a client certificate: either one
+//! of a fixed set, or a JWT. The identity a token gives is checked
+//! against the ACL like a SPIFFE ID.
+
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use tonic::Status;
+use tonic::metadata::MetadataMap;
+
+use crate::http::auth::{AuthError, read_tokens, sha256, token_caller};
+
+#[derive(Debug, thiserror::Error)]
+pub enum TokenError {
+    #[error("--grpc-bearer-token-file: {0}")]
+    TokenFileError(#[from] AuthError),
+    #[error("Reading --jwt-key-file {0}: {1}")]
+    KeyFileError(PathBuf, std::io::Error),
+    #[error("--jwt-key-file {0}: {1}")]
+    BadKey(PathBuf, jsonwebtoken::errors::Error),
+    #[error("--jwt-key-file {0} is empty")]
+    EmptyKey(PathBuf),
+}
+
+#[derive(clap::Args)]
+pub struct TokenArgs {
+    /// File of bearer tokens which callers may give instead of a client
+    /// certificate, one per line. A caller with one is identified as
+    /// `token:` and the first 8 hex digits of the token's SHA-256, which
+    /// must be allowed by `--allow-spiffe` or `--acl-file`.
+    #[arg(long)]
+    grpc_bearer_token_file: Option<PathBuf>,
+    /// Accept bearer tokens which are JWTs signed with this key: a PEM
+    /// RSA or EC public key, or else an HMAC secret on the first line.
+    /// Their `sub` claim identifies the caller, like the SPIFFE ID of a
+    /// JWT-SVID, and must be allowed by `--allow-spiffe` or `--acl-file`.
+    #[arg(long, requires = "jwt_audience")]
+    jwt_key_file: Option<PathBuf>,
+    /// Accept only JWTs with this `aud`.
+    #[arg(long, requires = "jwt_key_file")]
+    jwt_audience: Option<String>,
+    /// Accept only JWTs with this `iss`.
+    #[arg(long, requires = "jwt_key_file")]
+    jwt_issuer: Option<String>,
+}
+
+struct Jwt {
+    key: DecodingKey,
+    validation: Validation,
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+}
+
+pub struct Tokens {
+    /// SHA-256 of each token.
+    tokens: Vec<[u8; 32]>,
+    jwt: Option<Jwt>,
+}
+
+/// The key and the algorithms it can be used with.
+fn read_key(path: &Path) -> Result<(DecodingKey, &'static [Algorithm]), TokenError> {
+    let data = std::fs::read(path).map_err(|e| TokenError::KeyFileError(path.to_owned(), e))?;
+    let bad_key = |e| TokenError::BadKey(path.to_owned(), e);
+    if data.starts_with(b"-----BEGIN") {
+        return match DecodingKey::from_rsa_pem(&data) {
+            Ok(key) => Ok((
+                key,
+                &[
+                    Algorithm::RS256,
+                    Algorithm::RS384,
+                    Algorithm::RS512,
+                    Algorithm::PS256,
+                    Algorithm::PS384,
+                    Algorithm::PS512,
+                ],
+            )),
+            Err(_) => DecodingKey::from_ec_pem(&data)
+                .map(|key| (key, &[Algorithm::ES256, Algorithm::ES384][..]))
+                .map_err(bad_key),
+        };
+    }
+    let secret = data.split(|b| *b == b'\n').next().unwrap_or_default();
+    if secret.is_empty() {
+        return Err(TokenError::EmptyKey(path.to_owned()));
+    }
+    Ok((
+        DecodingKey::from_secret(secret),
+        &[Algorithm::HS256, Algorithm::HS384, Algorithm::HS512],
+    ))
+}
+
+impl Tokens {
+    pub fn new(args: TokenArgs) -> Result<Self, TokenError> {
+        let tokens = match args.grpc_bearer_token_file {
+            Some(ref path) => read_tokens(path)?,
+            None => Vec::new(),
+        };
+        let jwt = match args.jwt_key_file {
+            Some(ref path) => {
+                let (key, algorithms) = read_key(path)?;
+                let mut validation = Validation::new(algorithms[0]);
+                validation.algorithms = algorithms.to_vec();
+                validation.set_required_spec_claims(&["exp", "sub", "aud"]);
+                validation.set_audience(&[args.jwt_audience.unwrap_or_default()]);
+                if let Some(issuer) = args.jwt_issuer {
+                    validation.set_issuer(&[issuer]);
+                }
+                Some(Jwt { key, validation })
+            }
+            None => None,
+        };
+        Ok(Self { tokens, jwt })
+    }
+
+    /// Who the bearer token in `metadata` belongs to, or `None` if there
+    /// is none or tokens are not accepted, so that the caller is
+    /// identified some other way.
+    pub fn identify(&self, metadata: &MetadataMap) -> Result<Option<String>, Status> {
+        if self.tokens.is_empty() && self.jwt.is_none() {
+            return Ok(None);
+        }
+        let Some(token) = metadata
+            .get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .map(str::trim)
+        else {
+            return Ok(None);
+        };
+        let hash = sha256(token.as_bytes());
+        if self.tokens.contains(&hash) {
+            return Ok(Some(token_caller(&hash)));
+        }
+        let Some(ref jwt) = self.jwt else {
+            return Err(Status::unauthenticated("unknown bearer token"));
+        };
+        jsonwebtoken::decode::<Claims>(token, &jwt.key, &jwt.validation)
+            .map(|data| Some(data.claims.sub))
+            .map_err(|e| Status::unauthenticated(format!("invalid bearer token: {e}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::file;
+    use jsonwebtoken::{EncodingKey, Header};
+
+    fn metadata(token: &str) -> MetadataMap {
+        let mut metadata = MetadataMap::new();
+        metadata.insert("authorization", format!("Bearer {token}").parse().unwrap());
+        metadata
+    }
+
+    fn jwt(claims: serde_json::Value) -> String {
+        jsonwebtoken::encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn static_tokens() {
+        let tokens_file = file("secret\n");
+        let tokens = Tokens::new(TokenArgs {
+            grpc_bearer_token_file: Some(tokens_file.path().to_owned()),
+            jwt_key_file: None,
+            jwt_audience: None,
+            jwt_issuer: None,
+        })
+        .unwrap();
+        assert_eq!(
+            tokens.identify(&metadata("secret")).unwrap().as_deref(),
+            Some("token:2bb80d53")
+        );
+        assert!(tokens.identify(&metadata("wrong")).is_err());
+        assert_eq!(tokens.identify(&MetadataMap::new()).unwrap(), None);
+    }
+
+    #[test]
+    fn jwts() {
+        let key = file("secret\n");
+        let tokens = Tokens::new(TokenArgs {
+            grpc_bearer_token_file: None,
+            jwt_key_file: Some(key.path().to_owned()),
+            jwt_audience: Some(String::from("signal-pager")),
+            jwt_issuer: Some(String::from("https://issuer.example.com")),
+        })
+        .unwrap();
+        let exp = jsonwebtoken::get_current_timestamp() + 60;
+        let good = jwt(serde_json::json!({
+            "sub": "spiffe://example.com/ci",
+            "aud": "signal-pager",
+            "iss": "https://issuer.example.com",
+            "exp": exp,
+        }));
+        assert_eq!(
+            tokens.identify(&metadata(&good)).unwrap().as_deref(),
+            Some("spiffe://example.com/ci")
+        );
+        let wrong_audience = jwt(serde_json::json!({
+            "sub": "spiffe://example.com/ci",
+            "aud": "something-else",
+            "iss": "https://issuer.example.com",
+            "exp": exp,
+        }));
+        assert!(tokens.identify(&metadata(&wrong_audience)).is_err());
+        let expired = jwt(serde_json::json!({
+            "sub": "spiffe://example.com/ci",
+            "aud": "signal-pager",
+            "iss": "https://issuer.example.com",
+            "exp": exp - 3600,
+        }));
+        assert!(tokens.identify(&metadata(&expired)).is_err());
+    }
+}
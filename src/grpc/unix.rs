@@ -0,0 +1,140 @@
+//! The Pager service on a Unix domain socket, for cron jobs and scripts
+//! on the same host which have no certificate. Callers are told apart by
+//! the UID of the process on the other end of the socket instead.
+
+use comprehensive::ResourceDependencies;
+use comprehensive::v1::{AssemblyRuntime, Resource, resource};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tonic::Status;
+use tonic::service::interceptor::InterceptedService;
+use tonic::transport::server::UdsConnectInfo;
+
+use super::PagerService;
+use super::pb::pager_server::PagerServer;
+
+#[derive(Debug, thiserror::Error)]
+pub enum UnixSocketError {
+    #[error("Listening on --unix-socket {0}: {1}")]
+    ListenError(PathBuf, std::io::Error),
+    #[error("--unix-socket-allow-uid needs --unix-socket")]
+    NoSocket,
+}
+
+/// The caller of a request which came in on the socket, once its UID is
+/// found to be allowed. Identifies it as `uid:UID`.
+#[derive(Clone)]
+pub struct Peer(pub String);
+
+#[derive(clap::Args)]
+pub struct UnixSocketArgs {
+    /// Also serve the Pager service on a Unix domain socket at this path.
+    /// Whatever is there already is replaced.
+    #[arg(long)]
+    unix_socket: Option<PathBuf>,
+    /// UID of a process allowed to call us over `--unix-socket`. May be
+    /// repeated. Nobody is allowed by default.
+    #[arg(long)]
+    unix_socket_allow_uid: Vec<u32>,
+}
+
+#[derive(ResourceDependencies)]
+pub struct UnixSocketDependencies {
+    pager: Arc<PagerService>,
+}
+
+pub struct UnixSocketServer;
+
+/// Lets in only callers whose UID is allowed, and says who they are.
+fn check_uid(
+    allowed: &HashSet<u32>,
+    mut req: tonic::Request<()>,
+) -> Result<tonic::Request<()>, Status> {
+    let uid = req
+        .extensions()
+        .get::<UdsConnectInfo>()
+        .and_then(|info| info.peer_cred)
+        .map(|cred| cred.uid())
+        .ok_or_else(|| Status::permission_denied("no peer credentials"))?;
+    if !allowed.contains(&uid) {
+        return Err(Status::permission_denied(format!(
+            "uid {uid} not in --unix-socket-allow-uid"
+        )));
+    }
+    req.extensions_mut().insert(Peer(format!("uid:{uid}")));
+    Ok(req)
+}
+
+#[resource]
+impl Resource for UnixSocketServer {
+    fn new(
+        d: UnixSocketDependencies,
+        a: UnixSocketArgs,
+        api: &mut AssemblyRuntime<'_>,
+    ) -> Result<Arc<Self>, UnixSocketError> {
+        let Some(path) = a.unix_socket else {
+            if !a.unix_socket_allow_uid.is_empty() {
+                return Err(UnixSocketError::NoSocket);
+            }
+            return Ok(Arc::new(Self));
+        };
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(UnixSocketError::ListenError(path, e));
+            }
+            _ => (),
+        }
+        let listener = tokio::net::UnixListener::bind(&path)
+            .map_err(|e| UnixSocketError::ListenError(path.clone(), e))?;
+        let allowed = a.unix_socket_allow_uid.into_iter().collect::<HashSet<_>>();
+        let service = InterceptedService::new(PagerServer::from_arc(d.pager), move |req| {
+            check_uid(&allowed, req)
+        });
+        let incoming = futures::stream::unfold(listener, |listener| async {
+            let conn = listener.accept().await.map(|(stream, _)| stream);
+            Some((conn, listener))
+        });
+        log::info!("Serving the Pager service on {}", path.display());
+        api.set_task(async move {
+            tonic::transport::Server::builder()
+                .add_service(service)
+                .serve_with_incoming(incoming)
+                .await
+                .map_err(|e| Box::new(e) as Box<dyn std::error::Error>)
+        });
+        Ok(Arc::new(Self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tonic::transport::server::Connected;
+
+    #[test]
+    fn check_uid_without_credentials() {
+        let allowed = HashSet::from([1000]);
+        let r = check_uid(&allowed, tonic::Request::new(()));
+        assert_eq!(r.unwrap_err().code(), tonic::Code::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn check_uid_of_peer() {
+        let (ours, _theirs) = tokio::net::UnixStream::pair().unwrap();
+        let info = ours.connect_info();
+        let uid = info.peer_cred.unwrap().uid();
+        let request = || {
+            let mut req = tonic::Request::new(());
+            req.extensions_mut().insert(info.clone());
+            req
+        };
+        let req = check_uid(&HashSet::from([uid]), request()).unwrap();
+        assert_eq!(
+            req.extensions().get::<Peer>().unwrap().0,
+            format!("uid:{uid}")
+        );
+        let r = check_uid(&HashSet::from([uid + 1]), request());
+        assert_eq!(r.unwrap_err().code(), tonic::Code::PermissionDenied);
+    }
+}
@@ -0,0 +1,183 @@
+//! The Pager service on the receiver HTTP server, for browsers and curl
+//! which cannot make gRPC calls of their own: as gRPC-web, or as JSON
+//! when the request says `Content-Type: application/json`. Callers have
+//! no client certificate there, so they are identified by `--auth-route`
+//! instead and that identity is checked against the same ACL.
+
+use axum::extract::{Path, Request, State};
+use axum::response::{IntoResponse, Response};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::sync::Arc;
+use tonic::codegen::Service;
+use tonic::service::{LayerExt, Layered};
+use tonic::{Code, Status};
+use tonic_web::{GrpcWebLayer, GrpcWebService};
+
+use super::PagerService;
+use super::pb::pager_server::{Pager, PagerServer};
+
+/// Like axum's own limit for JSON bodies.
+const MAX_JSON_BODY: usize = 2 << 20;
+
+#[derive(Clone)]
+struct Web {
+    service: Arc<PagerService>,
+    grpc_web: Layered<GrpcWebService<PagerServer<PagerService>>, PagerServer<PagerService>>,
+}
+
+/// Serves `POST /pager.Pager/{method}`.
+pub fn method_router<S: Clone + Send + Sync + 'static>(
+    service: Arc<PagerService>,
+) -> axum::routing::MethodRouter<S> {
+    let web = Web {
+        grpc_web: GrpcWebLayer::new().named_layer(PagerServer::from_arc(Arc::clone(&service))),
+        service,
+    };
+    axum::routing::post(call).with_state(web)
+}
+
+fn http_status(code: Code) -> http::StatusCode {
+    match code {
+        Code::Ok => http::StatusCode::OK,
+        Code::InvalidArgument | Code::FailedPrecondition | Code::OutOfRange => {
+            http::StatusCode::BAD_REQUEST
+        }
+        Code::Unauthenticated => http::StatusCode::UNAUTHORIZED,
+        Code::PermissionDenied => http::StatusCode::FORBIDDEN,
+        Code::NotFound => http::StatusCode::NOT_FOUND,
+        Code::AlreadyExists | Code::Aborted => http::StatusCode::CONFLICT,
+        Code::ResourceExhausted => http::StatusCode::TOO_MANY_REQUESTS,
+        Code::Unimplemented => http::StatusCode::NOT_IMPLEMENTED,
+        Code::Unavailable => http::StatusCode::SERVICE_UNAVAILABLE,
+        Code::DeadlineExceeded => http::StatusCode::GATEWAY_TIMEOUT,
+        _ => http::StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn status_error(s: Status) -> (http::StatusCode, String) {
+    (http_status(s.code()), String::from(s.message()))
+}
+
+async fn call(
+    State(web): State<Web>,
+    Path(method): Path<String>,
+    req: Request,
+) -> Result<Response, (http::StatusCode, String)> {
+    let json = req
+        .headers()
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/json"));
+    if json {
+        return transcode(&web.service, &method, req).await;
+    }
+    let mut grpc_web = web.grpc_web.clone();
+    match grpc_web.call(req).await {
+        Ok(response) => Ok(response.into_response()),
+        Err(e) => match e {},
+    }
+}
+
+/// Calls `method` with the request parsed from JSON, and answers with
+/// its response as JSON. Headers are passed on as metadata, so that
+/// `grpc-timeout` and trace context work as they do over gRPC.
+async fn transcode(
+    service: &PagerService,
+    method: &str,
+    req: Request,
+) -> Result<Response, (http::StatusCode, String)> {
+    let (parts, body) = req.into_parts();
+    let body = axum::body::to_bytes(body, MAX_JSON_BODY)
+        .await
+        .map_err(|e| (http::StatusCode::BAD_REQUEST, format!("reading body: {e}")))?;
+    let body = match method {
+        "Heartbeat" | "GetOnCall" | "GetStatus" => Vec::new(),
+        _ => body.to_vec(),
+    };
+    let req = http::Request::from_parts(parts, body);
+    match method {
+        "Page" => unary(req, |r| service.page(r)).await,
+        "PageBatch" => unary(req, |r| service.page_batch(r)).await,
+        "Heartbeat" => unary(req, |r| service.heartbeat(r)).await,
+        "Ack" => unary(req, |r| service.ack(r)).await,
+        "ListReceivedMessages" => unary(req, |r| service.list_received_messages(r)).await,
+        "GetOnCall" => unary(req, |r| service.get_on_call(r)).await,
+        "GetStatus" => unary(req, |r| service.get_status(r)).await,
+        "PageStream" => Err((
+            http::StatusCode::NOT_IMPLEMENTED,
+            String::from("PageStream cannot be called with JSON, use PageBatch"),
+        )),
+        _ => Err((
+            http::StatusCode::NOT_FOUND,
+            format!("no method {method:?} in pager.Pager"),
+        )),
+    }
+}
+
+/// An empty body is taken as an empty message, as are the bodies of
+/// methods which take `google.protobuf.Empty`.
+async fn unary<Q, R, F>(
+    req: http::Request<Vec<u8>>,
+    f: impl FnOnce(tonic::Request<Q>) -> F,
+) -> Result<Response, (http::StatusCode, String)>
+where
+    Q: DeserializeOwned + Default,
+    R: Serialize,
+    F: Future<Output = Result<tonic::Response<R>, Status>>,
+{
+    let (parts, body) = req.into_parts();
+    let message = if body.is_empty() {
+        Q::default()
+    } else {
+        serde_json::from_slice(&body)
+            .map_err(|e| (http::StatusCode::BAD_REQUEST, format!("parsing body: {e}")))?
+    };
+    let response = f(tonic::Request::from_http(http::Request::from_parts(
+        parts, message,
+    )))
+    .await
+    .map_err(status_error)?;
+    let json = match serde_json::to_value(response.into_inner()) {
+        Ok(serde_json::Value::Null) => serde_json::json!({}),
+        Ok(json) => json,
+        Err(e) => {
+            return Err((
+                http::StatusCode::INTERNAL_SERVER_ERROR,
+                format!("serializing response: {e}"),
+            ));
+        }
+    };
+    Ok(axum::Json(json).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_page_request() {
+        let req: super::super::pb::PageRequest =
+            serde_json::from_str(r#"{"message": "disk full", "labels": {"severity": "critical"}}"#)
+                .unwrap();
+        assert_eq!(req.message.as_deref(), Some("disk full"));
+        assert_eq!(req.labels["severity"], "critical");
+        assert_eq!(req.recipients, Vec::<String>::new());
+    }
+
+    #[test]
+    fn status_codes() {
+        assert_eq!(
+            http_status(Code::PermissionDenied),
+            http::StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            http_status(Code::ResourceExhausted),
+            http::StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(
+            http_status(Code::Internal),
+            http::StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+}
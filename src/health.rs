@@ -0,0 +1,36 @@
+//! What `/healthz` and the `Health` gRPC service say: each of the things
+//! which can stop pages from getting through, checked separately, so
+//! that it is clear which one is wrong.
+
+use serde::Serialize;
+
+#[derive(Clone, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub ok: bool,
+    /// What is wrong, or other useful detail.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl Check {
+    pub fn new(name: &'static str, ok: bool, detail: Option<String>) -> Self {
+        Self { name, ok, detail }
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct Report {
+    /// Whether every check is ok.
+    pub healthy: bool,
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    pub fn new(checks: Vec<Check>) -> Self {
+        Self {
+            healthy: checks.iter().all(|c| c.ok),
+            checks,
+        }
+    }
+}
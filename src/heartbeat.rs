@@ -0,0 +1,122 @@
+use comprehensive::ResourceDependencies;
+use comprehensive::v1::{AssemblyRuntime, Resource, resource};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+use crate::page::PageMeta;
+use crate::signal::SignalRunner;
+
+#[derive(clap::Args)]
+pub struct HeartbeatArgs {
+    /// Page if no heartbeat arrives for this long. Heartbeats are
+    /// alerts named by `--heartbeat-alertname`, calls to `/heartbeat`
+    /// and gRPC `Heartbeat` calls.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    heartbeat_timeout: Option<Duration>,
+    /// Alerts with this alertname label are heartbeats instead of pages.
+    #[arg(long, default_value = "Watchdog")]
+    heartbeat_alertname: String,
+}
+
+/// When we last heard that the monitoring pipeline is alive.
+pub struct Heartbeat {
+    args: HeartbeatArgs,
+    last: watch::Sender<Instant>,
+}
+
+#[resource]
+impl Resource for Heartbeat {
+    fn new(
+        _: comprehensive::NoDependencies,
+        a: HeartbeatArgs,
+        _: &mut AssemblyRuntime<'_>,
+    ) -> Result<Arc<Self>, std::convert::Infallible> {
+        Ok(Arc::new(Self {
+            args: a,
+            last: watch::Sender::new(Instant::now()),
+        }))
+    }
+}
+
+impl Heartbeat {
+    pub fn enabled(&self) -> bool {
+        self.args.heartbeat_timeout.is_some()
+    }
+
+    pub fn is_heartbeat(&self, labels: &HashMap<String, String>) -> bool {
+        self.enabled() && labels.get("alertname") == Some(&self.args.heartbeat_alertname)
+    }
+
+    pub fn beat(&self) {
+        self.last.send_replace(Instant::now());
+    }
+}
+
+#[derive(ResourceDependencies)]
+pub struct HeartbeatMonitorDependencies {
+    signal: Arc<SignalRunner>,
+    heartbeat: Arc<Heartbeat>,
+}
+
+/// Pages when heartbeats stop arriving, and again when they resume.
+pub struct HeartbeatMonitor;
+
+#[resource]
+impl Resource for HeartbeatMonitor {
+    fn new(
+        d: HeartbeatMonitorDependencies,
+        _: comprehensive::NoArgs,
+        api: &mut AssemblyRuntime<'_>,
+    ) -> Result<Arc<Self>, std::convert::Infallible> {
+        let Some(timeout) = d.heartbeat.args.heartbeat_timeout else {
+            return Ok(Arc::new(Self));
+        };
+        api.set_task(async move {
+            let mut rx = d.heartbeat.last.subscribe();
+            let mut missing = false;
+            loop {
+                let deadline = *rx.borrow_and_update() + timeout;
+                if !missing {
+                    tokio::select! {
+                        _ = tokio::time::sleep_until(deadline) => (),
+                        _ = rx.changed() => continue,
+                    }
+                    missing = true;
+                    log::error!("No heartbeat for {}", humantime::format_duration(timeout));
+                    page(&d.signal, &d.heartbeat, false).await;
+                } else {
+                    let _ = rx.changed().await;
+                    missing = false;
+                    log::info!("Heartbeat resumed");
+                    page(&d.signal, &d.heartbeat, true).await;
+                }
+            }
+        });
+        Ok(Arc::new(Self))
+    }
+}
+
+async fn page(signal: &SignalRunner, heartbeat: &Heartbeat, resolved: bool) {
+    let msg = if resolved {
+        String::from("RESOLVED\nHeartbeats are arriving again.")
+    } else {
+        format!(
+            "FIRING\nNo heartbeat for {}. The monitoring pipeline may be down.",
+            humantime::format_duration(heartbeat.args.heartbeat_timeout.unwrap_or_default())
+        )
+    };
+    let meta = PageMeta {
+        labels: HashMap::from([
+            (String::from("alertname"), String::from("HeartbeatMissing")),
+            (String::from("severity"), String::from("critical")),
+        ]),
+        resolved,
+        ..Default::default()
+    };
+    if let Err(e) = signal.page("heartbeat", None, msg, &meta).await {
+        log::error!("Paging about heartbeat: {e}");
+    }
+}
@@ -1,50 +1,341 @@
-use axum::extract::State;
-use axum::{Json, Router};
+use axum::extract::{Query, State};
+use axum::response::IntoResponse;
+use axum::{Extension, Json, Router};
 use comprehensive::ResourceDependencies;
 use comprehensive::v1::{AssemblyRuntime, Resource, resource};
 use comprehensive_http::server::HttpServingInstance;
-use serde::Deserialize;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::PathBuf;
 use std::sync::Arc;
 
-#[derive(Debug, Deserialize)]
-struct AlertInput {
-    status: String,
-    labels: HashMap<String, String>,
-    annotations: HashMap<String, String>,
+use crate::alert::{
+    Alert, AlertInput, AlertsInput, GrafanaAlertInput, GrafanaAlertsInput, GroupContext,
+    common_labels, render_alert, render_grouped, unrendered,
+};
+use crate::page::{Attachment, PageMeta};
+use crate::render::Renderer;
+
+pub mod auth;
+pub mod backend;
+pub mod filter;
+mod generic;
+mod links;
+mod pagerduty;
+mod signature;
+pub mod silences;
+mod tenant;
+
+#[derive(Debug, thiserror::Error)]
+pub enum HttpApiError {
+    #[error("Reading --webhook-secret-file: {0}")]
+    SecretError(#[from] std::io::Error),
+    #[error("--webhook-secret-file is empty")]
+    EmptySecret,
+    #[error("{0}")]
+    AuthError(#[from] auth::AuthError),
+    #[error("--tenant: {0}")]
+    TenantError(#[from] tenant::TenantError),
 }
 
-impl std::fmt::Display for AlertInput {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
-        writeln!(f, "{}", self.status.to_uppercase())?;
-        for (k, v) in &self.labels {
-            writeln!(f, "{k}: {v}")?;
-        }
-        if let Some(v) = self.annotations.get("summary") {
-            write!(f, "\n{v}\n")?;
-        }
-        if let Some(v) = self.annotations.get("description") {
-            write!(f, "\n{v}\n")?;
-        }
-        Ok(())
+impl AlertInput {
+    fn clean_links(&mut self, links: &links::Links) {
+        links.clean_in_place(&mut self.generator_url);
+    }
+}
+
+impl GrafanaAlertInput {
+    fn clean_links(&mut self, links: &links::Links) {
+        self.alert.clean_links(links);
+        links.clean_in_place(&mut self.silence_url);
+        links.clean_in_place(&mut self.dashboard_url);
+        links.clean_in_place(&mut self.panel_url);
     }
 }
 
+fn render_error(e: tera::Error) -> (http::StatusCode, String) {
+    (
+        http::StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Rendering alert: {e}"),
+    )
+}
+
 #[derive(Deserialize)]
-struct AlertsInput {
-    alerts: Vec<AlertInput>,
+struct AlertParams {
+    group: Option<bool>,
+    /// Comma-separated phone numbers or ACIs.
+    recipients: Option<String>,
+}
+
+impl AlertParams {
+    fn recipients(&self) -> Vec<String> {
+        self.recipients
+            .iter()
+            .flat_map(|r| r.split(','))
+            .filter(|r| !r.is_empty())
+            .map(String::from)
+            .collect()
+    }
+}
+
+#[derive(Clone)]
+struct AlertState {
+    runner: Arc<dyn backend::Backend>,
+    renderer: Arc<Renderer>,
+    oncall: Arc<crate::oncall::OnCall>,
+    audit: Arc<crate::audit::Audit>,
+    group_alerts: bool,
+    routing_keys: Arc<HashSet<String>>,
+    filter: Arc<filter::LabelFilter>,
+    links: Arc<links::Links>,
+    tenants: Arc<tenant::Tenants>,
+    json_mapping: Option<Arc<generic::Mapping>>,
+    webhook_secret: Option<Arc<[u8]>>,
+    auth: Arc<auth::Auth>,
+}
+
+/// The authenticated caller of an HTTP request, for the audit log.
+type MaybeCaller = Option<Extension<auth::Caller>>;
+
+fn caller_id(caller: &MaybeCaller) -> Option<&str> {
+    caller.as_ref().map(|Extension(c)| c.0.as_str())
+}
+
+/// How paging for one alert went, or for all of them if they were
+/// grouped.
+#[derive(Serialize)]
+struct AlertResult {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fingerprint: Option<String>,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct DeliverResponse {
+    alerts: Vec<AlertResult>,
+}
+
+/// The webhook fails with the status of the first alert that failed, so
+/// that it is retried if that is worth doing, and says how each alert
+/// went in its body.
+type Delivered = (http::StatusCode, Json<DeliverResponse>);
+
+fn delivered(
+    fingerprints: Vec<Option<String>>,
+    results: Vec<Result<(), (http::StatusCode, String)>>,
+) -> Delivered {
+    let status = results
+        .iter()
+        .find_map(|r| r.as_ref().err().map(|(code, _)| *code))
+        .unwrap_or(http::StatusCode::OK);
+    let alerts = fingerprints
+        .into_iter()
+        .zip(results)
+        .map(|(fingerprint, r)| match r {
+            Ok(()) => AlertResult {
+                fingerprint,
+                status: http::StatusCode::OK.as_u16(),
+                error: None,
+            },
+            Err((code, error)) => AlertResult {
+                fingerprint,
+                status: code.as_u16(),
+                error: Some(error),
+            },
+        })
+        .collect();
+    (status, Json(DeliverResponse { alerts }))
+}
+
+#[tracing::instrument(skip_all, fields(alerts = alerts.len()))]
+async fn deliver<A: Alert>(
+    state: &AlertState,
+    caller: Option<&str>,
+    params: &AlertParams,
+    tenant: Option<&tenant::Tenant>,
+    group: &GroupContext,
+    alerts: &[A],
+) -> Result<Delivered, (http::StatusCode, String)> {
+    let alerts = alerts
+        .iter()
+        .filter(|a| state.filter.allows(a.labels()))
+        .collect::<Vec<_>>();
+    let alerts = alerts.as_slice();
+    let renderer = tenant
+        .and_then(|t| t.renderer.as_deref())
+        .unwrap_or(&state.renderer);
+    let clean_link = |u: &str| state.links.clean(u);
+    // Labelled with the tenant, so that silences and routes can tell
+    // tenants' alerts apart.
+    let with_tenant = |mut meta: PageMeta| {
+        if let Some(t) = tenant {
+            meta.labels.insert(String::from("tenant"), t.name.clone());
+            meta.group = t.group.clone();
+        }
+        meta
+    };
+    if params.group.unwrap_or(state.group_alerts) && alerts.len() > 1 {
+        let msg = render_grouped(renderer, &clean_link, group, alerts).map_err(render_error)?;
+        let meta = with_tenant(PageMeta {
+            labels: common_labels(alerts),
+            resolved: alerts.iter().all(|a| a.status() == "resolved"),
+            recipients: params.recipients(),
+            attachment: alerts
+                .iter()
+                .find_map(Alert::attachment_url)
+                .map(Attachment::from_url),
+            ..Default::default()
+        });
+        let results = state
+            .runner
+            .page_batch("http", caller, vec![(msg, meta)])
+            .await;
+        return Ok(delivered(vec![None], results));
+    }
+    let mut pages = Vec::with_capacity(alerts.len());
+    for alert in alerts {
+        let msg = render_alert(renderer, &clean_link, group, alert).map_err(render_error)?;
+        let meta = with_tenant(PageMeta {
+            recipients: params.recipients(),
+            alert: unrendered(renderer, alert),
+            ..alert.meta()
+        });
+        pages.push((msg, meta));
+    }
+    let fingerprints = pages
+        .iter()
+        .map(|(_, meta)| meta.fingerprint.clone())
+        .collect();
+    let results = state.runner.page_batch("http", caller, pages).await;
+    Ok(delivered(fingerprints, results))
 }
 
 async fn alert(
-    State(runner): State<Arc<crate::signal::SignalRunner>>,
-    Json(payload): Json<AlertsInput>,
-) -> Result<(), (http::StatusCode, String)> {
-    for alert in payload.alerts {
-        runner.send(format!("{alert}")).await?;
+    State(state): State<AlertState>,
+    caller: MaybeCaller,
+    Query(params): Query<AlertParams>,
+    Json(mut payload): Json<AlertsInput>,
+) -> Result<Delivered, (http::StatusCode, String)> {
+    for alert in &mut payload.alerts {
+        alert.clean_links(&state.links);
     }
+    deliver(
+        &state,
+        caller_id(&caller),
+        &params,
+        None,
+        &payload.group,
+        &payload.alerts,
+    )
+    .await
+}
+
+async fn signal_failures(
+    State(state): State<AlertState>,
+) -> Result<Json<impl Serialize>, (http::StatusCode, String)> {
+    Ok(Json(state.runner.recent_failures()?))
+}
+
+/// 503 if any of the checks fail, so that it can be probed.
+async fn healthz(
+    State(state): State<AlertState>,
+) -> (http::StatusCode, Json<crate::health::Report>) {
+    let report = state.runner.health().await;
+    let status = if report.healthy {
+        http::StatusCode::OK
+    } else {
+        http::StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report))
+}
+
+async fn receive(State(state): State<AlertState>) -> Result<(), (http::StatusCode, String)> {
+    state.runner.receive_soon()?;
+    Ok(())
+}
+
+async fn maintenance(
+    State(state): State<AlertState>,
+) -> Result<Json<impl Serialize>, (http::StatusCode, String)> {
+    Ok(Json(state.runner.maintenance()?))
+}
+
+async fn audit(
+    State(state): State<AlertState>,
+    Query(query): Query<crate::audit::Query>,
+) -> Result<Json<impl Serialize>, (http::StatusCode, String)> {
+    Ok(Json(state.audit.query(&query)?))
+}
+
+async fn oncall(State(state): State<AlertState>) -> Json<impl Serialize> {
+    Json(state.oncall.current())
+}
+
+#[derive(Deserialize)]
+struct ListAlertsParams {
+    /// Only alerts created within this long, like `1h`.
+    #[serde(default)]
+    since: Option<String>,
+}
+
+/// JSON, or a page to look at for browsers which ask for HTML.
+async fn list_alerts(
+    State(state): State<AlertState>,
+    headers: http::HeaderMap,
+    Query(params): Query<ListAlertsParams>,
+) -> Result<axum::response::Response, (http::StatusCode, String)> {
+    let since = match params.since {
+        Some(ref s) => Some(
+            humantime::parse_duration(s)
+                .map_err(|e| (http::StatusCode::BAD_REQUEST, format!("since: {e}")))?,
+        ),
+        None => None,
+    };
+    let wants_html = headers
+        .get(http::header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("text/html"));
+    Ok(if wants_html {
+        axum::response::Html(state.runner.alerts_html(since)?).into_response()
+    } else {
+        Json(state.runner.list_alerts(since)?).into_response()
+    })
+}
+
+async fn no_pager() -> (http::StatusCode, &'static str) {
+    (
+        http::StatusCode::NOT_FOUND,
+        "there is no Pager service here, call the upstream pager instead",
+    )
+}
+
+async fn heartbeat(State(state): State<AlertState>) -> Result<(), (http::StatusCode, String)> {
+    state.runner.heartbeat().await?;
     Ok(())
 }
 
+async fn grafana(
+    State(state): State<AlertState>,
+    caller: MaybeCaller,
+    Query(params): Query<AlertParams>,
+    Json(mut payload): Json<GrafanaAlertsInput>,
+) -> Result<Delivered, (http::StatusCode, String)> {
+    for alert in &mut payload.alerts {
+        alert.clean_links(&state.links);
+    }
+    deliver(
+        &state,
+        caller_id(&caller),
+        &params,
+        None,
+        &payload.group,
+        &payload.alerts,
+    )
+    .await
+}
+
 #[derive(HttpServingInstance)]
 #[flag_prefix = "receiver-"]
 pub struct HttpApi(#[router] Router);
@@ -52,18 +343,182 @@ pub struct HttpApi(#[router] Router);
 #[derive(ResourceDependencies)]
 pub struct HttpApiDependencies {
     signal: Arc<crate::signal::SignalRunner>,
+    oncall: Arc<crate::oncall::OnCall>,
+    audit: Arc<crate::audit::Audit>,
+    renderer: Arc<Renderer>,
+    pager: Arc<crate::grpc::PagerService>,
+}
+
+#[derive(clap::Args)]
+pub struct HttpApiArgs {
+    /// Send all of the alerts in one webhook as a single message. This
+    /// can be overridden per request with the `group` query parameter.
+    #[arg(long)]
+    group_alerts: bool,
+    /// Rewrite links in alerts which start with the part before `=>` to
+    /// start with the part after instead, like
+    /// `http://prometheus:9090=>https://prometheus.example.com`. May be
+    /// repeated, and the first that matches is used.
+    #[arg(long)]
+    external_url_rewrite: Vec<links::Rewrite>,
+    /// Replace links in alerts with short ones to `/r/ID` on the receiver
+    /// HTTP port, which is reached at this URL, like
+    /// `https://pager.example.com`. Short links are only remembered
+    /// until a restart.
+    #[arg(long)]
+    short_url_base: Option<String>,
+    /// Accept PagerDuty events only with this routing key. May be
+    /// repeated. If not given then any routing key is accepted.
+    #[arg(long)]
+    pagerduty_routing_key: Vec<String>,
+    /// Discard alerts whose labels match, like `severity=info` or
+    /// `team=db,env=dev`, instead of paging for them. May be repeated,
+    /// and an alert matching any of them is discarded.
+    #[arg(long)]
+    drop_matching: Vec<filter::Matchers>,
+    /// Discard alerts unless their labels match, in the same form as
+    /// `--drop-matching`. May be repeated, and an alert matching any of
+    /// them is kept unless `--drop-matching` discards it.
+    #[arg(long)]
+    only_matching: Vec<filter::Matchers>,
+    /// File whose first line is a secret that `/alert`, `/grafana` and
+    /// `/json` requests must be signed with, as an HMAC-SHA256 of the body in
+    /// an `X-Hub-Signature-256: sha256=HEX` header.
+    #[arg(long)]
+    webhook_secret_file: Option<PathBuf>,
+    /// Require a bearer token or basic auth for a route, like
+    /// `/alert=bearer`, `/alerts=basic` or `/grafana=any`. `*` stands
+    /// for every route without a rule of its own. May be repeated.
+    #[arg(long)]
+    auth_route: Vec<auth::Rule>,
+    /// File of bearer tokens accepted by `--auth-route`, one per line.
+    #[arg(long)]
+    auth_bearer_token_file: Option<PathBuf>,
+    /// htpasswd file of users accepted by `--auth-route`, with bcrypt
+    /// password hashes as made by `htpasswd -B`.
+    #[arg(long)]
+    auth_htpasswd_file: Option<PathBuf>,
+    /// Accept alerts for a team at `/alert/TOKEN` and `/grafana/TOKEN`,
+    /// like
+    /// `name=team-a,token_file=PATH,group=GROUP_ID,template=PATH,rate=30/1h`.
+    /// The token is the first line of `token_file`. Its pages go to
+    /// `group` if given, are rendered with `template` if given and are
+    /// labelled `tenant=NAME`. More than `rate` of them are refused.
+    /// May be repeated.
+    #[arg(long)]
+    tenant: Vec<tenant::Spec>,
+    /// Accept webhooks in any JSON format at `/json`, finding the parts
+    /// of alerts in it with JSON pointers, like
+    /// `alerts=/items,status=/state,resolved=ok,fingerprint=/id,label.severity=/level,annotation.summary=/title`.
+    /// The other pointers are within each of `alerts`, or the whole
+    /// document is one alert if it is not given. An alert is resolved
+    /// if its status is `resolved`, or the value given.
+    #[arg(long)]
+    json_webhook: Option<generic::Mapping>,
 }
 
 #[resource]
 impl Resource for HttpApi {
     fn new(
         d: HttpApiDependencies,
-        _: comprehensive::NoArgs,
+        a: HttpApiArgs,
         _: &mut AssemblyRuntime<'_>,
-    ) -> Result<Arc<Self>, std::convert::Infallible> {
+    ) -> Result<Arc<Self>, HttpApiError> {
+        let router = Self::router(d.signal, Some(d.pager), d.oncall, d.audit, d.renderer, a)?;
+        Ok(Arc::new(Self(router)))
+    }
+}
+
+impl HttpApi {
+    /// The receiver's routes, paging with `backend`, for servers which
+    /// page some other way, like the relay. Without `pager` the gRPC-web
+    /// routes say that there is no Pager service.
+    pub fn router(
+        backend: Arc<dyn backend::Backend>,
+        pager: Option<Arc<crate::grpc::PagerService>>,
+        schedule: Arc<crate::oncall::OnCall>,
+        audit_log: Arc<crate::audit::Audit>,
+        renderer: Arc<Renderer>,
+        a: HttpApiArgs,
+    ) -> Result<Router, HttpApiError> {
+        let tenants = tenant::Tenants::new(a.tenant, &renderer)?;
+        let webhook_secret = match a.webhook_secret_file {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path)?;
+                let secret = contents.lines().next().unwrap_or_default();
+                if secret.is_empty() {
+                    return Err(HttpApiError::EmptySecret);
+                }
+                Some(Arc::from(secret.as_bytes()))
+            }
+            None => None,
+        };
+        let auth = auth::Auth::new(
+            a.auth_route,
+            a.auth_bearer_token_file.as_deref(),
+            a.auth_htpasswd_file.as_deref(),
+        )?;
+        tenants.check_auth(&auth)?;
+        let state = AlertState {
+            runner: backend,
+            oncall: schedule,
+            audit: audit_log,
+            renderer,
+            group_alerts: a.group_alerts,
+            routing_keys: Arc::new(a.pagerduty_routing_key.into_iter().collect()),
+            filter: Arc::new(filter::LabelFilter::new(a.drop_matching, a.only_matching)),
+            links: Arc::new(links::Links::new(a.external_url_rewrite, a.short_url_base)),
+            tenants: Arc::new(tenants),
+            json_mapping: a.json_webhook.map(Arc::new),
+            webhook_secret,
+            auth: Arc::new(auth),
+        };
+        let signed = || axum::middleware::from_fn_with_state(state.clone(), signature::verify);
         let app = Router::new()
-            .route("/alert", axum::routing::post(alert))
-            .with_state(d.signal);
-        Ok(Arc::new(Self(app)))
+            .route("/alert", axum::routing::post(alert).layer(signed()))
+            .route("/alerts", axum::routing::get(list_alerts))
+            .route("/signal-failures", axum::routing::get(signal_failures))
+            .route("/audit", axum::routing::get(audit))
+            .route("/receive", axum::routing::post(receive))
+            .route("/oncall", axum::routing::get(oncall))
+            .route("/maintenance", axum::routing::get(maintenance))
+            .route("/grafana", axum::routing::post(grafana).layer(signed()))
+            .route("/json", axum::routing::post(generic::json).layer(signed()))
+            .route("/heartbeat", axum::routing::get(heartbeat).post(heartbeat))
+            .route("/v2/enqueue", axum::routing::post(pagerduty::enqueue))
+            .route(
+                "/api/v2/silences",
+                axum::routing::get(silences::list).post(silences::post),
+            )
+            .route(
+                "/api/v2/silence/{id}",
+                axum::routing::get(silences::get).delete(silences::delete),
+            )
+            .route(
+                "/pager.Pager/{method}",
+                match pager {
+                    Some(pager) => crate::grpc::web::method_router(pager),
+                    None => axum::routing::post(no_pager),
+                },
+            )
+            .route_layer(axum::middleware::from_fn_with_state(
+                state.clone(),
+                auth::check,
+            ))
+            // Not subject to --auth-route, for probes.
+            .route("/healthz", axum::routing::get(healthz))
+            // The token in the path is the credential.
+            .route(
+                "/alert/{token}",
+                axum::routing::post(tenant::alert).layer(signed()),
+            )
+            .route(
+                "/grafana/{token}",
+                axum::routing::post(tenant::grafana).layer(signed()),
+            )
+            // Opened from phones, which have no credentials.
+            .route("/r/{id}", axum::routing::get(links::redirect))
+            .with_state(state);
+        Ok(app)
     }
 }
@@ -0,0 +1,342 @@
+//! Authentication of requests to the receiver HTTP API, for when mTLS
+//! between the sender and us is not practical.
+
+use axum::extract::{MatchedPath, Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use super::AlertState;
+
+#[derive(Debug, thiserror::Error)]
+pub enum AuthError {
+    #[error("Reading {0}: {1}")]
+    IOError(PathBuf, std::io::Error),
+    #[error("{0}:{1}: expected USER:BCRYPT_HASH")]
+    BadHtpasswdLine(PathBuf, usize),
+    #[error("--auth-route {0} accepts bearer tokens but there is no --auth-bearer-token-file")]
+    NoTokens(String),
+    #[error("--auth-route {0} accepts basic auth but there is no --auth-htpasswd-file")]
+    NoUsers(String),
+    #[error("--auth-route {0} needs --auth-bearer-token-file or --auth-htpasswd-file")]
+    NoCredentials(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Scheme {
+    Bearer,
+    Basic,
+    Any,
+}
+
+impl Scheme {
+    fn bearer(self) -> bool {
+        self != Self::Basic
+    }
+
+    fn basic(self) -> bool {
+        self != Self::Bearer
+    }
+}
+
+/// Requires authentication for a route. Parsed from `/alert=bearer`,
+/// `/alerts=basic` or `/grafana=any`, where `*` stands for every route
+/// that has no rule of its own.
+#[derive(Clone, Debug)]
+pub struct Rule {
+    route: String,
+    scheme: Scheme,
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((route, scheme)) = s.rsplit_once('=') else {
+            return Err(String::from("Expected ROUTE=bearer|basic|any"));
+        };
+        let scheme = match scheme {
+            "bearer" => Scheme::Bearer,
+            "basic" => Scheme::Basic,
+            "any" => Scheme::Any,
+            _ => {
+                return Err(format!(
+                    "Unknown scheme {scheme:?}, expected bearer, basic or any"
+                ));
+            }
+        };
+        Ok(Self {
+            route: String::from(route),
+            scheme,
+        })
+    }
+}
+
+pub struct Auth {
+    rules: Vec<Rule>,
+    /// SHA-256 of each token, so that comparing them takes no longer
+    /// for a closer guess.
+    tokens: Vec<[u8; 32]>,
+    /// Bcrypt hash of each user's password.
+    users: HashMap<String, String>,
+    /// SHA-256 of the password each user last gave which bcrypt
+    /// verified, so that bcrypt, which is slow on purpose, is not run
+    /// for every request.
+    verified: Mutex<HashMap<String, [u8; 32]>>,
+}
+
+fn read(path: &Path) -> Result<String, AuthError> {
+    std::fs::read_to_string(path).map_err(|e| AuthError::IOError(path.to_owned(), e))
+}
+
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+/// The SHA-256 of each bearer token in a file, one per line.
+pub fn read_tokens(path: &Path) -> Result<Vec<[u8; 32]>, AuthError> {
+    Ok(read(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|t| sha256(t.as_bytes()))
+        .collect())
+}
+
+/// Who a bearer token belongs to: `token:` and enough of its hash to
+/// tell tokens apart without revealing them.
+pub fn token_caller(hash: &[u8; 32]) -> String {
+    format!("token:{}", hex::encode(&hash[..4]))
+}
+
+impl Auth {
+    pub fn new(
+        rules: Vec<Rule>,
+        token_file: Option<&Path>,
+        htpasswd_file: Option<&Path>,
+    ) -> Result<Self, AuthError> {
+        let tokens = match token_file {
+            Some(path) => read_tokens(path)?,
+            None => Vec::new(),
+        };
+        let mut users = HashMap::new();
+        if let Some(path) = htpasswd_file {
+            for (i, line) in read(path)?.lines().enumerate() {
+                if line.trim().is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                match line.split_once(':') {
+                    Some((user, hash)) if hash.starts_with("$2") => {
+                        users.insert(String::from(user), String::from(hash));
+                    }
+                    _ => return Err(AuthError::BadHtpasswdLine(path.to_owned(), i + 1)),
+                }
+            }
+        }
+        for rule in &rules {
+            if rule.scheme == Scheme::Bearer && tokens.is_empty() {
+                return Err(AuthError::NoTokens(rule.route.clone()));
+            }
+            if rule.scheme == Scheme::Basic && users.is_empty() {
+                return Err(AuthError::NoUsers(rule.route.clone()));
+            }
+            if tokens.is_empty() && users.is_empty() {
+                return Err(AuthError::NoCredentials(rule.route.clone()));
+            }
+        }
+        Ok(Self {
+            rules,
+            tokens,
+            users,
+            verified: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Whether callers of `route` must authenticate.
+    pub fn covers(&self, route: &str) -> bool {
+        self.scheme_for(route).is_some()
+    }
+
+    fn scheme_for(&self, route: &str) -> Option<Scheme> {
+        self.rules
+            .iter()
+            .find(|r| r.route == route)
+            .or_else(|| self.rules.iter().find(|r| r.route == "*"))
+            .map(|r| r.scheme)
+    }
+
+    /// Who the credentials belong to, if the scheme accepts them.
+    async fn identify(&self, scheme: Scheme, authorization: &str) -> Option<Caller> {
+        if let Some(token) = authorization.strip_prefix("Bearer ") {
+            let hash = sha256(token.trim().as_bytes());
+            if !scheme.bearer() || !self.tokens.contains(&hash) {
+                return None;
+            }
+            return Some(Caller(token_caller(&hash)));
+        }
+        let credentials = authorization.strip_prefix("Basic ")?;
+        let decoded = BASE64
+            .decode(credentials.trim())
+            .ok()
+            .and_then(|d| String::from_utf8(d).ok())?;
+        let (user, password) = decoded.split_once(':')?;
+        let hash = self.users.get(user).filter(|_| scheme.basic())?;
+        let digest = sha256(password.as_bytes());
+        if self.verified.lock().unwrap().get(user) == Some(&digest) {
+            return Some(Caller(String::from(user)));
+        }
+        let (password, hash) = (String::from(password), hash.clone());
+        let verified = tokio::task::spawn_blocking(move || bcrypt::verify(password, &hash))
+            .await
+            .ok()?
+            .unwrap_or(false);
+        if !verified {
+            return None;
+        }
+        self.verified
+            .lock()
+            .unwrap()
+            .insert(String::from(user), digest);
+        Some(Caller(String::from(user)))
+    }
+}
+
+/// Who authenticated a request: a basic auth user, or `token:` and the
+/// start of the SHA-256 of a bearer token.
+#[derive(Clone)]
+pub struct Caller(pub String);
+
+/// Passes on only requests with credentials that the route accepts, if
+/// it needs any.
+pub(super) async fn check(
+    State(state): State<AlertState>,
+    mut req: Request,
+    next: Next,
+) -> Result<Response, Response> {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(MatchedPath::as_str)
+        .unwrap_or_else(|| req.uri().path());
+    let Some(scheme) = state.auth.scheme_for(route) else {
+        return Ok(next.run(req).await);
+    };
+    let authorization = req
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from);
+    let caller = match authorization {
+        Some(v) => state.auth.identify(scheme, &v).await,
+        None => None,
+    };
+    if let Some(caller) = caller {
+        req.extensions_mut().insert(caller);
+        return Ok(next.run(req).await);
+    }
+    let challenge = match scheme {
+        Scheme::Bearer => "Bearer",
+        Scheme::Basic | Scheme::Any => "Basic realm=\"signal-pager\"",
+    };
+    Err(Response::builder()
+        .status(http::StatusCode::UNAUTHORIZED)
+        .header(http::header::WWW_AUTHENTICATE, challenge)
+        .body(axum::body::Body::from("authentication required"))
+        .expect("valid response"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::file;
+
+    #[test]
+    fn parse_rule() {
+        let rule: Rule = "/alert=bearer".parse().unwrap();
+        assert_eq!(rule.route, "/alert");
+        assert_eq!(rule.scheme, Scheme::Bearer);
+        let rule: Rule = "*=any".parse().unwrap();
+        assert_eq!(rule.route, "*");
+        assert_eq!(rule.scheme, Scheme::Any);
+        let rule: Rule = "/a=b=basic".parse().unwrap();
+        assert_eq!(rule.route, "/a=b");
+        assert_eq!(rule.scheme, Scheme::Basic);
+        assert!("/alert".parse::<Rule>().is_err());
+        assert!("/alert=digest".parse::<Rule>().is_err());
+    }
+
+    #[test]
+    fn rules_need_credentials() {
+        let tokens = file("secret\n");
+        let rules = || vec!["/alert=basic".parse().unwrap()];
+        assert!(matches!(
+            Auth::new(rules(), Some(tokens.path()), None),
+            Err(AuthError::NoUsers(_))
+        ));
+        assert!(matches!(
+            Auth::new(vec!["*=any".parse().unwrap()], None, None),
+            Err(AuthError::NoCredentials(_))
+        ));
+        let htpasswd = file("alice:plaintext\n");
+        assert!(matches!(
+            Auth::new(rules(), None, Some(htpasswd.path())),
+            Err(AuthError::BadHtpasswdLine(_, 1))
+        ));
+    }
+
+    #[test]
+    fn scheme_for_route() {
+        let tokens = file("# comment\nsecret\n");
+        let auth = Auth::new(
+            vec!["/alert=bearer".parse().unwrap(), "*=any".parse().unwrap()],
+            Some(tokens.path()),
+            None,
+        )
+        .unwrap();
+        assert_eq!(auth.scheme_for("/alert"), Some(Scheme::Bearer));
+        assert_eq!(auth.scheme_for("/alerts"), Some(Scheme::Any));
+        let auth = Auth::new(
+            vec!["/alert=bearer".parse().unwrap()],
+            Some(tokens.path()),
+            None,
+        )
+        .unwrap();
+        assert_eq!(auth.scheme_for("/alerts"), None);
+        assert!(auth.covers("/alert") && !auth.covers("/alerts"));
+    }
+
+    #[tokio::test]
+    async fn identify() {
+        let tokens = file("secret\n");
+        let htpasswd = file(&format!("alice:{}\n", bcrypt::hash("pw", 4).unwrap()));
+        let auth = Auth::new(
+            vec!["*=any".parse().unwrap()],
+            Some(tokens.path()),
+            Some(htpasswd.path()),
+        )
+        .unwrap();
+        let caller = auth.identify(Scheme::Any, "Bearer secret").await.unwrap();
+        assert_eq!(caller.0, "token:2bb80d53");
+        assert!(auth.identify(Scheme::Any, "Bearer wrong").await.is_none());
+        assert!(
+            auth.identify(Scheme::Basic, "Bearer secret")
+                .await
+                .is_none()
+        );
+        let basic = format!("Basic {}", BASE64.encode("alice:pw"));
+        assert_eq!(auth.identify(Scheme::Any, &basic).await.unwrap().0, "alice");
+        // Verified again from the cache.
+        assert_eq!(auth.identify(Scheme::Any, &basic).await.unwrap().0, "alice");
+        assert!(auth.identify(Scheme::Bearer, &basic).await.is_none());
+        let wrong = format!("Basic {}", BASE64.encode("alice:wrong"));
+        assert!(auth.identify(Scheme::Any, &wrong).await.is_none());
+        assert!(auth.identify(Scheme::Any, "Basic !!!").await.is_none());
+        assert!(auth.identify(Scheme::Any, "Digest secret").await.is_none());
+    }
+}
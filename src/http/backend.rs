@@ -0,0 +1,128 @@
+//! What the receiver does with the pages it is sent: the pager sends
+//! them with its [`SignalRunner`], and the relay forwards them to an
+//! upstream pager.
+
+use futures::FutureExt;
+use futures::future::BoxFuture;
+use std::time::Duration;
+
+use super::silences::Silence;
+use crate::alerts::AlertRecord;
+use crate::health::Report;
+use crate::maintenance::MaintenanceStatus;
+use crate::page::PageMeta;
+use crate::signal::{SignalFailure, SignalRunner};
+
+/// Everything the HTTP API asks of whatever pages. Those which only keep
+/// what pages leave behind, such as alerts and silences, may refuse with
+/// a status saying where to look instead.
+pub trait Backend: Send + Sync {
+    fn page<'a>(
+        &'a self,
+        source: &'a str,
+        caller: Option<&'a str>,
+        msg: String,
+        meta: &'a PageMeta,
+    ) -> BoxFuture<'a, Result<(), (http::StatusCode, String)>>;
+
+    /// Says how each of `pages` went.
+    fn page_batch<'a>(
+        &'a self,
+        source: &'a str,
+        caller: Option<&'a str>,
+        pages: Vec<(String, PageMeta)>,
+    ) -> BoxFuture<'a, Vec<Result<(), (http::StatusCode, String)>>>;
+
+    fn heartbeat(&self) -> BoxFuture<'_, Result<(), (http::StatusCode, String)>>;
+
+    fn health(&self) -> BoxFuture<'_, Report>;
+
+    /// Receives Signal messages now instead of at the next interval.
+    fn receive_soon(&self) -> Result<(), (http::StatusCode, String)>;
+
+    /// Alerts created within `since`, or all of them.
+    fn list_alerts(
+        &self,
+        since: Option<Duration>,
+    ) -> Result<Vec<AlertRecord>, (http::StatusCode, String)>;
+
+    fn alerts_html(&self, since: Option<Duration>) -> Result<String, (http::StatusCode, String)>;
+
+    fn recent_failures(&self) -> Result<Vec<SignalFailure>, (http::StatusCode, String)>;
+
+    fn maintenance(&self) -> Result<MaintenanceStatus, (http::StatusCode, String)>;
+
+    fn silences(&self) -> Result<Vec<Silence>, (http::StatusCode, String)>;
+
+    /// Returns the new silence's ID.
+    fn add_silence(&self, silence: Silence) -> Result<u64, (http::StatusCode, String)>;
+
+    /// Whether there was such a silence.
+    fn expire_silence(&self, id: u64) -> Result<bool, (http::StatusCode, String)>;
+}
+
+impl Backend for SignalRunner {
+    fn page<'a>(
+        &'a self,
+        source: &'a str,
+        caller: Option<&'a str>,
+        msg: String,
+        meta: &'a PageMeta,
+    ) -> BoxFuture<'a, Result<(), (http::StatusCode, String)>> {
+        self.page(source, caller, msg, meta)
+            .map(|r| r.map(|_| ()).map_err(Into::into))
+            .boxed()
+    }
+
+    fn page_batch<'a>(
+        &'a self,
+        source: &'a str,
+        caller: Option<&'a str>,
+        pages: Vec<(String, PageMeta)>,
+    ) -> BoxFuture<'a, Vec<Result<(), (http::StatusCode, String)>>> {
+        self.page_batch(source, caller, pages).boxed()
+    }
+
+    fn heartbeat(&self) -> BoxFuture<'_, Result<(), (http::StatusCode, String)>> {
+        self.heartbeat().map(|r| r.map_err(Into::into)).boxed()
+    }
+
+    fn health(&self) -> BoxFuture<'_, Report> {
+        self.health().boxed()
+    }
+
+    fn receive_soon(&self) -> Result<(), (http::StatusCode, String)> {
+        Ok(self.receive_soon()?)
+    }
+
+    fn list_alerts(
+        &self,
+        since: Option<Duration>,
+    ) -> Result<Vec<AlertRecord>, (http::StatusCode, String)> {
+        Ok(self.list_alerts(since)?)
+    }
+
+    fn alerts_html(&self, since: Option<Duration>) -> Result<String, (http::StatusCode, String)> {
+        Ok(self.alerts_html(since)?)
+    }
+
+    fn recent_failures(&self) -> Result<Vec<SignalFailure>, (http::StatusCode, String)> {
+        Ok(self.recent_failures()?)
+    }
+
+    fn maintenance(&self) -> Result<MaintenanceStatus, (http::StatusCode, String)> {
+        Ok(self.maintenance()?)
+    }
+
+    fn silences(&self) -> Result<Vec<Silence>, (http::StatusCode, String)> {
+        Ok(self.silences()?)
+    }
+
+    fn add_silence(&self, silence: Silence) -> Result<u64, (http::StatusCode, String)> {
+        Ok(self.add_silence(silence)?)
+    }
+
+    fn expire_silence(&self, id: u64) -> Result<bool, (http::StatusCode, String)> {
+        Ok(self.expire_silence(id)?)
+    }
+}
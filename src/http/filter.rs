@@ -0,0 +1,97 @@
+//! Discarding alerts by their labels as they arrive, for those which
+//! should never page but which Alertmanager sends anyway.
+
+use std::collections::HashMap;
+
+/// Labels which must all have the given values, parsed from
+/// `severity=info,team=db`.
+#[derive(Clone, Debug)]
+pub struct Matchers(Vec<(String, String)>);
+
+impl std::str::FromStr for Matchers {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(|m| match m.split_once('=') {
+                Some((k, v)) => Ok((String::from(k), String::from(v))),
+                None => Err(format!("Expected label=value, got {m:?}")),
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(Self)
+    }
+}
+
+impl Matchers {
+    pub fn matches(&self, labels: &HashMap<String, String>) -> bool {
+        self.0.iter().all(|(k, v)| labels.get(k) == Some(v))
+    }
+}
+
+pub struct LabelFilter {
+    drop: Vec<Matchers>,
+    only: Vec<Matchers>,
+}
+
+impl LabelFilter {
+    pub fn new(drop: Vec<Matchers>, only: Vec<Matchers>) -> Self {
+        Self { drop, only }
+    }
+
+    /// Whether an alert with these labels should page.
+    pub fn allows(&self, labels: &HashMap<String, String>) -> bool {
+        let allowed = !self.drop.iter().any(|m| m.matches(labels))
+            && (self.only.is_empty() || self.only.iter().any(|m| m.matches(labels)));
+        if !allowed {
+            log::info!("Dropping alert with labels {labels:?}");
+        }
+        allowed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (String::from(*k), String::from(*v)))
+            .collect()
+    }
+
+    #[test]
+    fn parse() {
+        let m: Matchers = "severity=info,team=db".parse().unwrap();
+        assert_eq!(
+            m.0,
+            [
+                (String::from("severity"), String::from("info")),
+                (String::from("team"), String::from("db")),
+            ]
+        );
+        let m: Matchers = "url=a=b".parse().unwrap();
+        assert_eq!(m.0, [(String::from("url"), String::from("a=b"))]);
+        assert!("severity".parse::<Matchers>().is_err());
+        assert!("severity=info,".parse::<Matchers>().is_err());
+    }
+
+    #[test]
+    fn matches_every_label() {
+        let m: Matchers = "severity=info,team=db".parse().unwrap();
+        assert!(m.matches(&labels(&[("severity", "info"), ("team", "db"), ("x", "y")])));
+        assert!(!m.matches(&labels(&[("severity", "info")])));
+        assert!(!m.matches(&labels(&[("severity", "info"), ("team", "web")])));
+    }
+
+    #[test]
+    fn filter() {
+        let filter = LabelFilter::new(
+            vec!["severity=info".parse().unwrap()],
+            vec!["team=db".parse().unwrap()],
+        );
+        assert!(filter.allows(&labels(&[("team", "db")])));
+        assert!(!filter.allows(&labels(&[("team", "db"), ("severity", "info")])));
+        assert!(!filter.allows(&labels(&[("team", "web")])));
+    }
+}
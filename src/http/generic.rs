@@ -0,0 +1,197 @@
+//! Webhooks in any JSON format at `/json`, for senders which cannot send
+//! Alertmanager's or Grafana's: `--json-webhook` says where in the JSON
+//! to find each part of an alert, which is then paged for like one from
+//! Alertmanager.
+
+use axum::Json;
+use axum::extract::{Query, State};
+use std::collections::HashMap;
+
+use super::{AlertParams, AlertState, Delivered, MaybeCaller, caller_id};
+use crate::alert::{AlertInput, GroupContext};
+
+/// Where each part of an alert is, as JSON pointers, parsed from
+/// `alerts=/items,status=/state,resolved=ok,label.severity=/level,annotation.summary=/title`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Mapping {
+    /// An array of alerts. The other pointers are within each of them.
+    /// Without it, the whole document is one alert.
+    alerts: Option<String>,
+    status: Option<String>,
+    /// The status that an alert has once it is over.
+    resolved: Option<String>,
+    fingerprint: Option<String>,
+    starts_at: Option<String>,
+    ends_at: Option<String>,
+    generator_url: Option<String>,
+    labels: Vec<(String, String)>,
+    annotations: Vec<(String, String)>,
+}
+
+impl std::str::FromStr for Mapping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut mapping = Self::default();
+        for part in s.split(',') {
+            let Some((k, v)) = part.split_once('=') else {
+                return Err(format!("Expected name=/pointer, got {part:?}"));
+            };
+            let v = String::from(v);
+            if k == "resolved" {
+                mapping.resolved = Some(v);
+                continue;
+            }
+            if !v.starts_with('/') {
+                return Err(format!("{k}: {v:?} is not a JSON pointer"));
+            }
+            match k {
+                "alerts" => mapping.alerts = Some(v),
+                "status" => mapping.status = Some(v),
+                "fingerprint" => mapping.fingerprint = Some(v),
+                "starts_at" => mapping.starts_at = Some(v),
+                "ends_at" => mapping.ends_at = Some(v),
+                "generator_url" => mapping.generator_url = Some(v),
+                _ => match (k.strip_prefix("label."), k.strip_prefix("annotation.")) {
+                    (Some(label), _) => mapping.labels.push((String::from(label), v)),
+                    (_, Some(annotation)) => {
+                        mapping.annotations.push((String::from(annotation), v))
+                    }
+                    _ => return Err(format!("Unknown field {k:?}")),
+                },
+            }
+        }
+        Ok(mapping)
+    }
+}
+
+/// Strings as they are, and anything else but null as JSON.
+fn string_at(value: &serde_json::Value, pointer: Option<&str>) -> Option<String> {
+    match value.pointer(pointer?)? {
+        serde_json::Value::Null => None,
+        serde_json::Value::String(s) => Some(s.clone()),
+        v => Some(v.to_string()),
+    }
+}
+
+fn strings_at(value: &serde_json::Value, pointers: &[(String, String)]) -> HashMap<String, String> {
+    pointers
+        .iter()
+        .filter_map(|(name, pointer)| Some((name.clone(), string_at(value, Some(pointer))?)))
+        .collect()
+}
+
+impl Mapping {
+    fn alert(&self, value: &serde_json::Value) -> AlertInput {
+        let resolved = self.resolved.as_deref().unwrap_or("resolved");
+        let status = match string_at(value, self.status.as_deref()) {
+            Some(s) if s == resolved => "resolved",
+            _ => "firing",
+        };
+        AlertInput {
+            status: String::from(status),
+            labels: strings_at(value, &self.labels),
+            annotations: strings_at(value, &self.annotations),
+            starts_at: string_at(value, self.starts_at.as_deref()),
+            ends_at: string_at(value, self.ends_at.as_deref()),
+            generator_url: string_at(value, self.generator_url.as_deref()),
+            fingerprint: string_at(value, self.fingerprint.as_deref()),
+        }
+    }
+
+    fn alerts(&self, payload: &serde_json::Value) -> Result<Vec<AlertInput>, String> {
+        let Some(ref pointer) = self.alerts else {
+            return Ok(vec![self.alert(payload)]);
+        };
+        match payload.pointer(pointer) {
+            Some(serde_json::Value::Array(alerts)) => {
+                Ok(alerts.iter().map(|a| self.alert(a)).collect())
+            }
+            Some(_) => Err(format!("{pointer} is not an array")),
+            None => Err(format!("no {pointer}")),
+        }
+    }
+}
+
+pub(super) async fn json(
+    State(state): State<AlertState>,
+    caller: MaybeCaller,
+    Query(params): Query<AlertParams>,
+    Json(payload): Json<serde_json::Value>,
+) -> Result<Delivered, (http::StatusCode, String)> {
+    let Some(ref mapping) = state.json_mapping else {
+        return Err((
+            http::StatusCode::NOT_FOUND,
+            String::from("no --json-webhook is configured"),
+        ));
+    };
+    let mut alerts = mapping
+        .alerts(&payload)
+        .map_err(|e| (http::StatusCode::BAD_REQUEST, e))?;
+    for alert in &mut alerts {
+        alert.clean_links(&state.links);
+    }
+    super::deliver(
+        &state,
+        caller_id(&caller),
+        &params,
+        None,
+        &GroupContext::default(),
+        &alerts,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let m: Mapping = "alerts=/items,status=/state,resolved=ok,label.severity=/level"
+            .parse()
+            .unwrap();
+        assert_eq!(m.alerts.as_deref(), Some("/items"));
+        assert_eq!(m.resolved.as_deref(), Some("ok"));
+        assert_eq!(
+            m.labels,
+            [(String::from("severity"), String::from("/level"))]
+        );
+        assert!("status=state".parse::<Mapping>().is_err());
+        assert!("colour=/c".parse::<Mapping>().is_err());
+        assert!("status".parse::<Mapping>().is_err());
+    }
+
+    #[test]
+    fn alerts() {
+        let m: Mapping = "alerts=/items,status=/state,resolved=ok,fingerprint=/id,\
+                          label.severity=/level,label.host=/where/host,annotation.summary=/title"
+            .parse()
+            .unwrap();
+        let payload = serde_json::json!({
+            "items": [
+                {"id": 42, "state": "alerting", "level": "critical",
+                 "where": {"host": "db1"}, "title": "Disk full"},
+                {"id": 43, "state": "ok", "title": "Disk fine", "level": null},
+            ],
+        });
+        let alerts = m.alerts(&payload).unwrap();
+        assert_eq!(alerts.len(), 2);
+        assert_eq!(alerts[0].status, "firing");
+        assert_eq!(alerts[0].fingerprint.as_deref(), Some("42"));
+        assert_eq!(alerts[0].labels["severity"], "critical");
+        assert_eq!(alerts[0].labels["host"], "db1");
+        assert_eq!(alerts[0].annotations["summary"], "Disk full");
+        assert_eq!(alerts[1].status, "resolved");
+        assert!(alerts[1].labels.is_empty());
+        assert!(m.alerts(&serde_json::json!({"items": {}})).is_err());
+
+        let single: Mapping = "annotation.summary=/text".parse().unwrap();
+        let alerts = single
+            .alerts(&serde_json::json!({"text": "Deploy failed"}))
+            .unwrap();
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].status, "firing");
+        assert_eq!(alerts[0].annotations["summary"], "Deploy failed");
+    }
+}
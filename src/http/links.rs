@@ -0,0 +1,179 @@
+//! Making the links in alerts usable from a phone: Prometheus's
+//! `generatorURL`s point at hosts only reachable from inside and are
+//! long enough to fill the screen.
+
+use axum::extract::{Path, State};
+use axum::response::{IntoResponse, Redirect};
+use chacha20poly1305::aead::OsRng;
+use chacha20poly1305::aead::rand_core::RngCore;
+use std::collections::{HashMap, VecDeque};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use super::AlertState;
+
+/// How many short links are remembered before the oldest are forgotten.
+const MAX_SHORT_LINKS: usize = 10000;
+
+/// Replaces the start of links, parsed from `FROM=>TO`.
+#[derive(Clone, Debug)]
+pub struct Rewrite {
+    from: String,
+    to: String,
+}
+
+impl FromStr for Rewrite {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once("=>") {
+            Some((from, to)) if !from.is_empty() => Ok(Self {
+                from: String::from(from),
+                to: String::from(to),
+            }),
+            _ => Err(String::from("Expected FROM=>TO")),
+        }
+    }
+}
+
+#[derive(Default)]
+struct ShortLinks {
+    by_id: HashMap<String, String>,
+    by_url: HashMap<String, String>,
+    /// IDs, oldest first.
+    order: VecDeque<String>,
+}
+
+pub struct Links {
+    rewrites: Vec<Rewrite>,
+    short_base: Option<String>,
+    short: Mutex<ShortLinks>,
+}
+
+impl Links {
+    pub fn new(rewrites: Vec<Rewrite>, short_base: Option<String>) -> Self {
+        Self {
+            rewrites,
+            short_base: short_base.map(|b| String::from(b.trim_end_matches('/'))),
+            short: Mutex::new(ShortLinks::default()),
+        }
+    }
+
+    /// The link rewritten by the first `--external-url-rewrite` that
+    /// matches, and then shortened if there is a `--short-url-base`.
+    pub fn clean(&self, url: &str) -> String {
+        let url = match self.rewrites.iter().find(|r| url.starts_with(&r.from)) {
+            Some(r) => format!("{}{}", r.to, &url[r.from.len()..]),
+            None => String::from(url),
+        };
+        match self.short_base {
+            Some(ref base) if !url.is_empty() => format!("{base}/r/{}", self.shorten(url)),
+            _ => url,
+        }
+    }
+
+    pub fn clean_in_place(&self, url: &mut Option<String>) {
+        if let Some(u) = url {
+            *u = self.clean(u);
+        }
+    }
+
+    /// Returns the ID of the short link to `url`.
+    fn shorten(&self, url: String) -> String {
+        let mut short = self.short.lock().unwrap();
+        if let Some(id) = short.by_url.get(&url) {
+            return id.clone();
+        }
+        let mut bytes = [0u8; 6];
+        OsRng.fill_bytes(&mut bytes);
+        let id = hex::encode(bytes);
+        short.by_id.insert(id.clone(), url.clone());
+        short.by_url.insert(url, id.clone());
+        short.order.push_back(id.clone());
+        if short.order.len() > MAX_SHORT_LINKS
+            && let Some(old) = short.order.pop_front()
+            && let Some(url) = short.by_id.remove(&old)
+        {
+            short.by_url.remove(&url);
+        }
+        id
+    }
+
+    fn get(&self, id: &str) -> Option<String> {
+        self.short.lock().unwrap().by_id.get(id).cloned()
+    }
+}
+
+/// `/r/{id}`, where short links go.
+pub(super) async fn redirect(
+    State(state): State<AlertState>,
+    Path(id): Path<String>,
+) -> axum::response::Response {
+    match state.links.get(&id) {
+        Some(url) => Redirect::temporary(&url).into_response(),
+        None => (http::StatusCode::NOT_FOUND, "Unknown or expired link").into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let r: Rewrite = "http://prom:9090=>https://prom.example.com"
+            .parse()
+            .unwrap();
+        assert_eq!(r.from, "http://prom:9090");
+        assert_eq!(r.to, "https://prom.example.com");
+        assert!("http://prom:9090".parse::<Rewrite>().is_err());
+        assert!("=>https://prom.example.com".parse::<Rewrite>().is_err());
+    }
+
+    #[test]
+    fn rewrite() {
+        let links = Links::new(
+            vec![
+                "http://prom:9090=>https://prom.example.com"
+                    .parse()
+                    .unwrap(),
+                "http://prom=>https://other.example.com".parse().unwrap(),
+            ],
+            None,
+        );
+        assert_eq!(
+            links.clean("http://prom:9090/graph?g0.expr=up"),
+            "https://prom.example.com/graph?g0.expr=up"
+        );
+        assert_eq!(links.clean("http://grafana/d/1"), "http://grafana/d/1");
+        let mut url = Some(String::from("http://prom/x"));
+        links.clean_in_place(&mut url);
+        assert_eq!(url.as_deref(), Some("https://other.example.com/x"));
+    }
+
+    #[test]
+    fn shorten() {
+        let links = Links::new(Vec::new(), Some(String::from("https://pager.example.com/")));
+        let short = links.clean("http://prom:9090/graph?g0.expr=up");
+        let id = short.strip_prefix("https://pager.example.com/r/").unwrap();
+        assert_eq!(id.len(), 12);
+        assert_eq!(links.clean("http://prom:9090/graph?g0.expr=up"), short);
+        assert_ne!(links.clean("http://prom:9090/other"), short);
+        assert_eq!(
+            links.get(id).as_deref(),
+            Some("http://prom:9090/graph?g0.expr=up")
+        );
+        assert_eq!(links.clean(""), "");
+    }
+
+    #[test]
+    fn forgets_the_oldest() {
+        let links = Links::new(Vec::new(), Some(String::from("https://pager.example.com")));
+        let first = links.shorten(String::from("https://example.com/0"));
+        for i in 1..=MAX_SHORT_LINKS {
+            links.shorten(format!("https://example.com/{i}"));
+        }
+        assert_eq!(links.get(&first), None);
+        assert_eq!(links.short.lock().unwrap().by_url.len(), MAX_SHORT_LINKS);
+    }
+}
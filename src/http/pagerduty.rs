@@ -0,0 +1,174 @@
+//! Ingest compatible with the PagerDuty Events API v2, so that anything
+//! which can notify PagerDuty can page through us instead.
+
+use axum::Json;
+use axum::extract::State;
+use chacha20poly1305::aead::OsRng;
+use chacha20poly1305::aead::rand_core::RngCore;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use super::{AlertState, render_error};
+use crate::alert::{Alert, AlertInput, GroupContext, render_alert, unrendered};
+use crate::page::PageMeta;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum EventAction {
+    Trigger,
+    Acknowledge,
+    Resolve,
+}
+
+#[derive(Deserialize)]
+struct EventPayload {
+    summary: String,
+    source: String,
+    severity: String,
+    component: Option<String>,
+    group: Option<String>,
+    class: Option<String>,
+    custom_details: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct EventLink {
+    href: String,
+}
+
+#[derive(Deserialize)]
+struct EventImage {
+    src: String,
+}
+
+#[derive(Deserialize)]
+pub(super) struct Event {
+    routing_key: String,
+    event_action: EventAction,
+    dedup_key: Option<String>,
+    payload: Option<EventPayload>,
+    #[serde(default)]
+    links: Vec<EventLink>,
+    #[serde(default)]
+    images: Vec<EventImage>,
+}
+
+type EventResponse = (http::StatusCode, Json<serde_json::Value>);
+
+fn rejection(message: &str) -> EventResponse {
+    (
+        http::StatusCode::BAD_REQUEST,
+        Json(serde_json::json!({
+            "status": "invalid event",
+            "message": message,
+        })),
+    )
+}
+
+fn accepted(dedup_key: &str) -> EventResponse {
+    (
+        http::StatusCode::ACCEPTED,
+        Json(serde_json::json!({
+            "status": "success",
+            "message": "Event processed",
+            "dedup_key": dedup_key,
+        })),
+    )
+}
+
+fn new_dedup_key() -> String {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+impl Event {
+    fn into_alert(self, dedup_key: &str) -> Result<AlertInput, EventResponse> {
+        let status = match self.event_action {
+            EventAction::Trigger => "firing",
+            EventAction::Acknowledge => "acknowledged",
+            EventAction::Resolve => "resolved",
+        };
+        let mut labels = HashMap::from([(String::from("dedup_key"), String::from(dedup_key))]);
+        let mut annotations = HashMap::new();
+        match self.payload {
+            Some(p) => {
+                labels.insert(String::from("source"), p.source);
+                labels.insert(String::from("severity"), p.severity);
+                for (k, v) in [
+                    ("component", p.component),
+                    ("group", p.group),
+                    ("class", p.class),
+                ] {
+                    if let Some(v) = v {
+                        labels.insert(String::from(k), v);
+                    }
+                }
+                annotations.insert(String::from("summary"), p.summary);
+                if let Some(details) = p.custom_details {
+                    let details = match details {
+                        serde_json::Value::String(s) => s,
+                        other => serde_json::to_string_pretty(&other).unwrap_or_default(),
+                    };
+                    annotations.insert(String::from("description"), details);
+                }
+            }
+            None if matches!(self.event_action, EventAction::Trigger) => {
+                return Err(rejection("payload is required for trigger events"));
+            }
+            None => (),
+        }
+        if let Some(image) = self.images.into_iter().next() {
+            annotations.insert(String::from("attachment_url"), image.src);
+        }
+        Ok(AlertInput {
+            status: String::from(status),
+            labels,
+            annotations,
+            starts_at: None,
+            ends_at: None,
+            generator_url: self.links.into_iter().next().map(|l| l.href),
+            fingerprint: Some(String::from(dedup_key)),
+        })
+    }
+}
+
+#[tracing::instrument(skip_all)]
+pub(super) async fn enqueue(
+    State(state): State<AlertState>,
+    caller: super::MaybeCaller,
+    Json(event): Json<Event>,
+) -> Result<EventResponse, (http::StatusCode, String)> {
+    if !state.routing_keys.is_empty() && !state.routing_keys.contains(&event.routing_key) {
+        return Ok(rejection("Invalid routing key"));
+    }
+    let dedup_key = match event.dedup_key {
+        Some(ref k) => k.clone(),
+        None if matches!(event.event_action, EventAction::Trigger) => new_dedup_key(),
+        None => return Ok(rejection("dedup_key is required for this event_action")),
+    };
+    let mut alert = match event.into_alert(&dedup_key) {
+        Ok(alert) => alert,
+        Err(r) => return Ok(r),
+    };
+    if !state.filter.allows(alert.labels()) {
+        return Ok(accepted(&dedup_key));
+    }
+    alert.clean_links(&state.links);
+    let msg = render_alert(
+        &state.renderer,
+        &|u| state.links.clean(u),
+        &GroupContext::default(),
+        &alert,
+    )
+    .map_err(render_error)?;
+    let meta = PageMeta {
+        alert: unrendered(&state.renderer, &alert),
+        ..alert.meta()
+    };
+    state
+        .runner
+        .page("http", super::caller_id(&caller), msg, &meta)
+        .await?;
+    Ok(accepted(&dedup_key))
+}
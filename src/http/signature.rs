@@ -0,0 +1,70 @@
+//! Verification of webhooks signed like GitHub's, with an HMAC-SHA256 of
+//! the body under a shared secret in the `X-Hub-Signature-256` header.
+
+use axum::body::Body;
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use super::AlertState;
+
+const SIGNATURE_HEADER: &str = "x-hub-signature-256";
+/// Webhooks bigger than this are rejected rather than verified.
+const MAX_BODY_SIZE: usize = 16 << 20;
+
+/// Compares in constant time.
+fn signed(secret: &[u8], body: &[u8], signature: &[u8]) -> bool {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes any key length");
+    mac.update(body);
+    mac.verify_slice(signature).is_ok()
+}
+
+fn unauthorized(message: &str) -> (http::StatusCode, String) {
+    (http::StatusCode::UNAUTHORIZED, String::from(message))
+}
+
+/// Passes on only requests signed with the webhook secret, if there is
+/// one.
+pub(super) async fn verify(
+    State(state): State<AlertState>,
+    req: Request,
+    next: Next,
+) -> Result<Response, (http::StatusCode, String)> {
+    let Some(ref secret) = state.webhook_secret else {
+        return Ok(next.run(req).await);
+    };
+    let (parts, body) = req.into_parts();
+    let signature = parts
+        .headers
+        .get(SIGNATURE_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("sha256="))
+        .and_then(|v| hex::decode(v).ok())
+        .ok_or_else(|| unauthorized("missing or malformed X-Hub-Signature-256"))?;
+    let body = axum::body::to_bytes(body, MAX_BODY_SIZE)
+        .await
+        .map_err(|e| (http::StatusCode::PAYLOAD_TOO_LARGE, e.to_string()))?;
+    if !signed(secret, &body, &signature) {
+        return Err(unauthorized("bad signature"));
+    }
+    Ok(next.run(Request::from_parts(parts, Body::from(body))).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn github_example() {
+        let secret = b"It's a Secret to Everybody";
+        let signature =
+            hex::decode("757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17")
+                .unwrap();
+        assert!(signed(secret, b"Hello, World!", &signature));
+        assert!(!signed(secret, b"Hello, World?", &signature));
+        assert!(!signed(b"another secret", b"Hello, World!", &signature));
+        assert!(!signed(secret, b"Hello, World!", &signature[..16]));
+    }
+}
@@ -0,0 +1,167 @@
+//! The part of Alertmanager's API v2 that manages silences, so that
+//! amtool and Grafana can silence alerts in the pager directly.
+//! Silences are kept as maintenance windows which hold back pages
+//! without sending them in a digest. Since a silence can stop pages,
+//! they can only be changed on routes that `--auth-route` covers.
+
+use axum::Json;
+use axum::extract::{Path, State};
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+
+use super::{AlertState, MaybeCaller};
+
+fn yes() -> bool {
+    true
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Matcher {
+    pub name: String,
+    pub value: String,
+    #[serde(default)]
+    pub is_regex: bool,
+    #[serde(default = "yes")]
+    pub is_equal: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Silence {
+    /// Given when updating a silence.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub matchers: Vec<Matcher>,
+    pub starts_at: Timestamp,
+    pub ends_at: Timestamp,
+    #[serde(default)]
+    pub created_by: String,
+    #[serde(default)]
+    pub comment: String,
+}
+
+#[derive(Serialize)]
+struct SilenceStatus {
+    state: &'static str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GettableSilence {
+    #[serde(flatten)]
+    silence: Silence,
+    status: SilenceStatus,
+    /// We do not keep track of updates, which replace the silence.
+    updated_at: Timestamp,
+}
+
+impl From<Silence> for GettableSilence {
+    fn from(silence: Silence) -> Self {
+        let now = Timestamp::now();
+        let state = if silence.ends_at <= now {
+            "expired"
+        } else if silence.starts_at > now {
+            "pending"
+        } else {
+            "active"
+        };
+        Self {
+            updated_at: silence.starts_at,
+            status: SilenceStatus { state },
+            silence,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub(super) struct PostSilenceResponse {
+    #[serde(rename = "silenceID")]
+    silence_id: String,
+}
+
+fn bad_request(message: &str) -> (http::StatusCode, String) {
+    (http::StatusCode::BAD_REQUEST, String::from(message))
+}
+
+/// Only callers that `--auth-route` authenticated may change silences.
+fn authenticated(caller: &MaybeCaller) -> Result<(), (http::StatusCode, String)> {
+    match caller {
+        Some(_) => Ok(()),
+        None => Err((
+            http::StatusCode::FORBIDDEN,
+            String::from("changing silences needs --auth-route for this route"),
+        )),
+    }
+}
+
+fn silence_id(id: &str) -> Result<u64, (http::StatusCode, String)> {
+    id.parse()
+        .map_err(|_| (http::StatusCode::NOT_FOUND, format!("no silence {id}")))
+}
+
+pub(super) async fn post(
+    State(state): State<AlertState>,
+    caller: MaybeCaller,
+    Json(silence): Json<Silence>,
+) -> Result<Json<PostSilenceResponse>, (http::StatusCode, String)> {
+    authenticated(&caller)?;
+    if silence.matchers.is_empty() {
+        return Err(bad_request("at least one matcher is required"));
+    }
+    if silence.matchers.iter().any(|m| m.is_regex || !m.is_equal) {
+        return Err(bad_request("only equality matchers are supported"));
+    }
+    if silence.ends_at <= silence.starts_at {
+        return Err(bad_request("endsAt must be after startsAt"));
+    }
+    if let Some(ref id) = silence.id
+        && !state.runner.expire_silence(silence_id(id)?)?
+    {
+        return Err((http::StatusCode::NOT_FOUND, format!("no silence {id}")));
+    }
+    let id = state.runner.add_silence(silence)?;
+    Ok(Json(PostSilenceResponse {
+        silence_id: id.to_string(),
+    }))
+}
+
+pub(super) async fn list(
+    State(state): State<AlertState>,
+) -> Result<Json<impl Serialize>, (http::StatusCode, String)> {
+    let silences: Vec<GettableSilence> = state
+        .runner
+        .silences()?
+        .into_iter()
+        .map(GettableSilence::from)
+        .collect();
+    Ok(Json(silences))
+}
+
+pub(super) async fn get(
+    State(state): State<AlertState>,
+    Path(id): Path<String>,
+) -> Result<Json<impl Serialize>, (http::StatusCode, String)> {
+    state
+        .runner
+        .silences()?
+        .into_iter()
+        .find(|s| s.id.as_ref() == Some(&id))
+        .map(|s| Json(GettableSilence::from(s)))
+        .ok_or_else(|| (http::StatusCode::NOT_FOUND, format!("no silence {id}")))
+}
+
+/// Alertmanager keeps expired silences around for a while, but we
+/// forget them straight away.
+pub(super) async fn delete(
+    State(state): State<AlertState>,
+    caller: MaybeCaller,
+    Path(id): Path<String>,
+) -> Result<(), (http::StatusCode, String)> {
+    authenticated(&caller)?;
+    if state.runner.expire_silence(silence_id(&id)?)? {
+        Ok(())
+    } else {
+        Err((http::StatusCode::NOT_FOUND, format!("no silence {id}")))
+    }
+}
@@ -0,0 +1,345 @@
+//! Tenants of a shared receiver: each team gets its own token, which is
+//! the last part of the path that its webhooks post to, like
+//! `/alert/TOKEN`, and its own Signal group, template and rate limit.
+
+use axum::Json;
+use axum::extract::{Path as UrlPath, Query, State};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use super::auth::Auth;
+use super::{AlertParams, AlertState, Delivered};
+use crate::alert::{AlertsInput, GrafanaAlertsInput};
+use crate::ratelimit::{Override, Rate, RateLimiter};
+use crate::render::Renderer;
+
+#[derive(Debug, thiserror::Error)]
+pub enum TenantError {
+    #[error("Reading {0}: {1}")]
+    IOError(PathBuf, std::io::Error),
+    #[error("{0} is empty")]
+    EmptyToken(PathBuf),
+    #[error("More than one --tenant named {0:?}")]
+    DuplicateName(String),
+    #[error("Tenants {0:?} and {1:?} have the same token")]
+    DuplicateToken(String, String),
+    #[error("Template for tenant {0:?}: {1}")]
+    TemplateError(String, tera::Error),
+    #[error("{0} shows every tenant's alerts, so it needs an --auth-route")]
+    Unauthenticated(&'static str),
+}
+
+/// Routes which show the alerts, silences or on-call recipients of every
+/// tenant, and so must not be open to all of them.
+const SHARED_ROUTES: &[&str] = &[
+    "/alerts",
+    "/audit",
+    "/signal-failures",
+    "/maintenance",
+    "/oncall",
+    "/api/v2/silences",
+    "/api/v2/silence/{id}",
+    "/pager.Pager/{method}",
+];
+
+/// A tenant, parsed from
+/// `name=team-a,token_file=PATH,group=GROUP_ID,template=PATH,rate=30/1h`
+/// where only `name` and `token_file` are required.
+#[derive(Clone, Debug)]
+pub struct Spec {
+    name: String,
+    token_file: PathBuf,
+    group: Option<String>,
+    template: Option<PathBuf>,
+    rate: Option<Rate>,
+}
+
+impl FromStr for Spec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut name = None;
+        let mut token_file = None;
+        let mut group = None;
+        let mut template = None;
+        let mut rate = None;
+        for item in s.split(',') {
+            match item.split_once('=') {
+                Some(("name", v)) => name = Some(String::from(v)),
+                Some(("token_file", v)) => token_file = Some(PathBuf::from(v)),
+                Some(("group", v)) => group = Some(String::from(v)),
+                Some(("template", v)) => template = Some(PathBuf::from(v)),
+                Some(("rate", v)) => rate = Some(v.parse()?),
+                Some((k, _)) => return Err(format!("Unknown tenant key {k:?}")),
+                None => return Err(format!("Expected key=value, got {item:?}")),
+            }
+        }
+        let name = name
+            .filter(|n| !n.is_empty())
+            .ok_or("A tenant needs a name")?;
+        Ok(Self {
+            token_file: token_file.ok_or_else(|| format!("Tenant {name:?} needs a token_file"))?,
+            name,
+            group,
+            template,
+            rate,
+        })
+    }
+}
+
+pub struct Tenant {
+    pub name: String,
+    /// Where its pages go instead of wherever their labels route them.
+    pub group: Option<String>,
+    /// Its own template, instead of `--message-template-file`.
+    pub renderer: Option<Arc<Renderer>>,
+}
+
+pub struct Tenants {
+    /// Keyed by the SHA-256 of the token.
+    by_token: HashMap<[u8; 32], Arc<Tenant>>,
+    /// Keyed by tenant name.
+    limiter: RateLimiter,
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+impl Tenants {
+    /// Tenants' own templates are rendered like `renderer` but for the
+    /// template.
+    pub fn new(specs: Vec<Spec>, renderer: &Renderer) -> Result<Self, TenantError> {
+        let mut by_token = HashMap::<_, Arc<Tenant>>::new();
+        let mut overrides = Vec::new();
+        for spec in specs {
+            if by_token.values().any(|t| t.name == spec.name) {
+                return Err(TenantError::DuplicateName(spec.name));
+            }
+            let contents = std::fs::read_to_string(&spec.token_file)
+                .map_err(|e| TenantError::IOError(spec.token_file.clone(), e))?;
+            let token = contents.lines().next().unwrap_or_default().trim();
+            if token.is_empty() {
+                return Err(TenantError::EmptyToken(spec.token_file));
+            }
+            let renderer = match spec.template {
+                Some(ref path) => {
+                    Some(Arc::new(renderer.with_template(path).map_err(|e| {
+                        TenantError::TemplateError(spec.name.clone(), e)
+                    })?))
+                }
+                None => None,
+            };
+            if let Some(rate) = spec.rate {
+                overrides.push(Override::new(spec.name.clone(), rate));
+            }
+            let tenant = Arc::new(Tenant {
+                name: spec.name,
+                group: spec.group,
+                renderer,
+            });
+            if let Some(other) = by_token.insert(sha256(token.as_bytes()), Arc::clone(&tenant)) {
+                return Err(TenantError::DuplicateToken(
+                    other.name.clone(),
+                    tenant.name.clone(),
+                ));
+            }
+        }
+        Ok(Self {
+            by_token,
+            limiter: RateLimiter::new(None, overrides),
+        })
+    }
+
+    /// Refuses tenants unless the routes which would show them each
+    /// other's alerts need credentials, which tenants do not have.
+    pub fn check_auth(&self, auth: &Auth) -> Result<(), TenantError> {
+        if self.by_token.is_empty() {
+            return Ok(());
+        }
+        match SHARED_ROUTES.iter().find(|r| !auth.covers(r)) {
+            Some(route) => Err(TenantError::Unauthenticated(route)),
+            None => Ok(()),
+        }
+    }
+
+    /// The tenant whose token this is, if it is within its rate limit.
+    fn admit(&self, token: &str) -> Result<Arc<Tenant>, (http::StatusCode, String)> {
+        let Some(tenant) = self.by_token.get(&sha256(token.as_bytes())) else {
+            return Err((http::StatusCode::NOT_FOUND, String::from("unknown tenant")));
+        };
+        if let Err(rate) = self.limiter.take(&tenant.name) {
+            log::warn!("Tenant {} exceeded its rate of {rate}", tenant.name);
+            return Err((
+                http::StatusCode::TOO_MANY_REQUESTS,
+                format!("tenant {} is limited to {rate}", tenant.name),
+            ));
+        }
+        Ok(Arc::clone(tenant))
+    }
+}
+
+pub(super) async fn alert(
+    State(state): State<AlertState>,
+    UrlPath(token): UrlPath<String>,
+    Query(mut params): Query<AlertParams>,
+    Json(mut payload): Json<AlertsInput>,
+) -> Result<Delivered, (http::StatusCode, String)> {
+    let tenant = state.tenants.admit(&token)?;
+    // A tenant's pages go where its group or labels route them, not to
+    // whoever it names.
+    params.recipients = None;
+    for alert in &mut payload.alerts {
+        alert.clean_links(&state.links);
+    }
+    super::deliver(
+        &state,
+        Some(&tenant.name),
+        &params,
+        Some(&tenant),
+        &payload.group,
+        &payload.alerts,
+    )
+    .await
+}
+
+pub(super) async fn grafana(
+    State(state): State<AlertState>,
+    UrlPath(token): UrlPath<String>,
+    Query(mut params): Query<AlertParams>,
+    Json(mut payload): Json<GrafanaAlertsInput>,
+) -> Result<Delivered, (http::StatusCode, String)> {
+    let tenant = state.tenants.admit(&token)?;
+    params.recipients = None;
+    for alert in &mut payload.alerts {
+        alert.clean_links(&state.links);
+    }
+    super::deliver(
+        &state,
+        Some(&tenant.name),
+        &params,
+        Some(&tenant),
+        &payload.group,
+        &payload.alerts,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::file;
+
+    fn spec(name: &str, f: &tempfile::NamedTempFile, rest: &str) -> Spec {
+        format!("name={name},token_file={}{rest}", f.path().display())
+            .parse()
+            .unwrap()
+    }
+
+    fn renderer() -> Renderer {
+        let schedule = Arc::new(crate::oncall::OnCall::with_rotations(Vec::new()));
+        Renderer::build(None, None, schedule, None).unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        let s: Spec = "name=db,token_file=/t,group=abc,rate=30/1h"
+            .parse()
+            .unwrap();
+        assert_eq!(s.name, "db");
+        assert_eq!(s.group.as_deref(), Some("abc"));
+        assert_eq!(s.rate.unwrap().to_string(), "30/1h");
+        assert!("token_file=/t".parse::<Spec>().is_err());
+        assert!("name=db".parse::<Spec>().is_err());
+        assert!("name=db,token_file=/t,colour=red".parse::<Spec>().is_err());
+        assert!("name=db,token_file=/t,rate=fast".parse::<Spec>().is_err());
+    }
+
+    #[test]
+    fn admit() {
+        let (a, b) = (file("secret-a\n"), file("secret-b\n"));
+        let tenants = Tenants::new(
+            vec![spec("a", &a, ",group=g"), spec("b", &b, ",rate=1/1h")],
+            &renderer(),
+        )
+        .unwrap();
+        let tenant = tenants.admit("secret-a").unwrap();
+        assert_eq!(
+            (tenant.name.as_str(), tenant.group.as_deref()),
+            ("a", Some("g"))
+        );
+        assert_eq!(
+            tenants.admit("wrong").err().unwrap().0,
+            http::StatusCode::NOT_FOUND
+        );
+        assert!(tenants.admit("secret-b").is_ok());
+        assert_eq!(
+            tenants.admit("secret-b").err().unwrap().0,
+            http::StatusCode::TOO_MANY_REQUESTS
+        );
+        assert!(tenants.admit("secret-a").is_ok());
+    }
+
+    #[test]
+    fn check_auth() {
+        let a = file("secret-a\n");
+        let tenants = Tenants::new(vec![spec("a", &a, "")], &renderer()).unwrap();
+        let tokens = file("bearer\n");
+        let auth = |rules: &[&str]| {
+            let rules = rules.iter().map(|r| r.parse().unwrap()).collect();
+            Auth::new(rules, Some(tokens.path()), None).unwrap()
+        };
+        assert!(tenants.check_auth(&auth(&["*=bearer"])).is_ok());
+        assert!(matches!(
+            tenants.check_auth(&auth(&["/alerts=bearer"])),
+            Err(TenantError::Unauthenticated("/audit"))
+        ));
+        assert!(matches!(
+            tenants.check_auth(&auth(&[])),
+            Err(TenantError::Unauthenticated("/alerts"))
+        ));
+        let shown = [
+            "/alerts=bearer",
+            "/audit=bearer",
+            "/signal-failures=bearer",
+            "/maintenance=bearer",
+            "/pager.Pager/{method}=bearer",
+        ];
+        assert!(matches!(
+            tenants.check_auth(&auth(&shown)),
+            Err(TenantError::Unauthenticated("/oncall"))
+        ));
+        let with_oncall = [&shown[..], &["/oncall=bearer"]].concat();
+        assert!(matches!(
+            tenants.check_auth(&auth(&with_oncall)),
+            Err(TenantError::Unauthenticated("/api/v2/silences"))
+        ));
+        let with_silences = [&with_oncall[..], &["/api/v2/silences=bearer"]].concat();
+        assert!(matches!(
+            tenants.check_auth(&auth(&with_silences)),
+            Err(TenantError::Unauthenticated("/api/v2/silence/{id}"))
+        ));
+        let none = Tenants::new(Vec::new(), &renderer()).unwrap();
+        assert!(none.check_auth(&auth(&[])).is_ok());
+    }
+
+    #[test]
+    fn duplicates() {
+        let (a, b) = (file("secret\n"), file("secret\n"));
+        assert!(matches!(
+            Tenants::new(vec![spec("a", &a, ""), spec("a", &b, "")], &renderer()),
+            Err(TenantError::DuplicateName(_))
+        ));
+        assert!(matches!(
+            Tenants::new(vec![spec("a", &a, ""), spec("b", &b, "")], &renderer()),
+            Err(TenantError::DuplicateToken(_, _))
+        ));
+        assert!(matches!(
+            Tenants::new(vec![spec("a", &file("\n"), "")], &renderer()),
+            Err(TenantError::EmptyToken(_))
+        ));
+    }
+}
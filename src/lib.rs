@@ -0,0 +1,84 @@
+//! Everything in signal-pager but its `main`, for embedding in other
+//! services built with `comprehensive`.
+//!
+//! The `signal-pager` binary is only an assembly of the resources here,
+//! and another assembly can depend on any of them in the same way. The
+//! ones most worth depending on are:
+//!
+//! - [`signal::SignalRunner`], which sends pages with signal-cli,
+//!   queueing them while it is busy and retrying those it could not send.
+//! - [`state::SignalState`], which keeps signal-cli's state encrypted in
+//!   an object store and unpacked in a local directory while running.
+//! - [`render::Renderer`], which turns alerts into the text of messages.
+//!
+//! Each brings in the resources it needs, along with their flags, so
+//! that an assembly which pages needs no more than this:
+//!
+//! ```no_run
+//! use comprehensive::v1::{AssemblyRuntime, Resource, resource};
+//! use signal_pager_core::page::PageMeta;
+//! use signal_pager_core::signal::SignalRunner;
+//! use std::sync::Arc;
+//!
+//! struct Deploys(Arc<SignalRunner>);
+//!
+//! #[resource]
+//! impl Resource for Deploys {
+//!     fn new(
+//!         (runner,): (Arc<SignalRunner>,),
+//!         _: comprehensive::NoArgs,
+//!         _: &mut AssemblyRuntime<'_>,
+//!     ) -> Result<Arc<Self>, std::convert::Infallible> {
+//!         Ok(Arc::new(Self(runner)))
+//!     }
+//! }
+//!
+//! impl Deploys {
+//!     async fn announce(&self, what: &str) {
+//!         let msg = format!("Deploying {what}");
+//!         if let Err(e) = self.0.page("deploys", None, msg, &PageMeta::default()).await {
+//!             log::error!("Paging: {e}");
+//!         }
+//!     }
+//! }
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//!     let argv = std::env::args_os();
+//!     comprehensive::Assembly::<(Arc<Deploys>,)>::new_from_argv(argv)?
+//!         .run()
+//!         .await?;
+//!     Ok(())
+//! }
+//! ```
+
+pub mod alert;
+pub mod alerts;
+pub mod audit;
+pub mod aws;
+pub mod config;
+pub mod digest;
+pub mod escalation;
+pub mod fallback;
+pub mod gcp;
+pub mod grpc;
+pub mod health;
+pub mod heartbeat;
+pub mod http;
+pub mod maintenance;
+pub mod metrics;
+pub mod oncall;
+pub mod ops;
+pub mod page;
+pub mod policy;
+pub mod ratelimit;
+pub mod render;
+pub mod retry_queue;
+pub mod selftest;
+pub mod signal;
+pub mod state;
+pub mod store;
+pub mod telemetry;
+#[cfg(test)]
+mod testutil;
+pub mod watch;
@@ -2,24 +2,28 @@ use comprehensive_http::HttpServer;
 use std::marker::PhantomData;
 use std::sync::Arc;
 
-mod grpc;
-mod http;
-mod signal;
-mod state;
+use signal_pager_core::{config, escalation, grpc, heartbeat, http, ops, selftest, telemetry};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::fmt()
-        .with_writer(std::io::stderr)
-        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-        .init();
+    let _telemetry = telemetry::init("signal-pager")?;
+    let argv = std::env::args_os().collect::<Vec<_>>();
+    if let Some(r) = ops::run(&argv).await {
+        return r;
+    }
     comprehensive::Assembly::<(
         Arc<HttpServer<http::HttpApi>>,
+        Arc<escalation::Escalator>,
+        Arc<heartbeat::HeartbeatMonitor>,
+        Arc<selftest::SelfTest>,
         Arc<comprehensive_http::diag::HttpServer>,
         Arc<comprehensive_grpc::server::GrpcServer>,
         PhantomData<grpc::PagerService>,
+        Arc<grpc::unix::UnixSocketServer>,
+        PhantomData<grpc::admin::AdminService>,
+        PhantomData<grpc::health::HealthService>,
         PhantomData<comprehensive_spiffe::SpiffeTlsProvider>,
-    )>::new()?
+    )>::new_from_argv(config::argv()?)?
     .run()
     .await?;
     Ok(())
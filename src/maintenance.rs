@@ -0,0 +1,553 @@
+//! Maintenance windows, during which matching pages are not sent but
+//! recorded, to be summarised in a digest or replayed later.
+
+use comprehensive::ResourceDependencies;
+use comprehensive::v1::{AssemblyRuntime, Resource, resource};
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::http::silences::{self, Silence};
+use crate::page::PageMeta;
+
+const MAX_SUPPRESSED: usize = 1000;
+const APP_DATA_NAME: &str = "maintenance.json";
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// Only record matching pages.
+    Suppress,
+    /// Record matching pages and send them in the next digest.
+    Digest,
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "suppress" => Ok(Self::Suppress),
+            "digest" => Ok(Self::Digest),
+            _ => Err(format!("Unknown action {s:?}, expected suppress or digest")),
+        }
+    }
+}
+
+/// Parsed from
+/// `team=db,env=staging=>from=2025-02-01T09:00Z,until=2025-02-01T12:00Z,action=digest,comment=...`
+/// where there may be no matchers, to match every page, and all of the
+/// keys but `until` are optional.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Window {
+    /// Absent for windows given by flags, which cannot be removed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    /// Labels that a page must all have to be in the window.
+    pub matchers: HashMap<String, String>,
+    /// If absent then the window has already started.
+    pub from: Option<Timestamp>,
+    pub until: Timestamp,
+    pub action: Action,
+    #[serde(default)]
+    pub comment: String,
+    #[serde(default)]
+    pub created_by: String,
+}
+
+impl FromStr for Window {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((matchers, options)) = s.split_once("=>") else {
+            return Err(String::from("Expected MATCHERS=>until=TIME,..."));
+        };
+        let matchers = matchers
+            .split(',')
+            .filter(|m| !m.is_empty())
+            .map(|m| match m.split_once('=') {
+                Some((k, v)) => Ok((String::from(k), String::from(v))),
+                None => Err(format!("Expected label=value, got {m:?}")),
+            })
+            .collect::<Result<_, _>>()?;
+        let (mut from, mut until) = (None, None);
+        let mut action = Action::Suppress;
+        let mut comment = String::new();
+        for item in options.split(',') {
+            let Some((k, v)) = item.split_once('=') else {
+                return Err(format!("Expected key=value, got {item:?}"));
+            };
+            match k {
+                "from" => from = Some(v.parse().map_err(|e| format!("from: {e}"))?),
+                "until" => until = Some(v.parse().map_err(|e| format!("until: {e}"))?),
+                "action" => action = v.parse()?,
+                "comment" => comment = String::from(v),
+                _ => return Err(format!("Unknown maintenance window key {k:?}")),
+            }
+        }
+        Ok(Self {
+            id: None,
+            matchers,
+            from,
+            until: until.ok_or("until is required")?,
+            action,
+            comment,
+            created_by: String::new(),
+        })
+    }
+}
+
+impl From<Silence> for Window {
+    fn from(s: Silence) -> Self {
+        Self {
+            id: None,
+            matchers: s.matchers.into_iter().map(|m| (m.name, m.value)).collect(),
+            from: Some(s.starts_at),
+            until: s.ends_at,
+            action: Action::Suppress,
+            comment: s.comment,
+            created_by: s.created_by,
+        }
+    }
+}
+
+impl Window {
+    /// Windows added through the API which only record pages are
+    /// silences as far as Alertmanager clients are concerned.
+    pub fn silence(&self) -> Option<Silence> {
+        if self.action != Action::Suppress {
+            return None;
+        }
+        Some(Silence {
+            id: Some(self.id?.to_string()),
+            matchers: self
+                .matchers
+                .iter()
+                .map(|(name, value)| silences::Matcher {
+                    name: name.clone(),
+                    value: value.clone(),
+                    is_regex: false,
+                    is_equal: true,
+                })
+                .collect(),
+            starts_at: self.from.unwrap_or(Timestamp::UNIX_EPOCH),
+            ends_at: self.until,
+            created_by: self.created_by.clone(),
+            comment: self.comment.clone(),
+        })
+    }
+
+    fn matches(&self, labels: &HashMap<String, String>, now: Timestamp) -> bool {
+        self.from.is_none_or(|from| from <= now)
+            && now < self.until
+            && self.matchers.iter().all(|(k, v)| labels.get(k) == Some(v))
+    }
+}
+
+/// A page that was not sent because it was in a maintenance window.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct SuppressedPage {
+    pub id: u64,
+    pub window: Option<u64>,
+    pub action: Action,
+    pub source: String,
+    pub message: String,
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    #[serde(default)]
+    pub recipients: Vec<String>,
+    #[serde(default)]
+    pub group: Option<String>,
+    #[serde(default)]
+    pub target: Option<String>,
+    #[serde(default)]
+    pub resolved: bool,
+    pub received: Timestamp,
+    /// Whether it has been sent in a digest.
+    #[serde(default)]
+    pub digested: bool,
+}
+
+impl SuppressedPage {
+    /// For sending it after all. Attachments are not kept.
+    pub fn meta(&self) -> PageMeta {
+        PageMeta {
+            labels: self.labels.clone(),
+            resolved: self.resolved,
+            recipients: self.recipients.clone(),
+            group: self.group.clone(),
+            target: self.target.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Default, Deserialize, Serialize)]
+struct Inner {
+    next_id: u64,
+    /// Windows added through the API. Those from flags are not saved.
+    windows: Vec<Window>,
+    /// Oldest first.
+    suppressed: VecDeque<SuppressedPage>,
+}
+
+/// Everything there is to know about maintenance, for `/maintenance`.
+#[derive(Serialize)]
+pub struct MaintenanceStatus {
+    pub windows: Vec<Window>,
+    pub suppressed: Vec<SuppressedPage>,
+}
+
+#[derive(ResourceDependencies)]
+pub struct MaintenanceDependencies(Arc<crate::state::SignalState>);
+
+#[derive(clap::Args)]
+pub struct MaintenanceArgs {
+    /// Do not page for pages whose labels match during a window, like
+    /// `team=db=>from=2025-02-01T09:00Z,until=2025-02-01T12:00Z,action=digest`.
+    /// With `action=digest` they are sent in the next digest instead.
+    /// May be repeated.
+    #[arg(long)]
+    maintenance_window: Vec<Window>,
+    /// How often to send the pages held back by `action=digest`
+    /// maintenance windows.
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "24h")]
+    maintenance_digest_interval: Duration,
+}
+
+/// Maintenance windows and the pages held back by them. Windows added
+/// through the API and held back pages are kept in the state so that
+/// they survive restarts.
+pub struct Maintenance {
+    flag_windows: Vec<Window>,
+    digest_interval: Duration,
+    inner: Mutex<Inner>,
+    changed: tokio::sync::Notify,
+}
+
+#[resource]
+impl Resource for Maintenance {
+    fn new(
+        d: MaintenanceDependencies,
+        a: MaintenanceArgs,
+        api: &mut AssemblyRuntime<'_>,
+    ) -> Result<Arc<Self>, std::convert::Infallible> {
+        let shared = Arc::new(Self {
+            flag_windows: a.maintenance_window,
+            digest_interval: a.maintenance_digest_interval,
+            inner: Mutex::new(Inner {
+                next_id: 1,
+                ..Default::default()
+            }),
+            changed: tokio::sync::Notify::new(),
+        });
+        let shared2 = Arc::clone(&shared);
+        let state = d.0;
+        api.set_task(async move {
+            state.wait_available().await;
+            match state.get().await.read_app_data::<Inner>(APP_DATA_NAME) {
+                Ok(Some(saved)) => shared2.merge(saved),
+                Ok(None) => (),
+                Err(e) => log::error!("Loading maintenance windows from state: {e}"),
+            }
+            loop {
+                shared2.changed.notified().await;
+                let guard = state.get().await;
+                let r = {
+                    let inner = shared2.inner.lock().unwrap();
+                    guard.write_app_data(APP_DATA_NAME, &*inner)
+                };
+                if let Err(e) = r {
+                    log::error!("Saving maintenance windows to state: {e}");
+                }
+            }
+        });
+        Ok(shared)
+    }
+}
+
+impl Maintenance {
+    /// Windows and pages from before the state was loaded are kept, and
+    /// the saved ones are added to them.
+    fn merge(&self, saved: Inner) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_id = inner.next_id.max(saved.next_id);
+        for window in saved.windows {
+            if !inner.windows.iter().any(|w| w.id == window.id) {
+                inner.windows.push(window);
+            }
+        }
+        let mut suppressed = saved.suppressed;
+        suppressed.extend(inner.suppressed.drain(..));
+        let excess = suppressed.len().saturating_sub(MAX_SUPPRESSED);
+        suppressed.drain(..excess);
+        inner.suppressed = suppressed;
+        log::info!(
+            "Loaded {} maintenance windows and {} suppressed pages from state",
+            inner.windows.len(),
+            inner.suppressed.len()
+        );
+    }
+
+    fn changed(&self) {
+        self.changed.notify_one();
+    }
+
+    pub fn digest_interval(&self) -> Duration {
+        self.digest_interval
+    }
+
+    /// If the page is in a maintenance window, records it and returns
+    /// what the window says to do with it.
+    pub fn check(&self, source: &str, msg: &str, meta: &PageMeta) -> Option<Action> {
+        let now = Timestamp::now();
+        let mut inner = self.inner.lock().unwrap();
+        let window = self
+            .flag_windows
+            .iter()
+            .chain(inner.windows.iter())
+            .find(|w| w.matches(&meta.labels, now))?;
+        let (window, action) = (window.id, window.action);
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.suppressed.push_back(SuppressedPage {
+            id,
+            window,
+            action,
+            source: String::from(source),
+            message: String::from(msg),
+            labels: meta.labels.clone(),
+            recipients: meta.recipients.clone(),
+            group: meta.group.clone(),
+            target: meta.target.clone(),
+            resolved: meta.resolved,
+            received: now,
+            digested: false,
+        });
+        if inner.suppressed.len() > MAX_SUPPRESSED {
+            inner.suppressed.pop_front();
+        }
+        drop(inner);
+        self.changed();
+        Some(action)
+    }
+
+    /// Returns the window with its new ID. Windows which are over are
+    /// forgotten at the same time.
+    pub fn add(&self, mut window: Window) -> Window {
+        let now = Timestamp::now();
+        let mut inner = self.inner.lock().unwrap();
+        inner.windows.retain(|w| w.until > now);
+        window.id = Some(inner.next_id);
+        inner.next_id += 1;
+        inner.windows.push(window.clone());
+        drop(inner);
+        self.changed();
+        window
+    }
+
+    /// Returns false if there is no such window.
+    pub fn remove(&self, id: u64) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let before = inner.windows.len();
+        inner.windows.retain(|w| w.id != Some(id));
+        let found = inner.windows.len() < before;
+        drop(inner);
+        if found {
+            self.changed();
+        }
+        found
+    }
+
+    /// Windows which are not over yet.
+    pub fn windows(&self) -> Vec<Window> {
+        let now = Timestamp::now();
+        let inner = self.inner.lock().unwrap();
+        self.flag_windows
+            .iter()
+            .chain(inner.windows.iter())
+            .filter(|w| w.until > now)
+            .cloned()
+            .collect()
+    }
+
+    /// Oldest first.
+    pub fn suppressed(&self) -> Vec<SuppressedPage> {
+        self.inner
+            .lock()
+            .unwrap()
+            .suppressed
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    pub fn status(&self) -> MaintenanceStatus {
+        MaintenanceStatus {
+            windows: self.windows(),
+            suppressed: self.suppressed(),
+        }
+    }
+
+    /// Forgets the suppressed pages, or those of one window, and returns
+    /// them to be sent after all.
+    pub fn take_suppressed(&self, window: Option<u64>) -> Vec<SuppressedPage> {
+        let mut inner = self.inner.lock().unwrap();
+        let (taken, kept): (Vec<_>, Vec<_>) = inner
+            .suppressed
+            .drain(..)
+            .partition(|p| window.is_none() || p.window == window);
+        inner.suppressed = kept.into();
+        drop(inner);
+        self.changed();
+        taken
+    }
+
+    /// Pages waiting to be sent in a digest, oldest first.
+    pub fn pending_digest(&self) -> Vec<SuppressedPage> {
+        self.inner
+            .lock()
+            .unwrap()
+            .suppressed
+            .iter()
+            .filter(|p| p.action == Action::Digest && !p.digested)
+            .cloned()
+            .collect()
+    }
+
+    pub fn record_digested(&self, ids: &[u64]) {
+        let mut inner = self.inner.lock().unwrap();
+        for p in inner.suppressed.iter_mut() {
+            if ids.contains(&p.id) {
+                p.digested = true;
+            }
+        }
+        drop(inner);
+        self.changed();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn maintenance(flag_windows: &[&str]) -> Maintenance {
+        Maintenance {
+            flag_windows: flag_windows.iter().map(|w| w.parse().unwrap()).collect(),
+            digest_interval: Duration::new(3600, 0),
+            inner: Mutex::new(Inner {
+                next_id: 1,
+                ..Default::default()
+            }),
+            changed: tokio::sync::Notify::new(),
+        }
+    }
+
+    fn meta(labels: &[(&str, &str)]) -> PageMeta {
+        PageMeta {
+            labels: labels
+                .iter()
+                .map(|(k, v)| (String::from(*k), String::from(*v)))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn at(s: &str) -> Timestamp {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        let w: Window = "team=db,env=staging=>from=2025-02-01T09:00Z,until=2025-02-01T12:00Z,action=digest,comment=upgrade"
+            .parse()
+            .unwrap();
+        assert_eq!(w.matchers.len(), 2);
+        assert_eq!(w.from, Some(at("2025-02-01T09:00Z")));
+        assert_eq!(w.action, Action::Digest);
+        assert_eq!(w.comment, "upgrade");
+        let w: Window = "=>until=2025-02-01T12:00Z".parse().unwrap();
+        assert!(w.matchers.is_empty());
+        assert_eq!(w.action, Action::Suppress);
+        assert!("team=db".parse::<Window>().is_err());
+        assert!("team=db=>from=2025-02-01T09:00Z".parse::<Window>().is_err());
+        assert!(
+            "=>until=2025-02-01T12:00Z,action=drop"
+                .parse::<Window>()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn matches() {
+        let w: Window = "team=db=>from=2025-02-01T09:00Z,until=2025-02-01T12:00Z"
+            .parse()
+            .unwrap();
+        let db = meta(&[("team", "db"), ("host", "x")]).labels;
+        assert!(w.matches(&db, at("2025-02-01T09:00Z")));
+        assert!(!w.matches(&db, at("2025-02-01T08:59Z")));
+        assert!(!w.matches(&db, at("2025-02-01T12:00Z")));
+        assert!(!w.matches(&meta(&[("team", "web")]).labels, at("2025-02-01T10:00Z")));
+        let always: Window = "=>until=2125-01-01T00:00Z".parse().unwrap();
+        assert!(always.matches(&HashMap::new(), Timestamp::now()));
+    }
+
+    #[test]
+    fn suppress_and_replay() {
+        let m = maintenance(&["team=db=>until=2125-01-01T00:00Z"]);
+        assert_eq!(m.check("test", "web down", &meta(&[("team", "web")])), None);
+        assert_eq!(
+            m.check("test", "db down", &meta(&[("team", "db")])),
+            Some(Action::Suppress)
+        );
+        let added = m.add(
+            "team=web=>until=2125-01-01T00:00Z,action=digest"
+                .parse()
+                .unwrap(),
+        );
+        let id = added.id.unwrap();
+        assert_eq!(
+            m.check("test", "web down", &meta(&[("team", "web")])),
+            Some(Action::Digest)
+        );
+        assert_eq!(m.windows().len(), 2);
+
+        let pending = m.pending_digest();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].message, "web down");
+        m.record_digested(&[pending[0].id]);
+        assert!(m.pending_digest().is_empty());
+
+        let taken = m.take_suppressed(Some(id));
+        assert_eq!(taken.len(), 1);
+        assert_eq!(taken[0].meta().labels["team"], "web");
+        assert_eq!(m.suppressed().len(), 1);
+        assert!(m.remove(id));
+        assert!(!m.remove(id));
+        assert_eq!(m.windows().len(), 1);
+    }
+
+    #[test]
+    fn merge_keeps_both() {
+        let m = maintenance(&[]);
+        m.add("=>until=2125-01-01T00:00Z".parse().unwrap());
+        m.check("test", "new", &PageMeta::default());
+        let saved = Inner {
+            next_id: 10,
+            windows: vec![Window {
+                id: Some(5),
+                ..("=>until=2125-01-01T00:00Z".parse().unwrap())
+            }],
+            suppressed: VecDeque::new(),
+        };
+        m.merge(saved);
+        assert_eq!(m.windows().len(), 2);
+        assert_eq!(
+            m.add("=>until=2125-01-01T00:00Z".parse().unwrap()).id,
+            Some(10)
+        );
+    }
+}
@@ -0,0 +1,192 @@
+use prometheus::{
+    CounterVec, Gauge, HistogramVec, IntCounter, IntGauge, IntGaugeVec, register_counter_vec,
+    register_gauge, register_histogram_vec, register_int_counter, register_int_gauge,
+    register_int_gauge_vec,
+};
+use std::sync::LazyLock;
+
+const SIGNAL_CLI_BUCKETS: [f64; 12] = [
+    0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 7.5, 10.0, 15.0, 30.0, 60.0, 120.0,
+];
+
+pub static PAGES: LazyLock<CounterVec> = LazyLock::new(|| {
+    register_counter_vec!(
+        "signal_pager_pages_total",
+        "Pages received, by where they came from and what became of them.",
+        &["source", "result"],
+    )
+    .expect("failed to init signal_pager_pages_total")
+});
+
+pub static SIGNAL_CLI_SECONDS: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "signal_pager_signal_cli_seconds",
+        "Time taken by signal-cli invocations.",
+        &["command", "result"],
+        SIGNAL_CLI_BUCKETS.to_vec(),
+    )
+    .expect("failed to init signal_pager_signal_cli_seconds")
+});
+
+pub static SIGNAL_CLI_WAITING: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "signal_pager_signal_cli_waiting",
+        "signal-cli invocations waiting for the one before them to finish with the same account.",
+        &["account"],
+    )
+    .expect("failed to init signal_pager_signal_cli_waiting")
+});
+
+pub static SIGNAL_CLI_WAIT_SECONDS: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "signal_pager_signal_cli_wait_seconds",
+        "Time signal-cli invocations waited for the one before them to finish with the same account.",
+        &["account"],
+        SIGNAL_CLI_BUCKETS.to_vec(),
+    )
+    .expect("failed to init signal_pager_signal_cli_wait_seconds")
+});
+
+pub static SIGNAL_CLI_FAILURES: LazyLock<CounterVec> = LazyLock::new(|| {
+    register_counter_vec!(
+        "signal_pager_signal_cli_failures_total",
+        "Failed signal-cli invocations, by what went wrong.",
+        &["command", "kind"],
+    )
+    .expect("failed to init signal_pager_signal_cli_failures_total")
+});
+
+pub static STATE_OPERATION_SECONDS: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "signal_pager_state_operation_seconds",
+        "Time taken to save or load the state, and how many times each was done.",
+        &["operation", "result"],
+    )
+    .expect("failed to init signal_pager_state_operation_seconds")
+});
+
+pub static STATE_VERSION: LazyLock<Gauge> = LazyLock::new(|| {
+    register_gauge!(
+        "signal_pager_state_version",
+        "Version of the state currently loaded."
+    )
+    .expect("failed to init signal_pager_state_version")
+});
+
+pub static STATE_VERIFICATIONS: LazyLock<CounterVec> = LazyLock::new(|| {
+    register_counter_vec!(
+        "signal_pager_state_verifications_total",
+        "Checks that stored state versions can be opened, by whether the newest or an older version was checked and how it went.",
+        &["which", "result"],
+    )
+    .expect("failed to init signal_pager_state_verifications_total")
+});
+
+pub static STATE_MIRROR_ERRORS: LazyLock<CounterVec> = LazyLock::new(|| {
+    register_counter_vec!(
+        "signal_pager_state_mirror_errors_total",
+        "Failures to write to or delete from the state storage mirror, by operation.",
+        &["operation"],
+    )
+    .expect("failed to init signal_pager_state_mirror_errors_total")
+});
+
+pub static STATE_PRUNED_FILES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "signal_pager_state_pruned_files_total",
+        "Old attachments and avatars deleted from the state before persisting it."
+    )
+    .expect("failed to init signal_pager_state_pruned_files_total")
+});
+
+pub static STATE_NOTIFICATIONS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "signal_pager_state_notifications_total",
+        "Notifications received that a new version of the state was stored."
+    )
+    .expect("failed to init signal_pager_state_notifications_total")
+});
+
+pub static STATE_RETRIES: LazyLock<CounterVec> = LazyLock::new(|| {
+    register_counter_vec!(
+        "signal_pager_state_retries_total",
+        "State storage operations retried after failing in a way that may pass, by operation.",
+        &["operation"],
+    )
+    .expect("failed to init signal_pager_state_retries_total")
+});
+
+pub static STATE_READ_ONLY: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "signal_pager_state_read_only",
+        "1 if another instance was found writing the state and this one stopped persisting it."
+    )
+    .expect("failed to init signal_pager_state_read_only")
+});
+
+pub static STATE_BAD_VERSIONS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "signal_pager_state_bad_versions_total",
+        "Number of state versions which could not be decrypted or unpacked and were passed over."
+    )
+    .expect("failed to init signal_pager_state_bad_versions_total")
+});
+
+pub static SEND_QUEUE_DEPTH: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "signal_pager_send_queue_depth",
+        "Number of pages waiting in the send queue."
+    )
+    .expect("failed to init signal_pager_send_queue_depth")
+});
+
+pub static SELF_TESTS: LazyLock<CounterVec> = LazyLock::new(|| {
+    register_counter_vec!(
+        "signal_pager_self_tests_total",
+        "Self-test messages sent, by whether signal-cli succeeded.",
+        &["result"],
+    )
+    .expect("failed to init signal_pager_self_tests_total")
+});
+
+pub static SELF_TEST_LAST_SUCCESS: LazyLock<Gauge> = LazyLock::new(|| {
+    register_gauge!(
+        "signal_pager_self_test_last_success_timestamp_seconds",
+        "When a self-test message was last sent successfully."
+    )
+    .expect("failed to init signal_pager_self_test_last_success_timestamp_seconds")
+});
+
+pub static FALLBACKS: LazyLock<CounterVec> = LazyLock::new(|| {
+    register_counter_vec!(
+        "signal_pager_fallbacks_total",
+        "Pages sent some other way because Signal failed, by transport and result.",
+        &["transport", "result"],
+    )
+    .expect("failed to init signal_pager_fallbacks_total")
+});
+
+pub static POLICY_DECISIONS: LazyLock<CounterVec> = LazyLock::new(|| {
+    register_counter_vec!(
+        "signal_pager_policy_decisions_total",
+        "Answers from --policy-url, or error if it could not be asked.",
+        &["decision"],
+    )
+    .expect("failed to init signal_pager_policy_decisions_total")
+});
+
+pub fn result_label<T, E>(r: &Result<T, E>) -> &'static str {
+    match r {
+        Ok(_) => "ok",
+        Err(_) => "error",
+    }
+}
+
+pub static SIGNAL_FAILOVERS: LazyLock<CounterVec> = LazyLock::new(|| {
+    register_counter_vec!(
+        "signal_pager_signal_failovers_total",
+        "Messages sent through the secondary Signal account because the primary one could not send, by result.",
+        &["result"],
+    )
+    .expect("failed to init signal_pager_signal_failovers_total")
+});
@@ -0,0 +1,469 @@
+//! Who is on call: rotations whose participants take turns daily or
+//! weekly, changing over at a local time of day, plus overrides for
+//! when somebody swaps a shift.
+
+use comprehensive::v1::{AssemblyRuntime, Resource, resource};
+use jiff::civil::DateTime;
+use jiff::tz::TimeZone;
+use jiff::{Timestamp, ToSpan, Unit};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+#[derive(Debug, thiserror::Error)]
+pub enum OnCallError {
+    #[error("{0}")]
+    IOError(#[from] std::io::Error),
+    #[error("Parsing {0}: {1}")]
+    ParseError(PathBuf, serde_json::Error),
+    #[error("Rotation {0:?}: {1}")]
+    InvalidRotation(String, String),
+    #[error("Override for unknown rotation {0:?}")]
+    UnknownRotation(String),
+    #[error("{0}")]
+    InvalidConfig(String),
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Period {
+    Daily,
+    Weekly,
+}
+
+impl FromStr for Period {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "daily" => Ok(Self::Daily),
+            "weekly" => Ok(Self::Weekly),
+            _ => Err(format!("Unknown period {s:?}, expected daily or weekly")),
+        }
+    }
+}
+
+impl Period {
+    fn days(self) -> i64 {
+        match self {
+            Self::Daily => 1,
+            Self::Weekly => 7,
+        }
+    }
+}
+
+fn utc() -> String {
+    String::from("UTC")
+}
+
+/// Parsed from
+/// `name=primary,period=weekly,start=2025-01-06T09:00,tz=Europe/London,participant=+1555...,participant=+1666...`
+/// where `tz` defaults to UTC and `participant` is repeated.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Rotation {
+    name: String,
+    period: Period,
+    /// When the first participant's first shift starts, in `time_zone`.
+    /// Later shifts start at the same local time of day.
+    start: DateTime,
+    #[serde(default = "utc")]
+    time_zone: String,
+    /// Phone numbers or ACIs, in the order that they take turns.
+    participants: Vec<String>,
+}
+
+fn key_values(s: &str) -> impl Iterator<Item = Result<(&str, &str), String>> {
+    s.split(',').map(|item| {
+        item.split_once('=')
+            .ok_or_else(|| format!("Expected key=value, got {item:?}"))
+    })
+}
+
+impl FromStr for Rotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mut name, mut period, mut start) = (None, None, None);
+        let mut time_zone = utc();
+        let mut participants = Vec::new();
+        for kv in key_values(s) {
+            let (k, v) = kv?;
+            match k {
+                "name" => name = Some(String::from(v)),
+                "period" => period = Some(v.parse()?),
+                "start" => start = Some(v.parse().map_err(|e| format!("start: {e}"))?),
+                "tz" => time_zone = String::from(v),
+                "participant" => participants.push(String::from(v)),
+                _ => return Err(format!("Unknown rotation key {k:?}")),
+            }
+        }
+        Ok(Self {
+            name: name.ok_or("name is required")?,
+            period: period.ok_or("period is required")?,
+            start: start.ok_or("start is required")?,
+            time_zone,
+            participants,
+        })
+    }
+}
+
+/// Somebody on call for a rotation in place of whoever would be.
+/// Parsed from `rotation=primary,recipient=+1555...,from=2025-02-01T09:00Z,until=2025-02-08T09:00Z`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Override {
+    rotation: String,
+    recipient: String,
+    from: Timestamp,
+    until: Timestamp,
+}
+
+impl FromStr for Override {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mut rotation, mut recipient, mut from, mut until) = (None, None, None, None);
+        for kv in key_values(s) {
+            let (k, v) = kv?;
+            match k {
+                "rotation" => rotation = Some(String::from(v)),
+                "recipient" => recipient = Some(String::from(v)),
+                "from" => from = Some(v.parse().map_err(|e| format!("from: {e}"))?),
+                "until" => until = Some(v.parse().map_err(|e| format!("until: {e}"))?),
+                _ => return Err(format!("Unknown override key {k:?}")),
+            }
+        }
+        Ok(Self {
+            rotation: rotation.ok_or("rotation is required")?,
+            recipient: recipient.ok_or("recipient is required")?,
+            from: from.ok_or("from is required")?,
+            until: until.ok_or("until is required")?,
+        })
+    }
+}
+
+/// Somebody's turn on call.
+#[derive(Clone, Debug, Serialize)]
+pub struct Shift {
+    pub rotation: String,
+    pub recipient: String,
+    /// When the next person takes over.
+    pub until: Timestamp,
+}
+
+impl Rotation {
+    fn check(&self) -> Result<(), OnCallError> {
+        let invalid = |e: String| OnCallError::InvalidRotation(self.name.clone(), e);
+        if self.participants.is_empty() {
+            return Err(invalid(String::from("no participants")));
+        }
+        let tz = TimeZone::get(&self.time_zone).map_err(|e| invalid(e.to_string()))?;
+        self.start
+            .to_zoned(tz)
+            .map_err(|e| invalid(e.to_string()))?;
+        Ok(())
+    }
+
+    fn shift_at(&self, now: Timestamp) -> Result<Shift, jiff::Error> {
+        let tz = TimeZone::get(&self.time_zone)?;
+        let start = self.start.to_zoned(tz.clone())?;
+        let now = now.to_zoned(tz);
+        // Whole days, counted by the calendar so that shifts still
+        // change at the same local time across daylight saving changes.
+        let mut days = i64::from(start.until((Unit::Day, &now))?.get_days());
+        if start.checked_add(days.days())? > now {
+            days -= 1;
+        }
+        let period = self.period.days();
+        let n = days.div_euclid(period);
+        let until = start.checked_add(((n + 1) * period).days())?;
+        let i = n.rem_euclid(self.participants.len() as i64) as usize;
+        Ok(Shift {
+            rotation: self.name.clone(),
+            recipient: self.participants[i].clone(),
+            until: until.timestamp(),
+        })
+    }
+}
+
+#[derive(Default, Deserialize)]
+struct Schedule {
+    #[serde(default)]
+    rotations: Vec<Rotation>,
+    #[serde(default)]
+    overrides: Vec<Override>,
+}
+
+impl Schedule {
+    fn check(&self) -> Result<(), OnCallError> {
+        for r in &self.rotations {
+            r.check()?;
+        }
+        for o in &self.overrides {
+            if !self.rotations.iter().any(|r| r.name == o.rotation) {
+                return Err(OnCallError::UnknownRotation(o.rotation.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    fn shift_at(&self, rotation: &Rotation, now: Timestamp) -> Option<Shift> {
+        let mut shift = match rotation.shift_at(now) {
+            Ok(shift) => shift,
+            Err(e) => {
+                log::error!("Working out who is on call for {}: {e}", rotation.name);
+                return None;
+            }
+        };
+        if let Some(o) = self
+            .overrides
+            .iter()
+            .find(|o| o.rotation == rotation.name && o.from <= now && now < o.until)
+        {
+            shift.recipient = o.recipient.clone();
+            shift.until = o.until;
+        }
+        Some(shift)
+    }
+}
+
+#[derive(clap::Args)]
+pub struct OnCallArgs {
+    /// An on-call rotation, like
+    /// `name=primary,period=weekly,start=2025-01-06T09:00,tz=Europe/London,participant=+15551234567,participant=+15557654321`.
+    /// May be repeated. Pages can be routed to `oncall:NAME`.
+    #[arg(long)]
+    rotation: Vec<Rotation>,
+    /// Somebody on call for a rotation in place of whoever would be, like
+    /// `rotation=primary,recipient=+15550000000,from=2025-02-01T09:00Z,until=2025-02-08T09:00Z`.
+    /// May be repeated.
+    #[arg(long)]
+    oncall_override: Vec<Override>,
+    /// JSON file of more `rotations` and `overrides`. It is reloaded
+    /// whenever it changes, and on SIGHUP.
+    #[arg(long)]
+    oncall_schedule_file: Option<PathBuf>,
+}
+
+struct ScheduleSource {
+    rotations: Vec<Rotation>,
+    overrides: Vec<Override>,
+    file: Option<PathBuf>,
+}
+
+impl ScheduleSource {
+    /// Rotations and overrides from `--config` are read again each time.
+    fn load(&self) -> Result<Schedule, OnCallError> {
+        let mut schedule = match self.file {
+            Some(ref path) => serde_json::from_slice(&std::fs::read(path)?)
+                .map_err(|e| OnCallError::ParseError(path.clone(), e))?,
+            None => Schedule::default(),
+        };
+        match crate::config::parsed("rotation") {
+            Some(r) => schedule
+                .rotations
+                .extend(r.map_err(OnCallError::InvalidConfig)?),
+            None => schedule.rotations.extend(self.rotations.iter().cloned()),
+        }
+        match crate::config::parsed("oncall-override") {
+            Some(o) => schedule
+                .overrides
+                .extend(o.map_err(OnCallError::InvalidConfig)?),
+            None => schedule.overrides.extend(self.overrides.iter().cloned()),
+        }
+        schedule.check()?;
+        Ok(schedule)
+    }
+}
+
+pub struct OnCall(Arc<RwLock<Schedule>>);
+
+#[resource]
+impl Resource for OnCall {
+    fn new(
+        d: (Arc<crate::config::Reloader>,),
+        a: OnCallArgs,
+        api: &mut AssemblyRuntime<'_>,
+    ) -> Result<Arc<Self>, OnCallError> {
+        let source = ScheduleSource {
+            rotations: a.rotation,
+            overrides: a.oncall_override,
+            file: a.oncall_schedule_file,
+        };
+        let shared = Arc::new(RwLock::new(source.load()?));
+        let shared2 = Arc::clone(&shared);
+        let reloads = d.0.subscribe();
+        api.set_task(async move {
+            let path = source.file.clone();
+            crate::watch::watch_and_reload(path.as_deref(), reloads, || match source.load() {
+                Ok(schedule) => {
+                    *shared2.write().unwrap() = schedule;
+                    log::info!("Reloaded on-call schedule");
+                }
+                Err(e) => log::error!("Reloading on-call schedule: {e}"),
+            })
+            .await
+            .map_err(|e| Box::new(e) as Box<dyn std::error::Error>)
+        });
+        Ok(Arc::new(Self(shared)))
+    }
+}
+
+impl OnCall {
+    /// Who is on call for each rotation right now.
+    pub fn current(&self) -> Vec<Shift> {
+        let schedule = self.0.read().unwrap();
+        let now = Timestamp::now();
+        schedule
+            .rotations
+            .iter()
+            .filter_map(|r| schedule.shift_at(r, now))
+            .collect()
+    }
+
+    /// Who is on call for `rotation` right now, if it exists.
+    pub fn recipient(&self, rotation: &str) -> Option<String> {
+        let schedule = self.0.read().unwrap();
+        let r = schedule.rotations.iter().find(|r| r.name == rotation)?;
+        schedule
+            .shift_at(r, Timestamp::now())
+            .map(|shift| shift.recipient)
+    }
+}
+
+#[cfg(test)]
+impl OnCall {
+    pub fn with_rotations(rotations: Vec<Rotation>) -> Self {
+        Self(Arc::new(RwLock::new(Schedule {
+            rotations,
+            overrides: Vec::new(),
+        })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> Timestamp {
+        s.parse().unwrap()
+    }
+
+    fn shift(rotation: &str, now: &str) -> (String, Timestamp) {
+        let shift = rotation
+            .parse::<Rotation>()
+            .unwrap()
+            .shift_at(at(now))
+            .unwrap();
+        (shift.recipient, shift.until)
+    }
+
+    const WEEKLY: &str = "name=primary,period=weekly,start=2025-01-06T09:00,tz=Europe/London,participant=a,participant=b";
+
+    #[test]
+    fn parse() {
+        let r: Rotation = WEEKLY.parse().unwrap();
+        assert_eq!(r.name, "primary");
+        assert_eq!(r.participants, ["a", "b"]);
+        assert_eq!(r.time_zone, "Europe/London");
+        assert!("name=x,start=2025-01-06T09:00".parse::<Rotation>().is_err());
+        assert!("name=x,period=monthly".parse::<Rotation>().is_err());
+        assert!(
+            "rotation=primary,recipient=c,from=2025-01-01T00:00Z"
+                .parse::<Override>()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn takes_turns() {
+        let day = |now| shift(WEEKLY, now);
+        assert_eq!(
+            day("2025-01-13T08:59Z"),
+            (String::from("a"), at("2025-01-13T09:00Z"))
+        );
+        assert_eq!(
+            day("2025-01-13T09:00Z"),
+            (String::from("b"), at("2025-01-20T09:00Z"))
+        );
+        assert_eq!(
+            day("2025-01-20T10:00Z"),
+            (String::from("a"), at("2025-01-27T09:00Z"))
+        );
+        // Before the start the turns go backwards.
+        assert_eq!(
+            day("2025-01-05T09:00Z"),
+            (String::from("b"), at("2025-01-06T09:00Z"))
+        );
+    }
+
+    #[test]
+    fn local_time_across_daylight_saving() {
+        let daily = "name=d,period=daily,start=2025-03-29T09:00,tz=Europe/London,participant=a,participant=b";
+        // 09:00 in London is 08:00 UTC from the 30th.
+        assert_eq!(
+            shift(daily, "2025-03-30T07:30Z"),
+            (String::from("a"), at("2025-03-30T08:00Z"))
+        );
+        assert_eq!(
+            shift(daily, "2025-03-30T08:30Z"),
+            (String::from("b"), at("2025-03-31T08:00Z"))
+        );
+    }
+
+    #[test]
+    fn overrides() {
+        let schedule = Schedule {
+            rotations: vec![WEEKLY.parse().unwrap()],
+            overrides: vec![
+                "rotation=primary,recipient=c,from=2025-01-07T00:00Z,until=2025-01-08T00:00Z"
+                    .parse()
+                    .unwrap(),
+            ],
+        };
+        schedule.check().unwrap();
+        let r = &schedule.rotations[0];
+        let shift = schedule.shift_at(r, at("2025-01-07T12:00Z")).unwrap();
+        assert_eq!(
+            (shift.recipient.as_str(), shift.until),
+            ("c", at("2025-01-08T00:00Z"))
+        );
+        let shift = schedule.shift_at(r, at("2025-01-08T00:00Z")).unwrap();
+        assert_eq!(shift.recipient, "a");
+    }
+
+    #[test]
+    fn check() {
+        let mut schedule = Schedule {
+            rotations: vec![
+                "name=x,period=daily,start=2025-01-01T00:00"
+                    .parse()
+                    .unwrap(),
+            ],
+            overrides: Vec::new(),
+        };
+        assert!(matches!(
+            schedule.check(),
+            Err(OnCallError::InvalidRotation(..))
+        ));
+        schedule.rotations = vec![
+            "name=x,period=daily,start=2025-01-01T00:00,tz=Mars/Olympus,participant=a"
+                .parse()
+                .unwrap(),
+        ];
+        assert!(matches!(
+            schedule.check(),
+            Err(OnCallError::InvalidRotation(..))
+        ));
+        schedule.rotations = vec![WEEKLY.parse().unwrap()];
+        schedule.overrides = vec![
+            "rotation=other,recipient=c,from=2025-01-07T00:00Z,until=2025-01-08T00:00Z"
+                .parse()
+                .unwrap(),
+        ];
+        assert!(matches!(
+            schedule.check(),
+            Err(OnCallError::UnknownRotation(_))
+        ));
+    }
+}
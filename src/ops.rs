@@ -0,0 +1,295 @@
+//! Subcommands which do one thing with the state or the Signal account
+//! and exit, instead of running the pager:
+//!
+//! - `state versions` lists the versions in the state storage.
+//! - `state export --output FILE` decrypts a version to a tarball.
+//! - `state import --input FILE` persists a tarball as the newest version.
+//! - `state rotate-key --encryption-keyring FILE` adds a key to a keyring.
+//! - `send-test` sends a message to check that sending works.
+//! - `verify-account` checks that signal-cli can use the account.
+//!
+//! They take the same flags as the pager for the parts they use, but
+//! not `--config`. `state versions` and `state export` only read the
+//! state storage, so they can be used while the pager is running.
+
+use comprehensive::ResourceDependencies;
+use comprehensive::v1::{AssemblyRuntime, Resource, resource};
+use futures::StreamExt;
+use std::error::Error;
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::signal::{SignalRunner, Target};
+use crate::state::{self, SignalState};
+
+/// The subcommands, as the words which start the command line.
+const SUBCOMMANDS: &[&[&str]] = &[
+    &["state", "versions"],
+    &["state", "export"],
+    &["state", "import"],
+    &["state", "rotate-key"],
+    &["send-test"],
+    &["verify-account"],
+];
+
+/// Runs the subcommand if `argv` starts with one, or else returns `None`.
+pub async fn run(argv: &[OsString]) -> Option<Result<(), Box<dyn Error>>> {
+    let words = argv
+        .iter()
+        .skip(1)
+        .map(|a| a.to_str().unwrap_or_default())
+        .collect::<Vec<_>>();
+    let sub = SUBCOMMANDS.iter().find(|s| words.starts_with(s))?;
+    let mut argv = argv.to_vec();
+    argv.drain(1..1 + sub.len());
+    Some(match *sub {
+        ["state", "versions"] => one_shot::<Versions>(read_only(argv)).await,
+        ["state", "export"] => one_shot::<Export>(read_only(argv)).await,
+        ["state", "import"] => one_shot::<Import>(argv).await,
+        ["state", "rotate-key"] => rotate_key(argv),
+        ["send-test"] => one_shot::<SendTest>(argv).await,
+        ["verify-account"] => one_shot::<VerifyAccount>(argv).await,
+        _ => unreachable!(),
+    })
+}
+
+/// Looking at the state does not need the lease, so it works while an
+/// instance is running.
+fn read_only(mut argv: Vec<OsString>) -> Vec<OsString> {
+    if !argv.iter().any(|a| a == "--state-read-only") {
+        argv.push(OsString::from("--state-read-only"));
+    }
+    argv
+}
+
+trait OneShot {
+    async fn run(&self) -> Result<(), Box<dyn Error>>;
+}
+
+/// Builds an assembly for `C`, runs it and then shuts the assembly down
+/// the way SIGTERM would, so that the state is persisted if it changed
+/// and the lease is released.
+async fn one_shot<C>(argv: Vec<OsString>) -> Result<(), Box<dyn Error>>
+where
+    C: OneShot,
+    (Arc<C>,): ResourceDependencies,
+{
+    let assembly = comprehensive::Assembly::<(Arc<C>,)>::new_from_argv(argv)?;
+    let command = Arc::clone(&assembly.top.0);
+    let (done, stop) = tokio::sync::oneshot::channel::<()>();
+    let stop = futures::stream::once(stop)
+        .map(|_| ())
+        .chain(futures::stream::pending());
+    let run = assembly.run_with_termination_signal(Box::pin(stop));
+    tokio::pin!(run);
+    let r = tokio::select! {
+        r = &mut run => {
+            r?;
+            return Err("exited before finishing".into());
+        }
+        r = command.run() => r,
+    };
+    let _ = done.send(());
+    run.await?;
+    r
+}
+
+pub struct Versions(Arc<SignalState>);
+
+#[resource]
+impl Resource for Versions {
+    fn new(
+        d: (Arc<SignalState>,),
+        _: comprehensive::NoArgs,
+        _: &mut AssemblyRuntime<'_>,
+    ) -> Result<Arc<Self>, std::convert::Infallible> {
+        Ok(Arc::new(Self(d.0)))
+    }
+}
+
+impl OneShot for Versions {
+    async fn run(&self) -> Result<(), Box<dyn Error>> {
+        for v in self.0.versions().await? {
+            match v.modified {
+                Some(t) => println!("{}\t{}", v.version, humantime::format_rfc3339_seconds(t)),
+                None => println!("{}", v.version),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(clap::Args)]
+pub struct ExportArgs {
+    /// Write the state here as a tarball, or to stdout if this is `-`.
+    #[arg(long)]
+    output: PathBuf,
+    /// Export this version of the state instead of the newest.
+    #[arg(long)]
+    state_version: Option<u32>,
+}
+
+pub struct Export {
+    state: Arc<SignalState>,
+    args: ExportArgs,
+}
+
+#[resource]
+impl Resource for Export {
+    fn new(
+        d: (Arc<SignalState>,),
+        a: ExportArgs,
+        _: &mut AssemblyRuntime<'_>,
+    ) -> Result<Arc<Self>, std::convert::Infallible> {
+        Ok(Arc::new(Self {
+            state: d.0,
+            args: a,
+        }))
+    }
+}
+
+impl OneShot for Export {
+    async fn run(&self) -> Result<(), Box<dyn Error>> {
+        let version = match self.args.output.to_str() {
+            Some("-") => {
+                self.state
+                    .export(self.args.state_version, std::io::stdout())
+                    .await?
+            }
+            _ => {
+                let f = std::fs::File::create(&self.args.output)?;
+                self.state.export(self.args.state_version, f).await?
+            }
+        };
+        eprintln!("Exported state version {version}");
+        Ok(())
+    }
+}
+
+#[derive(clap::Args)]
+pub struct ImportArgs {
+    /// Tarball of the state to import, as from `state export`, or `-`
+    /// to read it from stdin.
+    #[arg(long)]
+    input: PathBuf,
+}
+
+pub struct Import {
+    state: Arc<SignalState>,
+    args: ImportArgs,
+}
+
+#[resource]
+impl Resource for Import {
+    fn new(
+        d: (Arc<SignalState>,),
+        a: ImportArgs,
+        _: &mut AssemblyRuntime<'_>,
+    ) -> Result<Arc<Self>, std::convert::Infallible> {
+        Ok(Arc::new(Self {
+            state: d.0,
+            args: a,
+        }))
+    }
+}
+
+impl OneShot for Import {
+    async fn run(&self) -> Result<(), Box<dyn Error>> {
+        // Wait for the lease and the newest version, which is replaced.
+        self.state.wait_available().await;
+        let version = match self.args.input.to_str() {
+            Some("-") => self.state.import(std::io::stdin()).await?,
+            _ => {
+                let f = std::fs::File::open(&self.args.input)?;
+                self.state.import(f).await?
+            }
+        };
+        eprintln!("Imported state as version {version}");
+        Ok(())
+    }
+}
+
+/// Only the keyring file is needed: the state is re-encrypted by the
+/// instances which are given the new keyring, so that this works on a
+/// copy of the keyring while they keep running with theirs.
+#[derive(clap::Parser)]
+struct RotateKeyArgs {
+    /// The keyring to add a key to. It is created if there is none.
+    #[arg(long)]
+    encryption_keyring: PathBuf,
+}
+
+fn rotate_key(argv: Vec<OsString>) -> Result<(), Box<dyn Error>> {
+    let args = <RotateKeyArgs as clap::Parser>::try_parse_from(argv)?;
+    state::rotate_key(&args.encryption_keyring)?;
+    Ok(())
+}
+
+#[derive(clap::Args)]
+pub struct SendTestArgs {
+    /// The text of the test message.
+    #[arg(long, default_value = "Test message from signal-pager")]
+    message: String,
+    /// Send it here, as in `--route`, instead of to `--signal-group-id`.
+    #[arg(long)]
+    to: Option<Target>,
+}
+
+pub struct SendTest {
+    state: Arc<SignalState>,
+    runner: Arc<SignalRunner>,
+    args: SendTestArgs,
+}
+
+#[resource]
+impl Resource for SendTest {
+    fn new(
+        d: (Arc<SignalState>, Arc<SignalRunner>),
+        a: SendTestArgs,
+        _: &mut AssemblyRuntime<'_>,
+    ) -> Result<Arc<Self>, std::convert::Infallible> {
+        Ok(Arc::new(Self {
+            state: d.0,
+            runner: d.1,
+            args: a,
+        }))
+    }
+}
+
+impl OneShot for SendTest {
+    async fn run(&self) -> Result<(), Box<dyn Error>> {
+        let target = self
+            .args
+            .to
+            .clone()
+            .unwrap_or_else(|| self.runner.default_target());
+        self.state.wait_available().await;
+        match self.runner.send(&target, &self.args.message, None).await? {
+            Some(timestamp) => println!("Sent to {target} at {timestamp}"),
+            None => println!("Sent to {target}"),
+        }
+        Ok(())
+    }
+}
+
+pub struct VerifyAccount(Arc<SignalRunner>);
+
+#[resource]
+impl Resource for VerifyAccount {
+    fn new(
+        d: (Arc<SignalRunner>,),
+        _: comprehensive::NoArgs,
+        _: &mut AssemblyRuntime<'_>,
+    ) -> Result<Arc<Self>, std::convert::Infallible> {
+        Ok(Arc::new(Self(d.0)))
+    }
+}
+
+impl OneShot for VerifyAccount {
+    async fn run(&self) -> Result<(), Box<dyn Error>> {
+        self.0.verify_account().await?;
+        println!("Signal account is usable");
+        Ok(())
+    }
+}
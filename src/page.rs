@@ -0,0 +1,77 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Everything we know about a page besides the text of its message.
+#[derive(Clone, Debug, Default)]
+pub struct PageMeta {
+    pub labels: HashMap<String, String>,
+    /// This page reports that an alert is over.
+    pub resolved: bool,
+    /// Identifies repeats of the same alert for deduplication. If not
+    /// given then the labels are used.
+    pub fingerprint: Option<String>,
+    /// Phone numbers or ACIs to send to directly, as well as wherever
+    /// the page is routed.
+    pub recipients: Vec<String>,
+    /// Send to this group instead of wherever the labels route it.
+    pub group: Option<String>,
+    /// Unless there is a `group`, send here instead of wherever the
+    /// labels route it, as a `--route` target like `group:GROUP_ID`. From
+    /// the caller's entry in the gRPC ACL.
+    pub target: Option<String>,
+    /// The rest of the alert, if the message was rendered from a single
+    /// alert with the default template, so that wherever the page is
+    /// forwarded to can render it with its own template instead.
+    /// Only the relay forwards pages, so the pager never reads it.
+    #[allow(dead_code)]
+    pub alert: Option<AlertFields>,
+    /// Sent along with the page, like a graph of the alert.
+    pub attachment: Option<Attachment>,
+}
+
+/// What templates see of an alert besides its status and labels.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct AlertFields {
+    pub annotations: HashMap<String, String>,
+    /// RFC 3339, as Alertmanager gives them.
+    pub starts_at: Option<String>,
+    pub ends_at: Option<String>,
+    pub generator_url: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AttachmentData {
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum Attachment {
+    /// To be fetched when the page is received.
+    Url(String),
+    Data(AttachmentData),
+}
+
+impl Attachment {
+    /// Decodes `data:` URLs straight away and leaves others to be
+    /// fetched.
+    pub fn from_url(url: &str) -> Self {
+        let decoded = url.strip_prefix("data:").and_then(|rest| {
+            let (params, data) = rest.split_once(',')?;
+            let content_type = params.strip_suffix(";base64")?.split(';').next()?;
+            Some(AttachmentData {
+                content_type: match content_type {
+                    "" => String::from("application/octet-stream"),
+                    t => String::from(t),
+                },
+                data: BASE64.decode(data).ok()?,
+            })
+        });
+        match decoded {
+            Some(data) => Self::Data(data),
+            None => Self::Url(String::from(url)),
+        }
+    }
+}
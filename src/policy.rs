@@ -0,0 +1,236 @@
+//! Asks a central policy service, in the style of OPA or Envoy's
+//! ext_authz, whether each page may be sent, so that who may page whom
+//! can be decided in one place for many pagers rather than by each
+//! one's flags. The service may also change the page.
+
+use comprehensive::v1::{AssemblyRuntime, Resource, resource};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::page::PageMeta;
+
+#[derive(clap::Args)]
+pub struct PolicyArgs {
+    /// POST each page's caller, labels and message as JSON to this URL
+    /// before sending it, and send it only if the answer allows it. The
+    /// answer may also change the page's labels, recipients, group or
+    /// message.
+    #[arg(long)]
+    policy_url: Option<String>,
+    /// Give up on `--policy-url` after this long.
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "5s")]
+    policy_timeout: Duration,
+    /// Refuse pages when `--policy-url` cannot be asked, instead of
+    /// sending them anyway.
+    #[arg(long)]
+    policy_fail_closed: bool,
+}
+
+/// What the policy service is told about a page.
+#[derive(Serialize)]
+struct PolicyInput<'a> {
+    /// The API that the page came in on.
+    source: &'a str,
+    caller: Option<&'a str>,
+    labels: &'a HashMap<String, String>,
+    resolved: bool,
+    fingerprint: Option<&'a str>,
+    recipients: &'a [String],
+    group: Option<&'a str>,
+    message: &'a str,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Verdict {
+    Allow,
+    Deny,
+    Modify,
+}
+
+impl Verdict {
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Allow => "allow",
+            Self::Deny => "deny",
+            Self::Modify => "modify",
+        }
+    }
+}
+
+fn count(decision: &str) {
+    crate::metrics::POLICY_DECISIONS
+        .with_label_values(&[decision])
+        .inc();
+}
+
+/// The policy service's answer. Changes are only made with `modify`,
+/// and each field not given is left as it is.
+#[derive(Debug, Deserialize)]
+struct PolicyOutput {
+    decision: Verdict,
+    /// Why a page was denied.
+    #[serde(default)]
+    reason: Option<String>,
+    #[serde(default)]
+    labels: Option<HashMap<String, String>>,
+    #[serde(default)]
+    recipients: Option<Vec<String>>,
+    #[serde(default)]
+    group: Option<String>,
+    #[serde(default)]
+    message: Option<String>,
+}
+
+impl PolicyOutput {
+    fn apply(self, msg: &mut String, meta: &mut PageMeta) {
+        if let Some(labels) = self.labels {
+            meta.labels = labels;
+        }
+        if let Some(recipients) = self.recipients {
+            meta.recipients = recipients;
+        }
+        if let Some(group) = self.group {
+            meta.group = Some(group).filter(|g| !g.is_empty());
+        }
+        if let Some(message) = self.message {
+            *msg = message;
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PolicyError {
+    #[error("Denied by policy: {0}")]
+    Denied(String),
+    #[error("Asking --policy-url: {0}")]
+    Unavailable(String),
+}
+
+pub struct Policy {
+    url: Option<String>,
+    timeout: Duration,
+    fail_closed: bool,
+    http: reqwest::Client,
+}
+
+#[resource]
+impl Resource for Policy {
+    fn new(
+        _: comprehensive::NoDependencies,
+        a: PolicyArgs,
+        _: &mut AssemblyRuntime<'_>,
+    ) -> Result<Arc<Self>, std::convert::Infallible> {
+        Ok(Arc::new(Self {
+            url: a.policy_url,
+            timeout: a.policy_timeout,
+            fail_closed: a.policy_fail_closed,
+            http: reqwest::Client::new(),
+        }))
+    }
+}
+
+impl Policy {
+    /// Returns the page as the policy service would have it sent, or
+    /// why it may not be.
+    pub async fn check(
+        &self,
+        source: &str,
+        caller: Option<&str>,
+        mut msg: String,
+        meta: &PageMeta,
+    ) -> Result<(String, Option<PageMeta>), PolicyError> {
+        let Some(ref url) = self.url else {
+            return Ok((msg, None));
+        };
+        let input = PolicyInput {
+            source,
+            caller,
+            labels: &meta.labels,
+            resolved: meta.resolved,
+            fingerprint: meta.fingerprint.as_deref(),
+            recipients: &meta.recipients,
+            group: meta.group.as_deref(),
+            message: &msg,
+        };
+        let output = match self.ask(url, &input).await {
+            Ok(output) => output,
+            Err(e) => {
+                count("error");
+                if self.fail_closed {
+                    return Err(PolicyError::Unavailable(e));
+                }
+                log::error!("Asking --policy-url, sending the page anyway: {e}");
+                return Ok((msg, None));
+            }
+        };
+        count(output.decision.label());
+        match output.decision {
+            Verdict::Allow => Ok((msg, None)),
+            Verdict::Deny => Err(PolicyError::Denied(
+                output
+                    .reason
+                    .unwrap_or_else(|| String::from("no reason given")),
+            )),
+            Verdict::Modify => {
+                let mut meta = meta.clone();
+                output.apply(&mut msg, &mut meta);
+                Ok((msg, Some(meta)))
+            }
+        }
+    }
+
+    async fn ask(&self, url: &str, input: &PolicyInput<'_>) -> Result<PolicyOutput, String> {
+        self.http
+            .post(url)
+            .timeout(self.timeout)
+            .json(input)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| e.to_string())?
+            .json()
+            .await
+            .map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(json: &str) -> PolicyOutput {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn parse_decisions() {
+        assert_eq!(output(r#"{"decision": "allow"}"#).decision, Verdict::Allow);
+        let deny = output(r#"{"decision": "deny", "reason": "not your team"}"#);
+        assert_eq!(deny.decision, Verdict::Deny);
+        assert_eq!(deny.reason.as_deref(), Some("not your team"));
+        assert!(serde_json::from_str::<PolicyOutput>(r#"{"decision": "maybe"}"#).is_err());
+        assert!(serde_json::from_str::<PolicyOutput>("{}").is_err());
+    }
+
+    #[test]
+    fn modify() {
+        let mut msg = String::from("disk full");
+        let mut meta = PageMeta {
+            labels: HashMap::from([(String::from("team"), String::from("db"))]),
+            recipients: vec![String::from("+15550001")],
+            group: Some(String::from("g1")),
+            ..Default::default()
+        };
+        output(
+            r#"{"decision": "modify", "recipients": [], "group": "", "message": "[db] disk full"}"#,
+        )
+        .apply(&mut msg, &mut meta);
+        assert_eq!(msg, "[db] disk full");
+        assert_eq!(meta.labels["team"], "db");
+        assert!(meta.recipients.is_empty());
+        assert_eq!(meta.group, None);
+    }
+}
@@ -0,0 +1,231 @@
+//! Token buckets, for holding callers and ourselves to a rate.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// So many pages per so long, like `30/1h`. Up to that many can be sent
+/// at once, and then more as time passes at that rate.
+#[derive(Clone, Copy, Debug)]
+pub struct Rate {
+    pages: u32,
+    per: Duration,
+}
+
+impl FromStr for Rate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((pages, per)) = s.split_once('/') else {
+            return Err(format!("Expected PAGES/DURATION, got {s:?}"));
+        };
+        let rate = Self {
+            pages: pages.parse().map_err(|e| format!("{pages:?}: {e}"))?,
+            per: humantime::parse_duration(per).map_err(|e| format!("{per:?}: {e}"))?,
+        };
+        if rate.pages == 0 || rate.per.is_zero() {
+            return Err(format!("Rate {s:?} would allow nothing"));
+        }
+        Ok(rate)
+    }
+}
+
+impl std::fmt::Display for Rate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.pages, humantime::format_duration(self.per))
+    }
+}
+
+/// A different `Rate` for one caller. Parsed from `SPIFFE_ID=RATE`.
+#[derive(Clone, Debug)]
+pub struct Override {
+    id: String,
+    rate: Rate,
+}
+
+impl Override {
+    pub fn new(id: String, rate: Rate) -> Self {
+        Self { id, rate }
+    }
+}
+
+impl FromStr for Override {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((id, rate)) = s.rsplit_once('=') else {
+            return Err(format!("Expected SPIFFE_ID=RATE, got {s:?}"));
+        };
+        Ok(Self {
+            id: String::from(id),
+            rate: rate.parse()?,
+        })
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(capacity: f64, now: Instant) -> Self {
+        Self {
+            tokens: capacity,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, rate: Rate, capacity: f64, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate.per_second()).min(capacity);
+        self.updated = now;
+    }
+}
+
+impl Rate {
+    fn per_second(self) -> f64 {
+        f64::from(self.pages) / self.per.as_secs_f64()
+    }
+}
+
+/// A token bucket for each caller.
+pub struct RateLimiter {
+    default: Option<Rate>,
+    overrides: HashMap<String, Rate>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(default: Option<Rate>, overrides: Vec<Override>) -> Self {
+        Self {
+            default,
+            overrides: overrides.into_iter().map(|o| (o.id, o.rate)).collect(),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token for one page from the caller's bucket, or returns
+    /// the rate that it has exceeded.
+    pub fn take(&self, caller: &str) -> Result<(), Rate> {
+        let Some(rate) = self.overrides.get(caller).copied().or(self.default) else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        let capacity = f64::from(rate.pages);
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets
+            .entry(String::from(caller))
+            .or_insert_with(|| Bucket::full(capacity, now));
+        bucket.refill(rate, capacity, now);
+        if bucket.tokens < 1.0 {
+            return Err(rate);
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+/// Makes its callers wait their turn so that together they keep to a
+/// rate, after a burst of up to `burst`. Waiters are served in order.
+pub struct Throttle {
+    rate: Rate,
+    burst: f64,
+    bucket: tokio::sync::Mutex<Bucket>,
+}
+
+impl Throttle {
+    pub fn new(rate: Rate, burst: Option<u32>) -> Self {
+        let burst = f64::from(burst.unwrap_or(rate.pages).max(1));
+        Self {
+            rate,
+            burst,
+            bucket: tokio::sync::Mutex::new(Bucket::full(burst, Instant::now())),
+        }
+    }
+
+    #[tracing::instrument(name = "throttle", skip_all)]
+    pub async fn wait(&self) {
+        let mut bucket = self.bucket.lock().await;
+        bucket.refill(self.rate, self.burst, Instant::now());
+        if bucket.tokens < 1.0 {
+            let delay = Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate.per_second());
+            log::debug!("Waiting {delay:?} to keep to {}", self.rate);
+            tokio::time::sleep(delay).await;
+            bucket.refill(self.rate, self.burst, Instant::now());
+        }
+        bucket.tokens -= 1.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let rate: Rate = "30/1h".parse().unwrap();
+        assert_eq!((rate.pages, rate.per), (30, Duration::new(3600, 0)));
+        assert_eq!(rate.to_string(), "30/1h");
+        for bad in ["30", "0/1h", "30/0s", "x/1h", "30/soon"] {
+            assert!(bad.parse::<Rate>().is_err(), "{bad}");
+        }
+        let o: Override = "spiffe://example.com/a=5/1m".parse().unwrap();
+        assert_eq!(o.id, "spiffe://example.com/a");
+        assert_eq!(o.rate.pages, 5);
+    }
+
+    #[test]
+    fn bucket_per_caller() {
+        let limiter = RateLimiter::new(
+            Some("2/1h".parse().unwrap()),
+            vec!["b=3/1h".parse().unwrap()],
+        );
+        assert!(limiter.take("a").is_ok());
+        assert!(limiter.take("a").is_ok());
+        assert!(limiter.take("a").is_err());
+        for _ in 0..3 {
+            assert!(limiter.take("b").is_ok());
+        }
+        assert!(limiter.take("b").is_err());
+        assert!(limiter.take("c").is_ok());
+    }
+
+    #[test]
+    fn refills() {
+        let limiter = RateLimiter::new(Some("1/1h".parse().unwrap()), Vec::new());
+        assert!(limiter.take("a").is_ok());
+        assert!(limiter.take("a").is_err());
+        // Half an hour later there is half a token, and an hour later one.
+        let mut buckets = limiter.buckets.lock().unwrap();
+        buckets.get_mut("a").unwrap().updated -= Duration::new(1800, 0);
+        drop(buckets);
+        assert!(limiter.take("a").is_err());
+        let mut buckets = limiter.buckets.lock().unwrap();
+        buckets.get_mut("a").unwrap().updated -= Duration::new(1800, 0);
+        drop(buckets);
+        assert!(limiter.take("a").is_ok());
+    }
+
+    #[test]
+    fn unlimited() {
+        let limiter = RateLimiter::new(None, vec!["b=1/1h".parse().unwrap()]);
+        for _ in 0..100 {
+            assert!(limiter.take("a").is_ok());
+        }
+        assert!(limiter.take("b").is_ok());
+        assert!(limiter.take("b").is_err());
+    }
+
+    #[tokio::test]
+    async fn throttle_after_burst() {
+        let throttle = Throttle::new("20/1s".parse().unwrap(), Some(2));
+        let start = Instant::now();
+        throttle.wait().await;
+        throttle.wait().await;
+        assert!(start.elapsed() < Duration::from_millis(40));
+        throttle.wait().await;
+        assert!(start.elapsed() >= Duration::from_millis(40));
+    }
+}
@@ -1,69 +1,103 @@
+//! A receiver without signal-cli which forwards the pages it is sent to
+//! upstream pagers over gRPC.
+
+use axum::Router;
+use comprehensive::ResourceDependencies;
+use comprehensive::v1::{AssemblyRuntime, Resource, resource};
 use comprehensive_http::HttpServer;
+use comprehensive_http::server::HttpServingInstance;
 use std::marker::PhantomData;
+use std::process::ExitCode;
 use std::sync::Arc;
 
-mod http;
-
-mod signal {
-    use comprehensive::v1::{AssemblyRuntime, Resource, resource};
-    use comprehensive_grpc::GrpcClient;
-    use std::sync::Arc;
-    use tonic::Code;
+use signal_pager_core::audit::Audit;
+use signal_pager_core::http::{HttpApi, HttpApiArgs, HttpApiError};
+use signal_pager_core::oncall::OnCall;
+use signal_pager_core::render::Renderer;
+use signal_pager_core::{config, telemetry};
 
-    mod pb {
-        tonic::include_proto!("pager");
-    }
+#[path = "relay/breaker.rs"]
+mod breaker;
+#[path = "relay/send.rs"]
+mod send;
+#[path = "relay/spool.rs"]
+mod spool;
+#[path = "relay/upstream.rs"]
+mod upstream;
 
-    #[derive(GrpcClient)]
-    pub struct Client(pb::pager_client::PagerClient<comprehensive_grpc::client::Channel>);
+/// The pager's receiver, with the same routes and flags, but paging
+/// through the upstreams.
+#[derive(HttpServingInstance)]
+#[flag_prefix = "receiver-"]
+struct RelayApi(#[router] Router);
 
-    pub struct SignalRunner(Arc<Client>);
+#[derive(ResourceDependencies)]
+struct RelayApiDependencies {
+    forwarder: Arc<upstream::Forwarder>,
+    oncall: Arc<OnCall>,
+    audit: Arc<Audit>,
+    renderer: Arc<Renderer>,
+}
 
-    #[resource]
-    impl Resource for SignalRunner {
-        fn new(
-            (client_resource,): (Arc<Client>,),
-            _: comprehensive::NoArgs,
-            _: &mut AssemblyRuntime<'_>,
-        ) -> Result<Arc<Self>, std::convert::Infallible> {
-            Ok(Arc::new(Self(client_resource)))
-        }
+#[resource]
+impl Resource for RelayApi {
+    fn new(
+        d: RelayApiDependencies,
+        a: HttpApiArgs,
+        _: &mut AssemblyRuntime<'_>,
+    ) -> Result<Arc<Self>, HttpApiError> {
+        let router = HttpApi::router(d.forwarder, None, d.oncall, d.audit, d.renderer, a)?;
+        Ok(Arc::new(Self(router)))
     }
+}
 
-    impl SignalRunner {
-        pub async fn send(&self, msg: String) -> Result<(), (http::StatusCode, String)> {
-            match self
-                .0
-                .client()
-                .page(pb::PageRequest { message: Some(msg) })
-                .await
-            {
-                Ok(_) => Ok(()),
-                Err(s) => Err((
-                    match s.code() {
-                        Code::NotFound => http::StatusCode::NOT_FOUND,
-                        Code::PermissionDenied => http::StatusCode::FORBIDDEN,
-                        _ => http::StatusCode::INTERNAL_SERVER_ERROR,
-                    },
-                    s.to_string(),
-                )),
-            }
+/// `send` sends one page and exits with a status saying how that went.
+/// It takes the `--client-*` flags but not `--config`.
+async fn send(argv: Vec<std::ffi::OsString>) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let assembly = comprehensive::Assembly::<(
+        Arc<send::Sender>,
+        PhantomData<comprehensive_spiffe::SpiffeTlsProvider>,
+    )>::new_from_argv(argv)?;
+    let sender = Arc::clone(&assembly.top.0);
+    tokio::select! {
+        r = assembly.run() => {
+            r?;
+            Err("exited before the page was sent".into())
         }
+        r = sender.send() => Ok(match r {
+            Ok(response) => {
+                let delivery = response
+                    .delivery()
+                    .as_str_name()
+                    .to_ascii_lowercase();
+                match response.id {
+                    Some(id) => println!("Page {id} {delivery}"),
+                    None => println!("Page {delivery}"),
+                }
+                ExitCode::SUCCESS
+            }
+            Err(s) => {
+                eprintln!("Paging failed: {s}");
+                send::exit_code(&s)
+            }
+        }),
     }
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::fmt()
-        .with_writer(std::io::stderr)
-        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-        .init();
+async fn main() -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let _telemetry = telemetry::init("signal-pager-relay")?;
+    let mut argv = std::env::args_os().collect::<Vec<_>>();
+    if argv.get(1).is_some_and(|a| a == "send") {
+        argv.remove(1);
+        return send(argv).await;
+    }
     comprehensive::Assembly::<(
-        Arc<HttpServer<http::HttpApi>>,
+        Arc<HttpServer<RelayApi>>,
         Arc<comprehensive_http::diag::HttpServer>,
         PhantomData<comprehensive_spiffe::SpiffeTlsProvider>,
-    )>::new()?
+    )>::new_from_argv(config::argv()?)?
     .run()
     .await?;
-    Ok(())
+    Ok(ExitCode::SUCCESS)
 }
@@ -0,0 +1,86 @@
+//! Stops the relay from waiting on upstreams which keep failing, so that
+//! pages are spooled straight away and Alertmanager can see from the
+//! relay's health that it should use another receiver.
+
+use std::sync::Mutex;
+
+#[derive(Default)]
+struct State {
+    /// Calls in a row which no upstream took.
+    failures: u32,
+    open: bool,
+}
+
+pub struct Breaker {
+    /// Open after this many failures in a row, or never if 0.
+    threshold: u32,
+    state: Mutex<State>,
+}
+
+impl Breaker {
+    pub fn new(threshold: u32) -> Self {
+        Self {
+            threshold,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.state.lock().unwrap().open
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.state.lock().unwrap().failures
+    }
+
+    /// Records that an upstream answered. Returns whether that closed
+    /// the circuit.
+    pub fn success(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.failures = 0;
+        std::mem::replace(&mut state.open, false)
+    }
+
+    /// Records that no upstream answered. Returns whether that opened
+    /// the circuit.
+    pub fn failure(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.failures = state.failures.saturating_add(1);
+        if state.open || self.threshold == 0 || state.failures < self.threshold {
+            return false;
+        }
+        state.open = true;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opens_after_failures_in_a_row() {
+        let breaker = Breaker::new(3);
+        assert!(!breaker.failure());
+        assert!(!breaker.failure());
+        assert!(!breaker.success());
+        assert!(!breaker.failure());
+        assert!(!breaker.failure());
+        assert!(breaker.failure());
+        assert!(breaker.is_open());
+        assert!(!breaker.failure());
+        assert_eq!(breaker.failures(), 4);
+        assert!(breaker.success());
+        assert!(!breaker.is_open());
+        assert_eq!(breaker.failures(), 0);
+    }
+
+    #[test]
+    fn disabled() {
+        let breaker = Breaker::new(0);
+        for _ in 0..10 {
+            assert!(!breaker.failure());
+        }
+        assert!(!breaker.is_open());
+    }
+}
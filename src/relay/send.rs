@@ -0,0 +1,148 @@
+//! `signal-pager-relay send`: sends one page straight to the upstream
+//! pager and exits, so that scripts and people can page without making
+//! up a webhook.
+
+use comprehensive::v1::{AssemblyRuntime, Resource, resource};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tonic::{Code, Status};
+
+use crate::upstream::{Client, PagerClient, idempotency_key, pb, retryable};
+
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+fn parse_pair(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(k, v)| (String::from(k), String::from(v)))
+        .ok_or_else(|| format!("Expected NAME=VALUE, got {s:?}"))
+}
+
+#[derive(clap::Args)]
+pub struct SendArgs {
+    /// The text of the page. If not given, the upstream renders the
+    /// page from its labels and annotations with its message template.
+    #[arg(long, required_unless_present = "annotation")]
+    message: Option<String>,
+    /// A label to route the page by, like `team=db`. May be repeated.
+    #[arg(long, value_parser = parse_pair)]
+    label: Vec<(String, String)>,
+    /// An annotation for the message template, like `summary=Disk full`.
+    /// May be repeated.
+    #[arg(long, value_parser = parse_pair)]
+    annotation: Vec<(String, String)>,
+    /// Say that the alert is over.
+    #[arg(long)]
+    resolved: bool,
+    /// Sets the `severity` label.
+    #[arg(long)]
+    severity: Option<String>,
+    /// What the page is about, like a host or service. Sets the `source`
+    /// label unless `--label` does.
+    #[arg(long)]
+    source: Option<String>,
+    /// A phone number or ACI to also send the page to. May be repeated.
+    #[arg(long)]
+    recipient: Vec<String>,
+    /// File to send along with the page, like a graph.
+    #[arg(long)]
+    attachment: Option<PathBuf>,
+    /// Keep trying for this long while the upstream is unavailable.
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "30s")]
+    send_timeout: Duration,
+}
+
+pub struct Sender {
+    client: PagerClient,
+    args: SendArgs,
+}
+
+#[resource]
+impl Resource for Sender {
+    fn new(
+        d: (Arc<Client>,),
+        a: SendArgs,
+        _: &mut AssemblyRuntime<'_>,
+    ) -> Result<Arc<Self>, std::convert::Infallible> {
+        Ok(Arc::new(Self {
+            client: d.0.client(),
+            args: a,
+        }))
+    }
+}
+
+/// Enough to show images and documents properly.
+fn content_type(path: &std::path::Path) -> Option<String> {
+    let t = match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "pdf" => "application/pdf",
+        "txt" => "text/plain",
+        _ => return None,
+    };
+    Some(String::from(t))
+}
+
+/// Exit codes from sysexits.h, so that scripts can tell whether trying
+/// again later might help.
+pub fn exit_code(s: &Status) -> ExitCode {
+    ExitCode::from(match s.code() {
+        Code::InvalidArgument | Code::FailedPrecondition => 65, // EX_DATAERR
+        Code::NotFound => 69,                                   // EX_UNAVAILABLE
+        _ if retryable(s) => 75,                                // EX_TEMPFAIL
+        Code::PermissionDenied | Code::Unauthenticated => 77,   // EX_NOPERM
+        _ => 70,                                                // EX_SOFTWARE
+    })
+}
+
+impl Sender {
+    /// Sends the page, retrying while the upstream is unavailable until
+    /// `--send-timeout` runs out.
+    pub async fn send(&self) -> Result<pb::PageResponse, Status> {
+        let a = &self.args;
+        let attachment = match a.attachment {
+            Some(ref path) => Some(std::fs::read(path).map_err(|e| {
+                Status::invalid_argument(format!("reading {}: {e}", path.display()))
+            })?),
+            None => None,
+        };
+        let req = pb::PageRequest {
+            message: a.message.clone(),
+            labels: a.label.iter().cloned().collect::<HashMap<_, _>>(),
+            status: a.resolved.then(|| String::from("resolved")),
+            annotations: a.annotation.iter().cloned().collect(),
+            severity: a.severity.clone(),
+            source: a.source.clone(),
+            idempotency_key: Some(idempotency_key()),
+            recipients: a.recipient.clone(),
+            attachment,
+            attachment_content_type: a.attachment.as_deref().and_then(content_type),
+            ..Default::default()
+        };
+        let deadline = Instant::now() + a.send_timeout;
+        let mut delay = Duration::from_millis(250);
+        loop {
+            let mut client = self.client.clone();
+            let r = tokio::time::timeout_at(deadline.into(), client.page(req.clone())).await;
+            match r {
+                Ok(Ok(response)) => return Ok(response.into_inner()),
+                Ok(Err(s)) if retryable(&s) && Instant::now() + delay < deadline => {
+                    log::warn!("Upstream failed, retrying in {delay:?}: {s}");
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(MAX_RETRY_DELAY);
+                }
+                Ok(Err(s)) => return Err(s),
+                Err(_) => {
+                    return Err(Status::deadline_exceeded(format!(
+                        "upstream did not answer within {:?}",
+                        a.send_timeout
+                    )));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,85 @@
+//! The relay's spool: pages which no upstream has taken yet, sent in
+//! the order they came in.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use signal_pager_core::page::{AlertFields, Attachment, PageMeta};
+use signal_pager_core::retry_queue::{Item, RetryQueue};
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct SpooledPage {
+    pub message: String,
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    #[serde(default)]
+    pub recipients: Vec<String>,
+    #[serde(default)]
+    pub group: Option<String>,
+    #[serde(default)]
+    pub resolved: bool,
+    #[serde(default)]
+    pub fingerprint: Option<String>,
+    #[serde(default)]
+    pub alert: Option<AlertFields>,
+    #[serde(default)]
+    pub attachment: Option<Attachment>,
+    /// The same for every attempt, so that the upstream pages once even
+    /// if it took an attempt which looked to us as if it failed.
+    #[serde(default = "crate::upstream::idempotency_key")]
+    pub idempotency_key: String,
+}
+
+impl SpooledPage {
+    pub fn new(message: String, meta: &PageMeta) -> Self {
+        Self {
+            message,
+            labels: meta.labels.clone(),
+            recipients: meta.recipients.clone(),
+            group: meta.group.clone(),
+            resolved: meta.resolved,
+            fingerprint: meta.fingerprint.clone(),
+            alert: meta.alert.clone(),
+            attachment: meta.attachment.clone(),
+            idempotency_key: crate::upstream::idempotency_key(),
+        }
+    }
+}
+
+impl Item for SpooledPage {
+    fn is(&self, other: &SpooledPage) -> bool {
+        self.idempotency_key == other.idempotency_key
+    }
+}
+
+pub type Spool = RetryQueue<SpooledPage>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    async fn push(spool: &Spool, message: &str) {
+        let page = SpooledPage::new(String::from(message), &PageMeta::default());
+        spool.push(page).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn persisted() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("spool.json");
+        let spool = Spool::new("spool", Some(file.clone())).unwrap().in_order();
+        push(&spool, "one").await;
+        push(&spool, "two").await;
+        let one = spool.next_due().await;
+        spool.retry_later(&one, Duration::ZERO, |_| ()).await;
+
+        let reloaded = Spool::new("spool", Some(file)).unwrap().in_order();
+        let page = reloaded.next_due().await;
+        assert_eq!((page.message.as_str(), page.attempts), ("one", 1));
+        assert_eq!(page.idempotency_key, one.idempotency_key);
+        assert_eq!(page.retry_delay(), Duration::new(10, 0));
+        reloaded.remove(&page).await;
+        assert_eq!(reloaded.next_due().await.message, "two");
+    }
+}
@@ -0,0 +1,645 @@
+//! Forwarding pages to the upstream pagers, and spooling those which
+//! none of them takes for now to try again later.
+
+use chacha20poly1305::aead::OsRng;
+use chacha20poly1305::aead::rand_core::RngCore;
+use comprehensive::ResourceDependencies;
+use comprehensive::health::{HealthReporter, HealthSignaller};
+use comprehensive::v1::{AssemblyRuntime, Resource, resource};
+use comprehensive_dns::DNSResolver;
+use comprehensive_grpc::GrpcClient;
+use comprehensive_grpc::client::Channel;
+use comprehensive_tls::TlsConfig;
+use comprehensive_warm_channels::WarmChannelsDiag;
+use comprehensive_warm_channels::warm_channels::grpc::{GRPCChannelConfig, grpc_channel};
+use comprehensive_warm_channels::warm_channels::resolver::resolve_uri;
+use comprehensive_warm_channels::warm_channels::stream::StreamConnector;
+use comprehensive_warm_channels::warm_channels::tls::TLSConnector;
+use futures::future::BoxFuture;
+use futures::{FutureExt, TryStreamExt};
+use http::Uri;
+use std::marker::PhantomData;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tonic::{Code, Status};
+use tracing::Instrument;
+
+use signal_pager_core::alerts::AlertRecord;
+use signal_pager_core::audit::{self, Audit};
+use signal_pager_core::health::{Check, Report};
+use signal_pager_core::http::backend::Backend;
+use signal_pager_core::http::silences::Silence;
+use signal_pager_core::maintenance::MaintenanceStatus;
+use signal_pager_core::page::{Attachment, PageMeta};
+use signal_pager_core::signal::SignalFailure;
+use signal_pager_core::telemetry;
+
+pub use signal_pager_core::grpc::pb;
+
+use crate::breaker::Breaker;
+use crate::spool::{Spool, SpooledPage};
+
+pub type PagerClient = pb::pager_client::PagerClient<Channel>;
+
+// The derive needs the client's own type, not the alias.
+#[derive(GrpcClient)]
+pub struct Client(pb::pager_client::PagerClient<Channel>);
+
+#[derive(ResourceDependencies)]
+pub struct UpstreamsDependencies {
+    resolver: Arc<DNSResolver>,
+    tls_config: Option<Arc<TlsConfig>>,
+    _diag: PhantomData<WarmChannelsDiag>,
+}
+
+#[derive(clap::Args)]
+pub struct UpstreamsArgs {
+    /// Another upstream pager, tried when `--client-uri` and those given
+    /// before it fail. May be repeated.
+    #[arg(long = "upstream", value_name = "URI")]
+    upstreams: Vec<Uri>,
+}
+
+/// The upstream pagers from `--upstream`. Unlike `--client-uri` they
+/// do not count towards the relay's health.
+pub struct Upstreams(Vec<PagerClient>);
+
+#[resource]
+impl Resource for Upstreams {
+    fn new(
+        d: UpstreamsDependencies,
+        a: UpstreamsArgs,
+        api: &mut AssemblyRuntime<'_>,
+    ) -> Result<Arc<Self>, std::io::Error> {
+        let mut clients = Vec::with_capacity(a.upstreams.len());
+        let mut workers = Vec::with_capacity(a.upstreams.len());
+        for uri in a.upstreams {
+            let tls_config = d
+                .tls_config
+                .as_ref()
+                .map(|tls| tls.client_config(&uri, None))
+                .transpose()
+                .map_err(std::io::Error::other)?;
+            let connector = TLSConnector::new(StreamConnector, &uri, tls_config.as_ref())
+                .map_err(std::io::Error::other)?;
+            let resolved = resolve_uri(&uri, Arc::clone(&d.resolver).resolver())
+                .map_err(std::io::Error::other)?
+                .map_ok(|addrs| addrs.into_iter().map(Into::into).collect());
+            let (channel, worker) = grpc_channel(
+                uri.clone(),
+                GRPCChannelConfig::default(),
+                uri.to_string(),
+                connector,
+                resolved,
+                |_| (),
+            );
+            clients.push(PagerClient::with_origin(channel, uri));
+            workers.push(worker);
+        }
+        api.set_task(async move {
+            futures::future::join_all(workers).await;
+            Ok(())
+        });
+        Ok(Arc::new(Self(clients)))
+    }
+}
+
+#[derive(ResourceDependencies)]
+pub struct ForwarderDependencies(Arc<Client>, Arc<Upstreams>, Arc<Audit>, Arc<HealthReporter>);
+
+#[derive(clap::Args)]
+pub struct ForwarderArgs {
+    /// Keep pages which could not be delivered upstream in this file
+    /// so that they survive restarts.
+    #[arg(long)]
+    spool_file: Option<PathBuf>,
+    /// Drop the oldest spooled pages beyond this many.
+    #[arg(long, default_value_t = 1000)]
+    spool_max_pages: usize,
+    /// Give up retrying a spooled page once it is this old.
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "1h")]
+    spool_max_age: Duration,
+    /// Try the next upstream if one does not answer within this long.
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "10s")]
+    upstream_timeout: Duration,
+    /// Spread pages over the upstreams instead of always trying them
+    /// in order.
+    #[arg(long)]
+    upstream_round_robin: bool,
+    /// After this many pages in a row that no upstream takes, stop
+    /// trying them and spool pages straight away, reporting
+    /// unhealthy, until an upstream answers a probe. 0 never stops.
+    #[arg(long, default_value_t = 5)]
+    circuit_breaker_failures: u32,
+    /// Probe the upstreams this often while they are not being tried.
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "30s")]
+    circuit_breaker_probe_interval: Duration,
+}
+
+pub struct Forwarder {
+    upstreams: Vec<PagerClient>,
+    next_upstream: AtomicUsize,
+    timeout: Duration,
+    round_robin: bool,
+    spool: Spool,
+    max_age: Duration,
+    audit: Arc<Audit>,
+    breaker: Breaker,
+    probe_interval: Duration,
+    health: HealthSignaller,
+}
+
+#[resource]
+impl Resource for Forwarder {
+    fn new(
+        d: ForwarderDependencies,
+        a: ForwarderArgs,
+        api: &mut AssemblyRuntime<'_>,
+    ) -> Result<Arc<Self>, std::io::Error> {
+        let upstreams = std::iter::once(d.0.client())
+            .chain(d.1.0.iter().cloned())
+            .collect();
+        let health =
+            d.3.register("upstream circuit")
+                .map_err(std::io::Error::other)?;
+        health.set_healthy(true);
+        let shared = Arc::new(Self {
+            upstreams,
+            next_upstream: AtomicUsize::new(0),
+            timeout: a.upstream_timeout,
+            round_robin: a.upstream_round_robin,
+            spool: Spool::new("spool", a.spool_file)?
+                .max(a.spool_max_pages)
+                .in_order(),
+            max_age: a.spool_max_age,
+            audit: d.2,
+            breaker: Breaker::new(a.circuit_breaker_failures),
+            probe_interval: a.circuit_breaker_probe_interval,
+            health,
+        });
+        let shared2 = Arc::clone(&shared);
+        api.set_task(async move {
+            tokio::join!(shared2.drain_spool(), shared2.probe());
+            Ok(())
+        });
+        Ok(shared)
+    }
+}
+
+/// A new key for `PageRequest.idempotency_key`, so that retrying the
+/// page cannot page twice.
+pub fn idempotency_key() -> String {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// Errors after which the upstream may well accept the page later.
+pub fn retryable(s: &Status) -> bool {
+    matches!(
+        s.code(),
+        Code::Unavailable
+            | Code::DeadlineExceeded
+            | Code::Unknown
+            | Code::ResourceExhausted
+            | Code::Aborted
+    )
+}
+
+/// Pages which can be rendered upstream are sent without their
+/// message, so that the upstream's template is the one used.
+fn page_request(page: &SpooledPage) -> pb::PageRequest {
+    let mut req = pb::PageRequest {
+        message: Some(page.message.clone()),
+        labels: page.labels.clone(),
+        recipients: page.recipients.clone(),
+        group_id: page.group.clone(),
+        status: Some(String::from(if page.resolved {
+            "resolved"
+        } else {
+            "firing"
+        })),
+        fingerprint: page.fingerprint.clone(),
+        idempotency_key: Some(page.idempotency_key.clone()),
+        ..Default::default()
+    };
+    if let Some(ref alert) = page.alert {
+        req.message = None;
+        req.annotations = alert.annotations.clone();
+        req.starts_at = alert.starts_at.clone();
+        req.ends_at = alert.ends_at.clone();
+        req.generator_url = alert.generator_url.clone();
+    }
+    match &page.attachment {
+        Some(Attachment::Url(url)) => req.attachment_url = Some(url.clone()),
+        Some(Attachment::Data(a)) => {
+            req.attachment = Some(a.data.clone());
+            req.attachment_content_type = Some(a.content_type.clone());
+        }
+        None => (),
+    }
+    req
+}
+
+/// Upstreams from before `PageResult.code` are taken to have failed
+/// in a way worth retrying.
+fn page_result(r: pb::PageResult) -> Result<(), Status> {
+    match r.error {
+        Some(error) => Err(Status::new(
+            r.code.map(Code::from).unwrap_or(Code::Unknown),
+            error,
+        )),
+        None => Ok(()),
+    }
+}
+
+fn no_alerts() -> (http::StatusCode, String) {
+    (
+        http::StatusCode::NOT_FOUND,
+        String::from("alerts are tracked by the upstream pager"),
+    )
+}
+
+fn no_silences() -> (http::StatusCode, String) {
+    (
+        http::StatusCode::NOT_FOUND,
+        String::from("silences are kept by the upstream pager"),
+    )
+}
+
+impl Forwarder {
+    /// Pages pile up in the spool while no upstream takes them.
+    async fn health(&self) -> Report {
+        let spooled = self.spool.len();
+        let open = self.breaker.is_open();
+        Report::new(vec![
+            Check::new(
+                "upstream",
+                spooled == 0,
+                (spooled > 0).then(|| format!("{spooled} pages spooled")),
+            ),
+            Check::new(
+                "upstream circuit",
+                !open,
+                open.then(|| format!("open after {} failures in a row", self.breaker.failures())),
+            ),
+        ])
+    }
+
+    /// Like `try_upstreams`, but fails straight away while the
+    /// circuit breaker is open.
+    async fn call<Q, R, F, Fut>(&self, method: &str, req: Q, f: F) -> Result<R, Status>
+    where
+        Q: Clone,
+        F: Fn(PagerClient, tonic::Request<Q>) -> Fut,
+        Fut: Future<Output = Result<tonic::Response<R>, Status>>,
+    {
+        if self.breaker.is_open() {
+            return Err(Status::unavailable(
+                "upstreams keep failing, waiting for one to answer a probe",
+            ));
+        }
+        let r = self.try_upstreams(method, req, f).await;
+        match r {
+            Err(ref s) if retryable(s) => {
+                if self.breaker.failure() {
+                    log::error!(
+                        "No upstream has taken the last {} calls, spooling pages until one answers",
+                        self.breaker.failures()
+                    );
+                    self.health.set_healthy(false);
+                }
+            }
+            _ => self.closed(),
+        }
+        r
+    }
+
+    fn closed(&self) {
+        if self.breaker.success() {
+            log::info!("An upstream answered, sending pages again");
+            self.health.set_healthy(true);
+        }
+    }
+
+    /// While the circuit breaker is open, asks the upstreams for
+    /// their status every so often to see whether one answers.
+    async fn probe(&self) {
+        loop {
+            tokio::time::sleep(self.probe_interval).await;
+            if !self.breaker.is_open() {
+                continue;
+            }
+            let r = self
+                .try_upstreams("GetStatus", (), |mut client, req| async move {
+                    client.get_status(req).await
+                })
+                .await;
+            match r {
+                Err(s) if retryable(&s) => log::warn!("Probing upstreams: {s}"),
+                // Any answer at all will do.
+                _ => self.closed(),
+            }
+        }
+    }
+
+    /// Tries each upstream in turn until one answers `f` or fails in
+    /// a way that another upstream would too.
+    async fn try_upstreams<Q, R, F, Fut>(&self, method: &str, req: Q, f: F) -> Result<R, Status>
+    where
+        Q: Clone,
+        F: Fn(PagerClient, tonic::Request<Q>) -> Fut,
+        Fut: Future<Output = Result<tonic::Response<R>, Status>>,
+    {
+        let n = self.upstreams.len();
+        let first = if self.round_robin {
+            self.next_upstream.fetch_add(1, Ordering::Relaxed) % n
+        } else {
+            0
+        };
+        let mut last_error = Status::unavailable("no upstream");
+        for i in (first..n).chain(0..first) {
+            let span = tracing::info_span!("upstream", method, upstream = i);
+            let mut req = tonic::Request::new(req.clone());
+            telemetry::inject(&span, req.metadata_mut());
+            let r = match tokio::time::timeout(self.timeout, f(self.upstreams[i].clone(), req))
+                .instrument(span)
+                .await
+            {
+                Ok(r) => r,
+                Err(_) => Err(Status::deadline_exceeded("upstream timed out")),
+            };
+            match r {
+                Ok(response) => return Ok(response.into_inner()),
+                Err(s) if retryable(&s) => {
+                    log::warn!("Upstream {i} failed: {s}");
+                    last_error = s;
+                }
+                Err(s) => return Err(s),
+            }
+        }
+        Err(last_error)
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn send(&self, page: &SpooledPage) -> Result<(), Status> {
+        self.call("Page", page_request(page), |mut client, req| async move {
+            client.page(req).await
+        })
+        .await
+        .map(|_| ())
+    }
+
+    /// Sends all of the pages in one call. Each one succeeds or fails
+    /// on its own, unless no upstream takes the call at all.
+    #[tracing::instrument(skip_all, fields(pages = pages.len()))]
+    async fn send_batch(&self, pages: &[SpooledPage]) -> Result<Vec<Result<(), Status>>, Status> {
+        let req = pb::PageBatchRequest {
+            pages: pages.iter().map(page_request).collect(),
+        };
+        let mut results = self
+            .call("PageBatch", req, |mut client, req| async move {
+                client.page_batch(req).await
+            })
+            .await?
+            .results
+            .into_iter()
+            .map(page_result)
+            .collect::<Vec<_>>();
+        results.resize_with(pages.len(), || {
+            Err(Status::unknown("upstream gave no result for the page"))
+        });
+        Ok(results)
+    }
+
+    /// Heartbeats are not spooled, since a late heartbeat is no use.
+    async fn heartbeat(&self) -> Result<(), (http::StatusCode, String)> {
+        let mut last_error = Status::unavailable("no upstream");
+        for (i, upstream) in self.upstreams.iter().enumerate() {
+            let mut client = upstream.clone();
+            match tokio::time::timeout(self.timeout, client.heartbeat(())).await {
+                Ok(Ok(_)) => return Ok(()),
+                Ok(Err(s)) => last_error = s,
+                Err(_) => last_error = Status::deadline_exceeded("upstream timed out"),
+            }
+            log::warn!("Heartbeat to upstream {i} failed: {last_error}");
+        }
+        Err((http::StatusCode::BAD_GATEWAY, last_error.to_string()))
+    }
+
+    /// Pages are recorded in the audit log as coming from the relay,
+    /// whichever API they came in on.
+    #[tracing::instrument(skip(self, msg, meta))]
+    async fn page(
+        &self,
+        _source: &str,
+        caller: Option<&str>,
+        msg: String,
+        meta: &PageMeta,
+    ) -> Result<(), (http::StatusCode, String)> {
+        let started = Instant::now();
+        let entry = audit::Entry::new("relay", caller, &msg, meta);
+        let r = self.forward(msg, meta).await;
+        self.audit
+            .record(entry.finish(started, r.as_ref().copied().map_err(|(_, e)| e)));
+        r.map(|_| ())
+    }
+
+    /// Like `page`, but sends all of the pages upstream in a single
+    /// call, and says how each one went.
+    #[tracing::instrument(skip_all, fields(pages = pages.len()))]
+    async fn page_batch(
+        &self,
+        _source: &str,
+        caller: Option<&str>,
+        pages: Vec<(String, PageMeta)>,
+    ) -> Vec<Result<(), (http::StatusCode, String)>> {
+        let started = Instant::now();
+        let entries = pages
+            .iter()
+            .map(|(msg, meta)| audit::Entry::new("relay", caller, msg, meta))
+            .collect::<Vec<_>>();
+        let pages = pages
+            .iter()
+            .map(|(msg, meta)| SpooledPage::new(msg.clone(), meta))
+            .collect::<Vec<_>>();
+        let mut results = Vec::with_capacity(pages.len());
+        if !self.spool.is_empty() {
+            for page in pages {
+                results.push(self.spool_page(page).await);
+            }
+        } else {
+            let sent = match self.send_batch(&pages).await {
+                Ok(sent) => sent,
+                Err(s) => vec![Err(s); pages.len()],
+            };
+            for (page, r) in pages.into_iter().zip(sent) {
+                results.push(self.settle(page, r).await);
+            }
+        }
+        entries
+            .into_iter()
+            .zip(results)
+            .map(|(entry, r)| {
+                self.audit
+                    .record(entry.finish(started, r.as_ref().copied().map_err(|(_, e)| e)));
+                r.map(|_| ())
+            })
+            .collect()
+    }
+
+    /// Pages which cannot be delivered upstream for now are spooled
+    /// and retried in the background. So are all pages while there
+    /// are already spooled pages, to keep them in order.
+    async fn forward(
+        &self,
+        msg: String,
+        meta: &PageMeta,
+    ) -> Result<&'static str, (http::StatusCode, String)> {
+        let page = SpooledPage::new(msg, meta);
+        if !self.spool.is_empty() {
+            return self.spool_page(page).await;
+        }
+        let r = self.send(&page).await;
+        self.settle(page, r).await
+    }
+
+    /// Spools the page if sending it failed in a way that might not
+    /// happen again.
+    async fn settle(
+        &self,
+        page: SpooledPage,
+        r: Result<(), Status>,
+    ) -> Result<&'static str, (http::StatusCode, String)> {
+        match r {
+            Ok(()) => Ok("forwarded"),
+            Err(s) if retryable(&s) => {
+                log::warn!("Spooling page: {s}");
+                self.spool_page(page).await
+            }
+            Err(s) => Err((
+                match s.code() {
+                    Code::NotFound => http::StatusCode::NOT_FOUND,
+                    Code::PermissionDenied => http::StatusCode::FORBIDDEN,
+                    _ => http::StatusCode::INTERNAL_SERVER_ERROR,
+                },
+                s.to_string(),
+            )),
+        }
+    }
+
+    async fn spool_page(
+        &self,
+        page: SpooledPage,
+    ) -> Result<&'static str, (http::StatusCode, String)> {
+        self.spool
+            .push(page)
+            .await
+            .map(|()| "spooled")
+            .map_err(|e| {
+                (
+                    http::StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Spooling page: {e}"),
+                )
+            })
+    }
+
+    async fn drain_spool(&self) {
+        let spool = &self.spool;
+        loop {
+            let page = spool.next_due().await;
+            match self.send(&page).await {
+                Ok(()) => spool.remove(&page).await,
+                Err(s) if !retryable(&s) || page.age() >= self.max_age => {
+                    log::error!(
+                        "Giving up on spooled page after {} attempts: {s}",
+                        page.attempts + 1
+                    );
+                    spool.remove(&page).await;
+                }
+                Err(s) => {
+                    let delay = page.retry_delay();
+                    log::warn!("Delivering spooled page failed, retrying in {delay:?}: {s}");
+                    spool.retry_later(&page, delay, |_| ()).await;
+                }
+            }
+        }
+    }
+}
+
+/// The upstream pager keeps the alerts, silences and failures, so the
+/// relay only forwards pages and heartbeats.
+impl Backend for Forwarder {
+    fn page<'a>(
+        &'a self,
+        source: &'a str,
+        caller: Option<&'a str>,
+        msg: String,
+        meta: &'a PageMeta,
+    ) -> BoxFuture<'a, Result<(), (http::StatusCode, String)>> {
+        self.page(source, caller, msg, meta).boxed()
+    }
+
+    fn page_batch<'a>(
+        &'a self,
+        source: &'a str,
+        caller: Option<&'a str>,
+        pages: Vec<(String, PageMeta)>,
+    ) -> BoxFuture<'a, Vec<Result<(), (http::StatusCode, String)>>> {
+        self.page_batch(source, caller, pages).boxed()
+    }
+
+    fn heartbeat(&self) -> BoxFuture<'_, Result<(), (http::StatusCode, String)>> {
+        self.heartbeat().boxed()
+    }
+
+    fn health(&self) -> BoxFuture<'_, Report> {
+        self.health().boxed()
+    }
+
+    fn receive_soon(&self) -> Result<(), (http::StatusCode, String)> {
+        Err((
+            http::StatusCode::NOT_FOUND,
+            String::from("the relay does not receive Signal messages"),
+        ))
+    }
+
+    fn list_alerts(
+        &self,
+        _since: Option<Duration>,
+    ) -> Result<Vec<AlertRecord>, (http::StatusCode, String)> {
+        Err(no_alerts())
+    }
+
+    fn alerts_html(&self, _since: Option<Duration>) -> Result<String, (http::StatusCode, String)> {
+        Err(no_alerts())
+    }
+
+    fn recent_failures(&self) -> Result<Vec<SignalFailure>, (http::StatusCode, String)> {
+        Err((
+            http::StatusCode::NOT_FOUND,
+            String::from("the relay does not run signal-cli"),
+        ))
+    }
+
+    fn maintenance(&self) -> Result<MaintenanceStatus, (http::StatusCode, String)> {
+        Err((
+            http::StatusCode::NOT_FOUND,
+            String::from("maintenance windows are kept by the upstream pager"),
+        ))
+    }
+
+    fn silences(&self) -> Result<Vec<Silence>, (http::StatusCode, String)> {
+        Err(no_silences())
+    }
+
+    fn add_silence(&self, _: Silence) -> Result<u64, (http::StatusCode, String)> {
+        Err(no_silences())
+    }
+
+    fn expire_silence(&self, _: u64) -> Result<bool, (http::StatusCode, String)> {
+        Err(no_silences())
+    }
+}
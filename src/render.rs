@@ -0,0 +1,272 @@
+use comprehensive::v1::{AssemblyRuntime, Resource, resource};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::oncall::OnCall;
+
+const TEMPLATE_NAME: &str = "alert";
+
+/// Around the recipient of an @mention in a rendered message, until it
+/// is sent. These are in the Unicode private use area so that they
+/// cannot be confused with anything in an alert.
+pub const MENTION_START: char = '\u{e000}';
+pub const MENTION_END: char = '\u{e001}';
+
+/// Equivalent to how alerts were formatted before templates could be
+/// customised, plus a mention of whoever is on call if anyone is.
+pub const DEFAULT_TEMPLATE: &str =
+    "{% if oncall %}{{ mention(recipient=oncall) }} {% endif %}{{ status | upper }}
+{% for k, v in labels %}{{ k }}: {{ v }}
+{% endfor %}{% if annotations.summary %}
+{{ annotations.summary }}
+{% endif %}{% if annotations.description %}
+{{ annotations.description }}
+{% endif %}";
+
+/// `mention(recipient=...)` @mentions a phone number or ACI, and
+/// `mention(rotation=...)` whoever is on call for a rotation, so that
+/// their phone notifies them even if they have muted the group. Nobody
+/// being on call for the rotation mentions nobody.
+fn mention(schedule: &OnCall, args: &HashMap<String, tera::Value>) -> tera::Result<tera::Value> {
+    let arg = |name| args.get(name).and_then(tera::Value::as_str);
+    let recipient = match (arg("recipient"), arg("rotation")) {
+        (Some(r), _) if !r.is_empty() => String::from(r),
+        (None, Some(rotation)) => match schedule.recipient(rotation) {
+            Some(r) => r,
+            None => return Ok("".into()),
+        },
+        _ => {
+            return Err(tera::Error::msg(
+                "mention() needs a recipient or a rotation",
+            ));
+        }
+    };
+    Ok(format!("{MENTION_START}{recipient}{MENTION_END}").into())
+}
+
+/// Cuts annotation values longer than `max` characters short, ending
+/// them with an ellipsis.
+fn truncate_annotations(alert: &mut serde_json::Value, max: usize) {
+    let Some(annotations) = alert
+        .get_mut("annotations")
+        .and_then(serde_json::Value::as_object_mut)
+    else {
+        return;
+    };
+    for value in annotations.values_mut() {
+        if let serde_json::Value::String(s) = value
+            && let Some((end, _)) = s.char_indices().nth(max)
+        {
+            s.truncate(end);
+            s.push('\u{2026}');
+        }
+    }
+}
+
+#[derive(clap::Args)]
+pub struct RendererArgs {
+    /// Tera template used to render each alert into a message.
+    #[arg(long)]
+    message_template_file: Option<PathBuf>,
+    /// Phone number or ACI of the on-call person, if not the first
+    /// `--rotation`. The default template @mentions them in group
+    /// messages, and custom templates can with
+    /// `{{ mention(recipient=oncall) }}`.
+    #[arg(long)]
+    oncall: Option<String>,
+    /// Cut annotation values longer than this many characters short,
+    /// ending them with an ellipsis, before rendering the message.
+    #[arg(long)]
+    max_annotation_length: Option<usize>,
+}
+
+/// How long an alert has been firing, or fired for if it is resolved, to
+/// the minute, like `1h 25m`, from its RFC 3339 start and end.
+pub fn firing_for(status: &str, starts_at: Option<&str>, ends_at: Option<&str>) -> Option<String> {
+    let start = starts_at?.parse::<jiff::Timestamp>().ok()?;
+    let end = if status == "resolved" {
+        ends_at?.parse().ok()?
+    } else {
+        jiff::Timestamp::now()
+    };
+    let secs = std::time::Duration::try_from(end.duration_since(start))
+        .ok()?
+        .as_secs();
+    let secs = if secs >= 60 { secs - secs % 60 } else { secs };
+    Some(humantime::format_duration(std::time::Duration::from_secs(secs)).to_string())
+}
+
+/// Turns an alert into the text of a Signal message.
+pub struct Renderer {
+    tera: tera::Tera,
+    custom: bool,
+    oncall: Option<String>,
+    schedule: Arc<OnCall>,
+    max_annotation_length: Option<usize>,
+}
+
+#[resource]
+impl Resource for Renderer {
+    fn new(
+        d: (Arc<OnCall>,),
+        a: RendererArgs,
+        _: &mut AssemblyRuntime<'_>,
+    ) -> Result<Arc<Self>, tera::Error> {
+        Ok(Arc::new(Self::build(
+            a.message_template_file.as_deref(),
+            a.oncall,
+            d.0,
+            a.max_annotation_length,
+        )?))
+    }
+}
+
+impl Renderer {
+    /// Templates see `oncall` as `oncall` if given, or else whoever is on
+    /// call for the first rotation in `schedule`, and annotations cut
+    /// short at `max_annotation_length` characters.
+    pub fn build(
+        template_file: Option<&Path>,
+        oncall: Option<String>,
+        schedule: Arc<OnCall>,
+        max_annotation_length: Option<usize>,
+    ) -> Result<Self, tera::Error> {
+        let mut tera = tera::Tera::default();
+        let schedule2 = Arc::clone(&schedule);
+        tera.register_function("mention", move |args: &HashMap<String, tera::Value>| {
+            mention(&schedule2, args)
+        });
+        match template_file {
+            Some(path) => tera.add_template_file(path, Some(TEMPLATE_NAME))?,
+            None => tera.add_raw_template(TEMPLATE_NAME, DEFAULT_TEMPLATE)?,
+        }
+        Ok(Self {
+            tera,
+            custom: template_file.is_some(),
+            oncall,
+            schedule,
+            max_annotation_length,
+        })
+    }
+
+    /// The same but with another template, like a tenant's own.
+    pub fn with_template(&self, template_file: &Path) -> Result<Self, tera::Error> {
+        Self::build(
+            Some(template_file),
+            self.oncall.clone(),
+            Arc::clone(&self.schedule),
+            self.max_annotation_length,
+        )
+    }
+
+    /// Whether this is `--message-template-file` rather than the default
+    /// template.
+    pub fn custom(&self) -> bool {
+        self.custom
+    }
+
+    pub fn render<T: Serialize>(&self, alert: &T) -> Result<String, tera::Error> {
+        let mut alert = serde_json::to_value(alert).map_err(tera::Error::json)?;
+        if let Some(max) = self.max_annotation_length {
+            truncate_annotations(&mut alert, max);
+        }
+        let mut context = tera::Context::from_value(alert)?;
+        let oncall = self.oncall.clone().or_else(|| {
+            self.schedule
+                .current()
+                .into_iter()
+                .next()
+                .map(|shift| shift.recipient)
+        });
+        context.insert("oncall", &oncall);
+        self.tera.render(TEMPLATE_NAME, &context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::file;
+
+    fn schedule(rotations: &[&str]) -> Arc<OnCall> {
+        Arc::new(OnCall::with_rotations(
+            rotations.iter().map(|r| r.parse().unwrap()).collect(),
+        ))
+    }
+
+    #[test]
+    fn default_template() {
+        let renderer = Renderer::build(None, None, schedule(&[]), None).unwrap();
+        let alert = serde_json::json!({
+            "status": "firing",
+            "labels": {"alertname": "DiskFull", "instance": "db1"},
+            "annotations": {"summary": "Disk is full"},
+        });
+        assert_eq!(
+            renderer.render(&alert).unwrap(),
+            "FIRING\nalertname: DiskFull\ninstance: db1\n\nDisk is full\n"
+        );
+    }
+
+    #[test]
+    fn template_file() {
+        let f = file("{{ labels.alertname }} is {{ status }}");
+        let renderer = Renderer::build(Some(f.path()), None, schedule(&[]), None).unwrap();
+        let alert = serde_json::json!({"status": "resolved", "labels": {"alertname": "Up"}});
+        assert_eq!(renderer.render(&alert).unwrap(), "Up is resolved");
+    }
+
+    #[test]
+    fn bad_template() {
+        let f = file("{{ unclosed");
+        assert!(Renderer::build(Some(f.path()), None, schedule(&[]), None).is_err());
+    }
+
+    #[test]
+    fn mention_oncall() {
+        let renderer =
+            Renderer::build(None, Some(String::from("+15550001")), schedule(&[]), None).unwrap();
+        let alert = serde_json::json!({"status": "firing", "labels": {}, "annotations": {}});
+        assert_eq!(
+            renderer.render(&alert).unwrap(),
+            format!("{MENTION_START}+15550001{MENTION_END} FIRING\n")
+        );
+        let f = file("{{ mention(recipient=\"\") }}");
+        let renderer = Renderer::build(Some(f.path()), None, schedule(&[]), None).unwrap();
+        assert!(renderer.render(&alert).is_err());
+    }
+
+    #[test]
+    fn mention_rotation() {
+        let schedule =
+            schedule(&["name=primary,period=daily,start=2025-01-01T00:00,participant=+15550002"]);
+        let f = file("{{ mention(rotation=\"primary\") }}|{{ mention(rotation=\"other\") }}");
+        let renderer = Renderer::build(Some(f.path()), None, Arc::clone(&schedule), None).unwrap();
+        let alert = serde_json::json!({"status": "firing"});
+        assert_eq!(
+            renderer.render(&alert).unwrap(),
+            format!("{MENTION_START}+15550002{MENTION_END}|")
+        );
+        // Whoever is on call for the first rotation is `oncall`.
+        let renderer = Renderer::build(None, None, schedule, None).unwrap();
+        let alert = serde_json::json!({"status": "firing", "labels": {}, "annotations": {}});
+        assert_eq!(
+            renderer.render(&alert).unwrap(),
+            format!("{MENTION_START}+15550002{MENTION_END} FIRING\n")
+        );
+    }
+
+    #[test]
+    fn truncates_annotations() {
+        let renderer = Renderer::build(None, None, schedule(&[]), Some(4)).unwrap();
+        let alert = serde_json::json!({
+            "status": "firing",
+            "labels": {"alertname": "DiskFull"},
+            "annotations": {"summary": "Disk is full", "runbook": "run"},
+        });
+        let text = renderer.render(&alert).unwrap();
+        assert!(text.contains("\n\nDisk\u{2026}\n"), "{text}");
+    }
+}
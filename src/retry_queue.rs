@@ -0,0 +1,434 @@
+//! A queue of things to send which are tried again, with backoff, until
+//! they go through, and which can be kept in a file so that they survive
+//! restarts. The send queue of pages for signal-cli and the relay's spool
+//! of pages for the upstreams are both one of these.
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+const INITIAL_RETRY_DELAY: Duration = Duration::new(5, 0);
+const MAX_RETRY_DELAY: Duration = Duration::new(300, 0);
+
+/// What is queued.
+pub trait Item: Clone + Serialize + DeserializeOwned + Send + 'static {
+    /// Whether this is `other`, perhaps since changed, given that both
+    /// were queued at the same time.
+    fn is(&self, other: &Self) -> bool;
+}
+
+/// An item in the queue, with what the queue knows about it. It is kept
+/// as the item's own fields followed by these.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Entry<T> {
+    #[serde(flatten)]
+    pub item: T,
+    pub enqueued: SystemTime,
+    pub attempts: u32,
+    /// When it may be tried again after failing.
+    #[serde(default)]
+    pub not_before: Option<SystemTime>,
+}
+
+impl<T> std::ops::Deref for Entry<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.item
+    }
+}
+
+impl<T: Item> Entry<T> {
+    pub fn age(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(self.enqueued)
+            .unwrap_or_default()
+    }
+
+    pub fn retry_delay(&self) -> Duration {
+        INITIAL_RETRY_DELAY
+            .saturating_mul(1 << self.attempts.min(16))
+            .min(MAX_RETRY_DELAY)
+    }
+
+    fn is(&self, other: &Self) -> bool {
+        self.enqueued == other.enqueued && self.item.is(&other.item)
+    }
+
+    pub fn due(&self, now: SystemTime) -> bool {
+        self.not_before.is_none_or(|t| t <= now)
+    }
+}
+
+/// Items waiting to be sent, oldest first. An item which fails goes
+/// behind the others until it is due to be tried again, unless the queue
+/// is `in_order`. If a file is given then the queue is rewritten to it
+/// after every change and reloaded from it at startup.
+pub struct RetryQueue<T> {
+    /// For logs, like `send queue`.
+    name: &'static str,
+    items: Mutex<VecDeque<Entry<T>>>,
+    file: Option<PathBuf>,
+    max: Option<usize>,
+    in_order: bool,
+    depth: Option<&'static prometheus::IntGauge>,
+    /// Held from changing the items until they are written, so that the
+    /// file is written in the order of the changes.
+    writing: tokio::sync::Mutex<()>,
+    notify: tokio::sync::Notify,
+    changed: tokio::sync::Notify,
+}
+
+impl<T: Item> RetryQueue<T> {
+    pub fn new(name: &'static str, file: Option<PathBuf>) -> Result<Self, std::io::Error> {
+        let items: VecDeque<Entry<T>> = match file {
+            Some(ref path) => match std::fs::read(path) {
+                Ok(data) => serde_json::from_slice(&data)?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => VecDeque::new(),
+                Err(e) => return Err(e),
+            },
+            None => VecDeque::new(),
+        };
+        if !items.is_empty() {
+            log::info!("Loaded {} pages into the {name}", items.len());
+        }
+        Ok(Self {
+            name,
+            items: Mutex::new(items),
+            file,
+            max: None,
+            in_order: false,
+            depth: None,
+            writing: tokio::sync::Mutex::new(()),
+            notify: tokio::sync::Notify::new(),
+            changed: tokio::sync::Notify::new(),
+        })
+    }
+
+    /// Holds at most `max` items, dropping the oldest.
+    pub fn max(mut self, max: usize) -> Self {
+        self.max = Some(max);
+        self
+    }
+
+    /// Has an item which fails hold up the others until it goes through
+    /// or is given up on, so that they are sent in the order queued.
+    pub fn in_order(mut self) -> Self {
+        self.in_order = true;
+        self
+    }
+
+    /// Keeps `gauge` set to the number of items.
+    pub fn depth_gauge(mut self, gauge: &'static prometheus::IntGauge) -> Self {
+        gauge.set(self.len() as i64);
+        self.depth = Some(gauge);
+        self
+    }
+
+    /// Notes that the items changed, and serializes them for `persist`
+    /// if there is a file.
+    fn changed_to(&self, items: &VecDeque<Entry<T>>) -> Result<Option<Vec<u8>>, std::io::Error> {
+        if let Some(depth) = self.depth {
+            depth.set(items.len() as i64);
+        }
+        self.changed.notify_one();
+        match self.file {
+            Some(_) => Ok(Some(serde_json::to_vec(items)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Writes what `changed_to` returned on a blocking thread, since it
+    /// waits for the disk.
+    async fn persist(
+        &self,
+        data: Result<Option<Vec<u8>>, std::io::Error>,
+    ) -> Result<(), std::io::Error> {
+        let (Some(path), Some(data)) = (self.file.clone(), data?) else {
+            return Ok(());
+        };
+        tokio::task::spawn_blocking(move || {
+            let tmp = path.with_extension("tmp");
+            let mut f = std::fs::File::create(&tmp)?;
+            f.write_all(&data)?;
+            f.sync_all()?;
+            std::fs::rename(tmp, path)
+        })
+        .await
+        .map_err(std::io::Error::other)?
+    }
+
+    /// Changes the items and persists them, logging any failure.
+    pub async fn update<R>(&self, change: impl FnOnce(&mut VecDeque<Entry<T>>) -> R) -> R {
+        let _writing = self.writing.lock().await;
+        let (r, data) = {
+            let mut items = self.items.lock().unwrap();
+            let r = change(&mut items);
+            (r, self.changed_to(&items))
+        };
+        if let Err(e) = self.persist(data).await {
+            log::error!("Persisting the {}: {e}", self.name);
+        }
+        self.notify.notify_one();
+        r
+    }
+
+    /// Fails, leaving the item out, if it cannot be persisted.
+    pub async fn push(&self, item: T) -> Result<(), std::io::Error> {
+        let entry = Entry {
+            item,
+            enqueued: SystemTime::now(),
+            attempts: 0,
+            not_before: None,
+        };
+        let _writing = self.writing.lock().await;
+        let data = {
+            let mut items = self.items.lock().unwrap();
+            items.push_back(entry.clone());
+            while self.max.is_some_and(|max| items.len() > max) {
+                items.pop_front();
+                log::error!("The {} is full, dropped the oldest page", self.name);
+            }
+            self.changed_to(&items)
+        };
+        if let Err(e) = self.persist(data).await {
+            let mut items = self.items.lock().unwrap();
+            items.retain(|p| !p.is(&entry));
+            if let Some(depth) = self.depth {
+                depth.set(items.len() as i64);
+            }
+            return Err(e);
+        }
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    /// Waits until an item is due to be tried and returns the first such
+    /// without removing it.
+    pub async fn next_due(&self) -> Entry<T> {
+        loop {
+            let wait = {
+                let items = self.items.lock().unwrap();
+                let candidates = if self.in_order { 1 } else { items.len() };
+                let now = SystemTime::now();
+                if let Some(entry) = items.iter().take(candidates).find(|p| p.due(now)) {
+                    return entry.clone();
+                }
+                items
+                    .iter()
+                    .take(candidates)
+                    .filter_map(|p| p.not_before)
+                    .min()
+                    .map(|t| t.duration_since(now).unwrap_or_default())
+            };
+            match wait {
+                Some(wait) => tokio::select! {
+                    _ = tokio::time::sleep(wait) => (),
+                    _ = self.notify.notified() => (),
+                },
+                None => self.notify.notified().await,
+            }
+        }
+    }
+
+    pub fn snapshot(&self) -> VecDeque<Entry<T>> {
+        self.items.lock().unwrap().clone()
+    }
+
+    /// Resolves once the queue has changed since this last resolved.
+    pub async fn changed(&self) {
+        self.changed.notified().await
+    }
+
+    /// Puts items saved from an earlier run back ahead of any queued
+    /// since, which must be newer, leaving out any which are already
+    /// queued because the file kept them too.
+    pub async fn restore(&self, mut saved: VecDeque<Entry<T>>) {
+        self.update(|items| {
+            saved.retain(|s| !items.iter().any(|p| p.is(s)));
+            let newer = std::mem::replace(items, saved);
+            items.extend(newer);
+        })
+        .await
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.lock().unwrap().is_empty()
+    }
+
+    /// Takes an item off the queue, once it has been sent or given up on.
+    pub async fn remove(&self, entry: &Entry<T>) {
+        self.update(|items| items.retain(|p| !p.is(entry))).await
+    }
+
+    /// Records a failed attempt to send an item, changing it with
+    /// `change`, and has it wait until `delay` has passed.
+    pub async fn retry_later(
+        &self,
+        entry: &Entry<T>,
+        delay: Duration,
+        change: impl FnOnce(&mut T),
+    ) {
+        self.update(|items| {
+            let Some(i) = items.iter().position(|p| p.is(entry)) else {
+                return;
+            };
+            let mut entry = items[i].clone();
+            entry.attempts += 1;
+            entry.not_before = Some(SystemTime::now() + delay);
+            change(&mut entry.item);
+            if self.in_order {
+                items[i] = entry;
+            } else {
+                items.remove(i);
+                items.push_back(entry);
+            }
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Deserialize, Serialize)]
+    struct Message {
+        text: String,
+        #[serde(default)]
+        tries: Vec<String>,
+    }
+
+    impl Item for Message {
+        fn is(&self, other: &Self) -> bool {
+            self.text == other.text
+        }
+    }
+
+    async fn push(queue: &RetryQueue<Message>, text: &str) {
+        let item = Message {
+            text: String::from(text),
+            tries: Vec::new(),
+        };
+        queue.push(item).await.unwrap();
+    }
+
+    fn texts(queue: &RetryQueue<Message>) -> Vec<String> {
+        queue.snapshot().into_iter().map(|p| p.item.text).collect()
+    }
+
+    #[test]
+    fn retry_delay_backs_off() {
+        let mut entry = Entry {
+            item: Message {
+                text: String::new(),
+                tries: Vec::new(),
+            },
+            enqueued: SystemTime::now(),
+            attempts: 0,
+            not_before: None,
+        };
+        let delays: Vec<_> = (0..8)
+            .map(|attempts| {
+                entry.attempts = attempts;
+                entry.retry_delay().as_secs()
+            })
+            .collect();
+        assert_eq!(delays, [5, 10, 20, 40, 80, 160, 300, 300]);
+        entry.attempts = u32::MAX;
+        assert_eq!(entry.retry_delay(), MAX_RETRY_DELAY);
+    }
+
+    #[tokio::test]
+    async fn persisted_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("queue.json");
+        let queue = RetryQueue::new("queue", Some(file.clone())).unwrap();
+        push(&queue, "one").await;
+        push(&queue, "two").await;
+        push(&queue, "three").await;
+        let one = queue.next_due().await;
+        queue
+            .retry_later(&one, Duration::from_secs(60), |m| {
+                m.tries.push(String::from("a"))
+            })
+            .await;
+        queue.remove(&queue.next_due().await).await;
+
+        let reloaded = RetryQueue::<Message>::new("queue", Some(file)).unwrap();
+        assert_eq!(texts(&reloaded), ["three", "one"]);
+        let entry = &reloaded.snapshot()[1];
+        assert_eq!(entry.attempts, 1);
+        assert_eq!(entry.tries, ["a"]);
+    }
+
+    #[tokio::test]
+    async fn failed_items_wait_behind_others() {
+        let queue = RetryQueue::new("queue", None).unwrap();
+        push(&queue, "one").await;
+        push(&queue, "two").await;
+        let one = queue.next_due().await;
+        queue
+            .retry_later(&one, Duration::from_secs(60), |_| ())
+            .await;
+        let two = queue.next_due().await;
+        assert_eq!(two.text, "two");
+        queue.remove(&two).await;
+        // Only the failed item is left, and it is not due yet.
+        let waited = tokio::time::timeout(Duration::from_millis(50), queue.next_due()).await;
+        assert!(waited.is_err());
+
+        push(&queue, "three").await;
+        let one = queue.snapshot()[0].clone();
+        queue.retry_later(&one, Duration::ZERO, |_| ()).await;
+        assert_eq!(texts(&queue), ["three", "one"]);
+        assert_eq!(queue.next_due().await.text, "three");
+    }
+
+    #[tokio::test]
+    async fn in_order_holds_up_the_rest() {
+        let queue = RetryQueue::new("spool", None).unwrap().in_order();
+        push(&queue, "one").await;
+        push(&queue, "two").await;
+        let one = queue.next_due().await;
+        queue
+            .retry_later(&one, Duration::from_secs(60), |_| ())
+            .await;
+        assert_eq!(texts(&queue), ["one", "two"]);
+        let waited = tokio::time::timeout(Duration::from_millis(50), queue.next_due()).await;
+        assert!(waited.is_err());
+    }
+
+    #[tokio::test]
+    async fn drops_the_oldest_when_full() {
+        let queue = RetryQueue::new("spool", None).unwrap().max(2);
+        push(&queue, "one").await;
+        push(&queue, "two").await;
+        push(&queue, "three").await;
+        assert_eq!(texts(&queue), ["two", "three"]);
+    }
+
+    #[tokio::test]
+    async fn restore_ahead_of_newer() {
+        let queue = RetryQueue::new("queue", None).unwrap();
+        push(&queue, "one").await;
+        let saved = queue.snapshot();
+        queue.remove(&saved[0]).await;
+        assert!(queue.is_empty());
+        push(&queue, "two").await;
+        queue.restore(saved.clone()).await;
+        assert_eq!(queue.next_due().await.text, "one");
+        queue.restore(saved).await;
+        assert_eq!(queue.len(), 2);
+        queue.remove(&queue.next_due().await).await;
+        assert_eq!(queue.next_due().await.text, "two");
+    }
+}
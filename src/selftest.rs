@@ -0,0 +1,119 @@
+//! Sends a test message every so often to find out that paging is
+//! broken before a real page does. Since Signal is what is broken then,
+//! the operator is told some other way.
+
+use comprehensive::ResourceDependencies;
+use comprehensive::v1::{AssemblyRuntime, Resource, resource};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::signal::{SignalRunner, Target};
+use crate::state::SignalState;
+
+#[derive(clap::Args)]
+pub struct SelfTestArgs {
+    /// Send a test message this often and check that signal-cli
+    /// succeeds. The outcome is in the `signal_pager_self_tests_total`
+    /// and `signal_pager_self_test_last_success_timestamp_seconds`
+    /// metrics.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    self_test_interval: Option<Duration>,
+    /// Where to send test messages, in the same form as `--route`
+    /// targets. Defaults to our own number, which is "Note to Self".
+    #[arg(long)]
+    self_test_target: Option<Target>,
+    /// The text of test messages.
+    #[arg(long, default_value = "signal-pager self-test")]
+    self_test_message: String,
+    /// POST `{"text": "..."}` to this URL, as accepted by Slack and
+    /// compatible incoming webhooks, when self-tests start failing and
+    /// when they work again.
+    #[arg(long, requires = "self_test_interval")]
+    self_test_alert_url: Option<String>,
+    /// How many self-tests in a row must fail before
+    /// `--self-test-alert-url` is told.
+    #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u32).range(1..))]
+    self_test_failures: u32,
+}
+
+#[derive(ResourceDependencies)]
+pub struct SelfTestDependencies {
+    signal: Arc<SignalRunner>,
+    state: Arc<SignalState>,
+}
+
+pub struct SelfTest;
+
+#[resource]
+impl Resource for SelfTest {
+    fn new(
+        d: SelfTestDependencies,
+        a: SelfTestArgs,
+        api: &mut AssemblyRuntime<'_>,
+    ) -> Result<Arc<Self>, std::convert::Infallible> {
+        let Some(interval) = a.self_test_interval else {
+            return Ok(Arc::new(Self));
+        };
+        let target = a
+            .self_test_target
+            .clone()
+            .unwrap_or_else(|| d.signal.own_number());
+        let http = reqwest::Client::new();
+        api.set_task(async move {
+            let mut failures = 0;
+            loop {
+                d.state.wait_available().await;
+                let r = d.signal.send(&target, &a.self_test_message, None).await;
+                crate::metrics::SELF_TESTS
+                    .with_label_values(&[crate::metrics::result_label(&r)])
+                    .inc();
+                match r {
+                    Ok(_) => {
+                        crate::metrics::SELF_TEST_LAST_SUCCESS.set(
+                            SystemTime::now()
+                                .duration_since(SystemTime::UNIX_EPOCH)
+                                .unwrap_or_default()
+                                .as_secs_f64(),
+                        );
+                        if failures >= a.self_test_failures {
+                            log::info!("Self-test works again");
+                            alert(&http, &a, "signal-pager can send pages again.").await;
+                        }
+                        failures = 0;
+                    }
+                    Err(e) => {
+                        failures += 1;
+                        log::error!("Self-test to {target} failed: {e}");
+                        if failures == a.self_test_failures {
+                            alert(
+                                &http,
+                                &a,
+                                &format!(
+                                    "signal-pager cannot send pages: {failures} self-tests in a row failed. Last error: {e}"
+                                ),
+                            )
+                            .await;
+                        }
+                    }
+                }
+                tokio::time::sleep(interval).await;
+            }
+        });
+        Ok(Arc::new(Self))
+    }
+}
+
+async fn alert(http: &reqwest::Client, a: &SelfTestArgs, text: &str) {
+    let Some(ref url) = a.self_test_alert_url else {
+        return;
+    };
+    let r = http
+        .post(url)
+        .json(&serde_json::json!({ "text": text }))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+    if let Err(e) = r {
+        log::error!("Posting to --self-test-alert-url: {e}");
+    }
+}
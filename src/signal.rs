@@ -1,56 +1,366 @@
 use comprehensive::ResourceDependencies;
+use comprehensive::health::{HealthReporter, HealthSignaller};
 use comprehensive::v1::{AssemblyRuntime, Resource, resource};
-use pin_project_lite::pin_project;
-use std::io::Write;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
 use std::path::PathBuf;
-use std::pin::Pin;
-use std::process::{Child, Command, ExitStatus, Stdio};
 use std::sync::Arc;
-use std::task::{Context, Poll};
-use std::time::Duration;
-use tokio::task::{JoinError, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::task::JoinError;
+use tracing::Instrument;
 
-const INITIAL_RECEIVE_DELAY: Duration = Duration::new(3600, 0);
-const RECEIVE_INTERVAL: Duration = Duration::new(86400, 0);
+mod attachment;
+mod cli;
+mod decoration;
+mod dedup;
+mod failover;
+mod failure;
+mod incoming;
+mod jsonrpc;
+mod mention;
+mod queue;
+mod route;
+mod shutdown;
+mod split;
+mod target;
+mod transport;
+
+use crate::alerts::Status as AlertStatus;
+use crate::page::{Attachment, AttachmentData, PageMeta};
+pub use failure::{LastOutcome, SignalFailure};
+pub use incoming::IncomingMessage;
+use queue::QueuedPage;
+use route::ResolvedAction;
+pub use target::Target;
+use transport::{Outgoing, Reference, SignalTransport};
+
+const DEFAULT_RECEIVE_DELAY: Duration = Duration::new(3600, 0);
+const DEFAULT_RECEIVE_INTERVAL: Duration = Duration::new(86400, 0);
+const ACK_RECEIVE_INTERVAL: Duration = Duration::new(60, 0);
+const ACK_EMOJI: &str = "\u{1f44d}";
+const VALIDATE_RETRY_INTERVAL: Duration = Duration::new(30, 0);
+/// Where the send queue is kept in the state, so that queued pages are
+/// not lost if we stop before sending them.
+const QUEUE_APP_DATA_NAME: &str = "send-queue.json";
 
 #[derive(Debug, thiserror::Error)]
 pub enum SignalRunnerError {
     #[error("No state loaded")]
     NoStateAvailable,
+    #[error("Shutting down")]
+    ShuttingDown,
     #[error("Error running Signal: {0}")]
     IOError(#[from] std::io::Error),
     #[error("Error joining Signal: {0}")]
     JoinError(#[from] JoinError),
-    #[error("Signal exited with code {0:?}")]
-    SignalFailed(Option<i32>),
+    #[error("Signal exited with code {0:?}: {1}")]
+    SignalFailed(Option<i32>, String),
+    #[error("Signal JSON-RPC call failed: {0}")]
+    RpcFailed(String),
+    #[error("Signal rate limit reached: {0}")]
+    RateLimited(String),
+    #[error("Signal account is not registered: {0}")]
+    Unregistered(String),
+    #[error("Signal requires a captcha: {0}")]
+    CaptchaRequired(String),
+    #[error("Untrusted Signal identity: {0}")]
+    UntrustedIdentity(String),
+    #[error("Network error reaching Signal: {0}")]
+    NetworkError(String),
+    #[error("signal-cli did not finish within {0:?}")]
+    Timeout(Duration),
+    #[error("Nobody is on call for {0:?}")]
+    NobodyOnCall(String),
+    #[error("Signal JSON-RPC daemon exited")]
+    DaemonExited,
+    #[error("Signal JSON-RPC encoding: {0}")]
+    JsonError(#[from] serde_json::Error),
+    #[error("{0}")]
+    PolicyError(#[from] crate::policy::PolicyError),
 }
 
 impl From<SignalRunnerError> for (http::StatusCode, String) {
     fn from(e: SignalRunnerError) -> (http::StatusCode, String) {
-        (http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+        let code = match e {
+            SignalRunnerError::RateLimited(_) => http::StatusCode::TOO_MANY_REQUESTS,
+            SignalRunnerError::Timeout(_) => http::StatusCode::GATEWAY_TIMEOUT,
+            SignalRunnerError::NoStateAvailable
+            | SignalRunnerError::ShuttingDown
+            | SignalRunnerError::NetworkError(_) => http::StatusCode::SERVICE_UNAVAILABLE,
+            SignalRunnerError::Unregistered(_)
+            | SignalRunnerError::CaptchaRequired(_)
+            | SignalRunnerError::UntrustedIdentity(_) => http::StatusCode::BAD_GATEWAY,
+            SignalRunnerError::NobodyOnCall(_) => http::StatusCode::CONFLICT,
+            SignalRunnerError::PolicyError(crate::policy::PolicyError::Denied(_)) => {
+                http::StatusCode::FORBIDDEN
+            }
+            SignalRunnerError::PolicyError(crate::policy::PolicyError::Unavailable(_)) => {
+                http::StatusCode::SERVICE_UNAVAILABLE
+            }
+            _ => http::StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (code, e.to_string())
     }
 }
 
 impl From<SignalRunnerError> for tonic::Status {
     fn from(e: SignalRunnerError) -> tonic::Status {
-        tonic::Status::internal(e.to_string())
+        let message = e.to_string();
+        match e {
+            SignalRunnerError::RateLimited(_) => tonic::Status::resource_exhausted(message),
+            SignalRunnerError::Timeout(_) => tonic::Status::deadline_exceeded(message),
+            SignalRunnerError::NoStateAvailable
+            | SignalRunnerError::ShuttingDown
+            | SignalRunnerError::NetworkError(_) => tonic::Status::unavailable(message),
+            SignalRunnerError::Unregistered(_)
+            | SignalRunnerError::CaptchaRequired(_)
+            | SignalRunnerError::UntrustedIdentity(_)
+            | SignalRunnerError::NobodyOnCall(_) => tonic::Status::failed_precondition(message),
+            SignalRunnerError::PolicyError(crate::policy::PolicyError::Denied(_)) => {
+                tonic::Status::permission_denied(message)
+            }
+            SignalRunnerError::PolicyError(crate::policy::PolicyError::Unavailable(_)) => {
+                tonic::Status::unavailable(message)
+            }
+            _ => tonic::Status::internal(message),
+        }
     }
 }
 
+/// Says that a page is about the alert of an earlier one resolving.
+#[derive(Clone, Copy, Deserialize, Serialize)]
+struct Resolves {
+    alert: u64,
+    action: ResolvedAction,
+}
+
 #[derive(ResourceDependencies)]
-pub struct SignalRunnerDependencies(Arc<crate::state::SignalState>);
+pub struct SignalRunnerDependencies(
+    Arc<crate::state::SignalState>,
+    Arc<crate::alerts::Alerts>,
+    Arc<crate::heartbeat::Heartbeat>,
+    Arc<HealthReporter>,
+    Arc<crate::oncall::OnCall>,
+    Arc<crate::maintenance::Maintenance>,
+    Arc<crate::audit::Audit>,
+    Arc<crate::fallback::Fallback>,
+    Arc<crate::digest::Digest>,
+    Arc<crate::policy::Policy>,
+);
 
 #[derive(clap::Args)]
 pub struct SignalRunnerArgs {
     #[arg(long)]
     signal_phone_number: String,
+    /// A second registered account to send pages through when the
+    /// first one cannot send at all, such as when it is no longer
+    /// registered, needs a captcha or is rate limited. Its state is kept
+    /// under `--secondary-state-prefix`. It has to be a member of the
+    /// groups that pages go to.
+    #[arg(long)]
+    signal_secondary_phone_number: Option<String>,
+    /// The group that pages are sent to unless a `--route` matches.
     #[arg(long)]
     signal_group_id: String,
+    /// Send pages whose labels match to other targets instead, for
+    /// example `team=db,severity=critical=>group:GROUP_ID`. May be
+    /// repeated, and the first matching route is used.
+    #[arg(long)]
+    route: Vec<route::Route>,
+    /// What to do with pages saying that an alert is over, unless their
+    /// route says otherwise with `resolved=`: `send` them, `suppress`
+    /// them, send them as a `reply` to the page about the alert firing,
+    /// `edit` that page to say that the alert is over, or `delete` it.
+    #[arg(long, default_value = "send")]
+    resolved_action: ResolvedAction,
+    /// Put a prefix, like an emoji, before pages with a `severity`
+    /// label, as in `critical=🔥`, or before resolved pages with
+    /// `resolved=✅`. May be repeated.
+    #[arg(long)]
+    severity_prefix: Vec<decoration::SeverityPrefix>,
+    /// Send messages longer than this many characters in numbered
+    /// parts, split between lines where possible.
+    #[arg(long, default_value_t = 2000, value_parser = clap::value_parser!(u64).range(100..))]
+    max_message_length: u64,
     #[arg(long)]
     signal_bin: PathBuf,
+    /// Keep one signal-cli running in jsonRpc mode instead of starting
+    /// a new process for every message.
+    #[arg(long)]
+    signal_jsonrpc: bool,
+    /// Kill signal-cli if sending or receiving takes longer than this.
+    /// Pages which timed out are queued to be sent again.
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "2m")]
+    signal_timeout: Duration,
+    /// Accept pages into a queue and send them in the background,
+    /// retrying failures, instead of sending them synchronously.
+    /// Pages which arrive before the state is loaded are always queued.
+    #[arg(long)]
+    send_queue: bool,
+    /// Keep the send queue in this file so that it survives restarts.
+    #[arg(long)]
+    send_queue_file: Option<PathBuf>,
+    /// Give up retrying a queued page once it is this old.
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "1h")]
+    send_queue_max_age: Duration,
+    /// Once more than this many pages are queued, send the queued pages
+    /// which go to the same place together as one message.
+    #[arg(long)]
+    send_queue_coalesce_threshold: Option<usize>,
+    /// On shutdown, keep sending queued pages for up to this long. Any
+    /// still queued after that are saved in the state and sent after
+    /// the next start.
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "30s")]
+    shutdown_drain_timeout: Duration,
+    /// Send at most this many messages over this long, like `20/1m`,
+    /// however many pages arrive, so that Signal does not rate limit the
+    /// account. Messages wait their turn.
+    #[arg(long)]
+    signal_send_rate: Option<crate::ratelimit::Rate>,
+    /// How many messages may be sent at once before `--signal-send-rate`
+    /// applies. Defaults to the number in the rate.
+    #[arg(long)]
+    signal_send_burst: Option<u32>,
+    /// Number each page and accept acknowledgements of them by replies
+    /// like "ack 1234" or by reacting with a thumbs up. This makes us
+    /// receive messages every minute.
+    #[arg(long)]
+    enable_acks: bool,
+    /// A phone number or ACI allowed to acknowledge pages by direct
+    /// message, or from groups that pages are not sent to. Anyone in the
+    /// `--signal-group-id` group or a `--route`'s group always may. May
+    /// be repeated.
+    #[arg(long, requires = "enable_acks")]
+    ack_sender: Vec<String>,
+    /// A phone number or ACI that pages may name in their `recipients`
+    /// to be sent to directly. Others are left out. May be repeated.
+    #[arg(long)]
+    recipient_allow: Vec<String>,
+    /// Send repeats of the same alert (by fingerprint, or else by
+    /// labels) at most once per this window. When a suppressed alert is
+    /// sent again its message says how many times it was seen.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    dedup_window: Option<Duration>,
+    /// Wait this long after starting before the first receive.
+    /// Defaults to 1h, or none with `--enable-acks`.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    receive_initial_delay: Option<Duration>,
+    /// Receive messages this often. Accounts which never receive
+    /// eventually stop working. Defaults to 24h, or 1m with
+    /// `--enable-acks`.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    receive_interval: Option<Duration>,
+    /// Keep this many of the most recently received messages for
+    /// `ListReceivedMessages`.
+    #[arg(long, default_value_t = 100)]
+    received_messages_kept: usize,
+    /// Fetch attachments given by URL from here: `https:` for any host
+    /// over HTTPS, or `https://HOST[:PORT]` for one host. May be
+    /// repeated. Without it only `data:` URLs are attached.
+    #[arg(long)]
+    attachment_url_allow: Vec<attachment::UrlAllow>,
+}
+
+/// A transport for the account whose state is `state`, which it lets go
+/// of whenever the state directory is about to be copied or replaced.
+fn new_transport(
+    a: &SignalRunnerArgs,
+    phone_number: &str,
+    failures: &Arc<failure::RecentFailures>,
+    state: &crate::state::SignalState,
+) -> Arc<dyn SignalTransport> {
+    let transport: Arc<dyn SignalTransport> = if a.signal_jsonrpc {
+        Arc::new(jsonrpc::Daemon::new(
+            a.signal_bin.clone(),
+            String::from(phone_number),
+            a.signal_timeout,
+        ))
+    } else {
+        Arc::new(cli::Cli::new(
+            a.signal_bin.clone(),
+            String::from(phone_number),
+            a.signal_timeout,
+            Arc::clone(failures),
+        ))
+    };
+    let releasing = Arc::clone(&transport);
+    state.on_raise(Box::new(move || {
+        let transport = Arc::clone(&releasing);
+        Box::pin(async move { transport.release().await })
+    }));
+    transport
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Delivery {
+    Sent,
+    Queued,
+    Suppressed,
+    /// It was a heartbeat, not a page.
+    Heartbeat,
+    /// Held back by a maintenance window.
+    Maintenance,
+    /// Held back for the next `--digest-matching` digest.
+    Digest,
+}
+
+impl Delivery {
+    fn label(self) -> &'static str {
+        match self {
+            Self::Sent => "sent",
+            Self::Queued => "queued",
+            Self::Suppressed => "suppressed",
+            Self::Heartbeat => "heartbeat",
+            Self::Maintenance => "maintenance",
+            Self::Digest => "digest",
+        }
+    }
+}
+
+/// What became of a page.
+pub struct PageOutcome {
+    /// The alert's ID, unless it was suppressed as a duplicate or held
+    /// back by a maintenance window. Resolved pages which were not sent
+    /// because of `--resolved-action=suppress` still have one.
+    pub id: Option<u64>,
+    pub received: SystemTime,
+    pub delivery: Delivery,
+}
+
+pub struct RunnerStatus {
+    pub state: crate::state::StateStatus,
+    pub last_send: Option<LastOutcome>,
+    pub last_receive: Option<LastOutcome>,
+    pub queue_depth: usize,
 }
 
-pub struct SignalRunner(SignalRunnerDependencies, SignalRunnerArgs);
+pub struct SignalRunner {
+    state: Arc<crate::state::SignalState>,
+    alerts: Arc<crate::alerts::Alerts>,
+    heartbeat: Arc<crate::heartbeat::Heartbeat>,
+    oncall: Arc<crate::oncall::OnCall>,
+    maintenance: Arc<crate::maintenance::Maintenance>,
+    audit: Arc<crate::audit::Audit>,
+    fallback: Arc<crate::fallback::Fallback>,
+    digest: Arc<crate::digest::Digest>,
+    policy: Arc<crate::policy::Policy>,
+    args: SignalRunnerArgs,
+    transport: Arc<dyn SignalTransport>,
+    secondary: Option<failover::Secondary>,
+    queue: queue::SendQueue,
+    gate: shutdown::Gate,
+    /// Whether any queue saved in the state has been put back in the
+    /// send queue, so that the state's copy may be overwritten.
+    queue_restored: tokio::sync::watch::Sender<bool>,
+    dedup: Option<dedup::Deduplicator>,
+    throttle: Option<crate::ratelimit::Throttle>,
+    receive_now: tokio::sync::Notify,
+    failures: Arc<failure::RecentFailures>,
+    outcomes: failure::LastOutcomes,
+    received: std::sync::Mutex<VecDeque<IncomingMessage>>,
+    attachments: attachment::Fetcher,
+}
 
 #[resource]
 impl Resource for SignalRunner {
@@ -58,105 +368,1207 @@ impl Resource for SignalRunner {
         d: SignalRunnerDependencies,
         a: SignalRunnerArgs,
         api: &mut AssemblyRuntime<'_>,
-    ) -> Result<Arc<Self>, std::convert::Infallible> {
-        let shared = Arc::new(Self(d, a));
+    ) -> Result<Arc<Self>, std::io::Error> {
+        let queue = queue::SendQueue::new("send queue", a.send_queue_file.clone())?
+            .depth_gauge(&crate::metrics::SEND_QUEUE_DEPTH);
+        let failures = Arc::new(failure::RecentFailures::default());
+        let transport = new_transport(&a, &a.signal_phone_number, &failures, &d.0);
+        let secondary = match (a.signal_secondary_phone_number.as_ref(), d.0.secondary()) {
+            (None, _) => None,
+            (Some(phone_number), Some(state)) => Some(failover::Secondary::new(
+                phone_number.clone(),
+                Arc::clone(state),
+                new_transport(&a, phone_number, &failures, state),
+            )),
+            (Some(_), None) => {
+                return Err(std::io::Error::other(
+                    "--signal-secondary-phone-number requires --secondary-state-prefix",
+                ));
+            }
+        };
+        let dedup = a.dedup_window.map(dedup::Deduplicator::new);
+        let attachments = attachment::Fetcher::new(&a.attachment_url_allow);
+        let throttle = a
+            .signal_send_rate
+            .map(|rate| crate::ratelimit::Throttle::new(rate, a.signal_send_burst));
+        let health =
+            d.3.register("signal account")
+                .map_err(std::io::Error::other)?;
+        let shared = Arc::new(Self {
+            state: d.0,
+            alerts: d.1,
+            heartbeat: d.2,
+            oncall: d.4,
+            maintenance: d.5,
+            audit: d.6,
+            fallback: d.7,
+            digest: d.8,
+            policy: d.9,
+            args: a,
+            transport,
+            secondary,
+            queue,
+            gate: shutdown::Gate::default(),
+            queue_restored: tokio::sync::watch::Sender::new(false),
+            dedup,
+            throttle,
+            receive_now: tokio::sync::Notify::new(),
+            failures,
+            outcomes: failure::LastOutcomes::default(),
+            received: std::sync::Mutex::new(VecDeque::new()),
+            attachments,
+        });
         let shared_for_receive = Arc::clone(&shared);
+        let shared_for_queue = Arc::clone(&shared);
+        let shared_for_validate = Arc::clone(&shared);
+        let shared_for_digest = Arc::clone(&shared);
+        let shared_for_alert_digest = Arc::clone(&shared);
+        let shared_for_save_queue = Arc::clone(&shared);
+        let shared_for_shutdown = Arc::clone(&shared);
+        let stop = api.self_stop();
         api.set_task(async move {
-            tokio::time::sleep(INITIAL_RECEIVE_DELAY).await;
-            loop {
-                log::info!("Invoking Signal receive");
-                if let Err(e) = shared_for_receive.receive().await {
-                    log::error!("Signal receive: {e}");
+            let receive = async move {
+                let args = &shared_for_receive.args;
+                let (default_delay, default_interval) = if args.enable_acks {
+                    (Duration::ZERO, ACK_RECEIVE_INTERVAL)
+                } else {
+                    (DEFAULT_RECEIVE_DELAY, DEFAULT_RECEIVE_INTERVAL)
+                };
+                let mut delay = args.receive_initial_delay.unwrap_or(default_delay);
+                let interval = args.receive_interval.unwrap_or(default_interval);
+                loop {
+                    tokio::select! {
+                        _ = tokio::time::sleep(delay) => (),
+                        _ = shared_for_receive.receive_now.notified() => (),
+                    }
+                    delay = interval;
+                    log::info!("Invoking Signal receive");
+                    match shared_for_receive.receive().await {
+                        Ok(messages) => shared_for_receive.handle_incoming(messages),
+                        Err(e) => log::error!("Signal receive: {e}"),
+                    }
                 }
-                tokio::time::sleep(RECEIVE_INTERVAL).await;
-            }
+            };
+            let background = futures::future::join5(
+                receive,
+                shared_for_save_queue.keep_queue_in_state(),
+                shared_for_validate.validate_account(health),
+                shared_for_digest.send_digests(),
+                shared_for_alert_digest.send_alert_digests(),
+            );
+            // The queue is drained until shutting down begins rather
+            // than dropped, so as not to interrupt a send.
+            let until_stopped = async {
+                tokio::select! {
+                    _ = background => (),
+                    _ = stop => (),
+                }
+                shared_for_shutdown.gate.close().await;
+            };
+            futures::future::join(until_stopped, shared_for_queue.drain_queue()).await;
+            shared_for_shutdown.shut_down().await;
+            Ok(())
         });
         Ok(shared)
     }
 }
 
-pin_project! {
-    struct ChildDriver {
-        #[pin] writer: Option<JoinHandle<Result<(), std::io::Error>>>,
-        #[pin] waiter: JoinHandle<Result<ExitStatus, std::io::Error>>,
+impl SignalRunner {
+    /// Deliver a page, either right away or by way of the send queue.
+    /// `source` names the API it came in on, for metrics, and `caller`
+    /// who sent it, if known, for the audit log.
+    #[tracing::instrument(skip(self, msg, meta))]
+    pub async fn page(
+        &self,
+        source: &str,
+        caller: Option<&str>,
+        msg: String,
+        meta: &PageMeta,
+    ) -> Result<PageOutcome, SignalRunnerError> {
+        let Some(_pass) = self.gate.enter() else {
+            return Err(SignalRunnerError::ShuttingDown);
+        };
+        let started = Instant::now();
+        let entry = crate::audit::Entry::new(source, caller, &msg, meta);
+        let r = match self.policy.check(source, caller, msg, meta).await {
+            Ok((msg, None)) => self.page_unaudited(source, msg, meta).await,
+            Ok((msg, Some(ref meta))) => self.page_unaudited(source, msg, meta).await,
+            Err(e) => Err(e.into()),
+        };
+        self.audit(entry, started, meta, &r);
+        r
     }
-}
 
-impl ChildDriver {
-    fn new<M: AsRef<[u8]> + Send + 'static>(mut child: Child, msg: M) -> Self {
-        let stdin = child.stdin.take();
-        Self {
-            writer: stdin
-                .map(|mut f| tokio::task::spawn_blocking(move || f.write_all(msg.as_ref()))),
-            waiter: tokio::task::spawn_blocking(move || child.wait()),
+    /// Delivers each of the pages in turn, carrying on past those that
+    /// fail, and says how each one went.
+    pub async fn page_batch(
+        &self,
+        source: &str,
+        caller: Option<&str>,
+        pages: Vec<(String, PageMeta)>,
+    ) -> Vec<Result<(), (http::StatusCode, String)>> {
+        let mut results = Vec::with_capacity(pages.len());
+        for (msg, meta) in pages {
+            let r = self.page(source, caller, msg, &meta).await;
+            results.push(r.map(|_| ()).map_err(Into::into));
         }
+        results
+    }
+
+    async fn page_unaudited(
+        &self,
+        source: &str,
+        msg: String,
+        meta: &PageMeta,
+    ) -> Result<PageOutcome, SignalRunnerError> {
+        let received = SystemTime::now();
+        if self.heartbeat.is_heartbeat(&meta.labels) {
+            if !meta.resolved {
+                self.heartbeat.beat();
+            }
+            crate::metrics::PAGES
+                .with_label_values(&[source, Delivery::Heartbeat.label()])
+                .inc();
+            return Ok(PageOutcome {
+                id: None,
+                received,
+                delivery: Delivery::Heartbeat,
+            });
+        }
+        if let Some(action) = self.maintenance.check(source, &msg, meta) {
+            log::info!("Holding back page in a maintenance window ({action:?})");
+            crate::metrics::PAGES
+                .with_label_values(&[source, Delivery::Maintenance.label()])
+                .inc();
+            return Ok(PageOutcome {
+                id: None,
+                received,
+                delivery: Delivery::Maintenance,
+            });
+        }
+        let seen = match self.dedup {
+            None => 1,
+            Some(ref dedup) => match dedup.check(meta) {
+                Some(seen) => seen,
+                None => {
+                    crate::metrics::PAGES
+                        .with_label_values(&[source, Delivery::Suppressed.label()])
+                        .inc();
+                    return Ok(PageOutcome {
+                        id: None,
+                        received,
+                        delivery: Delivery::Suppressed,
+                    });
+                }
+            },
+        };
+        let msg = if seen > 1 {
+            format!("{msg}\n(seen {seen}x)")
+        } else {
+            msg
+        };
+        if self.digest.hold(&msg, meta) {
+            crate::metrics::PAGES
+                .with_label_values(&[source, Delivery::Digest.label()])
+                .inc();
+            return Ok(PageOutcome {
+                id: None,
+                received,
+                delivery: Delivery::Digest,
+            });
+        }
+        self.deliver(source, msg, meta, received).await
+    }
+
+    /// Sends a page held back by a maintenance window after all, as
+    /// `caller` asked.
+    pub async fn replay(
+        &self,
+        caller: &str,
+        page: &crate::maintenance::SuppressedPage,
+    ) -> Result<PageOutcome, SignalRunnerError> {
+        let Some(_pass) = self.gate.enter() else {
+            return Err(SignalRunnerError::ShuttingDown);
+        };
+        let started = Instant::now();
+        let meta = page.meta();
+        let entry = crate::audit::Entry::new("replay", Some(caller), &page.message, &meta);
+        let r = self
+            .deliver("replay", page.message.clone(), &meta, SystemTime::now())
+            .await;
+        self.audit(entry, started, &meta, &r);
+        r
+    }
+
+    fn audit(
+        &self,
+        mut entry: crate::audit::Entry,
+        started: Instant,
+        meta: &PageMeta,
+        r: &Result<PageOutcome, SignalRunnerError>,
+    ) {
+        entry.route = self.targets(meta).iter().map(Target::to_string).collect();
+        entry.alert_id = r.as_ref().ok().and_then(|o| o.id);
+        self.audit
+            .record(entry.finish(started, r.as_ref().map(|o| o.delivery.label())));
+    }
+
+    #[tracing::instrument(skip_all, fields(alert_id))]
+    async fn deliver(
+        &self,
+        source: &str,
+        msg: String,
+        meta: &PageMeta,
+        received: SystemTime,
+    ) -> Result<PageOutcome, SignalRunnerError> {
+        let msg = decoration::decorate(&self.args.severity_prefix, msg, meta);
+        let targets = self.targets(meta);
+        let action = if meta.resolved {
+            self.resolved_action(&meta.labels)
+        } else {
+            ResolvedAction::Send
+        };
+        let firing = match action {
+            ResolvedAction::Send | ResolvedAction::Suppress => None,
+            _ => self.alerts.firing(meta),
+        };
+        let id = self.alerts.create(source, &msg, meta, &targets).await;
+        tracing::Span::current().record("alert_id", id);
+        if action == ResolvedAction::Suppress {
+            self.alerts.record_status(id, AlertStatus::Suppressed, None);
+            crate::metrics::PAGES
+                .with_label_values(&[source, Delivery::Suppressed.label()])
+                .inc();
+            return Ok(PageOutcome {
+                id: Some(id),
+                received,
+                delivery: Delivery::Suppressed,
+            });
+        }
+        let resolves = firing.map(|alert| Resolves { alert, action });
+        let msg = if self.args.enable_acks {
+            format!("#{id} {msg}")
+        } else {
+            msg
+        };
+        let attachment = match meta.attachment {
+            None => None,
+            Some(Attachment::Data(ref data)) => Some(data.clone()),
+            Some(Attachment::Url(ref url)) => self.fetch_attachment(url).await,
+        };
+        let (r, delivery) = if self.args.send_queue || !self.state.is_available() {
+            (
+                self.queue
+                    .push(QueuedPage {
+                        alert_id: id,
+                        targets,
+                        message: msg,
+                        attachment,
+                        resolves,
+                        delivered: Vec::new(),
+                    })
+                    .await
+                    .map_err(SignalRunnerError::from),
+                Delivery::Queued,
+            )
+        } else {
+            let mut delivered = Vec::new();
+            match self
+                .send_alert(
+                    id,
+                    &targets,
+                    &msg,
+                    attachment.as_ref(),
+                    resolves,
+                    &mut delivered,
+                )
+                .await
+            {
+                Err(SignalRunnerError::Timeout(_)) => {
+                    log::warn!("Queueing page {id} to be sent again after a timeout");
+                    let mut targets = targets;
+                    targets.retain(|t| !delivered.contains(t));
+                    (
+                        self.queue
+                            .push(QueuedPage {
+                                alert_id: id,
+                                targets,
+                                message: msg,
+                                attachment,
+                                resolves,
+                                delivered: Vec::new(),
+                            })
+                            .await
+                            .map_err(SignalRunnerError::from),
+                        Delivery::Queued,
+                    )
+                }
+                r => {
+                    if let Err(ref e) = r {
+                        self.fall_back(id, &msg, e).await;
+                    }
+                    (r, Delivery::Sent)
+                }
+            }
+        };
+        match (&r, delivery) {
+            (Ok(()), Delivery::Queued) => (),
+            (Ok(()), _) => self.alerts.record_status(id, AlertStatus::Sent, None),
+            (Err(e), _) => self
+                .alerts
+                .record_status(id, AlertStatus::Failed, Some(e.to_string())),
+        }
+        let result = if r.is_ok() {
+            delivery.label()
+        } else {
+            "failed"
+        };
+        crate::metrics::PAGES
+            .with_label_values(&[source, result])
+            .inc();
+        r.map(|()| PageOutcome {
+            id: Some(id),
+            received,
+            delivery,
+        })
     }
-}
 
-impl Future for ChildDriver {
-    type Output = Result<Result<ExitStatus, std::io::Error>, JoinError>;
+    pub async fn heartbeat(&self) -> Result<(), SignalRunnerError> {
+        self.heartbeat.beat();
+        Ok(())
+    }
 
-    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let mut this = self.project();
-        if let Some(writer) = this.writer.as_mut().as_pin_mut() {
-            if writer.poll(cx).is_ready() {
-                this.writer.set(None);
+    /// Sends queued pages until shutting down begins, first putting
+    /// back any that were saved in the state by the last run.
+    async fn drain_queue(&self) {
+        let queue = &self.queue;
+        tokio::select! {
+            _ = self.restore_queue() => (),
+            _ = self.gate.closed() => return,
+        }
+        loop {
+            let page = tokio::select! {
+                page = queue.next_due() => page,
+                _ = self.gate.closed() => return,
+            };
+            if let Some(threshold) = self.args.send_queue_coalesce_threshold {
+                let n = queue.coalesce(threshold).await;
+                if n > 0 {
+                    log::warn!("Combined {} queued pages into one message", n + 1);
+                    continue;
+                }
             }
+            tokio::select! {
+                _ = self.state.wait_available() => (),
+                _ = self.gate.closed() => return,
+            }
+            self.send_queued(&page).await;
         }
-        this.waiter.poll(cx)
     }
-}
 
-impl SignalRunner {
-    pub async fn send<M: AsRef<[u8]> + Send + 'static>(
+    /// Tries to send a queued page, and takes it off the queue unless it
+    /// should be retried, in which case it waits behind the other pages
+    /// until it is due. Returns whether it was taken off.
+    async fn send_queued(&self, page: &queue::Queued) -> bool {
+        let queue = &self.queue;
+        let span = tracing::info_span!(
+            "send queued page",
+            alert_id = page.alert_id,
+            attempt = page.attempts + 1,
+            queued_ms = page.age().as_millis() as u64,
+        );
+        let mut delivered = page.delivered.clone();
+        match self
+            .send_alert(
+                page.alert_id,
+                &page.targets,
+                &page.message,
+                page.attachment.as_ref(),
+                page.resolves,
+                &mut delivered,
+            )
+            .instrument(span)
+            .await
+        {
+            Ok(()) => {
+                self.alerts
+                    .record_status(page.alert_id, AlertStatus::Sent, None);
+                queue.remove(page).await;
+            }
+            Err(e) if e.retry_after().is_none() => {
+                log::error!("Not retrying queued page: {e}");
+                self.fall_back_queued(page, &e, true).await;
+                self.alerts
+                    .record_status(page.alert_id, AlertStatus::Failed, Some(e.to_string()));
+                queue.remove(page).await;
+            }
+            Err(e) if page.age() >= self.args.send_queue_max_age => {
+                log::error!(
+                    "Giving up on queued page after {} attempts: {e}",
+                    page.attempts + 1
+                );
+                self.fall_back_queued(page, &e, true).await;
+                self.alerts
+                    .record_status(page.alert_id, AlertStatus::Failed, Some(e.to_string()));
+                queue.remove(page).await;
+            }
+            Err(e) => {
+                let delay = page.retry_delay().max(e.retry_after().unwrap_or_default());
+                log::warn!("Sending queued page failed, retrying in {delay:?}: {e}");
+                self.fall_back_queued(page, &e, false).await;
+                queue
+                    .retry_later(page, delay, |p| p.delivered = delivered)
+                    .await;
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Puts pages saved in the state by the last run back in the queue
+    /// once the state is loaded.
+    async fn restore_queue(&self) {
+        self.state.wait_available().await;
+        self.restore_saved(&self.state.get().await).await;
+    }
+
+    async fn restore_saved(&self, guard: &crate::state::StateGuard<'_>) {
+        match guard.read_app_data::<VecDeque<queue::Queued>>(QUEUE_APP_DATA_NAME) {
+            Ok(Some(saved)) if !saved.is_empty() => {
+                log::info!("Restoring {} queued pages from state", saved.len());
+                self.queue.restore(saved).await;
+            }
+            Ok(_) => (),
+            // Overwriting what could not be read loses nothing more.
+            Err(e) => log::error!("Loading queued pages from state: {e}"),
+        }
+        self.queue_restored.send_replace(true);
+    }
+
+    /// Writes the send queue into the state whenever it changes, so that
+    /// it is saved with the state and survives a crash.
+    async fn keep_queue_in_state(&self) {
+        let _ = self.queue_restored.subscribe().wait_for(|r| *r).await;
+        loop {
+            self.save_queue(&self.state.get().await);
+            self.queue.changed().await;
+        }
+    }
+
+    fn save_queue(&self, guard: &crate::state::StateGuard<'_>) {
+        if let Err(e) = guard.write_app_data(QUEUE_APP_DATA_NAME, &self.queue.snapshot()) {
+            log::error!("Saving send queue to state: {e}");
+        }
+    }
+
+    /// Once no more pages are being accepted, sends what is still queued
+    /// for up to `--shutdown-drain-timeout` and saves the rest in the
+    /// state, which the state saves one last time after this.
+    async fn shut_down(&self) {
+        let deadline = Instant::now() + self.args.shutdown_drain_timeout;
+        for page in self.queue.snapshot() {
+            if Instant::now() >= deadline || !self.state.is_available() {
+                break;
+            }
+            self.send_queued(&page).await;
+        }
+        if !self.state.is_available() {
+            if !self.queue.is_empty() {
+                log::error!(
+                    "Shutting down with {} queued pages and no state to save them in",
+                    self.queue.len()
+                );
+            }
+            return;
+        }
+        let guard = self.state.get().await;
+        if !*self.queue_restored.borrow() {
+            self.restore_saved(&guard).await;
+        }
+        self.save_queue(&guard);
+        log::info!("Saved {} queued pages in the state", self.queue.len());
+    }
+
+    /// Our own account, where messages end up in "Note to Self".
+    pub fn own_number(&self) -> Target {
+        Target::Recipient(self.args.signal_phone_number.clone())
+    }
+
+    /// Sends a page which Signal failed to deliver some other way, if
+    /// `--fallback` says how.
+    async fn fall_back(&self, id: u64, msg: &str, e: &SignalRunnerError) {
+        let labels = self.alerts.get(id).map(|a| a.labels).unwrap_or_default();
+        self.fallback.send(id, &labels, msg, &e.to_string()).await;
+    }
+
+    /// Queued pages fall back once they have failed `--fallback-after`
+    /// times, or when they are given up on before that.
+    async fn fall_back_queued(&self, page: &queue::Queued, e: &SignalRunnerError, giving_up: bool) {
+        let failures = page.attempts + 1;
+        let after = self.fallback.after();
+        if failures == after || (giving_up && failures < after) {
+            self.fall_back(page.alert_id, &page.message, e).await;
+        }
+    }
+
+    pub fn default_target(&self) -> Target {
+        Target::Group(self.args.signal_group_id.clone())
+    }
+
+    /// The targets of the first `--route` that matches the labels, or
+    /// else the default group.
+    pub fn route(&self, labels: &HashMap<String, String>) -> Vec<Target> {
+        self.args
+            .route
+            .iter()
+            .find_map(|r| r.targets_for(labels))
+            .map(<[Target]>::to_vec)
+            .unwrap_or_else(|| vec![self.default_target()])
+    }
+
+    fn resolved_action(&self, labels: &HashMap<String, String>) -> ResolvedAction {
+        self.args
+            .route
+            .iter()
+            .find(|r| r.matches(labels))
+            .and_then(route::Route::resolved_action)
+            .unwrap_or(self.args.resolved_action)
+    }
+
+    /// Where a page is routed, or its own group or target if it has one,
+    /// plus those of its own recipients that `--recipient-allow` allows.
+    fn targets(&self, meta: &PageMeta) -> Vec<Target> {
+        let mut targets = match (&meta.group, &meta.target) {
+            (Some(group), _) => vec![Target::Group(group.clone())],
+            (None, Some(target)) => {
+                let Ok(target) = target.parse();
+                vec![target]
+            }
+            (None, None) => self.route(&meta.labels),
+        };
+        targets.extend(target::allowed_recipients(
+            &self.args.recipient_allow,
+            &meta.recipients,
+        ));
+        targets
+    }
+
+    /// Every digest interval, sends the pages held back by
+    /// `action=digest` maintenance windows, in one message per set of
+    /// targets.
+    async fn send_digests(&self) {
+        loop {
+            tokio::time::sleep(self.maintenance.digest_interval()).await;
+            let pages = self.maintenance.pending_digest();
+            if pages.is_empty() {
+                continue;
+            }
+            self.state.wait_available().await;
+            let mut by_targets = HashMap::<_, Vec<_>>::new();
+            for page in &pages {
+                by_targets
+                    .entry(self.targets(&page.meta()))
+                    .or_default()
+                    .push(page);
+            }
+            for (targets, pages) in by_targets {
+                let mut msg = format!("{} pages held back by maintenance windows:\n", pages.len());
+                for page in &pages {
+                    msg += &format!(
+                        "\n{}\n{}\n",
+                        page.received.strftime("%Y-%m-%d %H:%M UTC"),
+                        page.message
+                    );
+                }
+                let mut sent = true;
+                for target in &targets {
+                    if let Err(e) = self.send(target, &msg, None).await {
+                        log::error!("Sending maintenance digest to {target}: {e}");
+                        sent = false;
+                    }
+                }
+                if sent {
+                    let ids: Vec<u64> = pages.iter().map(|p| p.id).collect();
+                    self.maintenance.record_digested(&ids);
+                }
+            }
+        }
+    }
+
+    /// Sends the alerts held back by `--digest-matching` each time the
+    /// digest is due, in one message per set of targets.
+    async fn send_alert_digests(&self) {
+        loop {
+            self.digest.wait_due().await;
+            let (pages, since) = self.digest.pending();
+            if pages.is_empty() {
+                self.digest.record_sent(&[]);
+                continue;
+            }
+            self.state.wait_available().await;
+            let mut by_targets = HashMap::<_, Vec<_>>::new();
+            for page in &pages {
+                by_targets
+                    .entry(self.targets(&page.meta()))
+                    .or_default()
+                    .push(page);
+            }
+            let mut ids = Vec::new();
+            for (targets, pages) in by_targets {
+                let msg = format!(
+                    "Digest of {} alerts since {}:\n{}",
+                    pages.len(),
+                    since.strftime("%Y-%m-%d %H:%M UTC"),
+                    crate::digest::summarise(&pages)
+                );
+                let mut sent = true;
+                for target in &targets {
+                    if let Err(e) = self.send(target, &msg, None).await {
+                        log::error!("Sending digest to {target}: {e}");
+                        sent = false;
+                    }
+                }
+                if sent {
+                    ids.extend(pages.iter().map(|p| p.id));
+                }
+            }
+            // Those which could not be sent wait for the next digest.
+            self.digest.record_sent(&ids);
+        }
+    }
+
+    /// Maintenance windows and the pages they held back.
+    pub fn maintenance(&self) -> Result<crate::maintenance::MaintenanceStatus, SignalRunnerError> {
+        Ok(self.maintenance.status())
+    }
+
+    /// Silences set through the Alertmanager API, which are kept as
+    /// maintenance windows.
+    pub fn silences(&self) -> Result<Vec<crate::http::silences::Silence>, SignalRunnerError> {
+        Ok(self
+            .maintenance
+            .windows()
+            .iter()
+            .filter_map(crate::maintenance::Window::silence)
+            .collect())
+    }
+
+    /// Returns the new silence's ID.
+    pub fn add_silence(
+        &self,
+        silence: crate::http::silences::Silence,
+    ) -> Result<u64, SignalRunnerError> {
+        let window = self.maintenance.add(silence.into());
+        log::info!(
+            "Added silence {} for {:?} by {:?}",
+            window.id.unwrap_or_default(),
+            window.matchers,
+            window.created_by
+        );
+        Ok(window.id.unwrap_or_default())
+    }
+
+    /// Returns false if there is no such silence.
+    pub fn expire_silence(&self, id: u64) -> Result<bool, SignalRunnerError> {
+        Ok(self.maintenance.remove(id))
+    }
+
+    pub fn acks_enabled(&self) -> bool {
+        self.args.enable_acks
+    }
+
+    /// Pages queued by older versions have no targets and go to the
+    /// default group. Resolved pages are sent as their action says to
+    /// the targets that the page about the alert firing went to, and
+    /// like any other page to the rest.
+    ///
+    /// Targets in `delivered` are left out, and those sent to are added
+    /// to it, so that a retry only goes to the targets that failed. A
+    /// failure to send to one target does not stop the others, unless
+    /// the account cannot send at all.
+    #[tracing::instrument(skip(self, targets, msg, attachment, resolves, delivered))]
+    async fn send_alert(
         &self,
-        msg: M,
+        id: u64,
+        targets: &[Target],
+        msg: &str,
+        attachment: Option<&AttachmentData>,
+        resolves: Option<Resolves>,
+        delivered: &mut Vec<Target>,
     ) -> Result<(), SignalRunnerError> {
-        match self.0.0.get().await.path() {
-            None => Err(SignalRunnerError::NoStateAvailable),
-            Some(path) => {
-                let status = ChildDriver::new(
-                    Command::new(&self.1.signal_bin)
-                        .arg("--config")
-                        .arg(path)
-                        .arg("--username")
-                        .arg(&self.1.signal_phone_number)
-                        .arg("send")
-                        .arg("--group")
-                        .arg(&self.1.signal_group_id)
-                        .arg("--message-from-stdin")
-                        .stdin(Stdio::piped())
-                        .spawn()?,
-                    msg,
-                )
-                .await??;
-                if status.success() {
-                    Ok(())
-                } else {
-                    Err(SignalRunnerError::SignalFailed(status.code()))
+        let default = [self.default_target()];
+        let targets = if targets.is_empty() {
+            &default
+        } else {
+            targets
+        };
+        let firing = resolves.and_then(|r| Some((r.action, self.alerts.get(r.alert)?)));
+        let mut failed: Option<SignalRunnerError> = None;
+        for target in targets {
+            if delivered.contains(target) {
+                continue;
+            }
+            let earlier = firing.as_ref().and_then(|(action, alert)| {
+                let (_, timestamp) = alert.sent_to.iter().find(|(t, _)| t == target)?;
+                Some((*action, *timestamp, alert.message.as_str()))
+            });
+            let r = async {
+                let reference = match earlier {
+                    Some((ResolvedAction::Delete, timestamp, _)) => {
+                        return self.delete(target, timestamp).await;
+                    }
+                    Some((ResolvedAction::Reply, timestamp, text)) => {
+                        Reference::Reply { timestamp, text }
+                    }
+                    Some((ResolvedAction::Edit, timestamp, _)) => Reference::Edit(timestamp),
+                    _ => Reference::None,
+                };
+                if let Some(timestamp) = self
+                    .send_referring(target, msg, attachment, reference)
+                    .await?
+                {
+                    self.alerts.record_sent(id, target, timestamp);
+                }
+                Ok(())
+            }
+            .await;
+            match r {
+                Ok(()) => delivered.push(target.clone()),
+                Err(e) => {
+                    log::warn!("Sending page {id} to {target} failed: {e}");
+                    let account_level = e.is_account_level();
+                    // An error worth retrying wins, so that the targets
+                    // which may yet work are tried again.
+                    if failed
+                        .as_ref()
+                        .is_none_or(|f| f.retry_after().is_none() && e.retry_after().is_some())
+                    {
+                        failed = Some(e);
+                    }
+                    if account_level {
+                        break;
+                    }
+                }
+            }
+        }
+        failed.map_or(Ok(()), Err)
+    }
+
+    /// Fetches an attachment to go with a page. If that fails then the
+    /// page is sent without it.
+    async fn fetch_attachment(&self, url: &str) -> Option<AttachmentData> {
+        match self.attachments.fetch(url).await {
+            Ok(data) => Some(data),
+            Err(e) => {
+                log::warn!("Leaving out attachment {url}: {e}");
+                None
+            }
+        }
+    }
+
+    fn handle_incoming(&self, messages: Vec<IncomingMessage>) {
+        {
+            let mut received = self.received.lock().unwrap();
+            received.extend(messages.iter().cloned());
+            let excess = received
+                .len()
+                .saturating_sub(self.args.received_messages_kept);
+            received.drain(..excess);
+        }
+        if !self.args.enable_acks {
+            return;
+        }
+        let policy = self.ack_policy();
+        for m in messages {
+            let id = match m.reaction {
+                Some(ref r) if r.emoji == ACK_EMOJI && !r.is_remove => {
+                    self.alerts.id_for_timestamp(r.target_sent_timestamp)
+                }
+                Some(_) => None,
+                None => m.ack_id(),
+            };
+            let Some(id) = id else {
+                continue;
+            };
+            if !policy.accepts(&m) {
+                log::warn!("Ignoring acknowledgement of {id} from {}", m.sender);
+            } else if !self.alerts.ack(id, &m.sender) {
+                log::warn!("{} acknowledged unknown alert {id}", m.sender);
+            }
+        }
+    }
+
+    fn ack_policy(&self) -> incoming::AckPolicy {
+        let groups = std::iter::once(self.default_target())
+            .chain(self.args.route.iter().flat_map(|r| r.targets()).cloned())
+            .filter_map(|t| match t {
+                Target::Group(id) => Some(id),
+                _ => None,
+            })
+            .collect();
+        incoming::AckPolicy {
+            groups,
+            senders: self.args.ack_sender.iter().cloned().collect(),
+        }
+    }
+
+    /// Records the outcome of a signal-cli command in metrics, and
+    /// remembers it if it timed out.
+    fn observe<T>(&self, command: &'static str, start: Instant, r: &Result<T, SignalRunnerError>) {
+        if matches!(r, Err(SignalRunnerError::NoStateAvailable)) {
+            return;
+        }
+        crate::metrics::SIGNAL_CLI_SECONDS
+            .with_label_values(&[command, crate::metrics::result_label(r)])
+            .observe(start.elapsed().as_secs_f64());
+        self.outcomes
+            .record(command, r.as_ref().err().map(ToString::to_string));
+        if let Err(e) = r {
+            crate::metrics::SIGNAL_CLI_FAILURES
+                .with_label_values(&[command, e.kind()])
+                .inc();
+            if let Some(action) = e.operator_action() {
+                log::error!("signal-cli {command} needs attention: {action}");
+            }
+        }
+        if let Err(SignalRunnerError::Timeout(timeout)) = *r {
+            log::error!("signal-cli {command} killed after {timeout:?}");
+            self.failures.record(
+                command,
+                None,
+                "timeout",
+                format!("killed after {timeout:?}"),
+            );
+        }
+    }
+
+    /// Whether the state is loaded and being persisted, and whether the
+    /// latest send and receive worked.
+    pub async fn health(&self) -> crate::health::Report {
+        use crate::health::Check;
+        let state = self.state.status().await;
+        let mut checks = vec![
+            Check::new(
+                "state_loaded",
+                state.version.is_some(),
+                state.version.map(|v| format!("version {v}")),
+            ),
+            Check::new(
+                "state_fresh",
+                !state.stale(),
+                state.last_persisted.map(|t| {
+                    format!(
+                        "last loaded or saved at {}",
+                        humantime::format_rfc3339_seconds(t)
+                    )
+                }),
+            ),
+            Check::new(
+                "state_storage",
+                state.storage_error.is_none(),
+                state.storage_error,
+            ),
+        ];
+        for (name, command) in [("signal_send", "send"), ("signal_receive", "receive")] {
+            checks.push(match self.outcomes.get(command) {
+                None => Check::new(name, true, Some(String::from("not run yet"))),
+                Some(o) => Check::new(
+                    name,
+                    o.error.is_none(),
+                    Some(match o.error {
+                        None => format!("ok at {}", humantime::format_rfc3339_seconds(o.time)),
+                        Some(e) => format!(
+                            "failed at {}: {e}",
+                            humantime::format_rfc3339_seconds(o.time)
+                        ),
+                    }),
+                ),
+            });
+        }
+        crate::health::Report::new(checks)
+    }
+
+    /// The state, the latest send and receive, and how many pages are
+    /// waiting to be sent, for the `GetStatus` RPC.
+    pub async fn status(&self) -> RunnerStatus {
+        RunnerStatus {
+            state: self.state.status().await,
+            last_send: self.outcomes.get("send"),
+            last_receive: self.outcomes.get("receive"),
+            queue_depth: self.queue.len(),
+        }
+    }
+
+    /// The most recent signal-cli failures, oldest first.
+    pub fn recent_failures(&self) -> Result<Vec<SignalFailure>, SignalRunnerError> {
+        Ok(self.failures.list())
+    }
+
+    /// Receives messages now instead of at the next interval.
+    pub fn receive_soon(&self) -> Result<(), SignalRunnerError> {
+        self.receive_now.notify_one();
+        Ok(())
+    }
+
+    /// Recently received messages sent after `since_ms`, oldest first.
+    pub fn received_messages(&self, since_ms: u64) -> Vec<IncomingMessage> {
+        self.received
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|m| m.timestamp > since_ms)
+            .cloned()
+            .collect()
+    }
+
+    /// Most recent first, and only those created within `since` if it
+    /// is given.
+    pub fn list_alerts(
+        &self,
+        since: Option<Duration>,
+    ) -> Result<Vec<crate::alerts::AlertRecord>, SignalRunnerError> {
+        let mut alerts = self.alerts.list();
+        if let Some(cutoff) = since.and_then(|since| SystemTime::now().checked_sub(since)) {
+            alerts.retain(|a| a.created >= cutoff);
+        }
+        Ok(alerts)
+    }
+
+    pub fn alerts_html(&self, since: Option<Duration>) -> Result<String, SignalRunnerError> {
+        Ok(crate::alerts::html(&self.list_alerts(since)?))
+    }
+
+    /// On-call targets are whoever is on call at the time.
+    fn resolve_target<'a>(&self, target: &'a Target) -> Result<Cow<'a, Target>, SignalRunnerError> {
+        match target {
+            Target::OnCall(rotation) => Ok(Cow::Owned(Target::Recipient(
+                self.oncall
+                    .recipient(rotation)
+                    .ok_or_else(|| SignalRunnerError::NobodyOnCall(rotation.clone()))?,
+            ))),
+            _ => Ok(Cow::Borrowed(target)),
+        }
+    }
+
+    /// Returns the Signal timestamp of the sent message, if known.
+    pub async fn send(
+        &self,
+        target: &Target,
+        msg: &str,
+        attachment: Option<&AttachmentData>,
+    ) -> Result<Option<u64>, SignalRunnerError> {
+        self.send_referring(target, msg, attachment, Reference::None)
+            .await
+    }
+
+    /// Messages longer than `--max-message-length` are sent in numbered
+    /// parts. Only the first part has the attachment and the reference,
+    /// and its timestamp is returned.
+    async fn send_referring(
+        &self,
+        target: &Target,
+        msg: &str,
+        attachment: Option<&AttachmentData>,
+        reference: Reference<'_>,
+    ) -> Result<Option<u64>, SignalRunnerError> {
+        let target = self.resolve_target(target)?;
+        let parts = split::split(msg, self.args.max_message_length);
+        if parts.len() > 1 {
+            log::info!(
+                "Sending a long message to {target} in {} parts",
+                parts.len()
+            );
+        }
+        let mut timestamp = None;
+        for (i, part) in parts.iter().enumerate() {
+            if i == 0 {
+                timestamp = self.send_part(&target, part, attachment, reference).await?;
+            } else {
+                self.send_part(&target, part, None, Reference::None).await?;
+            }
+        }
+        Ok(timestamp)
+    }
+
+    async fn send_part(
+        &self,
+        target: &Target,
+        msg: &str,
+        attachment: Option<&AttachmentData>,
+        reference: Reference<'_>,
+    ) -> Result<Option<u64>, SignalRunnerError> {
+        let (text, mentions) = mention::extract(msg, matches!(*target, Target::Group(_)));
+        let msg = Outgoing {
+            text: &text,
+            attachment,
+            mentions: &mentions,
+            reference,
+        };
+        if let Some(ref throttle) = self.throttle {
+            throttle.wait().await;
+        }
+        let start = Instant::now();
+        let r = self.run_send(target, &msg).await;
+        self.observe("send", start, &r);
+        let Some(ref secondary) = self.secondary else {
+            return r;
+        };
+        match r {
+            Err(e) if e.is_account_level() => {
+                if secondary.fail_over() {
+                    self.alert_failover(secondary, &e).await;
                 }
+                let start = Instant::now();
+                let r = secondary.send(target, &msg).await;
+                self.observe("secondary_send", start, &r);
+                crate::metrics::SIGNAL_FAILOVERS
+                    .with_label_values(&[crate::metrics::result_label(&r)])
+                    .inc();
+                // The primary's error says what the operator has to fix.
+                r.map_err(|_| e)
             }
+            r => {
+                if r.is_ok() && secondary.recover() {
+                    log::warn!(
+                        "Signal account {} can send again; no longer failing over",
+                        self.args.signal_phone_number
+                    );
+                }
+                r
+            }
+        }
+    }
+
+    /// Tells the operator, through the secondary account, that the
+    /// primary account has stopped working.
+    async fn alert_failover(&self, secondary: &failover::Secondary, e: &SignalRunnerError) {
+        log::error!(
+            "Signal account {} cannot send, failing over to {}: {e}",
+            self.args.signal_phone_number,
+            secondary.phone_number
+        );
+        let mut text = format!(
+            "signal-pager is sending pages from {} because {} cannot send: {e}",
+            secondary.phone_number, self.args.signal_phone_number
+        );
+        if let Some(action) = e.operator_action() {
+            text.push_str(&format!("\n\nTo fix it: {action}"));
+        }
+        let msg = Outgoing {
+            text: &text,
+            attachment: None,
+            mentions: &[],
+            reference: Reference::None,
+        };
+        if let Err(e) = secondary.send(&self.default_target(), &msg).await {
+            log::error!("Sending failover alert: {e}");
         }
     }
 
-    pub async fn receive(&self) -> Result<(), SignalRunnerError> {
-        match self.0.0.get().await.path() {
+    /// Deletes a message sent earlier, for everyone.
+    #[tracing::instrument(name = "signal-cli remoteDelete", skip(self))]
+    async fn delete(&self, target: &Target, timestamp: u64) -> Result<(), SignalRunnerError> {
+        let target = self.resolve_target(target)?;
+        if let Some(ref throttle) = self.throttle {
+            throttle.wait().await;
+        }
+        let start = Instant::now();
+        let r = match self.state.invocation().await {
             None => Err(SignalRunnerError::NoStateAvailable),
-            Some(path) => {
-                let mut child = Command::new(&self.1.signal_bin)
-                    .arg("--config")
-                    .arg(path)
-                    .arg("--username")
-                    .arg(&self.1.signal_phone_number)
-                    .arg("receive")
-                    .spawn()?;
-                let status = tokio::task::spawn_blocking(move || child.wait()).await??;
-                if status.success() {
-                    Ok(())
-                } else {
-                    Err(SignalRunnerError::SignalFailed(status.code()))
+            Some(invocation) => {
+                self.transport
+                    .delete(invocation.path(), &target, timestamp)
+                    .await
+            }
+        };
+        self.observe("remoteDelete", start, &r);
+        r
+    }
+
+    pub async fn receive(&self) -> Result<Vec<IncomingMessage>, SignalRunnerError> {
+        let start = Instant::now();
+        let r = self.run_receive().await;
+        self.observe("receive", start, &r);
+        r
+    }
+
+    #[tracing::instrument(name = "signal-cli send", skip(self, msg))]
+    async fn run_send(
+        &self,
+        target: &Target,
+        msg: &Outgoing<'_>,
+    ) -> Result<Option<u64>, SignalRunnerError> {
+        match self.state.invocation().await {
+            None => Err(SignalRunnerError::NoStateAvailable),
+            Some(invocation) => self.transport.send(invocation.path(), target, msg).await,
+        }
+    }
+
+    /// Reports healthy once signal-cli is able to use the account in the
+    /// state, so that no traffic is sent our way until then.
+    async fn validate_account(&self, health: HealthSignaller) {
+        loop {
+            match self.verify_account().await {
+                Ok(()) => {
+                    log::info!("Signal account {} is usable", self.args.signal_phone_number);
+                    health.set_healthy(true);
+                    return;
                 }
+                Err(e) => log::error!("Validating Signal account: {e}"),
             }
+            tokio::time::sleep(VALIDATE_RETRY_INTERVAL).await;
+        }
+    }
+
+    /// Checks that signal-cli is able to use the account in the state,
+    /// once it has been loaded.
+    pub async fn verify_account(&self) -> Result<(), SignalRunnerError> {
+        self.state.wait_available().await;
+        let start = Instant::now();
+        let r = self.run_validate().await;
+        self.observe("listContacts", start, &r);
+        r
+    }
+
+    #[tracing::instrument(name = "signal-cli validate", skip_all)]
+    async fn run_validate(&self) -> Result<(), SignalRunnerError> {
+        match self.state.invocation().await {
+            None => Err(SignalRunnerError::NoStateAvailable),
+            Some(invocation) => self.transport.validate(invocation.path()).await,
         }
     }
+
+    #[tracing::instrument(name = "signal-cli receive", skip_all)]
+    async fn run_receive(&self) -> Result<Vec<IncomingMessage>, SignalRunnerError> {
+        match self.state.invocation().await {
+            None => Err(SignalRunnerError::NoStateAvailable),
+            Some(invocation) => self.transport.receive(invocation.path()).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nobody_on_call() {
+        let e = || SignalRunnerError::NobodyOnCall(String::from("primary"));
+        let (code, _) = e().into();
+        assert_eq!(code, http::StatusCode::CONFLICT);
+        assert_eq!(
+            tonic::Status::from(e()).code(),
+            tonic::Code::FailedPrecondition
+        );
+    }
+
+    #[test]
+    fn untrusted_identity() {
+        // Not PERMISSION_DENIED, which says that the caller is not allowed.
+        let e = SignalRunnerError::UntrustedIdentity(String::from("+15550001"));
+        assert_eq!(
+            tonic::Status::from(e).code(),
+            tonic::Code::FailedPrecondition
+        );
+    }
 }
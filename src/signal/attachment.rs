@@ -0,0 +1,252 @@
+//! Fetching the attachments that pages give by URL. Whoever sends a page
+//! chooses both the URL and, with `recipients`, who gets what is fetched,
+//! so only URLs allowed by `--attachment-url-allow` are fetched, never
+//! from loopback or link-local addresses such as cloud metadata servers,
+//! and no more than `MAX_SIZE` of them is read.
+
+use reqwest::Url;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::page::AttachmentData;
+
+/// Attachments bigger than this are left off the page.
+const MAX_SIZE: usize = 20 << 20;
+const TIMEOUT: Duration = Duration::new(30, 0);
+const MAX_REDIRECTS: usize = 5;
+/// The AWS instance metadata service's IPv6 address, which is neither
+/// loopback nor link-local.
+const AWS_METADATA_V6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0xec2, 0, 0, 0, 0, 0, 0x254);
+
+#[derive(Debug, thiserror::Error)]
+pub enum FetchError {
+    #[error("not allowed by --attachment-url-allow")]
+    NotAllowed,
+    #[error("invalid URL: {0}")]
+    InvalidUrl(String),
+    #[error("{0} is a loopback or link-local address")]
+    ForbiddenAddress(IpAddr),
+    #[error("bigger than {MAX_SIZE} bytes")]
+    TooBig,
+    #[error("{0}")]
+    HttpError(#[from] reqwest::Error),
+}
+
+/// Where attachments may be fetched from: `https:` for any host over
+/// HTTPS, or `https://grafana.example.com` (optionally with a port) for
+/// one host.
+#[derive(Clone, Debug)]
+pub struct UrlAllow {
+    scheme: String,
+    /// With the port, if one is given.
+    host: Option<(String, Option<u16>)>,
+}
+
+impl std::str::FromStr for UrlAllow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(scheme) = s.strip_suffix(':')
+            && !scheme.contains('/')
+        {
+            return Ok(Self {
+                scheme: scheme.to_ascii_lowercase(),
+                host: None,
+            });
+        }
+        let url = Url::parse(s).map_err(|e| format!("{s}: {e}"))?;
+        let Some(host) = url.host_str() else {
+            return Err(format!("{s}: expected SCHEME: or SCHEME://HOST"));
+        };
+        if url.path() != "/" || url.query().is_some() {
+            return Err(format!("{s}: expected SCHEME: or SCHEME://HOST"));
+        }
+        Ok(Self {
+            scheme: String::from(url.scheme()),
+            host: Some((String::from(host), url.port())),
+        })
+    }
+}
+
+impl UrlAllow {
+    fn allows(&self, url: &Url) -> bool {
+        url.scheme() == self.scheme
+            && self
+                .host
+                .as_ref()
+                .is_none_or(|(host, port)| url.host_str() == Some(host) && url.port() == *port)
+    }
+}
+
+fn forbidden(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_link_local() || ip.is_unspecified(),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => forbidden(IpAddr::V4(ip)),
+            None => {
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    || (ip.segments()[0] & 0xffc0) == 0xfe80
+                    || ip == AWS_METADATA_V6
+            }
+        },
+    }
+}
+
+/// Whether `url` may be fetched, as far as can be told without resolving
+/// its host.
+fn check(allow: &[UrlAllow], url: &Url) -> Result<(), FetchError> {
+    if !allow.iter().any(|a| a.allows(url)) {
+        return Err(FetchError::NotAllowed);
+    }
+    let ip = url
+        .host_str()
+        .map(|h| h.trim_start_matches('[').trim_end_matches(']'))
+        .and_then(|h| h.parse().ok());
+    match ip {
+        Some(ip) if forbidden(ip) => Err(FetchError::ForbiddenAddress(ip)),
+        _ => Ok(()),
+    }
+}
+
+/// Resolves names only to addresses which are not forbidden, so that a
+/// name cannot lead to a metadata server either.
+struct Resolver;
+
+impl Resolve for Resolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = String::from(name.as_str());
+        Box::pin(async move {
+            let addrs = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|a| !forbidden(a.ip()))
+                .collect::<Vec<_>>();
+            if addrs.is_empty() {
+                return Err(format!("{host} has only loopback or link-local addresses").into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+pub struct Fetcher {
+    client: reqwest::Client,
+    allow: Arc<[UrlAllow]>,
+}
+
+impl Fetcher {
+    pub fn new(allow: &[UrlAllow]) -> Self {
+        let allow: Arc<[UrlAllow]> = allow.into();
+        let redirect_allow = Arc::clone(&allow);
+        let client = reqwest::Client::builder()
+            .dns_resolver(Arc::new(Resolver))
+            .redirect(reqwest::redirect::Policy::custom(move |attempt| {
+                if attempt.previous().len() >= MAX_REDIRECTS {
+                    attempt.error("too many redirects")
+                } else if let Err(e) = check(&redirect_allow, attempt.url()) {
+                    attempt.error(e)
+                } else {
+                    attempt.follow()
+                }
+            }))
+            .timeout(TIMEOUT)
+            .build()
+            .expect("TLS backend initializes");
+        Self { client, allow }
+    }
+
+    pub async fn fetch(&self, url: &str) -> Result<AttachmentData, FetchError> {
+        let parsed = Url::parse(url).map_err(|e| FetchError::InvalidUrl(e.to_string()))?;
+        check(&self.allow, &parsed)?;
+        let mut response = self.client.get(parsed).send().await?.error_for_status()?;
+        if response
+            .content_length()
+            .is_some_and(|n| n > MAX_SIZE as u64)
+        {
+            return Err(FetchError::TooBig);
+        }
+        let content_type = response
+            .headers()
+            .get(http::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("application/octet-stream")
+            .to_owned();
+        let mut data = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            if data.len() + chunk.len() > MAX_SIZE {
+                return Err(FetchError::TooBig);
+            }
+            data.extend_from_slice(&chunk);
+        }
+        Ok(AttachmentData { content_type, data })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allow(flags: &[&str]) -> Vec<UrlAllow> {
+        flags.iter().map(|f| f.parse().unwrap()).collect()
+    }
+
+    fn checked(allow: &[UrlAllow], url: &str) -> Result<(), FetchError> {
+        check(allow, &Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn allowed_urls() {
+        let hosts = allow(&["https://grafana.example.com", "http://render:8081"]);
+        assert!(checked(&hosts, "https://grafana.example.com/render/x.png").is_ok());
+        assert!(checked(&hosts, "http://render:8081/x").is_ok());
+        assert!(checked(&hosts, "http://grafana.example.com/x").is_err());
+        assert!(checked(&hosts, "http://render/x").is_err());
+        assert!(checked(&hosts, "https://example.com/x").is_err());
+        let https = allow(&["https:"]);
+        assert!(checked(&https, "https://anywhere.example.com/x").is_ok());
+        assert!(checked(&https, "http://anywhere.example.com/x").is_err());
+        // Nothing is fetched unless allowed.
+        assert!(matches!(
+            checked(&[], "https://grafana.example.com/x"),
+            Err(FetchError::NotAllowed)
+        ));
+        assert!("https://example.com/path".parse::<UrlAllow>().is_err());
+    }
+
+    #[test]
+    fn metadata_servers() {
+        let any = allow(&["http:"]);
+        for url in [
+            "http://169.254.169.254/latest/meta-data/",
+            "http://127.0.0.1:9090/",
+            "http://[::1]/",
+            "http://[fe80::1]/",
+            "http://[::ffff:169.254.169.254]/",
+            "http://[fd00:ec2::254]/",
+        ] {
+            assert!(
+                matches!(checked(&any, url), Err(FetchError::ForbiddenAddress(_))),
+                "{url}"
+            );
+        }
+        assert!(checked(&any, "http://10.0.0.1/").is_ok());
+    }
+
+    #[tokio::test]
+    async fn names_of_loopback_addresses() {
+        let fetcher = Fetcher::new(&allow(&["http:"]));
+        let e = fetcher.fetch("http://localhost:1/").await.unwrap_err();
+        let mut source: Option<&dyn std::error::Error> = Some(&e);
+        let mut messages = Vec::new();
+        while let Some(e) = source {
+            messages.push(e.to_string());
+            source = e.source();
+        }
+        assert!(
+            messages.iter().any(|m| m.contains("loopback")),
+            "{messages:?}"
+        );
+    }
+}
@@ -0,0 +1,216 @@
+use futures::future::BoxFuture;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+
+use super::failure::{self, RecentFailures};
+use super::transport::{Outgoing, Reference, SignalTransport};
+use super::{IncomingMessage, SignalRunnerError, Target};
+
+/// How much of signal-cli's output to keep when it fails.
+const MAX_FAILURE_OUTPUT: usize = 2000;
+
+/// Starts a new signal-cli process for every command.
+pub struct Cli {
+    signal_bin: PathBuf,
+    phone_number: String,
+    timeout: Duration,
+    failures: Arc<RecentFailures>,
+}
+
+/// Returns stderr, or stdout if there is nothing on stderr, truncated.
+fn failure_output(output: &Output) -> String {
+    let s = if output.stderr.iter().all(u8::is_ascii_whitespace) {
+        String::from_utf8_lossy(&output.stdout)
+    } else {
+        String::from_utf8_lossy(&output.stderr)
+    };
+    let s = s.trim();
+    match s.char_indices().nth(MAX_FAILURE_OUTPUT) {
+        Some((i, _)) => format!("{}...", &s[..i]),
+        None => String::from(s),
+    }
+}
+
+/// Runs signal-cli to completion, feeding it `input` on stdin, and kills
+/// it if it does not finish within `timeout`.
+async fn run_child(
+    cmd: Command,
+    input: Option<&[u8]>,
+    timeout: Duration,
+) -> Result<Output, SignalRunnerError> {
+    let mut child = tokio::process::Command::from(cmd)
+        .kill_on_drop(true)
+        .spawn()?;
+    let stdin = child.stdin.take();
+    let write = async move {
+        if let (Some(mut stdin), Some(input)) = (stdin, input) {
+            // If signal-cli exits without reading it all, its exit status
+            // says why.
+            let _ = stdin.write_all(input).await;
+        }
+    };
+    match tokio::time::timeout(
+        timeout,
+        futures::future::join(write, child.wait_with_output()),
+    )
+    .await
+    {
+        Ok(((), output)) => Ok(output?),
+        // Dropping the child kills it.
+        Err(_) => Err(SignalRunnerError::Timeout(timeout)),
+    }
+}
+
+impl Cli {
+    pub fn new(
+        signal_bin: PathBuf,
+        phone_number: String,
+        timeout: Duration,
+        failures: Arc<RecentFailures>,
+    ) -> Self {
+        Self {
+            signal_bin,
+            phone_number,
+            timeout,
+            failures,
+        }
+    }
+
+    fn command(&self, config: &Path) -> Command {
+        let mut cmd = Command::new(&self.signal_bin);
+        cmd.arg("--config")
+            .arg(config)
+            .arg("--username")
+            .arg(&self.phone_number)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        cmd
+    }
+
+    /// Logs and remembers a signal-cli failure.
+    fn failed(&self, command: &'static str, output: &Output) -> SignalRunnerError {
+        let code = output.status.code();
+        let output = failure_output(output);
+        log::error!("signal-cli {command} exited with code {code:?}: {output}");
+        let e = failure::classify(code, output.clone(), |output| {
+            SignalRunnerError::SignalFailed(code, output)
+        });
+        self.failures.record(command, code, e.kind(), output);
+        e
+    }
+}
+
+impl SignalTransport for Cli {
+    fn send<'a>(
+        &'a self,
+        config: &'a Path,
+        target: &'a Target,
+        msg: &'a Outgoing<'a>,
+    ) -> BoxFuture<'a, Result<Option<u64>, SignalRunnerError>> {
+        Box::pin(async move {
+            let mut cmd = self.command(config);
+            cmd.arg("send");
+            // Passed as a file since it may be too big for an argument.
+            // It is removed when this returns.
+            let file = match msg.attachment {
+                Some(a) => {
+                    let mut f = tempfile::NamedTempFile::new()?;
+                    f.write_all(&a.data)?;
+                    Some(f)
+                }
+                None => None,
+            };
+            if let Some(ref f) = file {
+                cmd.arg("--attachment").arg(f.path());
+            }
+            for m in msg.mentions {
+                cmd.arg("--mention").arg(m);
+            }
+            match msg.reference {
+                Reference::None => (),
+                Reference::Reply { timestamp, text } => {
+                    cmd.arg("--quote-timestamp")
+                        .arg(timestamp.to_string())
+                        .arg("--quote-author")
+                        .arg(&self.phone_number)
+                        .arg("--quote-message")
+                        .arg(text);
+                }
+                Reference::Edit(timestamp) => {
+                    cmd.arg("--edit-timestamp").arg(timestamp.to_string());
+                }
+            }
+            target
+                .add_args(&mut cmd)
+                .arg("--message-from-stdin")
+                .stdin(Stdio::piped());
+            let output = run_child(cmd, Some(msg.text.as_bytes()), self.timeout).await?;
+            if output.status.success() {
+                Ok(String::from_utf8_lossy(&output.stdout).trim().parse().ok())
+            } else {
+                Err(self.failed("send", &output))
+            }
+        })
+    }
+
+    fn delete<'a>(
+        &'a self,
+        config: &'a Path,
+        target: &'a Target,
+        timestamp: u64,
+    ) -> BoxFuture<'a, Result<(), SignalRunnerError>> {
+        Box::pin(async move {
+            let mut cmd = self.command(config);
+            cmd.arg("remoteDelete")
+                .arg("--target-timestamp")
+                .arg(timestamp.to_string());
+            target.add_args(&mut cmd);
+            let output = run_child(cmd, None, self.timeout).await?;
+            if output.status.success() {
+                Ok(())
+            } else {
+                Err(self.failed("remoteDelete", &output))
+            }
+        })
+    }
+
+    fn receive<'a>(
+        &'a self,
+        config: &'a Path,
+    ) -> BoxFuture<'a, Result<Vec<IncomingMessage>, SignalRunnerError>> {
+        Box::pin(async move {
+            let mut cmd = self.command(config);
+            cmd.arg("--output=json").arg("receive");
+            let output = run_child(cmd, None, self.timeout).await?;
+            if output.status.success() {
+                Ok(output
+                    .stdout
+                    .split(|b| *b == b'\n')
+                    .filter_map(|line| serde_json::from_slice(line).ok())
+                    .filter_map(IncomingMessage::parse)
+                    .collect())
+            } else {
+                Err(self.failed("receive", &output))
+            }
+        })
+    }
+
+    /// Lists contacts, which only needs the local state but fails if the
+    /// account is not registered in it.
+    fn validate<'a>(&'a self, config: &'a Path) -> BoxFuture<'a, Result<(), SignalRunnerError>> {
+        Box::pin(async move {
+            let mut cmd = self.command(config);
+            cmd.arg("listContacts");
+            let output = run_child(cmd, None, self.timeout).await?;
+            if output.status.success() {
+                Ok(())
+            } else {
+                Err(self.failed("listContacts", &output))
+            }
+        })
+    }
+}
@@ -0,0 +1,63 @@
+use crate::page::PageMeta;
+
+/// Goes before the text of pages with a particular `severity` label, or
+/// before resolved pages for `resolved`. Parsed from `critical=🔥`.
+#[derive(Clone, Debug)]
+pub struct SeverityPrefix {
+    severity: String,
+    prefix: String,
+}
+
+impl std::str::FromStr for SeverityPrefix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((severity, prefix)) => Ok(Self {
+                severity: String::from(severity),
+                prefix: String::from(prefix),
+            }),
+            None => Err(format!("Expected severity=prefix, got {s:?}")),
+        }
+    }
+}
+
+/// Resolved pages get the `resolved` prefix whatever their severity.
+pub fn decorate(prefixes: &[SeverityPrefix], msg: String, meta: &PageMeta) -> String {
+    let severity = if meta.resolved {
+        Some("resolved")
+    } else {
+        meta.labels.get("severity").map(String::as_str)
+    };
+    match severity.and_then(|s| prefixes.iter().find(|p| p.severity == s)) {
+        Some(p) => format!("{} {msg}", p.prefix),
+        None => msg,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decorated(severity: Option<&str>, resolved: bool) -> String {
+        let prefixes = ["critical=🔥", "resolved=✅"].map(|p| p.parse().unwrap());
+        let mut meta = PageMeta {
+            resolved,
+            ..Default::default()
+        };
+        if let Some(s) = severity {
+            meta.labels
+                .insert(String::from("severity"), String::from(s));
+        }
+        decorate(&prefixes, String::from("disk full"), &meta)
+    }
+
+    #[test]
+    fn prefixes() {
+        assert_eq!(decorated(Some("critical"), false), "🔥 disk full");
+        assert_eq!(decorated(Some("critical"), true), "✅ disk full");
+        assert_eq!(decorated(Some("info"), false), "disk full");
+        assert_eq!(decorated(None, false), "disk full");
+        assert!("critical".parse::<SeverityPrefix>().is_err());
+    }
+}
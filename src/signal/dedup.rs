@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::page::PageMeta;
+
+/// How long to remember how many times an alert was suppressed, in case
+/// it fires again after the window.
+const FORGET_SUPPRESSED: Duration = Duration::new(86400, 0);
+
+struct Entry {
+    sent: Instant,
+    suppressed: u32,
+}
+
+/// Suppresses repeats of the same alert within a window.
+pub struct Deduplicator {
+    window: Duration,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+/// The alert's fingerprint if it has one, or else its labels. Firing and
+/// resolved pages for the same alert are deduplicated separately.
+fn key(meta: &PageMeta) -> Option<String> {
+    let mut key = String::from(if meta.resolved { "resolved" } else { "firing" });
+    match meta.fingerprint {
+        Some(ref f) => {
+            key.push(' ');
+            key += f;
+        }
+        None if meta.labels.is_empty() => return None,
+        None => {
+            let mut labels = meta.labels.iter().collect::<Vec<_>>();
+            labels.sort();
+            for (k, v) in labels {
+                key += &format!(" {k:?}={v:?}");
+            }
+        }
+    }
+    Some(key)
+}
+
+impl Deduplicator {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns None if the page should be suppressed, or else the number
+    /// of times it was seen since it was last sent, including this time.
+    pub fn check(&self, meta: &PageMeta) -> Option<u32> {
+        let Some(key) = key(meta) else {
+            return Some(1);
+        };
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, e| {
+            let age = now.duration_since(e.sent);
+            age < self.window || (e.suppressed > 0 && age < FORGET_SUPPRESSED)
+        });
+        match entries.get_mut(&key) {
+            Some(e) if now.duration_since(e.sent) < self.window => {
+                e.suppressed += 1;
+                None
+            }
+            Some(e) => {
+                let seen = e.suppressed + 1;
+                *e = Entry {
+                    sent: now,
+                    suppressed: 0,
+                };
+                Some(seen)
+            }
+            None => {
+                entries.insert(
+                    key,
+                    Entry {
+                        sent: now,
+                        suppressed: 0,
+                    },
+                );
+                Some(1)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta(fingerprint: &str, resolved: bool) -> PageMeta {
+        PageMeta {
+            fingerprint: Some(String::from(fingerprint)),
+            resolved,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn key_from_labels() {
+        let mut a = PageMeta::default();
+        assert_eq!(key(&a), None);
+        a.labels.insert(String::from("b"), String::from("2"));
+        a.labels.insert(String::from("a"), String::from("1"));
+        assert_eq!(key(&a).unwrap(), r#"firing "a"="1" "b"="2""#);
+        a.fingerprint = Some(String::from("abc"));
+        a.resolved = true;
+        assert_eq!(key(&a).unwrap(), "resolved abc");
+    }
+
+    #[test]
+    fn suppresses_within_window() {
+        let dedup = Deduplicator::new(Duration::new(3600, 0));
+        assert_eq!(dedup.check(&meta("a", false)), Some(1));
+        assert_eq!(dedup.check(&meta("a", false)), None);
+        assert_eq!(dedup.check(&meta("a", true)), Some(1));
+        assert_eq!(dedup.check(&meta("b", false)), Some(1));
+        assert_eq!(dedup.check(&PageMeta::default()), Some(1));
+        assert_eq!(dedup.check(&PageMeta::default()), Some(1));
+    }
+
+    #[test]
+    fn counts_suppressed_after_window() {
+        let dedup = Deduplicator::new(Duration::ZERO);
+        assert_eq!(dedup.check(&meta("a", false)), Some(1));
+        assert_eq!(dedup.check(&meta("a", false)), Some(1));
+        dedup
+            .entries
+            .lock()
+            .unwrap()
+            .get_mut("firing a")
+            .unwrap()
+            .suppressed = 2;
+        assert_eq!(dedup.check(&meta("a", false)), Some(3));
+        assert_eq!(dedup.check(&meta("a", false)), Some(1));
+    }
+}
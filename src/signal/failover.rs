@@ -0,0 +1,63 @@
+//! A second Signal account to send pages through when the first one
+//! cannot send at all, for example because it was unregistered or
+//! Signal wants a captcha solved.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use super::SignalRunnerError;
+use super::target::Target;
+use super::transport::{Outgoing, Reference, SignalTransport};
+use crate::state::SignalState;
+
+pub struct Secondary {
+    pub phone_number: String,
+    state: Arc<SignalState>,
+    transport: Arc<dyn SignalTransport>,
+    /// Set while the primary account is failing, so that the operator is
+    /// alerted once for each failover and not for every page.
+    failed_over: AtomicBool,
+}
+
+impl Secondary {
+    pub fn new(
+        phone_number: String,
+        state: Arc<SignalState>,
+        transport: Arc<dyn SignalTransport>,
+    ) -> Self {
+        Self {
+            phone_number,
+            state,
+            transport,
+            failed_over: AtomicBool::new(false),
+        }
+    }
+
+    /// Replies and edits refer to messages which the primary account
+    /// sent, which this account cannot do, so the message is sent
+    /// on its own.
+    pub async fn send(
+        &self,
+        target: &Target,
+        msg: &Outgoing<'_>,
+    ) -> Result<Option<u64>, SignalRunnerError> {
+        let msg = Outgoing {
+            reference: Reference::None,
+            ..*msg
+        };
+        match self.state.invocation().await {
+            None => Err(SignalRunnerError::NoStateAvailable),
+            Some(invocation) => self.transport.send(invocation.path(), target, &msg).await,
+        }
+    }
+
+    /// Returns true if this is a new failover.
+    pub fn fail_over(&self) -> bool {
+        !self.failed_over.swap(true, Ordering::AcqRel)
+    }
+
+    /// Returns true if the primary account was failed over until now.
+    pub fn recover(&self) -> bool {
+        self.failed_over.swap(false, Ordering::AcqRel)
+    }
+}
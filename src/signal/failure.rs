@@ -0,0 +1,186 @@
+//! Telling apart the ways signal-cli fails, so that each can be retried
+//! and reported appropriately. signal-cli says what went wrong through
+//! its exit code (negated, as the error code, over JSON-RPC) and in the
+//! exception it prints.
+
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use super::SignalRunnerError;
+
+const EXIT_IO_ERROR: i32 = 3;
+const EXIT_UNTRUSTED_KEY: i32 = 4;
+const EXIT_RATE_LIMIT: i32 = 5;
+
+/// Rate limits last from minutes to a day; retrying sooner only
+/// extends them.
+const RATE_LIMIT_RETRY: Duration = Duration::from_secs(5 * 60);
+/// Failures which need an operator to fix the account.
+const OPERATOR_RETRY: Duration = Duration::from_secs(15 * 60);
+
+/// How many signal-cli failures to keep for `/signal-failures`.
+const MAX_RECENT_FAILURES: usize = 20;
+
+#[derive(Clone, Serialize)]
+pub struct SignalFailure {
+    time: SystemTime,
+    command: &'static str,
+    code: Option<i32>,
+    kind: &'static str,
+    output: String,
+}
+
+/// The most recent signal-cli failures, oldest first.
+#[derive(Default)]
+pub struct RecentFailures(Mutex<VecDeque<SignalFailure>>);
+
+impl RecentFailures {
+    pub fn record(
+        &self,
+        command: &'static str,
+        code: Option<i32>,
+        kind: &'static str,
+        output: String,
+    ) {
+        let mut failures = self.0.lock().unwrap();
+        if failures.len() >= MAX_RECENT_FAILURES {
+            failures.pop_front();
+        }
+        failures.push_back(SignalFailure {
+            time: SystemTime::now(),
+            command,
+            code,
+            kind,
+            output,
+        });
+    }
+
+    pub fn list(&self) -> Vec<SignalFailure> {
+        self.0.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// How the latest run of a signal-cli command went.
+#[derive(Clone)]
+pub struct LastOutcome {
+    pub time: SystemTime,
+    pub error: Option<String>,
+}
+
+/// The latest outcome of each signal-cli command, for `/healthz`.
+#[derive(Default)]
+pub struct LastOutcomes(Mutex<HashMap<&'static str, LastOutcome>>);
+
+impl LastOutcomes {
+    pub fn record(&self, command: &'static str, error: Option<String>) {
+        self.0.lock().unwrap().insert(
+            command,
+            LastOutcome {
+                time: SystemTime::now(),
+                error,
+            },
+        );
+    }
+
+    pub fn get(&self, command: &str) -> Option<LastOutcome> {
+        self.0.lock().unwrap().get(command).cloned()
+    }
+}
+
+/// Classifies a failure from signal-cli's exit code and output, or
+/// returns `otherwise(output)` if it is none of the known kinds.
+pub fn classify(
+    code: Option<i32>,
+    output: String,
+    otherwise: impl FnOnce(String) -> SignalRunnerError,
+) -> SignalRunnerError {
+    let lower = output.to_lowercase();
+    let has = |needles: &[&str]| needles.iter().any(|n| lower.contains(n));
+    // A proof-required rate limit can be lifted by solving a captcha,
+    // so it is told apart from a plain one first.
+    if has(&["captcha", "proofrequired", "proof required"]) {
+        SignalRunnerError::CaptchaRequired(output)
+    } else if code == Some(EXIT_RATE_LIMIT) || has(&["ratelimit", "rate limit", "status code 429"])
+    {
+        SignalRunnerError::RateLimited(output)
+    } else if has(&["is not registered", "authorizationfailed", "deviceunlinked"]) {
+        SignalRunnerError::Unregistered(output)
+    } else if code == Some(EXIT_UNTRUSTED_KEY) || has(&["untrusted"]) {
+        SignalRunnerError::UntrustedIdentity(output)
+    } else if code == Some(EXIT_IO_ERROR)
+        || has(&[
+            "ioexception",
+            "unknownhostexception",
+            "connection",
+            "timed out",
+            "timeout",
+        ])
+    {
+        SignalRunnerError::NetworkError(output)
+    } else {
+        otherwise(output)
+    }
+}
+
+/// signal-cli's JSON-RPC error codes are its exit codes negated.
+pub fn rpc_code(code: Option<i64>) -> Option<i32> {
+    code.filter(|c| (-5..0).contains(c)).map(|c| -c as i32)
+}
+
+impl SignalRunnerError {
+    /// A short name for the kind of failure, for metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::NoStateAvailable => "no_state",
+            Self::RateLimited(_) => "rate_limited",
+            Self::Unregistered(_) => "unregistered",
+            Self::CaptchaRequired(_) => "captcha_required",
+            Self::UntrustedIdentity(_) => "untrusted_identity",
+            Self::NetworkError(_) => "network",
+            Self::Timeout(_) => "timeout",
+            _ => "other",
+        }
+    }
+
+    /// How long to wait at least before trying a page again, or `None`
+    /// if trying it again would not help.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::RateLimited(_) => Some(RATE_LIMIT_RETRY),
+            Self::Unregistered(_) | Self::CaptchaRequired(_) => Some(OPERATOR_RETRY),
+            // signal-cli has already sent to every recipient but the
+            // untrusted one, so retrying would send duplicates.
+            Self::UntrustedIdentity(_) => None,
+            // Only an unknown rotation has nobody on call.
+            Self::NobodyOnCall(_) => None,
+            _ => Some(Duration::ZERO),
+        }
+    }
+
+    /// Whether the account itself cannot send, whoever to, so that
+    /// another account might do better.
+    pub fn is_account_level(&self) -> bool {
+        matches!(
+            self,
+            Self::Unregistered(_) | Self::CaptchaRequired(_) | Self::RateLimited(_)
+        )
+    }
+
+    /// What an operator needs to do about the failure, if anything.
+    pub fn operator_action(&self) -> Option<&'static str> {
+        match self {
+            Self::Unregistered(_) => Some(
+                "the Signal account is no longer registered; register or link it again and bootstrap new state",
+            ),
+            Self::CaptchaRequired(_) => Some(
+                "Signal requires a captcha; solve one and submit it with signal-cli submitRateLimitChallenge",
+            ),
+            Self::UntrustedIdentity(_) => Some(
+                "a recipient's safety number changed; verify it and trust it with signal-cli trust",
+            ),
+            _ => None,
+        }
+    }
+}
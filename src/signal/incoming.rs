@@ -0,0 +1,188 @@
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashSet;
+
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Reaction {
+    pub emoji: String,
+    pub target_sent_timestamp: u64,
+    #[serde(default)]
+    pub is_remove: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DataMessage {
+    message: Option<String>,
+    reaction: Option<Reaction>,
+    group_info: Option<GroupInfo>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GroupInfo {
+    group_id: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Envelope {
+    source: Option<String>,
+    source_name: Option<String>,
+    source_number: Option<String>,
+    #[serde(default)]
+    timestamp: u64,
+    data_message: Option<DataMessage>,
+}
+
+#[derive(Deserialize)]
+struct Received {
+    envelope: Envelope,
+}
+
+/// A message that somebody sent to us (or to the group), as reported
+/// by `signal-cli --output=json receive`.
+#[derive(Clone)]
+pub struct IncomingMessage {
+    pub sender: String,
+    /// The sender's phone number and ACI, when known. Unlike `sender`,
+    /// which may be a profile name, these cannot be chosen by them.
+    pub sender_ids: Vec<String>,
+    /// When it was sent, in milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    /// Set if it was sent to a group rather than to us directly.
+    pub group_id: Option<String>,
+    pub text: Option<String>,
+    pub reaction: Option<Reaction>,
+}
+
+impl IncomingMessage {
+    pub fn parse(v: Value) -> Option<Self> {
+        let envelope = serde_json::from_value::<Received>(v).ok()?.envelope;
+        let data = envelope.data_message?;
+        let sender_ids = envelope
+            .source_number
+            .iter()
+            .chain(envelope.source.iter())
+            .cloned()
+            .collect();
+        Some(Self {
+            sender: envelope
+                .source_name
+                .filter(|n| !n.is_empty())
+                .or(envelope.source_number)
+                .or(envelope.source)
+                .unwrap_or_default(),
+            sender_ids,
+            timestamp: envelope.timestamp,
+            group_id: data.group_info.map(|g| g.group_id),
+            text: data.message,
+            reaction: data.reaction,
+        })
+    }
+
+    /// The alert ID from a message like "ack 1234".
+    pub fn ack_id(&self) -> Option<u64> {
+        let mut words = self.text.as_deref()?.split_whitespace();
+        if !words.next()?.eq_ignore_ascii_case("ack") {
+            return None;
+        }
+        words.next()?.trim_start_matches('#').parse().ok()
+    }
+}
+
+/// Who may acknowledge pages: anyone in the groups that pages are sent
+/// to, and the senders given by `--ack-sender` wherever they write from.
+/// Anyone else who knows the account's number could otherwise stop an
+/// escalation.
+pub struct AckPolicy {
+    pub groups: HashSet<String>,
+    pub senders: HashSet<String>,
+}
+
+impl AckPolicy {
+    pub fn accepts(&self, m: &IncomingMessage) -> bool {
+        m.group_id.as_ref().is_some_and(|g| self.groups.contains(g))
+            || m.sender_ids.iter().any(|s| self.senders.contains(s))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(text: &str) -> IncomingMessage {
+        IncomingMessage {
+            sender: String::from("Alice"),
+            sender_ids: vec![String::from("+15550001")],
+            timestamp: 0,
+            group_id: None,
+            text: Some(String::from(text)),
+            reaction: None,
+        }
+    }
+
+    #[test]
+    fn parse() {
+        let m = IncomingMessage::parse(serde_json::json!({
+            "envelope": {
+                "source": "uuid",
+                "sourceName": "",
+                "sourceNumber": "+15550001",
+                "timestamp": 1000,
+                "dataMessage": {
+                    "groupInfo": {"groupId": "abc"},
+                    "message": null,
+                    "reaction": {"emoji": "👍", "targetSentTimestamp": 1234},
+                },
+            },
+        }))
+        .unwrap();
+        assert_eq!(m.sender, "+15550001");
+        assert_eq!(m.sender_ids, ["+15550001", "uuid"]);
+        assert_eq!(m.timestamp, 1000);
+        assert_eq!(m.group_id.as_deref(), Some("abc"));
+        assert!(m.text.is_none());
+        let reaction = m.reaction.unwrap();
+        assert_eq!(reaction.target_sent_timestamp, 1234);
+        assert!(!reaction.is_remove);
+        // Receipts and typing notifications have no data message.
+        assert!(
+            IncomingMessage::parse(serde_json::json!({
+                "envelope": {"source": "uuid", "receiptMessage": {}},
+            }))
+            .is_none()
+        );
+    }
+
+    #[test]
+    fn ack_id() {
+        assert_eq!(message("ack 12").ack_id(), Some(12));
+        assert_eq!(message("ACK #12 on it").ack_id(), Some(12));
+        assert_eq!(message("ack").ack_id(), None);
+        assert_eq!(message("ack twelve").ack_id(), None);
+        assert_eq!(message("please ack 12").ack_id(), None);
+    }
+
+    #[test]
+    fn ack_policy() {
+        let policy = AckPolicy {
+            groups: HashSet::from([String::from("pages")]),
+            senders: HashSet::from([String::from("+15550002")]),
+        };
+        let in_group = |group: &str| IncomingMessage {
+            group_id: Some(String::from(group)),
+            ..message("ack 12")
+        };
+        assert!(policy.accepts(&in_group("pages")));
+        assert!(!policy.accepts(&in_group("elsewhere")));
+        // A stranger's direct message, even with a name like a member's.
+        assert!(!policy.accepts(&message("ack 12")));
+        let allowed = IncomingMessage {
+            sender_ids: vec![String::from("+15550002")],
+            ..message("ack 12")
+        };
+        assert!(policy.accepts(&allowed));
+    }
+}
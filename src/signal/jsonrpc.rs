@@ -0,0 +1,286 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use futures::future::BoxFuture;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::sync::oneshot;
+
+use super::transport::{Outgoing, Reference, SignalTransport};
+use super::{IncomingMessage, SignalRunnerError, Target};
+
+type Pending = Arc<Mutex<HashMap<u64, oneshot::Sender<Result<Value, RpcError>>>>>;
+
+#[derive(Deserialize)]
+struct RpcError {
+    code: Option<i64>,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct Response {
+    id: Option<u64>,
+    result: Option<Value>,
+    error: Option<RpcError>,
+}
+
+struct Running {
+    config: PathBuf,
+    child: Child,
+    stdin: ChildStdin,
+    pending: Pending,
+    next_id: u64,
+    /// Tells this process apart from those started before and after it.
+    generation: u64,
+}
+
+impl Running {
+    fn alive(&mut self) -> bool {
+        matches!(self.child.try_wait(), Ok(None))
+    }
+}
+
+/// A long-running `signal-cli jsonRpc` process which requests are
+/// multiplexed over. It is stopped whenever the state directory is to
+/// be copied or replaced, and started again by the next request, and
+/// restarted whenever it has died.
+pub struct Daemon {
+    signal_bin: PathBuf,
+    phone_number: String,
+    timeout: Duration,
+    running: tokio::sync::Mutex<Option<Running>>,
+    /// How many processes have been started.
+    spawned: AtomicU64,
+}
+
+async fn read_responses(stdout: ChildStdout, pending: Pending) {
+    let mut lines = BufReader::new(stdout).lines();
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(e) => {
+                log::error!("Reading from signal-cli: {e}");
+                break;
+            }
+        };
+        let r = match serde_json::from_str::<Response>(&line) {
+            Ok(r) => r,
+            Err(e) => {
+                log::warn!("Unparseable line from signal-cli: {e}");
+                continue;
+            }
+        };
+        let Some(id) = r.id else {
+            log::debug!("Ignoring signal-cli notification");
+            continue;
+        };
+        if let Some(tx) = pending.lock().unwrap().remove(&id) {
+            let _ = tx.send(match r.error {
+                Some(e) => Err(e),
+                None => Ok(r.result.unwrap_or(Value::Null)),
+            });
+        }
+    }
+    // Dropping the senders fails every request still in flight.
+    pending.lock().unwrap().clear();
+}
+
+impl Daemon {
+    pub fn new(signal_bin: PathBuf, phone_number: String, timeout: Duration) -> Self {
+        Self {
+            signal_bin,
+            phone_number,
+            timeout,
+            running: tokio::sync::Mutex::new(None),
+            spawned: AtomicU64::new(0),
+        }
+    }
+
+    fn spawn(&self, config: &Path) -> Result<Running, SignalRunnerError> {
+        log::info!("Starting signal-cli jsonRpc");
+        let mut child = Command::new(&self.signal_bin)
+            .arg("--config")
+            .arg(config)
+            .arg("--username")
+            .arg(&self.phone_number)
+            .arg("jsonRpc")
+            .arg("--receive-mode=manual")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let pending = Pending::default();
+        tokio::spawn(read_responses(stdout, Arc::clone(&pending)));
+        Ok(Running {
+            config: config.to_path_buf(),
+            child,
+            stdin,
+            pending,
+            next_id: 0,
+            generation: self.spawned.fetch_add(1, Ordering::Relaxed) + 1,
+        })
+    }
+
+    /// Make one JSON-RPC call.
+    async fn call(
+        &self,
+        config: &Path,
+        method: &str,
+        params: Value,
+    ) -> Result<Value, SignalRunnerError> {
+        let (rx, generation) = {
+            let mut running = self.running.lock().await;
+            let usable = match running.as_mut() {
+                Some(r) => r.config == config && r.alive(),
+                None => false,
+            };
+            if !usable {
+                if running.is_some() {
+                    log::warn!("signal-cli jsonRpc is stale or has exited, restarting");
+                }
+                *running = Some(self.spawn(config)?);
+            }
+            let r = running.as_mut().unwrap();
+            r.next_id += 1;
+            let id = r.next_id;
+            let (tx, rx) = oneshot::channel();
+            r.pending.lock().unwrap().insert(id, tx);
+            let mut line = serde_json::to_vec(&json!({
+                "jsonrpc": "2.0",
+                "method": method,
+                "params": params,
+                "id": id,
+            }))?;
+            line.push(b'\n');
+            if let Err(e) = r.stdin.write_all(&line).await {
+                r.pending.lock().unwrap().remove(&id);
+                *running = None;
+                return Err(e.into());
+            }
+            (rx, r.generation)
+        };
+        match tokio::time::timeout(self.timeout, rx).await {
+            Ok(Ok(Ok(v))) => Ok(v),
+            Ok(Ok(Err(e))) => Err(super::failure::classify(
+                super::failure::rpc_code(e.code),
+                e.message,
+                SignalRunnerError::RpcFailed,
+            )),
+            Ok(Err(_)) => Err(SignalRunnerError::DaemonExited),
+            Err(_) => {
+                log::warn!("signal-cli jsonRpc did not answer {method}, restarting it");
+                // Dropping it kills it, which fails any other calls in
+                // flight too. Unless it has been replaced meanwhile, by
+                // a call which found it dead, since then that would kill
+                // the new one and fail calls in flight on it instead.
+                let mut running = self.running.lock().await;
+                if running.as_ref().is_some_and(|r| r.generation == generation) {
+                    *running = None;
+                }
+                Err(SignalRunnerError::Timeout(self.timeout))
+            }
+        }
+    }
+}
+
+impl SignalTransport for Daemon {
+    fn send<'a>(
+        &'a self,
+        config: &'a Path,
+        target: &'a Target,
+        msg: &'a Outgoing<'a>,
+    ) -> BoxFuture<'a, Result<Option<u64>, SignalRunnerError>> {
+        Box::pin(async move {
+            let mut params = target.params();
+            params["message"] = msg.text.into();
+            if let Some(a) = msg.attachment {
+                let uri = format!("data:{};base64,{}", a.content_type, BASE64.encode(&a.data));
+                params["attachments"] = json!([uri]);
+            }
+            if !msg.mentions.is_empty() {
+                params["mention"] = json!(msg.mentions);
+            }
+            match msg.reference {
+                Reference::None => (),
+                Reference::Reply { timestamp, text } => {
+                    params["quoteTimestamp"] = timestamp.into();
+                    params["quoteAuthor"] = self.phone_number.as_str().into();
+                    params["quoteMessage"] = text.into();
+                }
+                Reference::Edit(timestamp) => params["editTimestamp"] = timestamp.into(),
+            }
+            let r = self.call(config, "send", params).await?;
+            Ok(r.get("timestamp").and_then(Value::as_u64))
+        })
+    }
+
+    fn delete<'a>(
+        &'a self,
+        config: &'a Path,
+        target: &'a Target,
+        timestamp: u64,
+    ) -> BoxFuture<'a, Result<(), SignalRunnerError>> {
+        Box::pin(async move {
+            let mut params = target.params();
+            params["targetTimestamp"] = timestamp.into();
+            self.call(config, "remoteDelete", params).await?;
+            Ok(())
+        })
+    }
+
+    fn receive<'a>(
+        &'a self,
+        config: &'a Path,
+    ) -> BoxFuture<'a, Result<Vec<IncomingMessage>, SignalRunnerError>> {
+        Box::pin(async move {
+            Ok(match self.call(config, "receive", json!({})).await? {
+                Value::Array(messages) => messages
+                    .into_iter()
+                    .filter_map(IncomingMessage::parse)
+                    .collect(),
+                _ => Vec::new(),
+            })
+        })
+    }
+
+    fn validate<'a>(&'a self, config: &'a Path) -> BoxFuture<'a, Result<(), SignalRunnerError>> {
+        Box::pin(async move {
+            self.call(config, "listContacts", json!({})).await?;
+            Ok(())
+        })
+    }
+
+    /// Closes the daemon's input so that it exits, killing it if it has
+    /// not within the timeout.
+    fn release(&self) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            let Some(Running {
+                mut child, stdin, ..
+            }) = self.running.lock().await.take()
+            else {
+                return;
+            };
+            log::info!("Stopping signal-cli jsonRpc");
+            drop(stdin);
+            if tokio::time::timeout(self.timeout, child.wait())
+                .await
+                .is_err()
+            {
+                log::warn!("signal-cli jsonRpc did not exit, killing it");
+                if let Err(e) = child.kill().await {
+                    log::error!("Killing signal-cli jsonRpc: {e}");
+                }
+            }
+        })
+    }
+}
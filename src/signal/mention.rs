@@ -0,0 +1,64 @@
+//! Signal @mentions, which the message template's `mention()` function
+//! writes into messages as a recipient between markers.
+
+use crate::render::{MENTION_END, MENTION_START};
+
+/// What Signal shows the mentioned person's name in place of.
+const PLACEHOLDER: char = '\u{FFFC}';
+
+/// Replaces the mention markers in `msg`. Sent to a group, they become
+/// mentions in signal-cli's `start:length:recipient` form. Elsewhere
+/// mentions are not possible so the recipient is written out instead.
+pub fn extract(msg: &str, group: bool) -> (String, Vec<String>) {
+    let mut text = String::with_capacity(msg.len());
+    let mut mentions = Vec::new();
+    let mut rest = msg;
+    while let Some(start) = rest.find(MENTION_START) {
+        let Some(len) = rest[start..].find(MENTION_END) else {
+            break;
+        };
+        let recipient = &rest[start + MENTION_START.len_utf8()..start + len];
+        text.push_str(&rest[..start]);
+        if group {
+            // Signal counts in UTF-16 code units.
+            let offset = text.encode_utf16().count();
+            mentions.push(format!("{offset}:1:{recipient}"));
+            text.push(PLACEHOLDER);
+        } else {
+            text.push('@');
+            text.push_str(recipient);
+        }
+        rest = &rest[start + len + MENTION_END.len_utf8()..];
+    }
+    text.push_str(rest);
+    (text, mentions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rendered(recipient: &str) -> String {
+        format!("é {MENTION_START}{recipient}{MENTION_END} disk full")
+    }
+
+    #[test]
+    fn group() {
+        let (text, mentions) = extract(&rendered("+15550001"), true);
+        assert_eq!(text, "é \u{FFFC} disk full");
+        assert_eq!(mentions, ["2:1:+15550001"]);
+    }
+
+    #[test]
+    fn direct() {
+        let (text, mentions) = extract(&rendered("+15550001"), false);
+        assert_eq!(text, "é @+15550001 disk full");
+        assert!(mentions.is_empty());
+    }
+
+    #[test]
+    fn unterminated() {
+        let msg = format!("a {MENTION_START}b");
+        assert_eq!(extract(&msg, true), (msg, Vec::new()));
+    }
+}
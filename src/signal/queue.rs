@@ -0,0 +1,187 @@
+//! The send queue: pages waiting for signal-cli, which can be combined
+//! into one message when too many pile up.
+
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::time::SystemTime;
+
+use crate::retry_queue::{Entry, Item, RetryQueue};
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct QueuedPage {
+    #[serde(default)]
+    pub alert_id: u64,
+    #[serde(default)]
+    pub targets: Vec<super::Target>,
+    pub message: String,
+    #[serde(default)]
+    pub attachment: Option<crate::page::AttachmentData>,
+    #[serde(default)]
+    pub(super) resolves: Option<super::Resolves>,
+    /// The targets it has already been sent to by earlier attempts.
+    #[serde(default)]
+    pub delivered: Vec<super::Target>,
+}
+
+impl Item for QueuedPage {
+    fn is(&self, other: &QueuedPage) -> bool {
+        self.alert_id == other.alert_id
+    }
+}
+
+pub type SendQueue = RetryQueue<QueuedPage>;
+
+/// A page which is queued.
+pub type Queued = Entry<QueuedPage>;
+
+impl SendQueue {
+    /// If more than `threshold` pages are queued, combines the ones
+    /// going to the same targets as the oldest into it, leaving out any
+    /// with attachments, that resolve earlier pages or that have been
+    /// sent to some of their targets. Returns how many were combined
+    /// into it.
+    pub async fn coalesce(&self, threshold: usize) -> usize {
+        self.update(|pages| {
+            if pages.len() <= threshold {
+                return 0;
+            }
+            let Some(mut front) = pages.pop_front() else {
+                return 0;
+            };
+            if !front.delivered.is_empty() || !front.due(SystemTime::now()) {
+                pages.push_front(front);
+                return 0;
+            }
+            let (same, rest): (VecDeque<_>, VecDeque<_>) = pages.drain(..).partition(|p| {
+                p.targets == front.targets
+                    && p.attachment.is_none()
+                    && p.resolves.is_none()
+                    && p.delivered.is_empty()
+            });
+            *pages = rest;
+            let n = same.len();
+            if n > 0 {
+                let mut message = format!("{} pages:\n\n{}", n + 1, front.message);
+                for page in same {
+                    message.push_str("\n\n");
+                    message.push_str(&page.message);
+                }
+                front.item.message = message;
+            }
+            pages.push_front(front);
+            n
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signal::route::ResolvedAction;
+    use crate::signal::{Resolves, Target};
+    use std::time::Duration;
+
+    async fn push(queue: &SendQueue, alert_id: u64, group: &str, message: &str) {
+        queue
+            .push(page(alert_id, group, message, None))
+            .await
+            .unwrap();
+    }
+
+    fn page(alert_id: u64, group: &str, message: &str, resolves: Option<Resolves>) -> QueuedPage {
+        QueuedPage {
+            alert_id,
+            targets: vec![Target::Group(String::from(group))],
+            message: String::from(message),
+            attachment: None,
+            resolves,
+            delivered: Vec::new(),
+        }
+    }
+
+    fn messages(queue: &SendQueue) -> Vec<String> {
+        queue
+            .snapshot()
+            .into_iter()
+            .map(|p| p.item.message)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn coalesce_below_threshold() {
+        let queue = SendQueue::new("send queue", None).unwrap();
+        push(&queue, 1, "a", "one").await;
+        push(&queue, 2, "a", "two").await;
+        assert_eq!(queue.coalesce(2).await, 0);
+        assert_eq!(queue.next_due().await.message, "one");
+    }
+
+    #[tokio::test]
+    async fn coalesce_same_targets() {
+        let queue = SendQueue::new("send queue", None).unwrap();
+        push(&queue, 1, "a", "one").await;
+        push(&queue, 2, "b", "two").await;
+        push(&queue, 3, "a", "three").await;
+        push(&queue, 4, "a", "four").await;
+        assert_eq!(queue.coalesce(1).await, 2);
+        assert_eq!(queue.len(), 2);
+        assert_eq!(
+            queue.next_due().await.message,
+            "3 pages:\n\none\n\nthree\n\nfour"
+        );
+        queue.remove(&queue.next_due().await).await;
+        assert_eq!(queue.next_due().await.alert_id, 2);
+    }
+
+    #[tokio::test]
+    async fn coalesce_leaves_out_partly_sent() {
+        let queue = SendQueue::new("send queue", None).unwrap();
+        push(&queue, 1, "a", "one").await;
+        push(&queue, 2, "a", "two").await;
+        let one = queue.next_due().await;
+        queue
+            .retry_later(&one, Duration::ZERO, |p| {
+                p.delivered = vec![Target::Group(String::from("a"))]
+            })
+            .await;
+        assert_eq!(queue.coalesce(0).await, 0);
+        assert_eq!(messages(&queue), ["two", "one"]);
+    }
+
+    #[tokio::test]
+    async fn coalesce_leaves_out_resolutions() {
+        let queue = SendQueue::new("send queue", None).unwrap();
+        push(&queue, 1, "a", "one").await;
+        let resolves = Resolves {
+            alert: 1,
+            action: ResolvedAction::Reply,
+        };
+        queue
+            .push(page(2, "a", "resolved", Some(resolves)))
+            .await
+            .unwrap();
+        push(&queue, 3, "a", "three").await;
+        assert_eq!(queue.coalesce(0).await, 1);
+        assert_eq!(queue.next_due().await.message, "2 pages:\n\none\n\nthree");
+        queue.remove(&queue.next_due().await).await;
+        assert_eq!(queue.next_due().await.alert_id, 2);
+    }
+
+    /// Queues kept by earlier releases, before the queue was generic,
+    /// still load.
+    #[test]
+    fn reads_older_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("queue.json");
+        std::fs::write(
+            &file,
+            r#"[{"alert_id":3,"message":"one","enqueued":{"secs_since_epoch":1,"nanos_since_epoch":0},"attempts":2}]"#,
+        )
+        .unwrap();
+        let queue = SendQueue::new("send queue", Some(file)).unwrap();
+        let page = &queue.snapshot()[0];
+        assert_eq!((page.alert_id, page.message.as_str()), (3, "one"));
+        assert_eq!(page.attempts, 2);
+    }
+}
@@ -0,0 +1,166 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use super::Target;
+
+/// What to do with a page saying that an alert is over.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResolvedAction {
+    /// Send it like any other page.
+    #[default]
+    Send,
+    /// Do not send it.
+    Suppress,
+    /// Send it as a reply quoting the page about the alert firing.
+    Reply,
+    /// Replace the text of the page about the alert firing with it.
+    Edit,
+    /// Delete the page about the alert firing instead of sending it.
+    Delete,
+}
+
+impl std::str::FromStr for ResolvedAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "send" => Ok(Self::Send),
+            "suppress" => Ok(Self::Suppress),
+            "reply" => Ok(Self::Reply),
+            "edit" => Ok(Self::Edit),
+            "delete" => Ok(Self::Delete),
+            _ => Err(format!(
+                "Unknown resolved action {s:?}, expected send, suppress, reply, edit or delete"
+            )),
+        }
+    }
+}
+
+/// Sends pages whose labels all match to particular targets instead of
+/// the default group. Parsed from `team=db,severity=critical=>group:ID`
+/// where there may be several comma-separated targets, and
+/// `resolved=ACTION` among them says what to do with resolved pages.
+#[derive(Clone, Debug)]
+pub struct Route {
+    matchers: Vec<(String, String)>,
+    targets: Vec<Target>,
+    resolved: Option<ResolvedAction>,
+}
+
+impl std::str::FromStr for Route {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((matchers, targets)) = s.split_once("=>") else {
+            return Err(String::from("Expected MATCHERS=>TARGETS"));
+        };
+        let matchers = matchers
+            .split(',')
+            .map(|m| match m.split_once('=') {
+                Some((k, v)) => Ok((String::from(k), String::from(v))),
+                None => Err(format!("Expected label=value, got {m:?}")),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut resolved = None;
+        let targets = targets
+            .split(',')
+            .filter(|t| !t.is_empty())
+            .filter_map(|t| match t.strip_prefix("resolved=") {
+                Some(action) => match action.parse() {
+                    Ok(action) => {
+                        resolved = Some(action);
+                        None
+                    }
+                    Err(e) => Some(Err(e)),
+                },
+                None => Some(t.parse().map_err(|e| format!("{e}"))),
+            })
+            .collect::<Result<Vec<Target>, _>>()?;
+        if targets.is_empty() {
+            return Err(String::from("Route has no targets"));
+        }
+        Ok(Self {
+            matchers,
+            targets,
+            resolved,
+        })
+    }
+}
+
+impl Route {
+    pub fn targets_for(&self, labels: &HashMap<String, String>) -> Option<&[Target]> {
+        self.matchers
+            .iter()
+            .all(|(k, v)| labels.get(k) == Some(v))
+            .then_some(self.targets.as_slice())
+    }
+
+    pub fn matches(&self, labels: &HashMap<String, String>) -> bool {
+        self.targets_for(labels).is_some()
+    }
+
+    pub fn resolved_action(&self) -> Option<ResolvedAction> {
+        self.resolved
+    }
+
+    pub fn targets(&self) -> &[Target] {
+        &self.targets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(l: &[(&str, &str)]) -> HashMap<String, String> {
+        l.iter()
+            .map(|(k, v)| (String::from(*k), String::from(*v)))
+            .collect()
+    }
+
+    #[test]
+    fn parse() {
+        let route: Route = "team=db,severity=critical=>group:abc,+15550001"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            route.matchers,
+            [
+                (String::from("team"), String::from("db")),
+                (String::from("severity"), String::from("critical"))
+            ]
+        );
+        assert_eq!(
+            route.targets,
+            [
+                Target::Group(String::from("abc")),
+                Target::Recipient(String::from("+15550001"))
+            ]
+        );
+        assert_eq!(route.resolved_action(), None);
+        let route: Route = "team=db=>resolved=reply,group:abc".parse().unwrap();
+        assert_eq!(route.targets, [Target::Group(String::from("abc"))]);
+        assert_eq!(route.resolved_action(), Some(ResolvedAction::Reply));
+        assert!(
+            "team=db=>resolved=ignore,group:abc"
+                .parse::<Route>()
+                .is_err()
+        );
+        assert!("team=db=>resolved=edit".parse::<Route>().is_err());
+        assert!("team=db".parse::<Route>().is_err());
+        assert!("team=>group:abc".parse::<Route>().is_err());
+        assert!("team=db=>".parse::<Route>().is_err());
+    }
+
+    #[test]
+    fn all_matchers_must_match() {
+        let route: Route = "team=db,severity=critical=>group:abc".parse().unwrap();
+        let targets = [Target::Group(String::from("abc"))];
+        let page = labels(&[("team", "db"), ("severity", "critical"), ("host", "x")]);
+        assert_eq!(route.targets_for(&page), Some(targets.as_slice()));
+        assert_eq!(route.targets_for(&labels(&[("team", "db")])), None);
+        let page = labels(&[("team", "web"), ("severity", "critical")]);
+        assert_eq!(route.targets_for(&page), None);
+    }
+}
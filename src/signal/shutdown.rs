@@ -0,0 +1,76 @@
+//! Lets pages which are being delivered finish before the runner shuts
+//! down, and turns away any which arrive once it has begun to.
+
+use tokio::sync::watch;
+
+#[derive(Default)]
+struct State {
+    closed: bool,
+    in_flight: usize,
+}
+
+pub struct Gate(watch::Sender<State>);
+
+/// Held while a page is being delivered.
+pub struct Pass<'a>(&'a Gate);
+
+impl Drop for Pass<'_> {
+    fn drop(&mut self) {
+        self.0.0.send_modify(|s| s.in_flight -= 1);
+    }
+}
+
+impl Default for Gate {
+    fn default() -> Self {
+        Self(watch::Sender::new(State::default()))
+    }
+}
+
+impl Gate {
+    /// Returns `None` once shutting down has begun.
+    pub fn enter(&self) -> Option<Pass<'_>> {
+        let mut entered = false;
+        self.0.send_if_modified(|s| {
+            if !s.closed {
+                s.in_flight += 1;
+                entered = true;
+            }
+            entered
+        });
+        entered.then(|| Pass(self))
+    }
+
+    /// Resolves once shutting down has begun.
+    pub async fn closed(&self) {
+        let _ = self.0.subscribe().wait_for(|s| s.closed).await;
+    }
+
+    /// Turns away any more pages and waits for those being delivered.
+    pub async fn close(&self) {
+        self.0.send_modify(|s| s.closed = true);
+        let _ = self.0.subscribe().wait_for(|s| s.in_flight == 0).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn close_waits_for_passes() {
+        let gate = Gate::default();
+        let pass = gate.enter().unwrap();
+        let close = gate.close();
+        tokio::pin!(close);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), &mut close)
+                .await
+                .is_err()
+        );
+        assert!(gate.enter().is_none());
+        drop(pass);
+        close.await;
+        gate.closed().await;
+    }
+}
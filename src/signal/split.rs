@@ -0,0 +1,88 @@
+//! Splitting messages which are too long for one Signal message.
+
+use crate::render::{MENTION_END, MENTION_START};
+
+/// Room left in each part for its `(1/2) ` number.
+const NUMBER_ROOM: usize = 10;
+
+/// Splits `msg` into parts of at most `max` characters, numbered like
+/// `(1/3) `. Parts end at the end of a line if there is one in them, and
+/// never in the middle of a mention. A message that fits is returned
+/// as it is.
+pub fn split(msg: &str, max: u64) -> Vec<String> {
+    let max = usize::try_from(max).unwrap_or(usize::MAX);
+    if msg.chars().count() <= max {
+        return vec![String::from(msg)];
+    }
+    let room = max.saturating_sub(NUMBER_ROOM).max(1);
+    let mut parts = Vec::new();
+    let mut rest = msg;
+    while let Some((end, _)) = rest.char_indices().nth(room) {
+        let mut cut = match rest[..end].rfind('\n') {
+            Some(i) if i > 0 => i + 1,
+            _ => end,
+        };
+        // Back up to the start of a mention which would be cut in two.
+        if let Some(start) = rest[..cut].rfind(MENTION_START)
+            && start > 0
+            && !rest[start..cut].contains(MENTION_END)
+        {
+            cut = start;
+        }
+        parts.push(rest[..cut].trim_end_matches('\n'));
+        rest = &rest[cut..];
+    }
+    parts.push(rest);
+    let n = parts.len();
+    parts
+        .into_iter()
+        .enumerate()
+        .map(|(i, part)| format!("({}/{n}) {part}", i + 1))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fits() {
+        assert_eq!(split("short", 5), ["short"]);
+    }
+
+    #[test]
+    fn at_line_ends() {
+        let parts = split("first line\nsecond\nthird", 21);
+        assert_eq!(parts, ["(1/3) first line", "(2/3) second", "(3/3) third"]);
+    }
+
+    #[test]
+    fn long_line() {
+        let parts = split(&"x".repeat(25), 20);
+        assert_eq!(
+            parts,
+            ["(1/3) xxxxxxxxxx", "(2/3) xxxxxxxxxx", "(3/3) xxxxx"]
+        );
+    }
+
+    #[test]
+    fn keeps_mentions_whole() {
+        let mention = format!("{MENTION_START}+15550001{MENTION_END}");
+        let parts = split(&format!("aaaaaaaaaa {mention} end"), 22);
+        assert_eq!(
+            parts,
+            [
+                String::from("(1/3) aaaaaaaaaa "),
+                format!("(2/3) {mention} "),
+                String::from("(3/3) end"),
+            ]
+        );
+    }
+
+    #[test]
+    fn counts_characters() {
+        let parts = split(&"é".repeat(25), 20);
+        assert_eq!(parts.len(), 3);
+        assert!(parts.iter().all(|p| p.chars().count() <= 20));
+    }
+}
@@ -0,0 +1,88 @@
+use serde::{Deserialize, Serialize};
+use std::process::Command;
+
+/// Who a message is sent to.
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub enum Target {
+    Group(String),
+    /// A phone number or ACI.
+    Recipient(String),
+    /// Whoever is on call for a rotation when the message is sent.
+    OnCall(String),
+}
+
+impl std::str::FromStr for Target {
+    type Err = std::convert::Infallible;
+
+    /// `group:GROUP_ID`, `oncall:ROTATION` or a recipient.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(if let Some(id) = s.strip_prefix("group:") {
+            Self::Group(String::from(id))
+        } else if let Some(rotation) = s.strip_prefix("oncall:") {
+            Self::OnCall(String::from(rotation))
+        } else {
+            Self::Recipient(String::from(s))
+        })
+    }
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Self::Group(id) => write!(f, "group:{id}"),
+            Self::Recipient(r) => f.write_str(r),
+            Self::OnCall(rotation) => write!(f, "oncall:{rotation}"),
+        }
+    }
+}
+
+impl Target {
+    pub fn add_args<'a>(&self, cmd: &'a mut Command) -> &'a mut Command {
+        match self {
+            Self::Group(id) => cmd.arg("--group").arg(id),
+            Self::Recipient(r) => cmd.arg(r),
+            Self::OnCall(_) => unreachable!("on-call targets are resolved before sending"),
+        }
+    }
+
+    pub fn params(&self) -> serde_json::Value {
+        match self {
+            Self::Group(id) => serde_json::json!({ "groupId": id }),
+            Self::Recipient(r) => serde_json::json!({ "recipient": [r] }),
+            Self::OnCall(_) => unreachable!("on-call targets are resolved before sending"),
+        }
+    }
+}
+
+/// The recipients named by a page which `--recipient-allow` allows.
+/// Whoever sends a page chooses its recipients, so any others are left
+/// out rather than let them message anyone as this account.
+pub fn allowed_recipients(allow: &[String], recipients: &[String]) -> Vec<Target> {
+    recipients
+        .iter()
+        .filter(|r| {
+            let allowed = allow.contains(r);
+            if !allowed {
+                log::warn!("Leaving out recipient {r}, which --recipient-allow does not allow");
+            }
+            allowed
+        })
+        .cloned()
+        .map(Target::Recipient)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_allowed_recipients() {
+        let recipients = [String::from("+15550001"), String::from("+15550002")];
+        assert!(allowed_recipients(&[], &recipients).is_empty());
+        assert_eq!(
+            allowed_recipients(&[String::from("+15550002")], &recipients),
+            [Target::Recipient(String::from("+15550002"))]
+        );
+    }
+}
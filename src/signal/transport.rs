@@ -0,0 +1,66 @@
+//! How pages reach Signal. Both transports run signal-cli: `cli` starts
+//! it for each command and `jsonrpc` keeps one `jsonRpc` process running.
+//! There is no native Signal client.
+
+use futures::future::BoxFuture;
+use std::path::Path;
+
+use super::{IncomingMessage, SignalRunnerError, Target};
+use crate::page::AttachmentData;
+
+/// How a message relates to one sent earlier to the same target.
+#[derive(Clone, Copy)]
+pub enum Reference<'a> {
+    None,
+    /// Quotes the earlier message, as a reply to it.
+    Reply {
+        timestamp: u64,
+        text: &'a str,
+    },
+    /// Replaces the text of the earlier message.
+    Edit(u64),
+}
+
+pub struct Outgoing<'a> {
+    pub text: &'a str,
+    pub attachment: Option<&'a AttachmentData>,
+    /// Ready to pass to signal-cli's `--mention`.
+    pub mentions: &'a [String],
+    pub reference: Reference<'a>,
+}
+
+/// A way of talking to Signal as the account in a state directory. The
+/// caller holds the state invocation that `config` was obtained from
+/// until the returned future completes.
+pub trait SignalTransport: Send + Sync {
+    /// Returns the Signal timestamp of the sent message, if known.
+    fn send<'a>(
+        &'a self,
+        config: &'a Path,
+        target: &'a Target,
+        msg: &'a Outgoing<'a>,
+    ) -> BoxFuture<'a, Result<Option<u64>, SignalRunnerError>>;
+
+    /// Deletes a message sent earlier, for everyone.
+    fn delete<'a>(
+        &'a self,
+        config: &'a Path,
+        target: &'a Target,
+        timestamp: u64,
+    ) -> BoxFuture<'a, Result<(), SignalRunnerError>>;
+
+    fn receive<'a>(
+        &'a self,
+        config: &'a Path,
+    ) -> BoxFuture<'a, Result<Vec<IncomingMessage>, SignalRunnerError>>;
+
+    /// Fails unless the account is registered and usable. This should
+    /// only need the local state.
+    fn validate<'a>(&'a self, config: &'a Path) -> BoxFuture<'a, Result<(), SignalRunnerError>>;
+
+    /// Stops using the state directory between calls, until the next
+    /// call. Called before the directory is copied or replaced.
+    fn release(&self) -> BoxFuture<'_, ()> {
+        Box::pin(async {})
+    }
+}
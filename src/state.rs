@@ -1,141 +1,914 @@
-use chacha20poly1305::aead::{Aead, OsRng};
-use chacha20poly1305::{AeadCore, ChaCha20Poly1305, KeyInit};
 use comprehensive::ResourceDependencies;
+use comprehensive::health::{HealthReporter, HealthSignaller};
 use comprehensive::v1::{AssemblyRuntime, Resource, resource};
-use flate2::Compression;
+use comprehensive_traits::http_diag::{HttpDiagHandler, HttpDiagHandlerInstaller};
 use futures::StreamExt;
+use futures::future::BoxFuture;
 use futures::stream::FuturesUnordered;
 use pin_project_lite::pin_project;
-use std::io::Write;
-use std::os::unix::fs::PermissionsExt;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::sync::Arc;
+use std::sync::Mutex;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::task::{Context, Poll};
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 use tempfile::TempDir;
 
-const MAINTENANCE_INTERVAL: Duration = Duration::new(15 * 60, 0);
+use crate::store::{StateStore, StateStoreArgs, StoreError, StoredVersion};
 
-comprehensive_s3::bucket!(SignalStateBucket, "signal state storage", "");
+mod barrier;
+mod chunk;
+mod compression;
+mod contents;
+mod fingerprint;
+mod keyring;
+mod kms;
+mod lease;
+mod notify;
+mod passphrase;
+mod prune;
+mod retention;
+mod retry;
+mod seal;
+mod verify;
 
-#[derive(ResourceDependencies)]
-pub struct SignalStateDependencies(Arc<SignalStateBucket>);
+use chunk::ChunkKey;
+use fingerprint::Fingerprint;
+use keyring::Keyring;
+use seal::Sealer;
+
+/// Where our own data is kept in the state, next to signal-cli's `data`.
+const APP_DATA_DIR: &str = "signal-pager";
 
 #[derive(Debug, thiserror::Error)]
 pub enum SignalStateError {
     #[error("{0}")]
-    S3Error(#[from] s3::error::S3Error),
+    StoreError(#[from] StoreError),
     #[error("{0}")]
     IOError(#[from] std::io::Error),
     #[error("{0}")]
     CryptoError(#[from] chacha20poly1305::Error),
-    #[error("No state available in S3")]
+    #[error("No state available in storage")]
     NoStateAvailable,
+    #[error("State version {0} is not in storage")]
+    NoSuchVersion(u32),
     #[error("Ciphertext too short")]
     CiphertextTooShort,
     #[error("{0}")]
     InvalidKeyLength(#[from] crypto_common::InvalidLength),
+    #[error("{0}")]
+    JsonError(#[from] serde_json::Error),
+    #[error("Invalid keyring {0}")]
+    InvalidKeyring(String),
+    #[error("{0}")]
+    KmsError(#[from] kms::KmsError),
+    #[error("State is encrypted with KMS but there is no --kms-key")]
+    KmsRequired,
+    #[error("No encryption key to encrypt the state with")]
+    NoEncryptionKey,
+    #[error("State is encrypted with key {0} which is not in the keyring")]
+    UnknownKey(u32),
+    #[error("State is in an unknown format")]
+    UnknownFormat,
+    #[error("State version is not bound to its version number and deployment")]
+    Unbound,
+    #[error("Lost the lease to another instance")]
+    LeaseLost,
+    #[error("State is encrypted with a passphrase but there is no --encryption-passphrase-file")]
+    PassphraseRequired,
+    #[error("Deriving key from passphrase: {0}")]
+    KeyDerivation(String),
+    #[error("Invalid --state-compression-level {0}")]
+    InvalidCompressionLevel(i32),
+    #[error("Invalid --state-notification-queue {0}: not an SQS queue URL")]
+    InvalidNotificationQueue(String),
+    #[error("Not persisting the state because another instance is using the state storage")]
+    ReadOnly,
+    #[error("{0}")]
+    HealthError(#[from] comprehensive::ComprehensiveError),
+}
+
+impl SignalStateError {
+    /// Whether trying again may work, because the state storage failed
+    /// in a way that may pass.
+    fn is_transient(&self) -> bool {
+        matches!(self, Self::StoreError(e) if e.is_transient())
+    }
+
+    /// Whether loading a version failed because of what is in it, such as
+    /// corruption or an unknown key, rather than trouble reaching the
+    /// storage or KMS which would pass.
+    fn is_bad_version(&self) -> bool {
+        match self {
+            Self::CryptoError(_)
+            | Self::CiphertextTooShort
+            | Self::InvalidKeyLength(_)
+            | Self::UnknownKey(_)
+            | Self::UnknownFormat
+            | Self::Unbound => true,
+            Self::IOError(e) => matches!(
+                e.kind(),
+                std::io::ErrorKind::InvalidData
+                    | std::io::ErrorKind::InvalidInput
+                    | std::io::ErrorKind::UnexpectedEof
+            ),
+            _ => false,
+        }
+    }
+}
+
+impl From<SignalStateError> for tonic::Status {
+    fn from(e: SignalStateError) -> tonic::Status {
+        match e {
+            SignalStateError::NoStateAvailable => tonic::Status::unavailable(e.to_string()),
+            SignalStateError::NoSuchVersion(_) => tonic::Status::not_found(e.to_string()),
+            SignalStateError::ReadOnly => tonic::Status::failed_precondition(e.to_string()),
+            _ => tonic::Status::internal(e.to_string()),
+        }
+    }
 }
 
 struct Inner {
     version: u32,
-    dir: TempDir,
+    /// Shared with the invocations using it, which keep it until they
+    /// are done even if it is replaced.
+    dir: Arc<TempDir>,
+    /// Set when the state might have changed, so that it is saved soon.
+    /// Whether it did is up to `fingerprint`.
     dirtied: AtomicBool,
+    /// Of the state directory when it was last loaded or saved, or
+    /// `None` if it is to be saved even if it has not changed.
+    fingerprint: Option<Fingerprint>,
+    /// Of the version loaded or saved, if it was chunked, so that the
+    /// next version is chunked the same way and shares its chunks.
+    chunk_key: Option<ChunkKey>,
+    /// When it was last loaded or saved.
+    persisted: SystemTime,
+    loaded: SystemTime,
+    saved: Option<SystemTime>,
 }
 
 impl Inner {
-    async fn save(
-        &mut self,
-        cipher: &ChaCha20Poly1305,
-        bucket: &s3::Bucket,
-    ) -> Result<(), SignalStateError> {
-        let state = pack_state(cipher, self.dir.path())?;
-        self.version += 1;
-        let version = self.version;
-        log::info!("Persisting state as {version}");
-        bucket.put_object(version.to_string(), &state).await?;
+    /// Persists the state directory as it is, if it has changed.
+    /// Nothing may be using it.
+    async fn save(&mut self, state: &SignalState) -> Result<(), SignalStateError> {
+        let fingerprint = fingerprint_dir(self.dir.path()).await?;
+        if self.fingerprint != Some(fingerprint) {
+            self.version += 1;
+            state
+                .seal(self.dir.path(), self.version, &mut self.chunk_key)
+                .await?;
+            self.fingerprint = Some(fingerprint);
+            self.persisted = SystemTime::now();
+            self.saved = Some(self.persisted);
+        }
         self.dirtied.store(false, Ordering::Release);
-        log::info!("Done persisting state as {version}");
         Ok(())
     }
 
-    async fn load(
-        cipher: &ChaCha20Poly1305,
-        bucket: &s3::Bucket,
-        version: u32,
-    ) -> Result<Self, SignalStateError> {
-        let ciphertext = bucket.get_object(version.to_string()).await?;
-        let s = ciphertext.as_slice();
-        let ns = 12; //<ChaCha20Poly1305 as AeadCore>::NonceSize;
-        if s.len() <= ns {
-            return Err(SignalStateError::CiphertextTooShort);
-        }
-        let tar_gz = cipher.decrypt((&s[0..ns]).into(), &s[ns..])?;
-        let cursor = std::io::Cursor::new(&tar_gz);
-        let tar = flate2::read::GzDecoder::new(cursor);
-        let mut archive = tar::Archive::new(tar);
-        let dir = tempfile::tempdir()?;
-        archive.unpack(dir.path())?;
-        log::info!(
-            "Loaded state at version {version} into {}",
-            dir.path().display()
-        );
-        Ok(Self {
-            version,
-            dir,
-            dirtied: AtomicBool::new(false),
-        })
+    /// Has the state persisted soon as the version after `version`,
+    /// whether or not it has changed, so that it supersedes `version`.
+    fn mark_newest(&mut self, version: u32) {
+        self.version = version;
+        self.fingerprint = None;
+        self.dirtied.store(true, Ordering::Release);
+    }
+
+    /// Has the state persisted soon with the newest key, in new chunks
+    /// since the old ones could be read by whoever had the old key.
+    fn reseal(&mut self) {
+        self.fingerprint = None;
+        self.chunk_key = None;
+        self.dirtied.store(true, Ordering::Release);
+    }
+
+    /// Whether the state directory has changed since it was last loaded
+    /// or saved, however it was changed.
+    async fn changed(&self) -> Result<bool, SignalStateError> {
+        let Some(saved) = self.fingerprint else {
+            return Ok(true);
+        };
+        Ok(fingerprint_dir(self.dir.path()).await? != saved)
+    }
+
+    /// Copies the state directory so that it can be persisted while
+    /// signal-cli goes on using it. Nothing may be using it meanwhile.
+    async fn snapshot(&self) -> Result<TempDir, SignalStateError> {
+        let snapshot = tempfile::tempdir()?;
+        let from = self.dir.path().to_owned();
+        let to = snapshot.path().to_owned();
+        tokio::task::spawn_blocking(move || copy_dir(&from, &to))
+            .await
+            .map_err(std::io::Error::other)??;
+        Ok(snapshot)
     }
+
+    async fn load(state: &SignalState, version: u32) -> Result<Self, SignalStateError> {
+        let start = Instant::now();
+        let r = async {
+            let dir = tempfile::tempdir()?;
+            let chunk_key = match state
+                .sealer
+                .open(state.store.as_ref(), version, dir.path())
+                .await
+            {
+                Ok(chunk_key) => chunk_key,
+                Err(e) => {
+                    discard(dir);
+                    return Err(e);
+                }
+            };
+            let fingerprint = fingerprint_dir(dir.path()).await?;
+            log::info!(
+                "Loaded {} at version {version} into {}",
+                state.name(),
+                dir.path().display()
+            );
+            if !state.is_secondary {
+                crate::metrics::STATE_VERSION.set(version.into());
+            }
+            Ok::<Self, SignalStateError>(Self {
+                version,
+                dir: Arc::new(dir),
+                dirtied: AtomicBool::new(false),
+                fingerprint: Some(fingerprint),
+                chunk_key,
+                persisted: SystemTime::now(),
+                loaded: SystemTime::now(),
+                saved: None,
+            })
+        }
+        .await;
+        observe_state_operation("load", start, &r);
+        r
+    }
+}
+
+async fn fingerprint_dir(path: &Path) -> Result<Fingerprint, SignalStateError> {
+    let path = path.to_owned();
+    Ok(
+        tokio::task::spawn_blocking(move || fingerprint::fingerprint(&path))
+            .await
+            .map_err(std::io::Error::other)??,
+    )
+}
+
+/// Drops `value` on a blocking thread. For what holds a temporary copy
+/// of the state, which would otherwise be deleted on the runtime thread,
+/// holding it up for as long as that takes with a large state.
+fn discard<T: Send + 'static>(value: T) {
+    tokio::task::spawn_blocking(move || drop(value));
+}
+
+fn copy_dir(from: &Path, to: &Path) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let to = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            std::fs::create_dir(&to)?;
+            copy_dir(&entry.path(), &to)?;
+        } else {
+            std::fs::copy(entry.path(), to)?;
+        }
+    }
+    Ok(())
+}
+
+fn observe_state_operation<T>(operation: &str, start: Instant, r: &Result<T, SignalStateError>) {
+    crate::metrics::STATE_OPERATION_SECONDS
+        .with_label_values(&[operation, crate::metrics::result_label(r)])
+        .observe(start.elapsed().as_secs_f64());
 }
 
 pub struct SignalState {
     inner: tokio::sync::RwLock<Option<Inner>>,
+    /// Kept by signal-cli invocations instead of a lock on `inner`, and
+    /// raised to have the state directory to ourselves.
+    barrier: barrier::Barrier,
+    /// Given by `on_raise`.
+    releases: Mutex<Vec<Release>>,
+    /// Held while persisting, so that versions are persisted one at a
+    /// time and in order.
+    saving: tokio::sync::Mutex<()>,
+    /// Held by each invocation, since signal-cli processes sharing a
+    /// state directory can corrupt its account database.
+    invoking: tokio::sync::Mutex<()>,
+    /// Held by each `StateGuard`, which may write our own files into the
+    /// state directory, and exclusively while the directory is compared
+    /// and copied, so that none of them is copied half-written.
+    app_data: tokio::sync::RwLock<()>,
+    available: tokio::sync::watch::Sender<bool>,
+    dirtied: tokio::sync::Notify,
+    /// Notified when a new version may have been stored by someone else.
+    stored: tokio::sync::Notify,
+    /// Set when another instance is found to be writing the same state
+    /// storage. Persisting our diverged state would clobber theirs.
+    read_only: AtomicBool,
+    store: Arc<dyn StateStore>,
+    /// Why the state storage could not be used the last time it was
+    /// tried, if it could not.
+    storage_error: Mutex<Option<String>>,
+    sealer: Sealer,
+    /// `--state-prune-age`.
+    prune_age: Option<Duration>,
+    /// `--state-sync-interval`.
+    sync_interval: Duration,
+    /// `--state-error-backoff`.
+    error_backoff: Duration,
+    /// Unhealthy, so that we are not sent traffic, while there is no state.
+    /// The secondary state has none: the primary account can send without it.
+    health: Option<HealthSignaller>,
+    /// The state of the secondary Signal account, if there is one.
+    secondary: Option<Arc<SignalState>>,
+    is_secondary: bool,
+}
+
+pub struct StateStatus {
+    pub version: Option<u32>,
+    pub dirty: bool,
+    pub read_only: bool,
+    pub last_persisted: Option<SystemTime>,
+    pub last_loaded: Option<SystemTime>,
+    pub last_saved: Option<SystemTime>,
+    pub storage_error: Option<String>,
+    /// Changes to the state which have not been persisted for this long
+    /// make it stale: two maintenance cycles.
+    stale_after: Duration,
+}
+
+impl StateStatus {
+    /// Whether there are changes which should have been persisted by now.
+    pub fn stale(&self) -> bool {
+        self.dirty
+            && self
+                .last_persisted
+                .and_then(|t| t.elapsed().ok())
+                .is_some_and(|age| age > self.stale_after)
+    }
+}
+
+/// What `/state` on the diag HTTP server shows.
+#[derive(Serialize)]
+pub struct StateContents {
+    version: Option<u32>,
+    dirty: bool,
+    read_only: bool,
+    /// Where signal-cli is given the state.
+    dir: Option<PathBuf>,
+    #[serde(flatten)]
+    contents: contents::Contents,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    secondary: Option<Box<StateContents>>,
+}
+
+/// Stops whatever goes on using the state directory between invocations.
+pub type Release = Box<dyn Fn() -> BoxFuture<'static, ()> + Send + Sync>;
+
+pub struct StateGuard<'a> {
+    inner: tokio::sync::RwLockReadGuard<'a, Option<Inner>>,
+    dirtied: &'a tokio::sync::Notify,
+    _app_data: tokio::sync::RwLockReadGuard<'a, ()>,
+}
+
+/// Use of the state directory by signal-cli, which it is not copied or
+/// replaced during.
+pub struct Invocation<'a> {
+    dir: Arc<TempDir>,
+    _active: barrier::Active<'a>,
+    _turn: tokio::sync::MutexGuard<'a, ()>,
+}
+
+/// Counts an invocation as waiting its turn for as long as it exists.
+struct Waiting(prometheus::IntGauge);
+
+impl Waiting {
+    fn new(gauge: prometheus::IntGauge) -> Self {
+        gauge.inc();
+        Self(gauge)
+    }
 }
 
-pub struct StateGuard<'a>(tokio::sync::RwLockReadGuard<'a, Option<Inner>>);
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+impl Invocation<'_> {
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+}
 
 impl<'a> StateGuard<'a> {
-    pub fn path(&'a self) -> Option<&'a Path> {
-        self.0.as_ref().map(|inner| {
-            inner.dirtied.store(true, Ordering::Release);
-            inner.dir.path()
-        })
+    /// Read one of our own files that are persisted along with the
+    /// signal-cli state.
+    pub fn read_app_data<T: DeserializeOwned>(
+        &self,
+        name: &str,
+    ) -> Result<Option<T>, SignalStateError> {
+        let Some(ref inner) = *self.inner else {
+            return Err(SignalStateError::NoStateAvailable);
+        };
+        match std::fs::read(inner.dir.path().join(APP_DATA_DIR).join(name)) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn write_app_data<T: Serialize>(
+        &self,
+        name: &str,
+        value: &T,
+    ) -> Result<(), SignalStateError> {
+        let Some(ref inner) = *self.inner else {
+            return Err(SignalStateError::NoStateAvailable);
+        };
+        let dir = inner.dir.path().join(APP_DATA_DIR);
+        std::fs::create_dir_all(&dir)?;
+        let tmp = dir.join(format!(".{name}.tmp"));
+        std::fs::write(&tmp, serde_json::to_vec(value)?)?;
+        std::fs::rename(tmp, dir.join(name))?;
+        inner.dirtied.store(true, Ordering::Release);
+        self.dirtied.notify_one();
+        Ok(())
     }
 }
 
 impl SignalState {
     pub async fn get(&self) -> StateGuard<'_> {
-        StateGuard(self.inner.read().await)
+        let inner = self.inner.read().await;
+        StateGuard {
+            inner,
+            dirtied: &self.dirtied,
+            _app_data: self.app_data.read().await,
+        }
+    }
+
+    /// The state directory for signal-cli to use, which is assumed to
+    /// change it, or `None` if no state is loaded. Persisting the state
+    /// and other invocations wait for it to be dropped, so it is held
+    /// only for as long as signal-cli runs.
+    pub async fn invocation(&self) -> Option<Invocation<'_>> {
+        let account = if self.is_secondary {
+            "secondary"
+        } else {
+            "primary"
+        };
+        let start = Instant::now();
+        let turn = {
+            let _waiting =
+                Waiting::new(crate::metrics::SIGNAL_CLI_WAITING.with_label_values(&[account]));
+            self.invoking.lock().await
+        };
+        crate::metrics::SIGNAL_CLI_WAIT_SECONDS
+            .with_label_values(&[account])
+            .observe(start.elapsed().as_secs_f64());
+        let active = self.barrier.enter().await;
+        let inner = self.inner.read().await;
+        let inner = inner.as_ref()?;
+        inner.dirtied.store(true, Ordering::Release);
+        self.dirtied.notify_one();
+        Some(Invocation {
+            dir: Arc::clone(&inner.dir),
+            _active: active,
+            _turn: turn,
+        })
+    }
+
+    /// Has `release` called whenever the state directory is about to be
+    /// copied or replaced, once the invocations using it have finished.
+    /// For a signal-cli process kept running between invocations, which
+    /// would otherwise go on using a directory being copied, or one that
+    /// has been replaced and deleted.
+    pub fn on_raise(&self, release: Release) {
+        self.releases.lock().unwrap().push(release);
+    }
+
+    /// Raises the barrier and waits for everything given to `on_raise`
+    /// to let go of the state directory.
+    async fn raise(&self) -> barrier::Raised<'_> {
+        let raised = self.barrier.raise().await;
+        let releases = self
+            .releases
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|release| release())
+            .collect::<Vec<_>>();
+        futures::future::join_all(releases).await;
+        raised
+    }
+
+    /// Packs, encrypts and stores the directory as `version`.
+    async fn seal(
+        &self,
+        path: &Path,
+        version: u32,
+        chunk_key: &mut Option<ChunkKey>,
+    ) -> Result<(), SignalStateError> {
+        let start = Instant::now();
+        log::info!("Persisting {} as {version}", self.name());
+        let r = self
+            .sealer
+            .seal(path, self.store.as_ref(), version, chunk_key)
+            .await;
+        observe_state_operation("save", start, &r);
+        r?;
+        log::info!("Done persisting {} as {version}", self.name());
+        if !self.is_secondary {
+            crate::metrics::STATE_VERSION.set(version.into());
+        }
+        Ok(())
+    }
+
+    /// Persists the state if it has changed. Signal-cli is only kept
+    /// from using it while it is compared and copied, not while it is
+    /// stored.
+    async fn save(&self) -> Result<(), SignalStateError> {
+        let _saving = self.saving.lock().await;
+        let (snapshot, version, mut chunk_key) = {
+            let _raised = self.raise().await;
+            let inner = self.inner.read().await;
+            let _app_data = self.app_data.write().await;
+            let inner = inner.as_ref().ok_or(SignalStateError::NoStateAvailable)?;
+            self.prune(inner.dir.path()).await;
+            if !inner.changed().await? {
+                inner.dirtied.store(false, Ordering::Release);
+                return Ok(());
+            }
+            let snapshot = inner.snapshot().await?;
+            inner.dirtied.store(false, Ordering::Release);
+            (snapshot, inner.version + 1, inner.chunk_key.clone())
+        };
+        let r = async {
+            let fingerprint = fingerprint_dir(snapshot.path()).await?;
+            self.seal(snapshot.path(), version, &mut chunk_key).await?;
+            Ok(fingerprint)
+        }
+        .await;
+        discard(snapshot);
+        let mut inner = self.inner.write().await;
+        if let Some(inner) = inner.as_mut() {
+            inner.version = version;
+            match r {
+                Ok(fingerprint) => {
+                    inner.fingerprint = Some(fingerprint);
+                    inner.chunk_key = chunk_key;
+                    inner.persisted = SystemTime::now();
+                    inner.saved = Some(inner.persisted);
+                }
+                // Whatever changed since the snapshot still needs saving.
+                Err(_) => inner.dirtied.store(true, Ordering::Release),
+            }
+        }
+        r.map(|_| ())
+    }
+
+    /// Deletes old files from the state directory for
+    /// `--state-prune-age`. Nothing may be using it meanwhile. Failing
+    /// to is only logged, so as not to stop the state being persisted.
+    async fn prune(&self, dir: &Path) {
+        let Some(age) = self.prune_age else {
+            return;
+        };
+        let path = dir.to_owned();
+        match tokio::task::spawn_blocking(move || prune::prune(&path, age)).await {
+            Ok(Ok(0)) => (),
+            Ok(Ok(pruned)) => {
+                log::info!("Pruned {pruned} old files from the {}", self.name());
+                crate::metrics::STATE_PRUNED_FILES.inc_by(pruned);
+            }
+            Ok(Err(e)) => log::error!("Pruning the {}: {e}", self.name()),
+            Err(e) => log::error!("Pruning the {}: {e}", self.name()),
+        }
+    }
+
+    /// Resolves once the state has been dirtied and then left alone
+    /// for `window`.
+    async fn settled_after_dirtied(&self, window: Duration) {
+        self.dirtied.notified().await;
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(window) => return,
+                _ = self.dirtied.notified() => (),
+            }
+        }
+    }
+
+    pub async fn status(&self) -> StateStatus {
+        let inner = self.inner.read().await;
+        StateStatus {
+            version: inner.as_ref().map(|inner| inner.version),
+            dirty: inner
+                .as_ref()
+                .is_some_and(|inner| inner.dirtied.load(Ordering::Acquire)),
+            read_only: self.read_only.load(Ordering::Acquire),
+            last_persisted: inner.as_ref().map(|inner| inner.persisted),
+            last_loaded: inner.as_ref().map(|inner| inner.loaded),
+            last_saved: inner.as_ref().and_then(|inner| inner.saved),
+            storage_error: self.storage_error.lock().unwrap().clone(),
+            stale_after: 2 * self.sync_interval,
+        }
+    }
+
+    pub async fn contents(&self) -> Result<StateContents, SignalStateError> {
+        let (version, dirty, dir) = match *self.inner.read().await {
+            Some(ref inner) => (
+                Some(inner.version),
+                inner.dirtied.load(Ordering::Acquire),
+                Some(Arc::clone(&inner.dir)),
+            ),
+            None => (None, false, None),
+        };
+        let path = dir.as_ref().map(|dir| dir.path().to_owned());
+        let contents = match dir {
+            // Keeping the directory until it has been looked at.
+            Some(dir) => tokio::task::spawn_blocking(move || contents::contents(dir.path()))
+                .await
+                .map_err(std::io::Error::other)??,
+            None => contents::Contents::default(),
+        };
+        let secondary = match self.secondary {
+            Some(ref secondary) => Some(Box::new(Box::pin(secondary.contents()).await?)),
+            None => None,
+        };
+        Ok(StateContents {
+            version,
+            dirty,
+            read_only: self.read_only.load(Ordering::Acquire),
+            dir: path,
+            contents,
+            secondary,
+        })
+    }
+
+    fn set_storage_error(&self, error: Option<String>) {
+        *self.storage_error.lock().unwrap() = error;
+    }
+
+    /// Saves the state now if it is dirty, rather than at the next
+    /// maintenance cycle.
+    pub async fn flush(&self) -> Result<(), SignalStateError> {
+        if self.read_only.load(Ordering::Acquire) {
+            return Err(SignalStateError::ReadOnly);
+        }
+        self.save().await
+    }
+
+    /// Saves any changes and then loads `version` in place of the current
+    /// state. If there are newer versions, it is marked dirty so that it
+    /// is saved again as the newest and the next maintenance cycle does
+    /// not switch back.
+    pub async fn reload(&self, version: u32) -> Result<(), SignalStateError> {
+        if self.read_only.load(Ordering::Acquire) {
+            return Err(SignalStateError::ReadOnly);
+        }
+        let _saving = self.saving.lock().await;
+        let _raised = self.raise().await;
+        let mut inner = self.inner.write().await;
+        let current = inner.as_mut().ok_or(SignalStateError::NoStateAvailable)?;
+        current.save(self).await?;
+        let versions = self.store.list().await?;
+        if !versions.iter().any(|v| v.version == version) {
+            return Err(SignalStateError::NoSuchVersion(version));
+        }
+        let newest = versions
+            .into_iter()
+            .map(|v| v.version)
+            .max()
+            .unwrap_or(version);
+        let mut loaded = Inner::load(self, version).await?;
+        if newest > version {
+            loaded.mark_newest(newest);
+            self.dirtied.notify_one();
+        }
+        if let Some(old) = inner.replace(loaded) {
+            discard(old);
+        }
+        Ok(())
+    }
+
+    fn set_available(&self, available: bool) {
+        self.available.send_replace(available);
+        if let Some(ref health) = self.health {
+            health.set_healthy(available);
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        if self.is_secondary {
+            "secondary state"
+        } else {
+            "state"
+        }
+    }
+
+    /// The state of the secondary Signal account, from
+    /// `--secondary-state-prefix`.
+    pub fn secondary(&self) -> Option<&Arc<SignalState>> {
+        self.secondary.as_ref()
+    }
+
+    /// Stops persisting this state and the secondary's, because another
+    /// instance is now using the state storage.
+    fn stop_persisting(&self) {
+        self.read_only.store(true, Ordering::Release);
+        if let Some(ref secondary) = self.secondary {
+            secondary.read_only.store(true, Ordering::Release);
+        }
+    }
+
+    pub fn is_available(&self) -> bool {
+        *self.available.borrow()
+    }
+
+    /// Resolves once some version of the state has been loaded.
+    pub async fn wait_available(&self) {
+        let _ = self.available.subscribe().wait_for(|a| *a).await;
+    }
+
+    /// Every version in the state storage, oldest first.
+    pub async fn versions(&self) -> Result<Vec<StoredVersion>, SignalStateError> {
+        let mut versions = self.store.list().await?;
+        versions.sort_by_key(|v| v.version);
+        Ok(versions)
     }
+
+    /// Decrypts `version`, or else the newest version, from the state
+    /// storage and writes it to `out` as a tarball. Returns the version.
+    pub async fn export<W: std::io::Write + Send + 'static>(
+        &self,
+        version: Option<u32>,
+        out: W,
+    ) -> Result<u32, SignalStateError> {
+        let version = match version {
+            Some(v) => v,
+            None => self
+                .store
+                .list()
+                .await?
+                .into_iter()
+                .map(|v| v.version)
+                .max()
+                .ok_or(SignalStateError::NoStateAvailable)?,
+        };
+        let inner = Inner::load(self, version).await?;
+        tokio::task::spawn_blocking(move || {
+            let mut tar = tar::Builder::new(out);
+            tar.append_dir_all(".", inner.dir.path())?;
+            tar.into_inner()?.flush()
+        })
+        .await
+        .map_err(std::io::Error::other)??;
+        Ok(version)
+    }
+
+    /// Replaces the loaded state with the contents of a tarball, such as
+    /// one from `export`, and persists it as a new version right away.
+    /// Returns the new version.
+    pub async fn import<R: std::io::Read + Send + 'static>(
+        &self,
+        tar: R,
+    ) -> Result<u32, SignalStateError> {
+        if self.read_only.load(Ordering::Acquire) {
+            return Err(SignalStateError::ReadOnly);
+        }
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().to_owned();
+        tokio::task::spawn_blocking(move || tar::Archive::new(tar).unpack(path))
+            .await
+            .map_err(std::io::Error::other)??;
+        let _saving = self.saving.lock().await;
+        let _raised = self.raise().await;
+        let mut inner = self.inner.write().await;
+        let current = inner.as_ref().ok_or(SignalStateError::NoStateAvailable)?;
+        let mut imported = Inner {
+            version: current.version,
+            dir: Arc::new(dir),
+            dirtied: AtomicBool::new(true),
+            fingerprint: None,
+            chunk_key: current.chunk_key.clone(),
+            persisted: current.persisted,
+            loaded: current.loaded,
+            saved: current.saved,
+        };
+        imported.save(self).await?;
+        let version = imported.version;
+        if let Some(old) = inner.replace(imported) {
+            discard(old);
+        }
+        Ok(version)
+    }
+}
+
+/// Adds a new key to the keyring file, for `state rotate-key`. Running
+/// instances given the new keyring re-encrypt the state with it.
+pub fn rotate_key(path: &Path) -> Result<(), SignalStateError> {
+    Keyring::rotate(path).map(drop)
 }
 
+#[derive(ResourceDependencies)]
+pub struct SignalStateDependencies(Arc<HealthReporter>);
+
 #[derive(clap::Args)]
 pub struct SignalStateArgs {
+    /// File containing the raw state encryption key.
+    #[arg(long, required_unless_present_any = ["encryption_keyring", "kms_key", "encryption_passphrase_file"])]
+    encryption_key: Option<PathBuf>,
+    /// Keyring file of state encryption keys, which allows them to be
+    /// rotated with `state rotate-key`.
+    #[arg(long, conflicts_with = "encryption_key")]
+    encryption_keyring: Option<PathBuf>,
+    /// Encrypt each version of the state with a new data key wrapped by
+    /// this KMS key: `gcp-kms://projects/P/locations/L/keyRings/R/cryptoKeys/K`
+    /// or `aws-kms://arn:aws:kms:REGION:ACCOUNT:key/ID`. Keys from
+    /// `--encryption-key` or `--encryption-keyring` are then only used
+    /// to read older versions.
     #[arg(long)]
-    encryption_key: PathBuf,
+    kms_key: Option<String>,
+    /// Derive the state encryption key from the passphrase on the first
+    /// line of this file, or prompt for it if this is `-`. As with
+    /// `--kms-key`, other keys are then only used to read older versions.
+    #[arg(long, conflicts_with = "kms_key")]
+    encryption_passphrase_file: Option<PathBuf>,
     #[arg(long)]
     bootstrap: Option<PathBuf>,
-}
-
-fn pack_state<P: AsRef<Path>>(
-    cipher: &ChaCha20Poly1305,
-    path: P,
-) -> Result<Vec<u8>, SignalStateError> {
-    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
-    let mut tar_gz = Vec::new();
-    let enc = flate2::write::GzEncoder::new(&mut tar_gz, Compression::default());
-    let mut tar = tar::Builder::new(enc);
-    tar.append_dir_all("", path)?;
-    tar.finish()?;
-    drop(tar);
-    let ciphertext = cipher.encrypt(&nonce, &*tar_gz)?;
-    Ok([nonce.as_slice(), &ciphertext]
-        .into_iter()
-        .flatten()
-        .copied()
-        .collect())
+    /// Load the state but never write to the state storage: take no
+    /// lease, delete no old versions and persist nothing. For looking at
+    /// the state while another instance is using it.
+    #[arg(long, conflicts_with = "bootstrap")]
+    state_read_only: bool,
+    /// Load this version of the state at startup instead of the newest,
+    /// to recover from a bad state having been persisted. Unless
+    /// `--state-read-only` is given, it is then persisted again as the
+    /// newest version. The Admin service's `Reload` does the same
+    /// without a restart.
+    #[arg(long, conflicts_with = "bootstrap")]
+    state_version: Option<u32>,
+    /// Persist the state once it has been this long since it was
+    /// last used, rather than waiting for the next maintenance cycle.
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "30s")]
+    state_flush_debounce: Duration,
+    /// How often to check the state storage for new versions and the
+    /// state for changes which were not noticed, persisting them, and to
+    /// delete old versions. This bounds how much is lost if the state is
+    /// changed without signal-pager noticing.
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "15m")]
+    state_sync_interval: Duration,
+    /// How long to wait before trying again when the state storage
+    /// cannot be listed even after retries, or state storage
+    /// notifications cannot be received.
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "30s")]
+    state_error_backoff: Duration,
+    /// Keep the state of a secondary Signal account, for
+    /// `--signal-secondary-phone-number`, in the same state storage
+    /// with this prefix on its object names, like `secondary/`. With
+    /// `--state-dir` it is a subdirectory. It is encrypted the same way.
+    #[arg(long)]
+    secondary_state_prefix: Option<String>,
+    /// Like `--bootstrap` for the secondary account's state.
+    #[arg(
+        long,
+        requires = "secondary_state_prefix",
+        conflicts_with = "state_read_only"
+    )]
+    secondary_bootstrap: Option<PathBuf>,
+    /// Store each version of the state as chunks cut where its contents
+    /// say, so that versions share the chunks which did not change and
+    /// only new ones are uploaded. Versions stored either way are read.
+    #[arg(long)]
+    state_chunked: bool,
+    /// Authenticate each version of the state along with this name and
+    /// its version number, so that a version copied from the state
+    /// storage of another deployment, or renamed, is refused. Versions
+    /// stored under another name cannot be read.
+    #[arg(long, default_value = "")]
+    state_deployment_id: String,
+    /// Refuse versions of the state which are not bound to their version
+    /// number and `--state-deployment-id`, because they were stored by a
+    /// release from before versions were bound, so that such a version
+    /// copied or renamed into the state storage cannot be loaded either.
+    #[arg(long)]
+    state_require_bound: bool,
+    #[command(flatten)]
+    compression: compression::CompressionArgs,
+    #[command(flatten)]
+    retention: retention::RetentionArgs,
+    #[command(flatten)]
+    verify: verify::VerifyArgs,
+    #[command(flatten)]
+    prune: prune::PruneArgs,
+    #[command(flatten)]
+    notification: notify::NotificationArgs,
+    #[command(flatten)]
+    lease: lease::LeaseArgs,
+    #[command(flatten)]
+    store: StateStoreArgs,
 }
 
 pin_project! {
@@ -194,176 +967,634 @@ enum MaintenanceAction {
     Reload(u32),
 }
 
+impl SignalState {
+    /// Keeps the loaded state in step with the state storage: loads the
+    /// newest version, persists changes and deletes old versions. Only
+    /// returns if there is nothing to load, or if `pinned`, the version
+    /// to load first instead of the newest, cannot be loaded. Versions
+    /// which cannot be loaded are quarantined and passed over for older
+    /// ones, and then the state is persisted as newer than them, as it is
+    /// when `pinned` is older than the newest.
+    async fn maintain(
+        &self,
+        bootstrap: Option<PathBuf>,
+        mut pinned: Option<u32>,
+        retention: &retention::RetentionArgs,
+        flush_debounce: Duration,
+        storage_read_only: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let store = self.store.as_ref();
+        let mut seen_version: u32 = 0;
+        // Versions which could not be loaded and are still listed, such
+        // as with --state-read-only.
+        let mut bad_versions = std::collections::HashSet::new();
+        // Of those, the ones not yet quarantined because no older version
+        // sealed the same way has been loaded, which would show that they
+        // are corrupt rather than that our keys or --state-deployment-id
+        // are wrong.
+        let mut suspects = std::collections::BTreeSet::new();
+        // The last version loaded and how it was sealed.
+        let mut proven = None;
+        if let Some(bootstrap) = bootstrap {
+            log::info!("Setting initial {} as 0", self.name());
+            self.sealer.seal(&bootstrap, store, 0, &mut None).await?;
+            log::info!("Done bootstrap");
+        }
+        loop {
+            let versions = match retry::STORAGE
+                .retry("listing", StoreError::is_transient, || store.list())
+                .await
+            {
+                Ok(l) => {
+                    self.set_storage_error(None);
+                    l
+                }
+                Err(e) => {
+                    log::warn!("Listing {} storage: {e}", self.name());
+                    self.set_storage_error(Some(e.to_string()));
+                    tokio::time::sleep(self.error_backoff).await;
+                    continue;
+                }
+            };
+            let newest_version = versions.iter().map(|v| v.version).max();
+            let best_version = versions
+                .iter()
+                .map(|v| v.version)
+                .filter(|v| !bad_versions.contains(v))
+                .max();
+            let delete_list = if storage_read_only {
+                Vec::new()
+            } else {
+                retention.expired(&versions, seen_version)
+            };
+            if !delete_list.is_empty() {
+                log::info!("Deleting old {} {delete_list:?}", self.name());
+                delete_list
+                    .into_iter()
+                    .map(|v| {
+                        retry::STORAGE.retry("deleting", StoreError::is_transient, move || {
+                            store.delete(v)
+                        })
+                    })
+                    .collect::<FuturesUnordered<_>>()
+                    .for_each_concurrent(None, |r| async move {
+                        if let Err(e) = r {
+                            log::error!("Deleting old state: {e}");
+                        }
+                    })
+                    .await;
+                if let Err(e) = self.collect_chunks().await {
+                    log::error!("Deleting unused chunks of the {}: {e}", self.name());
+                }
+            }
+            if let Some(v) = pinned
+                && !versions.iter().any(|stored| stored.version == v)
+            {
+                return Err(SignalStateError::NoSuchVersion(v).into());
+            }
+            let action = match *self.inner.read().await {
+                None => match pinned.or(best_version) {
+                    Some(v) => MaintenanceAction::Reload(v),
+                    None => {
+                        return Err(SignalStateError::NoStateAvailable.into());
+                    }
+                },
+                Some(ref inner) => {
+                    if self.read_only.load(Ordering::Acquire) {
+                        MaintenanceAction::NoAction
+                    } else if inner.dirtied.load(Ordering::Acquire)
+                        || inner.changed().await.unwrap_or(true)
+                    {
+                        MaintenanceAction::Flush
+                    } else {
+                        match best_version {
+                            Some(v) if v > inner.version => {
+                                log::warn!(
+                                    "Version mismatch: we have {} but {v} is available",
+                                    inner.version
+                                );
+                                MaintenanceAction::Reload(v)
+                            }
+                            // Going back to an older version would rewind
+                            // the Signal session state. Only --state-version
+                            // or the Admin service's Reload may.
+                            Some(v) if v < inner.version => {
+                                log::warn!(
+                                    "Not going back from {} {} to {v}, the newest listed",
+                                    self.name(),
+                                    inner.version
+                                );
+                                MaintenanceAction::NoAction
+                            }
+                            _ => MaintenanceAction::NoAction,
+                        }
+                    }
+                }
+            };
+            let mut fall_back = false;
+            match action {
+                MaintenanceAction::NoAction => (),
+                MaintenanceAction::Flush => {
+                    let saved = retry::STORAGE
+                        .retry("saving", SignalStateError::is_transient, || self.save())
+                        .await;
+                    if let Err(e) = saved {
+                        if let SignalStateError::StoreError(StoreError::Conflict(v)) = e {
+                            log::error!(
+                                "State version {v} was written by someone else. Another instance is using the same state storage! No longer persisting the {}.",
+                                self.name()
+                            );
+                            self.read_only.store(true, Ordering::Release);
+                            crate::metrics::STATE_READ_ONLY.set(1);
+                        } else {
+                            log::error!("Error persisting {}: {e}", self.name());
+                            self.set_storage_error(Some(e.to_string()));
+                        }
+                    }
+                }
+                MaintenanceAction::Reload(version) => {
+                    let _saving = self.saving.lock().await;
+                    let _raised = self.raise().await;
+                    let mut inner = self.inner.write().await;
+                    if !inner
+                        .as_ref()
+                        .map(|inner| inner.dirtied.load(Ordering::Acquire))
+                        .unwrap_or(false)
+                    {
+                        let loaded = retry::STORAGE
+                            .retry("loading", SignalStateError::is_transient, || {
+                                Inner::load(self, version)
+                            })
+                            .await;
+                        match loaded {
+                            Ok(mut r) => {
+                                if let Some(newest) = newest_version.filter(|&n| n > version)
+                                    && !self.read_only.load(Ordering::Acquire)
+                                {
+                                    if pinned.is_some() {
+                                        log::warn!(
+                                            "Persisting {} {version} from --state-version as newer than {newest}",
+                                            self.name()
+                                        );
+                                    } else {
+                                        log::warn!(
+                                            "Persisting {} {version} as newer than {newest}, which could not be loaded",
+                                            self.name()
+                                        );
+                                    }
+                                    r.mark_newest(newest);
+                                    self.dirtied.notify_one();
+                                }
+                                if let Some(old) = inner.replace(r) {
+                                    discard(old);
+                                }
+                                self.set_available(true);
+                                seen_version = version;
+                                pinned = None;
+                                match self.sealer.sealing(store, version).await {
+                                    Ok(sealing) => {
+                                        if self.sealer.outdated(&sealing)
+                                            && !self.read_only.load(Ordering::Acquire)
+                                            && let Some(current) = inner.as_mut()
+                                        {
+                                            log::info!(
+                                                "Re-encrypting {} {version} with the newest key",
+                                                self.name()
+                                            );
+                                            current.reseal();
+                                            self.dirtied.notify_one();
+                                        }
+                                        proven = Some((version, sealing));
+                                    }
+                                    Err(e) => log::warn!(
+                                        "Reading how {} {version} was sealed: {e}",
+                                        self.name()
+                                    ),
+                                }
+                                if let Some(ref proven) = proven {
+                                    self.quarantine(&mut suspects, proven).await;
+                                }
+                            }
+                            // Rather than fall back to another version.
+                            Err(e) if pinned.is_some() && e.is_bad_version() => {
+                                return Err(e.into());
+                            }
+                            Err(e) if e.is_bad_version() => {
+                                log::error!(
+                                    "{} {version} cannot be loaded and is being passed over for an older version: {e}",
+                                    self.name()
+                                );
+                                crate::metrics::STATE_BAD_VERSIONS.inc();
+                                bad_versions.insert(version);
+                                if !self.read_only.load(Ordering::Acquire) {
+                                    suspects.insert(version);
+                                    if let Some(ref proven) = proven {
+                                        self.quarantine(&mut suspects, proven).await;
+                                    }
+                                    if let Some(current) = inner.as_mut()
+                                        && current.version < version
+                                    {
+                                        current.mark_newest(version);
+                                        self.dirtied.notify_one();
+                                    }
+                                }
+                                fall_back = true;
+                            }
+                            Err(e) => {
+                                log::error!("Failed to load {} {version}: {e}", self.name());
+                            }
+                        }
+                    }
+                }
+            }
+            if fall_back {
+                continue;
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(self.sync_interval) => (),
+                _ = self.settled_after_dirtied(flush_debounce) => (),
+                _ = self.stored.notified() => (),
+            }
+        }
+    }
+
+    /// Moves the `suspects` newer than the `proven` version, which was
+    /// loaded, to quarantine if they were sealed the same way, since then
+    /// they must be corrupt. The rest are left alone for whoever has the
+    /// keys for them.
+    async fn quarantine(
+        &self,
+        suspects: &mut std::collections::BTreeSet<u32>,
+        (loaded, sealing): &(u32, seal::Sealing),
+    ) {
+        let newer = suspects.split_off(&(loaded + 1));
+        for version in newer {
+            match self.sealer.sealing(self.store.as_ref(), version).await {
+                Ok(ref s) if s == sealing => match self.store.quarantine(version).await {
+                    Ok(()) => log::warn!("Moved {} {version} to quarantine/", self.name()),
+                    Err(e) => log::error!("Quarantining {} {version}: {e}", self.name()),
+                },
+                Ok(_) => log::warn!(
+                    "Not quarantining {} {version}: it was sealed differently from {loaded}, which loaded",
+                    self.name()
+                ),
+                Err(e) => log::warn!(
+                    "Not quarantining {} {version}: cannot tell how it was sealed: {e}",
+                    self.name()
+                ),
+            }
+        }
+    }
+
+    /// Deletes the chunks which no version refers to any more. Nothing
+    /// is persisted meanwhile, so a version being stored cannot lose the
+    /// chunks stored ahead of it. If any version cannot be read nothing
+    /// is deleted.
+    async fn collect_chunks(&self) -> Result<(), SignalStateError> {
+        let _saving = self.saving.lock().await;
+        let chunks = self.store.list_chunks().await?;
+        if chunks.is_empty() {
+            return Ok(());
+        }
+        let versions = self.store.list().await?.into_iter().map(|v| v.version);
+        let used = self
+            .sealer
+            .chunks_in_use(self.store.as_ref(), versions)
+            .await?;
+        let unused: Vec<_> = chunks.into_iter().filter(|c| !used.contains(c)).collect();
+        if !unused.is_empty() {
+            log::info!(
+                "Deleting {} unused chunks of the {}",
+                unused.len(),
+                self.name()
+            );
+            unused
+                .iter()
+                .map(|c| self.store.delete_chunk(c))
+                .collect::<FuturesUnordered<_>>()
+                .for_each_concurrent(None, |r| async move {
+                    if let Err(e) = r {
+                        log::error!("Deleting unused chunk: {e}");
+                    }
+                })
+                .await;
+        }
+        Ok(())
+    }
+
+    /// Wakes maintenance of the primary and secondary state when `queue`
+    /// says that a version was stored, so that it is loaded without
+    /// waiting for the next cycle. Never returns.
+    async fn follow_notifications(&self, queue: Option<&notify::Queue>) {
+        let Some(queue) = queue else {
+            return std::future::pending().await;
+        };
+        loop {
+            match queue.receive().await {
+                Ok(keys) => {
+                    for key in keys.iter().filter(|k| notify::is_version(k)) {
+                        log::debug!("Notified that {key} was stored");
+                        crate::metrics::STATE_NOTIFICATIONS.inc();
+                        self.stored.notify_one();
+                        if let Some(ref secondary) = self.secondary {
+                            secondary.stored.notify_one();
+                        }
+                    }
+                }
+                Err(e) => {
+                    log::warn!("Receiving state storage notifications: {e}");
+                    tokio::time::sleep(self.error_backoff).await;
+                }
+            }
+        }
+    }
+
+    /// Every `--state-verify-interval`, checks that the stored versions
+    /// chosen by `verify` can be opened, without loading them. Never
+    /// returns.
+    async fn verify_periodically(&self, verify: &verify::VerifyArgs) {
+        let Some(interval) = verify.interval() else {
+            return std::future::pending().await;
+        };
+        loop {
+            tokio::time::sleep(interval).await;
+            match self.store.list().await {
+                Ok(versions) => {
+                    for (which, version) in verify.pick(&versions) {
+                        self.verify(which, version).await;
+                    }
+                }
+                Err(e) => log::warn!("Listing {} storage to verify it: {e}", self.name()),
+            }
+        }
+    }
+
+    async fn verify(&self, which: &str, version: u32) {
+        let r = async {
+            let dir = tempfile::tempdir()?;
+            let r = self
+                .sealer
+                .open(self.store.as_ref(), version, dir.path())
+                .await;
+            discard(dir);
+            r
+        }
+        .await;
+        match r {
+            Ok(_) => log::info!("Verified stored {} {version}", self.name()),
+            Err(ref e) => {
+                // Unless it was deleted as old meanwhile.
+                if let Ok(versions) = self.store.list().await
+                    && !versions.iter().any(|v| v.version == version)
+                {
+                    return;
+                }
+                log::error!("Stored {} {version} cannot be opened: {e}", self.name());
+            }
+        }
+        crate::metrics::STATE_VERIFICATIONS
+            .with_label_values(&[which, crate::metrics::result_label(&r)])
+            .inc();
+    }
+
+    /// Persists the state one last time, if it changed, and unloads it.
+    async fn shut_down(&self, storage_read_only: bool) -> Result<(), Box<dyn std::error::Error>> {
+        let _saving = self.saving.lock().await;
+        let _raised = self.raise().await;
+        let mut inner = self.inner.write().await;
+        log::info!("SignalState shutdown lock acquired for {}", self.name());
+        self.set_available(false);
+        match inner.take() {
+            None => {
+                log::info!("The {} was never loaded", self.name());
+            }
+            Some(mut inner) => {
+                self.prune(inner.dir.path()).await;
+                if storage_read_only {
+                    log::info!(
+                        "Not persisting final {} with --state-read-only",
+                        self.name()
+                    );
+                } else if self.read_only.load(Ordering::Acquire) {
+                    log::error!(
+                        "Not persisting final {} because another instance is",
+                        self.name()
+                    );
+                } else if inner.changed().await? {
+                    let version = inner.version + 1;
+                    log::info!("Setting final {} as {version}", self.name());
+                    self.sealer
+                        .seal(
+                            inner.dir.path(),
+                            self.store.as_ref(),
+                            version,
+                            &mut inner.chunk_key,
+                        )
+                        .await?;
+                    log::info!("Done cleanup");
+                } else {
+                    log::info!("The {} has not changed", self.name());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl HttpDiagHandler for SignalState {
+    fn install_handlers(self: Arc<Self>, installer: &mut dyn HttpDiagHandlerInstaller) {
+        installer.nest_diag_service(
+            "/state",
+            tower::util::BoxCloneSyncService::new(tower::service_fn(move |_| {
+                let state = Arc::clone(&self);
+                async move {
+                    let response = match state.contents().await {
+                        Ok(contents) => http::Response::builder()
+                            .header(http::header::CONTENT_TYPE, "application/json")
+                            .body(
+                                serde_json::to_vec_pretty(&contents)
+                                    .unwrap_or_default()
+                                    .into(),
+                            ),
+                        Err(e) => http::Response::builder()
+                            .status(http::StatusCode::INTERNAL_SERVER_ERROR)
+                            .body(e.to_string().into()),
+                    };
+                    Ok(response.expect("valid response"))
+                }
+            })),
+        );
+    }
+}
+
 #[resource]
+#[export(dyn HttpDiagHandler)]
 impl Resource for SignalState {
     fn new(
         d: SignalStateDependencies,
         a: SignalStateArgs,
         api: &mut AssemblyRuntime<'_>,
     ) -> Result<Arc<Self>, SignalStateError> {
-        let (cipher, initial_state) = match a.bootstrap {
-            Some(bootstrap) => {
-                let key = ChaCha20Poly1305::generate_key(&mut OsRng);
-                let mut f = std::fs::File::create_new(a.encryption_key)?;
-                f.set_permissions(std::fs::Permissions::from_mode(0o600))?;
-                f.write_all(key.as_slice())?;
-                f.set_permissions(std::fs::Permissions::from_mode(0o400))?;
-                f.sync_all()?;
-                drop(f);
-                let cipher = ChaCha20Poly1305::new(&key);
-                let state = pack_state(&cipher, &bootstrap)?;
-                (cipher, Some(state))
-            }
-            None => {
-                let key = std::fs::read(a.encryption_key)?;
-                let cipher = ChaCha20Poly1305::new_from_slice(&key)?;
-                (cipher, None)
+        let keys = match (a.encryption_keyring, a.encryption_key) {
+            (Some(ref path), _) if a.bootstrap.is_some() && !path.exists() => {
+                Keyring::rotate(path)?
             }
+            (Some(ref path), _) => Keyring::load(path)?,
+            (None, Some(ref path)) if a.bootstrap.is_some() => Keyring::create_key_file(path)?,
+            (None, Some(ref path)) => Keyring::from_key_file(path)?,
+            (None, None) => Keyring::default(),
         };
+        let kms = a.kms_key.map(|k| k.parse()).transpose()?;
+        let passphrase = a
+            .encryption_passphrase_file
+            .as_deref()
+            .map(passphrase::Passphrase::read)
+            .transpose()?;
+        let queue = a.notification.queue()?;
+        let sealer = Sealer::new(keys, kms, passphrase)
+            .chunked(a.state_chunked)
+            .deployment(a.state_deployment_id)
+            .require_bound(a.state_require_bound)
+            .compression(a.compression.compression()?);
+        let store = a.store.store("")?;
+        let storage_read_only = a.state_read_only;
+        let lease = if storage_read_only {
+            None
+        } else {
+            a.lease.into_lease(&store)?.map(Arc::new)
+        };
+        let cleanup_lease = lease.clone();
+        // The secondary state is covered by the primary's lease.
+        let secondary = a
+            .secondary_state_prefix
+            .as_deref()
+            .map(|prefix| {
+                Ok::<_, SignalStateError>(Arc::new(Self {
+                    inner: tokio::sync::RwLock::new(None),
+                    barrier: barrier::Barrier::default(),
+                    releases: Mutex::new(Vec::new()),
+                    saving: tokio::sync::Mutex::new(()),
+                    invoking: tokio::sync::Mutex::new(()),
+                    app_data: tokio::sync::RwLock::new(()),
+                    available: tokio::sync::watch::Sender::new(false),
+                    dirtied: tokio::sync::Notify::new(),
+                    stored: tokio::sync::Notify::new(),
+                    read_only: AtomicBool::new(storage_read_only),
+                    store: a.store.store(prefix)?,
+                    storage_error: Mutex::new(None),
+                    sealer: sealer.clone(),
+                    prune_age: a.prune.age(),
+                    sync_interval: a.state_sync_interval,
+                    error_backoff: a.state_error_backoff,
+                    health: None,
+                    secondary: None,
+                    is_secondary: true,
+                }))
+            })
+            .transpose()?;
         let shared = Arc::new(Self {
             inner: tokio::sync::RwLock::new(None),
+            barrier: barrier::Barrier::default(),
+            releases: Mutex::new(Vec::new()),
+            saving: tokio::sync::Mutex::new(()),
+            invoking: tokio::sync::Mutex::new(()),
+            app_data: tokio::sync::RwLock::new(()),
+            available: tokio::sync::watch::Sender::new(false),
+            dirtied: tokio::sync::Notify::new(),
+            stored: tokio::sync::Notify::new(),
+            read_only: AtomicBool::new(storage_read_only),
+            store,
+            storage_error: Mutex::new(None),
+            sealer,
+            prune_age: a.prune.age(),
+            sync_interval: a.state_sync_interval,
+            error_backoff: a.state_error_backoff,
+            health: Some(d.0.register("signal state")?),
+            secondary,
+            is_secondary: false,
         });
+        let bootstrap = a.bootstrap;
+        let secondary_bootstrap = a.secondary_bootstrap;
+        let flush_debounce = a.state_flush_debounce;
+        let retention = a.retention;
+        let state_version = a.state_version;
+        let verify = a.verify;
         let shared2 = Arc::clone(&shared);
         let shared3 = Arc::clone(&shared);
         let stopper = api.self_stop();
-        let cleanup_bucket = Arc::clone(&d.0);
-        let cleanup_cipher = cipher.clone();
         api.set_task(SignalStateMaintenance::new(
             stopper,
             async move {
-                let bucket = d.0.as_ref().as_ref();
-                let mut seen_version: u32 = 0;
-                if let Some(state) = initial_state {
-                    log::info!("Setting initial state as 0");
-                    bucket.put_object("0", &state).await?;
-                    log::info!("Done bootstrap");
+                if let Some(ref lease) = lease {
+                    lease.acquire().await;
                 }
-                loop {
-                    let mut delete_list = Vec::new();
-                    let bucket_list =
-                        match bucket.list(String::from(""), Some(String::from(""))).await {
-                            Ok(l) => l,
-                            Err(e) => {
-                                log::warn!("Listing bucket: {e}");
-                                tokio::time::sleep(Duration::new(30, 0)).await;
-                                continue;
-                            }
-                        };
-                    let best_version = bucket_list
-                        .into_iter()
-                        .flat_map(|entry| {
-                            entry
-                                .contents
-                                .into_iter()
-                                .filter_map(|obj| obj.key.parse::<u32>().ok())
-                        })
-                        .inspect(|v| {
-                            if *v < seen_version.saturating_sub(20) {
-                                delete_list.push(*v);
-                            }
-                        })
-                        .max();
-                    if !delete_list.is_empty() {
-                        log::info!("Deleting old state {delete_list:?}");
-                        delete_list
-                            .into_iter()
-                            .map(|v| bucket.delete_object(v.to_string()))
-                            .collect::<FuturesUnordered<_>>()
-                            .for_each_concurrent(None, |r| async move {
-                                if let Err(e) = r {
-                                    log::error!("Deleting old state: {e}");
-                                }
-                            })
+                let primary = shared.maintain(
+                    bootstrap,
+                    state_version,
+                    &retention,
+                    flush_debounce,
+                    storage_read_only,
+                );
+                // Trouble with the secondary state must not stop the
+                // primary account from paging, so it is only logged.
+                let secondary = async {
+                    if let Some(ref secondary) = shared.secondary {
+                        let r = secondary
+                            .maintain(
+                                secondary_bootstrap,
+                                None,
+                                &retention,
+                                flush_debounce,
+                                storage_read_only,
+                            )
                             .await;
+                        if let Err(e) = r {
+                            log::error!("Secondary state: {e}");
+                        }
                     }
-                    let action = match *shared.inner.read().await {
-                        None => match best_version {
-                            Some(v) => MaintenanceAction::Reload(v),
-                            None => {
-                                return Err(SignalStateError::NoStateAvailable.into());
-                            }
-                        },
-                        Some(ref inner) => {
-                            if inner.dirtied.load(Ordering::Acquire) {
-                                MaintenanceAction::Flush
-                            } else {
-                                match best_version {
-                                    Some(v) => {
-                                        if inner.version != v {
-                                            log::warn!(
-                                                "Version mismatch: we have {} but {v} is available",
-                                                inner.version
-                                            );
-                                            MaintenanceAction::Reload(v)
-                                        } else {
-                                            MaintenanceAction::NoAction
-                                        }
-                                    }
-                                    None => MaintenanceAction::NoAction,
-                                }
-                            }
+                    std::future::pending().await
+                };
+                let verify = async {
+                    let secondary = async {
+                        if let Some(ref secondary) = shared.secondary {
+                            secondary.verify_periodically(&verify).await;
                         }
                     };
-                    match action {
-                        MaintenanceAction::NoAction => (),
-                        MaintenanceAction::Flush => {
-                            if let Err(e) = shared
-                                .inner
-                                .write()
-                                .await
-                                .as_mut()
-                                .unwrap()
-                                .save(&cipher, bucket)
-                                .await
-                            {
-                                log::error!("Error persisting state: {e}");
-                            }
-                        }
-                        MaintenanceAction::Reload(version) => {
-                            let mut inner = shared.inner.write().await;
-                            if !inner
-                                .as_ref()
-                                .map(|inner| inner.dirtied.load(Ordering::Acquire))
-                                .unwrap_or(false)
-                            {
-                                match Inner::load(&cipher, bucket, version).await {
-                                    Ok(r) => {
-                                        *inner = Some(r);
-                                        seen_version = version;
-                                    }
-                                    Err(e) => {
-                                        log::error!("Failed to load state {version}: {e}");
-                                    }
-                                }
-                            }
-                        }
+                    tokio::join!(shared.verify_periodically(&verify), secondary);
+                    std::future::pending().await
+                };
+                let notifications = async {
+                    shared.follow_notifications(queue.as_ref()).await;
+                    std::future::pending().await
+                };
+                let maintenance = async {
+                    tokio::select! {
+                        r = primary => r,
+                        r = secondary => r,
+                        r = verify => r,
+                        r = notifications => r,
                     }
-                    tokio::time::sleep(MAINTENANCE_INTERVAL).await;
+                };
+                match lease {
+                    None => maintenance.await,
+                    Some(ref lease) => tokio::select! {
+                        r = maintenance => r,
+                        e = lease.hold() => {
+                            // Don't persist over the new holder's state on the way out.
+                            shared.stop_persisting();
+                            Err(e.into())
+                        }
+                    },
                 }
             },
             async move {
                 log::info!("SignalState shutdown requested");
-                let mut inner = shared2.inner.write().await;
-                log::info!("SignalState shutdown lock acquired");
-                match inner.take() {
-                    None => {
-                        log::info!("SignalState was never loaded");
-                    }
-                    Some(inner) => {
-                        if inner.dirtied.load(Ordering::Acquire) {
-                            let state = pack_state(&cleanup_cipher, inner.dir.path())?;
-                            let version = inner.version + 1;
-                            log::info!("Setting final state as {version}");
-                            cleanup_bucket
-                                .as_ref()
-                                .as_ref()
-                                .put_object(version.to_string(), &state)
-                                .await?;
-                            log::info!("Done cleanup");
-                        } else {
-                            log::info!("SignalState is not dirty");
-                        }
-                    }
+                shared2.shut_down(storage_read_only).await?;
+                if let Some(ref secondary) = shared2.secondary {
+                    secondary.shut_down(storage_read_only).await?;
+                }
+                if let Some(lease) = cleanup_lease {
+                    lease.release().await;
                 }
                 Ok(())
             },
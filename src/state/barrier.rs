@@ -0,0 +1,100 @@
+//! Counts the signal-cli invocations using the state directory, so that
+//! it is copied or replaced only while none are, without a lock on the
+//! state being held for as long as signal-cli runs.
+
+use tokio::sync::watch;
+
+#[derive(Default)]
+struct Counts {
+    /// Invocations using the state directory.
+    active: usize,
+    /// Those waiting for, or holding, the directory to themselves.
+    raised: usize,
+}
+
+#[derive(Default)]
+pub struct Barrier(watch::Sender<Counts>);
+
+/// Held by an invocation while it uses the state directory.
+pub struct Active<'a>(&'a Barrier);
+
+impl Drop for Active<'_> {
+    fn drop(&mut self) {
+        self.0.0.send_modify(|c| c.active -= 1);
+    }
+}
+
+/// Held while nothing may use the state directory.
+pub struct Raised<'a>(&'a Barrier);
+
+impl Drop for Raised<'_> {
+    fn drop(&mut self) {
+        self.0.0.send_modify(|c| c.raised -= 1);
+    }
+}
+
+impl Barrier {
+    /// Waits until the barrier is down.
+    pub async fn enter(&self) -> Active<'_> {
+        let mut rx = self.0.subscribe();
+        loop {
+            let _ = rx.wait_for(|c| c.raised == 0).await;
+            let entered = self.0.send_if_modified(|c| {
+                if c.raised > 0 {
+                    return false;
+                }
+                c.active += 1;
+                true
+            });
+            if entered {
+                return Active(self);
+            }
+        }
+    }
+
+    /// Stops any more invocations from starting and waits for those
+    /// running to finish.
+    pub async fn raise(&self) -> Raised<'_> {
+        self.0.send_modify(|c| c.raised += 1);
+        // Lowers it again if this is cancelled.
+        let raised = Raised(self);
+        let _ = self.0.subscribe().wait_for(|c| c.active == 0).await;
+        raised
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    async fn pending<F: Future>(f: F) -> bool {
+        tokio::time::timeout(Duration::from_millis(10), f)
+            .await
+            .is_err()
+    }
+
+    #[tokio::test]
+    async fn raise_waits_for_active() {
+        let barrier = Barrier::default();
+        let active = barrier.enter().await;
+        let raise = barrier.raise();
+        tokio::pin!(raise);
+        assert!(pending(&mut raise).await);
+        assert!(pending(barrier.enter()).await);
+        drop(active);
+        let raised = raise.await;
+        assert!(pending(barrier.enter()).await);
+        drop(raised);
+        let _active = barrier.enter().await;
+    }
+
+    #[tokio::test]
+    async fn cancelled_raise_lowers() {
+        let barrier = Barrier::default();
+        let active = barrier.enter().await;
+        assert!(pending(barrier.raise()).await);
+        drop(active);
+        let _active = barrier.enter().await;
+    }
+}
@@ -0,0 +1,292 @@
+//! Content-defined chunking for `--state-chunked`. The tarball of the
+//! state is cut wherever a rolling hash of the last 64 bytes says so, so
+//! that a change only alters the chunks around it and versions share the
+//! rest. Chunks are named by a keyed hash of their contents, which says
+//! nothing about them to whoever can list the state storage.
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::io::{Read, Write};
+use std::path::Path;
+use tokio::sync::mpsc;
+
+use super::compression::{Compression, Decoder};
+
+pub const KEY_LEN: usize = 32;
+pub const NAME_LEN: usize = 32;
+const NONCE_SIZE: usize = 12;
+const MIN_CHUNK: usize = 16 * 1024;
+pub const MAX_CHUNK: usize = 256 * 1024;
+/// Cuts on average every 64KiB after `MIN_CHUNK`. The high bits of the
+/// hash are used since they depend on more of the bytes before.
+const CUT_MASK: u64 = 0xffff << 48;
+
+/// Random values for the gear hash, from splitmix64.
+const GEAR: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0;
+    let mut i = 0;
+    while i < table.len() {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+pub type Name = [u8; NAME_LEN];
+
+/// Names and encrypts chunks. It is kept in the manifest of each version
+/// and reused for the next so that their chunks are the same.
+#[derive(Clone)]
+pub struct ChunkKey([u8; KEY_LEN]);
+
+fn invalid_chunk<E>(_: E) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid state chunk")
+}
+
+impl ChunkKey {
+    pub fn generate() -> Self {
+        let mut key = [0u8; KEY_LEN];
+        OsRng.fill_bytes(&mut key);
+        Self(key)
+    }
+
+    pub fn from_bytes(key: [u8; KEY_LEN]) -> Self {
+        Self(key)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; KEY_LEN] {
+        &self.0
+    }
+
+    fn derive(&self, purpose: &[u8]) -> Hmac<Sha256> {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.0).expect("any key length");
+        mac.update(purpose);
+        <Hmac<Sha256> as Mac>::new_from_slice(&mac.finalize().into_bytes()).expect("any key length")
+    }
+
+    pub fn name(&self, data: &[u8]) -> Name {
+        let mut mac = self.derive(b"name");
+        mac.update(data);
+        mac.finalize().into_bytes().into()
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(&self.derive(b"encrypt").finalize().into_bytes())
+    }
+
+    /// Compresses and encrypts a chunk, giving a nonce and the ciphertext
+    /// of the compression format byte and the compressed chunk.
+    pub fn seal(&self, data: &[u8], compression: Compression) -> std::io::Result<Vec<u8>> {
+        let mut encoder = compression.encoder(vec![compression.format()])?;
+        encoder.write_all(data)?;
+        let compressed = encoder.finish()?;
+        let mut nonce = [0u8; NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        let ciphertext = self
+            .cipher()
+            .encrypt((&nonce).into(), compressed.as_slice())
+            .map_err(std::io::Error::other)?;
+        Ok([&nonce[..], &ciphertext].concat())
+    }
+
+    /// Undoes `seal`, checking that the chunk is the one named.
+    pub fn open(&self, name: &Name, sealed: &[u8]) -> std::io::Result<Vec<u8>> {
+        let (nonce, ciphertext) = sealed
+            .split_at_checked(NONCE_SIZE)
+            .ok_or_else(|| invalid_chunk(()))?;
+        let compressed = self
+            .cipher()
+            .decrypt(nonce.into(), ciphertext)
+            .map_err(invalid_chunk)?;
+        let (&format, compressed) = compressed.split_first().ok_or_else(|| invalid_chunk(()))?;
+        let mut data = Vec::new();
+        Decoder::new(format, compressed)
+            .map_err(invalid_chunk)?
+            .read_to_end(&mut data)?;
+        if self.name(&data) != *name {
+            return Err(invalid_chunk(()));
+        }
+        Ok(data)
+    }
+}
+
+/// Cuts what is written to it into chunks and hands each to `emit`.
+pub struct Chunker<F> {
+    buf: Vec<u8>,
+    hash: u64,
+    emit: F,
+}
+
+impl<F: FnMut(Vec<u8>) -> std::io::Result<()>> Chunker<F> {
+    pub fn new(emit: F) -> Self {
+        Self {
+            buf: Vec::with_capacity(MAX_CHUNK),
+            hash: 0,
+            emit,
+        }
+    }
+
+    fn cut(&mut self) -> std::io::Result<()> {
+        self.hash = 0;
+        let chunk = std::mem::replace(&mut self.buf, Vec::with_capacity(MAX_CHUNK));
+        (self.emit)(chunk)
+    }
+
+    /// Hands over the last chunk.
+    pub fn finish(mut self) -> std::io::Result<()> {
+        if !self.buf.is_empty() {
+            self.cut()?;
+        }
+        Ok(())
+    }
+}
+
+impl<F: FnMut(Vec<u8>) -> std::io::Result<()>> Write for Chunker<F> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        for &b in data {
+            self.buf.push(b);
+            self.hash = (self.hash << 1).wrapping_add(GEAR[usize::from(b)]);
+            if self.buf.len() >= MAX_CHUNK
+                || (self.buf.len() >= MIN_CHUNK && self.hash & CUT_MASK == 0)
+            {
+                self.cut()?;
+            }
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Adds the directory to the tarball in name order, so that the same
+/// state makes the same tarball and so the same chunks.
+pub fn append_sorted<W: Write>(
+    tar: &mut tar::Builder<W>,
+    dir: &Path,
+    name: &Path,
+) -> std::io::Result<()> {
+    let mut entries = std::fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let name = name.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            tar.append_dir(&name, entry.path())?;
+            append_sorted(tar, &entry.path(), &name)?;
+        } else {
+            tar.append_path_with_name(entry.path(), &name)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads the chunks named in a manifest as they arrive from the state
+/// storage, decrypted and in order.
+pub struct ChunkReader {
+    rx: mpsc::Receiver<std::io::Result<Vec<u8>>>,
+    names: std::vec::IntoIter<Name>,
+    key: ChunkKey,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl ChunkReader {
+    pub fn new(
+        rx: mpsc::Receiver<std::io::Result<Vec<u8>>>,
+        names: Vec<Name>,
+        key: ChunkKey,
+    ) -> Self {
+        Self {
+            rx,
+            names: names.into_iter(),
+            key,
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.buf.len() {
+            let Some(name) = self.names.next() else {
+                return Ok(0);
+            };
+            let sealed = self
+                .rx
+                .blocking_recv()
+                .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::UnexpectedEof))??;
+            self.buf = self.key.open(&name, &sealed)?;
+            self.pos = 0;
+        }
+        let n = out.len().min(self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Data which looks random but is the same every time, so that where the
+/// cuts fall is too.
+#[cfg(test)]
+pub fn test_data(len: usize) -> Vec<u8> {
+    let mut x: u64 = 1;
+    (0..len)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x as u8
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunks(data: &[u8]) -> Vec<Vec<u8>> {
+        let mut chunks = Vec::new();
+        let mut chunker = Chunker::new(|c| {
+            chunks.push(c);
+            Ok(())
+        });
+        chunker.write_all(data).unwrap();
+        chunker.finish().unwrap();
+        chunks
+    }
+
+    #[test]
+    fn insertion_keeps_later_chunks() {
+        let mut data = test_data(2 * 1024 * 1024);
+        let before = chunks(&data);
+        assert!(before.len() > 4, "{}", before.len());
+        assert!(before.iter().all(|c| c.len() <= MAX_CHUNK));
+        assert_eq!(before.concat(), data);
+
+        data.splice(100..100, *b"inserted");
+        let after = chunks(&data);
+        assert_eq!(after.concat(), data);
+        assert_ne!(after[0], before[0]);
+        assert_eq!(after[1..], before[1..]);
+    }
+
+    #[test]
+    fn seal_open() {
+        let key = ChunkKey::generate();
+        let name = key.name(b"hello");
+        let sealed = key.seal(b"hello", Compression::default()).unwrap();
+        assert_eq!(key.open(&name, &sealed).unwrap(), b"hello");
+        assert!(key.open(&key.name(b"other"), &sealed).is_err());
+        assert!(ChunkKey::generate().open(&name, &sealed).is_err());
+    }
+}
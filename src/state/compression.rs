@@ -0,0 +1,195 @@
+//! How the tarball of the state is compressed. Each version records the
+//! format it was compressed in, so any can be read whatever is chosen.
+
+use std::io::{BufRead, Read, Write};
+
+use super::SignalStateError;
+
+pub const FORMAT_GZIP: u8 = 0;
+pub const FORMAT_ZSTD: u8 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum Algorithm {
+    Gzip,
+    Zstd,
+}
+
+#[derive(clap::Args)]
+pub struct CompressionArgs {
+    /// How to compress the state when persisting it. zstd is much
+    /// faster than gzip, which is the bottleneck for a large state.
+    #[arg(long, value_enum, default_value_t = Algorithm::Gzip)]
+    state_compression: Algorithm,
+    /// From 0 to 9 for gzip (6 by default) or 1 to 22 for zstd (3 by
+    /// default).
+    #[arg(long)]
+    state_compression_level: Option<i32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Compression {
+    algorithm: Algorithm,
+    level: i32,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            algorithm: Algorithm::Gzip,
+            level: 6,
+        }
+    }
+}
+
+impl CompressionArgs {
+    pub fn compression(&self) -> Result<Compression, SignalStateError> {
+        let (levels, default) = match self.state_compression {
+            Algorithm::Gzip => (0..=9, 6),
+            Algorithm::Zstd => (1..=22, 3),
+        };
+        let level = self.state_compression_level.unwrap_or(default);
+        if !levels.contains(&level) {
+            return Err(SignalStateError::InvalidCompressionLevel(level));
+        }
+        Ok(Compression {
+            algorithm: self.state_compression,
+            level,
+        })
+    }
+}
+
+impl Compression {
+    #[cfg(test)]
+    pub fn zstd(level: i32) -> Self {
+        Self {
+            algorithm: Algorithm::Zstd,
+            level,
+        }
+    }
+
+    /// Stored with what is compressed, for `Decoder::new`.
+    pub fn format(&self) -> u8 {
+        match self.algorithm {
+            Algorithm::Gzip => FORMAT_GZIP,
+            Algorithm::Zstd => FORMAT_ZSTD,
+        }
+    }
+
+    pub fn encoder<W: Write>(&self, out: W) -> std::io::Result<Encoder<W>> {
+        Ok(match self.algorithm {
+            Algorithm::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(
+                out,
+                flate2::Compression::new(self.level as u32),
+            )),
+            Algorithm::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(out, self.level)?),
+        })
+    }
+}
+
+pub enum Encoder<W: Write> {
+    Gzip(flate2::write::GzEncoder<W>),
+    Zstd(zstd::stream::write::Encoder<'static, W>),
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn finish(self) -> std::io::Result<W> {
+        match self {
+            Self::Gzip(e) => e.finish(),
+            Self::Zstd(e) => e.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Gzip(e) => e.write(data),
+            Self::Zstd(e) => e.write(data),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Gzip(e) => e.flush(),
+            Self::Zstd(e) => e.flush(),
+        }
+    }
+}
+
+pub enum Decoder<R: BufRead> {
+    Gzip(flate2::bufread::GzDecoder<R>),
+    Zstd(zstd::stream::read::Decoder<'static, R>),
+}
+
+impl<R: BufRead> Decoder<R> {
+    pub fn new(format: u8, compressed: R) -> Result<Self, SignalStateError> {
+        match format {
+            FORMAT_GZIP => Ok(Self::Gzip(flate2::bufread::GzDecoder::new(compressed))),
+            FORMAT_ZSTD => Ok(Self::Zstd(
+                zstd::stream::read::Decoder::with_buffer(compressed)?.single_frame(),
+            )),
+            _ => Err(SignalStateError::UnknownFormat),
+        }
+    }
+
+    /// What is left after the compressed data.
+    pub fn into_inner(self) -> R {
+        match self {
+            Self::Gzip(d) => d.into_inner(),
+            Self::Zstd(d) => d.finish(),
+        }
+    }
+}
+
+impl<R: BufRead> Read for Decoder<R> {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Gzip(d) => d.read(out),
+            Self::Zstd(d) => d.read(out),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(state_compression: Algorithm, level: Option<i32>) -> CompressionArgs {
+        CompressionArgs {
+            state_compression,
+            state_compression_level: level,
+        }
+    }
+
+    #[test]
+    fn levels() {
+        assert_eq!(
+            args(Algorithm::Gzip, None).compression().unwrap(),
+            Compression::default()
+        );
+        assert_eq!(
+            args(Algorithm::Zstd, None).compression().unwrap(),
+            Compression::zstd(3)
+        );
+        assert!(args(Algorithm::Zstd, Some(19)).compression().is_ok());
+        assert!(args(Algorithm::Gzip, Some(19)).compression().is_err());
+        assert!(args(Algorithm::Zstd, Some(0)).compression().is_err());
+    }
+
+    #[test]
+    fn round_trip() {
+        for compression in [Compression::default(), Compression::zstd(3)] {
+            let data = b"hello hello hello hello".repeat(100);
+            let mut e = compression.encoder(Vec::new()).unwrap();
+            e.write_all(&data).unwrap();
+            let mut compressed = e.finish().unwrap();
+            assert!(compressed.len() < data.len());
+            compressed.extend_from_slice(b"after");
+            let mut d = Decoder::new(compression.format(), compressed.as_slice()).unwrap();
+            let mut out = Vec::new();
+            d.read_to_end(&mut out).unwrap();
+            assert_eq!(out, data);
+            assert_eq!(d.into_inner(), b"after");
+        }
+    }
+}
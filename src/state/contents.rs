@@ -0,0 +1,85 @@
+//! A summary of what is in the state directory, for the `/state` page on
+//! the diag HTTP server, to see what makes the state big.
+
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+/// How many of the largest files are listed.
+const LARGEST: usize = 20;
+
+#[derive(Debug, Default, Serialize)]
+pub struct Contents {
+    pub files: u64,
+    pub bytes: u64,
+    /// Largest first.
+    pub largest: Vec<File>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct File {
+    pub path: PathBuf,
+    pub bytes: u64,
+}
+
+pub fn contents(dir: &Path) -> std::io::Result<Contents> {
+    let mut contents = Contents::default();
+    let mut files = Vec::new();
+    walk(dir, Path::new(""), &mut files)?;
+    contents.files = files.len() as u64;
+    contents.bytes = files.iter().map(|f| f.bytes).sum();
+    files.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.path.cmp(&b.path)));
+    files.truncate(LARGEST);
+    contents.largest = files;
+    Ok(contents)
+}
+
+fn walk(dir: &Path, relative: &Path, files: &mut Vec<File>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let relative = relative.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            walk(&entry.path(), &relative, files)?;
+        } else {
+            files.push(File {
+                path: relative,
+                bytes: entry.metadata()?.len(),
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+        std::fs::create_dir_all(path.join("data/attachments")).unwrap();
+        std::fs::write(path.join("data/accounts.json"), "{}").unwrap();
+        std::fs::write(path.join("data/attachments/a"), vec![0u8; 1000]).unwrap();
+        std::fs::write(path.join("data/attachments/b"), vec![0u8; 10]).unwrap();
+        let contents = contents(path).unwrap();
+        assert_eq!(contents.files, 3);
+        assert_eq!(contents.bytes, 1012);
+        assert_eq!(
+            contents.largest,
+            [
+                File {
+                    path: PathBuf::from("data/attachments/a"),
+                    bytes: 1000
+                },
+                File {
+                    path: PathBuf::from("data/attachments/b"),
+                    bytes: 10
+                },
+                File {
+                    path: PathBuf::from("data/accounts.json"),
+                    bytes: 2
+                },
+            ]
+        );
+    }
+}
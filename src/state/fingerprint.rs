@@ -0,0 +1,57 @@
+//! Hashes of everything in the state directory, to tell whether it has
+//! changed since it was last loaded or saved whoever changed it.
+
+use sha2::{Digest, Sha256};
+use std::path::Path;
+
+pub type Fingerprint = [u8; 32];
+
+pub fn fingerprint(dir: &Path) -> std::io::Result<Fingerprint> {
+    let mut hasher = Sha256::new();
+    hash_dir(dir, Path::new(""), &mut hasher)?;
+    Ok(hasher.finalize().into())
+}
+
+fn hash_dir(dir: &Path, relative: &Path, hasher: &mut Sha256) -> std::io::Result<()> {
+    let mut entries = std::fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let relative = relative.join(entry.file_name());
+        hasher.update(relative.as_os_str().as_encoded_bytes());
+        if entry.file_type()?.is_dir() {
+            hasher.update(b"\0d");
+            hash_dir(&entry.path(), &relative, hasher)?;
+        } else {
+            let data = std::fs::read(entry.path())?;
+            hasher.update(b"\0f");
+            hasher.update((data.len() as u64).to_be_bytes());
+            hasher.update(&data);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+        std::fs::create_dir(path.join("data")).unwrap();
+        std::fs::write(path.join("data/accounts.json"), "{}").unwrap();
+        let before = fingerprint(path).unwrap();
+        assert_eq!(fingerprint(path).unwrap(), before);
+
+        std::fs::write(path.join("data/accounts.json"), "{}").unwrap();
+        assert_eq!(fingerprint(path).unwrap(), before);
+
+        std::fs::write(path.join("data/accounts.json"), "{ }").unwrap();
+        let changed = fingerprint(path).unwrap();
+        assert_ne!(changed, before);
+
+        std::fs::write(path.join("data/empty"), "").unwrap();
+        assert_ne!(fingerprint(path).unwrap(), changed);
+    }
+}
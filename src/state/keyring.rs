@@ -0,0 +1,195 @@
+use chacha20poly1305::aead::{Aead, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit};
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use super::SignalStateError;
+
+/// State encryption keys. The state is always encrypted with the newest
+/// key and can be decrypted with any of them, so that old keys can be
+/// retired once no state versions encrypted with them remain.
+///
+/// A keyring file has one key per line: a numeric ID and then the key in
+/// hex. The key with the highest ID is the newest. Blank lines and `#`
+/// comments are ignored.
+#[derive(Clone, Default)]
+pub struct Keyring {
+    /// Sorted by ID, so the newest is last.
+    keys: Vec<(u32, ChaCha20Poly1305)>,
+}
+
+fn invalid(path: &Path, why: &str) -> SignalStateError {
+    SignalStateError::InvalidKeyring(format!("{}: {why}", path.display()))
+}
+
+fn write_private(path: &Path, data: &[u8]) -> Result<(), std::io::Error> {
+    let mut f = std::fs::File::create_new(path)?;
+    f.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    f.write_all(data)?;
+    f.set_permissions(std::fs::Permissions::from_mode(0o400))?;
+    f.sync_all()
+}
+
+impl Keyring {
+    /// A single raw key, as written by `--bootstrap` with `--encryption-key`.
+    pub fn from_key_file(path: &Path) -> Result<Self, SignalStateError> {
+        let key = std::fs::read(path)?;
+        Ok(Self {
+            keys: vec![(0, ChaCha20Poly1305::new_from_slice(&key)?)],
+        })
+    }
+
+    /// Generates a new key into a new raw key file.
+    pub fn create_key_file(path: &Path) -> Result<Self, SignalStateError> {
+        let key = ChaCha20Poly1305::generate_key(&mut OsRng);
+        write_private(path, key.as_slice())?;
+        Ok(Self {
+            keys: vec![(0, ChaCha20Poly1305::new(&key))],
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<Self, SignalStateError> {
+        let mut keys = Vec::new();
+        for line in std::fs::read_to_string(path)?.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (id, key) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| invalid(path, "expected ID and key"))?;
+            let id = id.parse().map_err(|_| invalid(path, "bad key ID"))?;
+            let key = hex::decode(key.trim()).map_err(|_| invalid(path, "bad hex key"))?;
+            keys.push((id, ChaCha20Poly1305::new_from_slice(&key)?));
+        }
+        if keys.is_empty() {
+            return Err(invalid(path, "no keys"));
+        }
+        keys.sort_by_key(|(id, _)| *id);
+        if let Some(w) = keys.windows(2).find(|w| w[0].0 == w[1].0) {
+            return Err(invalid(path, &format!("key ID {} is given twice", w[0].0)));
+        }
+        Ok(Self { keys })
+    }
+
+    /// Generates a new key and adds it to the keyring file, creating
+    /// the file if there is none. The file is replaced atomically.
+    pub fn rotate(path: &Path) -> Result<Self, SignalStateError> {
+        let existing = match std::fs::read_to_string(path) {
+            Ok(s) => s,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let next_id = if existing.is_empty() {
+            1
+        } else {
+            Self::load(path)?
+                .keys
+                .last()
+                .map(|(id, _)| id + 1)
+                .unwrap_or(1)
+        };
+        let key = ChaCha20Poly1305::generate_key(&mut OsRng);
+        let mut contents = existing;
+        if !contents.is_empty() && !contents.ends_with('\n') {
+            contents.push('\n');
+        }
+        contents += &format!("{next_id} {}\n", hex::encode(key));
+        let tmp = path.with_extension("tmp");
+        let _ = std::fs::remove_file(&tmp);
+        write_private(&tmp, contents.as_bytes())?;
+        std::fs::rename(&tmp, path)?;
+        log::info!("Added key {next_id} to {}", path.display());
+        Self::load(path)
+    }
+
+    /// The key that new versions of the state are encrypted with.
+    pub fn current(&self) -> Result<(u32, &ChaCha20Poly1305), SignalStateError> {
+        self.keys
+            .last()
+            .map(|(id, cipher)| (*id, cipher))
+            .ok_or(SignalStateError::NoEncryptionKey)
+    }
+
+    pub fn get(&self, id: u32) -> Result<&ChaCha20Poly1305, SignalStateError> {
+        self.keys
+            .iter()
+            .find(|(i, _)| *i == id)
+            .map(|(_, cipher)| cipher)
+            .ok_or(SignalStateError::UnknownKey(id))
+    }
+
+    /// Tries the newest key first since it is the most likely to work.
+    pub fn decrypt(&self, nonce: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, SignalStateError> {
+        for (id, cipher) in self.keys.iter().rev() {
+            if let Ok(plaintext) = cipher.decrypt(nonce.into(), ciphertext) {
+                log::debug!("Decrypted state with key {id}");
+                return Ok(plaintext);
+            }
+        }
+        Err(chacha20poly1305::Error.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keyring");
+        let key = "00".repeat(32);
+        std::fs::write(&path, format!("# old\n2 {key}\n\n1 {key}\n")).unwrap();
+        let keyring = Keyring::load(&path).unwrap();
+        assert_eq!(
+            keyring.keys.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+            [1, 2]
+        );
+
+        let twice = format!("1 {key}\n2 {key}\n1 {}\n", "11".repeat(32));
+        for bad in [
+            "",
+            "# nothing\n",
+            "1\n",
+            "x 00\n",
+            "1 0g\n",
+            "1 0000\n",
+            &twice,
+        ] {
+            std::fs::write(&path, bad).unwrap();
+            assert!(Keyring::load(&path).is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn rotate() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keyring");
+        let first = Keyring::rotate(&path).unwrap();
+        let nonce = [0u8; 12];
+        let old = first
+            .current()
+            .unwrap()
+            .1
+            .encrypt((&nonce).into(), &b"secret"[..])
+            .unwrap();
+
+        let second = Keyring::rotate(&path).unwrap();
+        assert_eq!(
+            second.keys.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+            [1, 2]
+        );
+        assert_eq!(second.decrypt(&nonce, &old).unwrap(), b"secret");
+        let new = second
+            .current()
+            .unwrap()
+            .1
+            .encrypt((&nonce).into(), &b"secret"[..])
+            .unwrap();
+        assert!(first.decrypt(&nonce, &new).is_err());
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o400);
+    }
+}
@@ -0,0 +1,145 @@
+//! Wrapping and unwrapping data keys with a cloud KMS key, so that the
+//! state encryption keys are never stored in plaintext.
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde_json::json;
+
+use crate::gcp::MetadataToken;
+
+#[derive(Debug, thiserror::Error)]
+pub enum KmsError {
+    #[error("Invalid KMS key {0:?}: expected gcp-kms://projects/... or aws-kms://arn:aws:kms:...")]
+    InvalidKey(String),
+    #[error("{0}")]
+    HttpError(#[from] reqwest::Error),
+    #[error("{0}")]
+    AwsError(#[from] crate::aws::AwsError),
+    #[error("KMS response: {0}")]
+    BadResponse(String),
+}
+
+enum Backend {
+    /// The full resource name of a Cloud KMS CryptoKey.
+    Gcp {
+        name: String,
+        token: MetadataToken,
+    },
+    Aws {
+        key_id: String,
+        region: String,
+    },
+}
+
+pub struct Kms {
+    client: reqwest::Client,
+    backend: Backend,
+}
+
+fn base64_field(v: &serde_json::Value, field: &str) -> Result<Vec<u8>, KmsError> {
+    let s = v
+        .get(field)
+        .and_then(serde_json::Value::as_str)
+        .ok_or_else(|| KmsError::BadResponse(format!("no {field}")))?;
+    BASE64
+        .decode(s)
+        .map_err(|e| KmsError::BadResponse(format!("{field}: {e}")))
+}
+
+impl std::str::FromStr for Kms {
+    type Err = KmsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let backend = if let Some(name) = s.strip_prefix("gcp-kms://") {
+            Backend::Gcp {
+                name: String::from(name),
+                token: MetadataToken::default(),
+            }
+        } else if let Some(arn) = s.strip_prefix("aws-kms://") {
+            // arn:aws:kms:REGION:ACCOUNT:key/ID
+            let region = arn
+                .split(':')
+                .nth(3)
+                .filter(|r| arn.starts_with("arn:") && !r.is_empty())
+                .ok_or_else(|| KmsError::InvalidKey(String::from(s)))?;
+            Backend::Aws {
+                key_id: String::from(arn),
+                region: String::from(region),
+            }
+        } else {
+            return Err(KmsError::InvalidKey(String::from(s)));
+        };
+        Ok(Self {
+            client: reqwest::Client::new(),
+            backend,
+        })
+    }
+}
+
+impl Kms {
+    pub async fn wrap(&self, key: &[u8]) -> Result<Vec<u8>, KmsError> {
+        match self.backend {
+            Backend::Gcp { ref name, .. } => {
+                let r = self
+                    .gcp(
+                        &format!("{name}:encrypt"),
+                        json!({ "plaintext": BASE64.encode(key) }),
+                    )
+                    .await?;
+                base64_field(&r, "ciphertext")
+            }
+            Backend::Aws { ref key_id, .. } => {
+                let body = json!({ "KeyId": key_id, "Plaintext": BASE64.encode(key) });
+                let r = self.aws("TrentService.Encrypt", body).await?;
+                base64_field(&r, "CiphertextBlob")
+            }
+        }
+    }
+
+    pub async fn unwrap(&self, wrapped: &[u8]) -> Result<Vec<u8>, KmsError> {
+        match self.backend {
+            Backend::Gcp { ref name, .. } => {
+                let body = json!({ "ciphertext": BASE64.encode(wrapped) });
+                let r = self.gcp(&format!("{name}:decrypt"), body).await?;
+                base64_field(&r, "plaintext")
+            }
+            Backend::Aws { ref key_id, .. } => {
+                let body = json!({ "KeyId": key_id, "CiphertextBlob": BASE64.encode(wrapped) });
+                let r = self.aws("TrentService.Decrypt", body).await?;
+                base64_field(&r, "Plaintext")
+            }
+        }
+    }
+
+    async fn gcp(
+        &self,
+        method: &str,
+        body: serde_json::Value,
+    ) -> Result<serde_json::Value, KmsError> {
+        let Backend::Gcp { ref token, .. } = self.backend else {
+            unreachable!();
+        };
+        Ok(self
+            .client
+            .post(format!("https://cloudkms.googleapis.com/v1/{method}"))
+            .bearer_auth(token.get(&self.client).await?)
+            .json(&body)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    /// A request to the AWS KMS JSON API.
+    async fn aws(
+        &self,
+        target: &str,
+        body: serde_json::Value,
+    ) -> Result<serde_json::Value, KmsError> {
+        let Backend::Aws { ref region, .. } = self.backend else {
+            unreachable!();
+        };
+        Ok(crate::aws::call(&self.client, "kms", region, "1.1", target, body).await?)
+    }
+}
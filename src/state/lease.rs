@@ -0,0 +1,220 @@
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use super::SignalStateError;
+use crate::store::{StateStore, StoreError};
+
+#[derive(clap::Args)]
+pub struct LeaseArgs {
+    /// Run as one of several replicas sharing the state storage. Only the
+    /// holder of a lease kept in the state storage loads the state and
+    /// uses signal-cli. The others stand by and take over once the lease
+    /// has gone unrenewed for this long.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    lease_duration: Option<Duration>,
+    /// Name of this replica in the lease. The default is the hostname.
+    #[arg(long, requires = "lease_duration")]
+    lease_holder: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LeaseRecord {
+    /// Empty once the lease has been released.
+    holder: String,
+    /// Changes with every renewal so that the others can see that the
+    /// holder is alive without comparing clocks.
+    serial: u64,
+}
+
+/// Leader election between replicas using an object in the state storage.
+pub struct Lease {
+    store: Arc<dyn StateStore>,
+    holder: String,
+    duration: Duration,
+    /// What we last wrote, while we hold the lease.
+    held: tokio::sync::Mutex<Option<Vec<u8>>>,
+}
+
+fn hostname() -> Result<String, std::io::Error> {
+    if let Ok(h) = std::env::var("HOSTNAME") {
+        return Ok(h);
+    }
+    Ok(std::fs::read_to_string("/proc/sys/kernel/hostname")?
+        .trim()
+        .to_owned())
+}
+
+impl LeaseArgs {
+    pub fn into_lease(
+        self,
+        store: &Arc<dyn StateStore>,
+    ) -> Result<Option<Lease>, SignalStateError> {
+        let Some(duration) = self.lease_duration else {
+            return Ok(None);
+        };
+        Ok(Some(Lease {
+            store: Arc::clone(store),
+            holder: match self.lease_holder {
+                Some(h) => h,
+                None => hostname()?,
+            },
+            duration,
+            held: tokio::sync::Mutex::new(None),
+        }))
+    }
+}
+
+impl Lease {
+    async fn write(
+        &self,
+        expected: Option<&[u8]>,
+        holder: &str,
+        serial: u64,
+    ) -> Result<(), StoreError> {
+        let data = serde_json::to_vec(&LeaseRecord {
+            holder: String::from(holder),
+            serial,
+        })
+        .map_err(std::io::Error::other)?;
+        self.store.swap_lease(expected, &data).await?;
+        *self.held.lock().await = (!holder.is_empty()).then_some(data);
+        Ok(())
+    }
+
+    /// Waits until this replica holds the lease.
+    pub async fn acquire(&self) {
+        // The lease as we first saw it, and when.
+        let mut seen: Option<(Option<Vec<u8>>, Instant)> = None;
+        loop {
+            match self.store.get_lease().await {
+                Ok(current) => {
+                    let record = current
+                        .as_deref()
+                        .and_then(|c| serde_json::from_slice::<LeaseRecord>(c).ok());
+                    let free = match record {
+                        None => true,
+                        Some(ref r) if r.holder.is_empty() || r.holder == self.holder => true,
+                        Some(ref r) => match seen {
+                            Some((ref s, since)) if *s == current => {
+                                let expired = since.elapsed() >= self.duration;
+                                if expired {
+                                    log::warn!("Taking over the expired lease from {}", r.holder);
+                                }
+                                expired
+                            }
+                            _ => {
+                                log::info!("Standing by while {} holds the lease", r.holder);
+                                seen = Some((current.clone(), Instant::now()));
+                                false
+                            }
+                        },
+                    };
+                    if free {
+                        let serial = record.map(|r| r.serial + 1).unwrap_or_default();
+                        match self.write(current.as_deref(), &self.holder, serial).await {
+                            Ok(()) => {
+                                log::info!("Acquired the lease as {}", self.holder);
+                                return;
+                            }
+                            Err(StoreError::LeaseConflict) => seen = None,
+                            Err(e) => log::warn!("Taking the lease: {e}"),
+                        }
+                    }
+                }
+                Err(e) => log::warn!("Reading the lease: {e}"),
+            }
+            tokio::time::sleep(self.duration / 3).await;
+        }
+    }
+
+    /// Keeps renewing the lease. Only returns if it is lost, or might be
+    /// about to be because renewing it keeps failing.
+    pub async fn hold(&self) -> SignalStateError {
+        let mut renewed = Instant::now();
+        loop {
+            tokio::time::sleep(self.duration / 3).await;
+            let Some(held) = self.held.lock().await.clone() else {
+                return SignalStateError::LeaseLost;
+            };
+            let serial = serde_json::from_slice::<LeaseRecord>(&held)
+                .map(|r| r.serial + 1)
+                .unwrap_or_default();
+            match self.write(Some(&held), &self.holder, serial).await {
+                Ok(()) => renewed = Instant::now(),
+                Err(StoreError::LeaseConflict) => return SignalStateError::LeaseLost,
+                Err(e) => {
+                    log::error!("Renewing the lease: {e}");
+                    // Give up before another replica could take over.
+                    if renewed.elapsed() + self.duration / 3 >= self.duration {
+                        return SignalStateError::LeaseLost;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Lets a standby take over right away.
+    pub async fn release(&self) {
+        let Some(held) = self.held.lock().await.clone() else {
+            return;
+        };
+        let serial = serde_json::from_slice::<LeaseRecord>(&held)
+            .map(|r| r.serial + 1)
+            .unwrap_or_default();
+        match self.write(Some(&held), "", serial).await {
+            Ok(()) => log::info!("Released the lease"),
+            Err(e) => log::warn!("Releasing the lease: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::store::mem::MemStore;
+
+    fn lease(store: &Arc<dyn StateStore>, holder: &str) -> Lease {
+        LeaseArgs {
+            lease_duration: Some(Duration::from_millis(90)),
+            lease_holder: Some(String::from(holder)),
+        }
+        .into_lease(store)
+        .unwrap()
+        .unwrap()
+    }
+
+    async fn holder(store: &Arc<dyn StateStore>) -> String {
+        let lease = store.get_lease().await.unwrap().unwrap();
+        serde_json::from_slice::<LeaseRecord>(&lease)
+            .unwrap()
+            .holder
+    }
+
+    #[tokio::test]
+    async fn released() {
+        let store: Arc<dyn StateStore> = Arc::new(MemStore::default());
+        let (a, b) = (lease(&store, "a"), lease(&store, "b"));
+        a.acquire().await;
+        assert_eq!(holder(&store).await, "a");
+        a.release().await;
+        tokio::time::timeout(Duration::from_millis(50), b.acquire())
+            .await
+            .expect("released lease is free");
+        assert_eq!(holder(&store).await, "b");
+    }
+
+    #[tokio::test]
+    async fn taken_over_once_expired() {
+        let store: Arc<dyn StateStore> = Arc::new(MemStore::default());
+        let (a, b) = (lease(&store, "a"), lease(&store, "b"));
+        a.acquire().await;
+        let start = Instant::now();
+        b.acquire().await;
+        assert!(start.elapsed() >= b.duration);
+        assert_eq!(holder(&store).await, "b");
+        assert!(matches!(a.hold().await, SignalStateError::LeaseLost));
+    }
+}
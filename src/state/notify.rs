@@ -0,0 +1,163 @@
+//! Finding out that a new version of the state was stored from the S3
+//! event notifications sent to an SQS queue, so that it is loaded within
+//! seconds rather than at the next maintenance cycle.
+
+use serde_json::{Value, json};
+
+use super::SignalStateError;
+use crate::aws::AwsError;
+
+/// The longest SQS allows a receive to wait for messages.
+const WAIT_SECONDS: u32 = 20;
+
+#[derive(clap::Args)]
+pub struct NotificationArgs {
+    /// URL of an SQS queue, like
+    /// `https://sqs.us-east-1.amazonaws.com/123456789012/signal-state`,
+    /// to which the state storage bucket sends notifications of the
+    /// objects created in it. New versions are then loaded as soon as
+    /// they are stored. The queue should not be shared with another
+    /// instance, since each notification is only received once.
+    #[arg(long)]
+    state_notification_queue: Option<String>,
+}
+
+impl NotificationArgs {
+    pub fn queue(&self) -> Result<Option<Queue>, SignalStateError> {
+        let Some(ref url) = self.state_notification_queue else {
+            return Ok(None);
+        };
+        let region = url
+            .strip_prefix("https://sqs.")
+            .and_then(|rest| rest.split_once(".amazonaws.com/"))
+            .map(|(region, _)| String::from(region))
+            .ok_or_else(|| SignalStateError::InvalidNotificationQueue(url.clone()))?;
+        Ok(Some(Queue {
+            client: reqwest::Client::new(),
+            url: url.clone(),
+            region,
+        }))
+    }
+}
+
+pub struct Queue {
+    client: reqwest::Client,
+    url: String,
+    region: String,
+}
+
+impl Queue {
+    async fn call(&self, target: &str, body: Value) -> Result<Value, AwsError> {
+        crate::aws::call(&self.client, "sqs", &self.region, "1.0", target, body).await
+    }
+
+    /// Waits a while for notifications and takes them off the queue.
+    /// Returns the names of the objects they were about.
+    pub async fn receive(&self) -> Result<Vec<String>, AwsError> {
+        let received = self
+            .call(
+                "AmazonSQS.ReceiveMessage",
+                json!({
+                    "QueueUrl": self.url,
+                    "MaxNumberOfMessages": 10,
+                    "WaitTimeSeconds": WAIT_SECONDS,
+                }),
+            )
+            .await?;
+        let messages = received["Messages"].as_array().cloned().unwrap_or_default();
+        if messages.is_empty() {
+            return Ok(Vec::new());
+        }
+        let keys = messages
+            .iter()
+            .filter_map(|m| m["Body"].as_str())
+            .flat_map(object_keys)
+            .collect();
+        let entries = messages
+            .iter()
+            .enumerate()
+            .map(|(i, m)| json!({ "Id": i.to_string(), "ReceiptHandle": m["ReceiptHandle"] }))
+            .collect::<Vec<_>>();
+        self.call(
+            "AmazonSQS.DeleteMessageBatch",
+            json!({ "QueueUrl": self.url, "Entries": entries }),
+        )
+        .await?;
+        Ok(keys)
+    }
+}
+
+/// The object names in a notification, sent straight from S3 or by way
+/// of SNS or EventBridge. Nothing for anything else, like the test event
+/// that S3 sends when notifications are set up.
+fn object_keys(body: &str) -> Vec<String> {
+    let Ok(mut event) = serde_json::from_str::<Value>(body) else {
+        return Vec::new();
+    };
+    // SNS wraps the S3 event in a string.
+    if let Some(message) = event["Message"].as_str() {
+        let Ok(inner) = serde_json::from_str(message) else {
+            return Vec::new();
+        };
+        event = inner;
+    }
+    if let Some(key) = event["detail"]["object"]["key"].as_str() {
+        return vec![String::from(key)];
+    }
+    event["Records"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|r| r["s3"]["object"]["key"].as_str())
+        .map(String::from)
+        .collect()
+}
+
+/// Whether an object name is that of a version of the state, under any
+/// prefix. The lease, which is rewritten all the time, and chunks are
+/// not.
+pub fn is_version(key: &str) -> bool {
+    key.rsplit('/')
+        .next()
+        .is_some_and(|name| name.parse::<u32>().is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notifications() {
+        let s3 = r#"{"Records":[{"eventName":"ObjectCreated:Put","s3":{"object":{"key":"12","size":100}}}]}"#;
+        assert_eq!(object_keys(s3), ["12"]);
+        let sns = json!({ "Type": "Notification", "Message": s3 }).to_string();
+        assert_eq!(object_keys(&sns), ["12"]);
+        let eventbridge =
+            r#"{"detail-type":"Object Created","detail":{"object":{"key":"secondary/3"}}}"#;
+        assert_eq!(object_keys(eventbridge), ["secondary/3"]);
+        let test = r#"{"Service":"Amazon S3","Event":"s3:TestEvent","Bucket":"b"}"#;
+        assert!(object_keys(test).is_empty());
+        assert!(object_keys("not JSON").is_empty());
+    }
+
+    #[test]
+    fn versions() {
+        assert!(is_version("12"));
+        assert!(is_version("secondary/3"));
+        assert!(!is_version("lease"));
+        assert!(!is_version("chunks/0123abcd"));
+    }
+
+    #[test]
+    fn queue_region() {
+        let args = |url: &str| NotificationArgs {
+            state_notification_queue: Some(String::from(url)),
+        };
+        let queue = args("https://sqs.eu-west-1.amazonaws.com/123456789012/signal-state")
+            .queue()
+            .unwrap()
+            .unwrap();
+        assert_eq!(queue.region, "eu-west-1");
+        assert!(args("https://example.com/queue").queue().is_err());
+    }
+}
@@ -0,0 +1,93 @@
+use chacha20poly1305::aead::OsRng;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit};
+use std::path::Path;
+
+use super::SignalStateError;
+
+pub const SALT_LEN: usize = 16;
+
+/// A state encryption key derived from a passphrase with Argon2id. Each
+/// version of the state is stored with the salt that its key was derived
+/// with. One salt is chosen per run so that the (deliberately slow)
+/// derivation only happens once for saving.
+pub struct Passphrase {
+    passphrase: String,
+    salt: [u8; SALT_LEN],
+    cipher: ChaCha20Poly1305,
+}
+
+fn derive(passphrase: &str, salt: &[u8]) -> Result<ChaCha20Poly1305, SignalStateError> {
+    let mut key = [0u8; 32];
+    argon2::Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| SignalStateError::KeyDerivation(e.to_string()))?;
+    Ok(ChaCha20Poly1305::new_from_slice(&key)?)
+}
+
+impl Passphrase {
+    /// Reads the passphrase from the first line of a file, or prompts for
+    /// it on the terminal if the path is `-`.
+    pub fn read(path: &Path) -> Result<Self, SignalStateError> {
+        let passphrase = if path == Path::new("-") {
+            rpassword::prompt_password("State encryption passphrase: ")?
+        } else {
+            let contents = std::fs::read_to_string(path)?;
+            contents.lines().next().unwrap_or_default().to_owned()
+        };
+        if passphrase.is_empty() {
+            return Err(SignalStateError::KeyDerivation(String::from(
+                "empty passphrase",
+            )));
+        }
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let cipher = derive(&passphrase, &salt)?;
+        Ok(Self {
+            passphrase,
+            salt,
+            cipher,
+        })
+    }
+
+    /// The salt and key that new versions of the state are encrypted with.
+    pub fn current(&self) -> (&[u8; SALT_LEN], &ChaCha20Poly1305) {
+        (&self.salt, &self.cipher)
+    }
+
+    pub fn for_salt(&self, salt: &[u8]) -> Result<ChaCha20Poly1305, SignalStateError> {
+        if salt == self.salt {
+            return Ok(self.cipher.clone());
+        }
+        derive(&self.passphrase, salt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chacha20poly1305::aead::Aead;
+
+    #[test]
+    fn from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("passphrase");
+        std::fs::write(&path, "\n").unwrap();
+        assert!(Passphrase::read(&path).is_err());
+
+        std::fs::write(&path, "correct horse\nignored\n").unwrap();
+        let a = Passphrase::read(&path).unwrap();
+        let b = Passphrase::read(&path).unwrap();
+        assert_eq!(a.passphrase, "correct horse");
+        assert_ne!(a.salt, b.salt);
+        let nonce = [0u8; 12];
+        let (salt, cipher) = a.current();
+        let ciphertext = cipher.encrypt((&nonce).into(), &b"state"[..]).unwrap();
+        let plaintext = b
+            .for_salt(salt)
+            .unwrap()
+            .decrypt((&nonce).into(), ciphertext.as_slice())
+            .unwrap();
+        assert_eq!(plaintext, b"state");
+    }
+}
@@ -0,0 +1,81 @@
+//! Deleting what signal-cli keeps but signal-pager does not need from the
+//! state before it is persisted, so that it does not grow without end.
+
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// Directories of the signal-cli state whose files are pruned. Messages
+/// not yet received are never pruned.
+const PRUNED: [&str; 2] = ["attachments", "avatars"];
+
+#[derive(clap::Args)]
+pub struct PruneArgs {
+    /// Delete received attachments and avatars older than this from the
+    /// state before persisting it.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    state_prune_age: Option<Duration>,
+}
+
+impl PruneArgs {
+    pub fn age(&self) -> Option<Duration> {
+        self.state_prune_age
+    }
+}
+
+/// Deletes the files under `PRUNED` in `dir` last modified longer than
+/// `age` ago. Returns how many were deleted.
+pub fn prune(dir: &Path, age: Duration) -> std::io::Result<u64> {
+    let Some(cutoff) = SystemTime::now().checked_sub(age) else {
+        return Ok(0);
+    };
+    let mut pruned = 0;
+    for name in PRUNED {
+        let path = dir.join(name);
+        if path.is_dir() {
+            pruned += prune_dir(&path, cutoff)?;
+        }
+    }
+    Ok(pruned)
+}
+
+fn prune_dir(dir: &Path, cutoff: SystemTime) -> std::io::Result<u64> {
+    let mut pruned = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            pruned += prune_dir(&entry.path(), cutoff)?;
+        } else if entry.metadata()?.modified()? < cutoff {
+            std::fs::remove_file(entry.path())?;
+            pruned += 1;
+        }
+    }
+    Ok(pruned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(path: &Path, age: Duration) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let file = std::fs::File::create(path).unwrap();
+        file.set_modified(SystemTime::now() - age).unwrap();
+    }
+
+    #[test]
+    fn old_attachments_and_avatars() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+        let day = Duration::from_secs(86400);
+        write(&path.join("attachments/old"), 10 * day);
+        write(&path.join("attachments/new"), day);
+        write(&path.join("avatars/profile-x/old"), 10 * day);
+        write(&path.join("data/accounts.json"), 10 * day);
+        assert_eq!(prune(path, 7 * day).unwrap(), 2);
+        assert!(!path.join("attachments/old").exists());
+        assert!(path.join("attachments/new").exists());
+        assert!(!path.join("avatars/profile-x/old").exists());
+        assert!(path.join("data/accounts.json").exists());
+        assert_eq!(prune(path, 7 * day).unwrap(), 0);
+    }
+}
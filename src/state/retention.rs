@@ -0,0 +1,88 @@
+use std::cmp::Reverse;
+use std::time::{Duration, SystemTime};
+
+use crate::store::StoredVersion;
+
+#[derive(clap::Args)]
+pub struct RetentionArgs {
+    /// Number of the most recent state versions to keep.
+    #[arg(long, default_value_t = 20, value_parser = clap::value_parser!(u32).range(1..))]
+    state_retain_versions: u32,
+    /// Also keep state versions younger than this, even beyond
+    /// `--state-retain-versions`.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    state_retain_age: Option<Duration>,
+}
+
+impl RetentionArgs {
+    /// Which of `versions` to delete. Nothing newer than `loaded`, the
+    /// version we have, is deleted, and since at least one version is
+    /// retained the newest one always remains.
+    pub fn expired(&self, versions: &[StoredVersion], loaded: u32) -> Vec<u32> {
+        let mut versions = versions.iter().collect::<Vec<_>>();
+        versions.sort_by_key(|v| Reverse(v.version));
+        let now = SystemTime::now();
+        versions
+            .into_iter()
+            .skip(self.state_retain_versions as usize)
+            .filter(|v| v.version < loaded)
+            .filter(|v| match self.state_retain_age {
+                None => true,
+                // Keep versions whose age the store doesn't know.
+                Some(age) => v
+                    .modified
+                    .is_some_and(|m| now.duration_since(m).unwrap_or_default() >= age),
+            })
+            .map(|v| v.version)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(state_retain_versions: u32, state_retain_age: Option<u64>) -> RetentionArgs {
+        RetentionArgs {
+            state_retain_versions,
+            state_retain_age: state_retain_age.map(|s| Duration::new(s, 0)),
+        }
+    }
+
+    /// Versions 1 to 5, each an hour newer than the last, with the age of
+    /// version 2 unknown.
+    fn versions() -> Vec<StoredVersion> {
+        let now = SystemTime::now();
+        (1..=5)
+            .map(|version| StoredVersion {
+                version,
+                modified: (version != 2)
+                    .then(|| now - Duration::new(3600 * (6 - version as u64), 0)),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn by_count() {
+        let mut expired = args(2, None).expired(&versions(), 5);
+        expired.sort();
+        assert_eq!(expired, [1, 2, 3]);
+        assert!(args(10, None).expired(&versions(), 5).is_empty());
+    }
+
+    #[test]
+    fn nothing_newer_than_loaded() {
+        let mut expired = args(1, None).expired(&versions(), 3);
+        expired.sort();
+        assert_eq!(expired, [1, 2]);
+    }
+
+    #[test]
+    fn by_age() {
+        // Versions 1 to 3 are at least 3 hours old, but the age of version
+        // 2 is not known so it stays.
+        let mut expired = args(1, Some(3 * 3600 - 60)).expired(&versions(), 5);
+        expired.sort();
+        assert_eq!(expired, [1, 3]);
+    }
+}
@@ -0,0 +1,131 @@
+//! Retrying operations on the state storage which failed in a way that
+//! may soon pass, waiting longer each time, rather than leaving them to
+//! the next maintenance cycle.
+
+use chacha20poly1305::aead::OsRng;
+use chacha20poly1305::aead::rand_core::RngCore;
+use std::time::Duration;
+
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    /// Including the first.
+    attempts: u32,
+}
+
+/// For the state storage. Operations may be retried with locks held, so
+/// this gives up after about a minute.
+pub const STORAGE: Backoff = Backoff {
+    initial: Duration::from_secs(1),
+    max: Duration::from_secs(30),
+    attempts: 5,
+};
+
+impl Backoff {
+    /// How long to wait before retry number `retry`, counting from 0:
+    /// doubling from `initial` up to `max`, less a random part of up to
+    /// half so that instances which failed together do not retry
+    /// together.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let delay = self
+            .initial
+            .saturating_mul(1 << retry.min(16))
+            .min(self.max);
+        let jitter = f64::from(OsRng.next_u32()) / f64::from(u32::MAX);
+        delay - (delay / 2).mul_f64(jitter)
+    }
+
+    /// Runs `f` until it succeeds, fails in a way that is not
+    /// `retryable` or has been tried `attempts` times.
+    pub async fn retry<T, E, F, Fut>(
+        &self,
+        operation: &str,
+        retryable: impl Fn(&E) -> bool,
+        mut f: F,
+    ) -> Result<T, E>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        E: std::fmt::Display,
+    {
+        let mut retry = 0;
+        loop {
+            match f().await {
+                Err(e) if retry + 1 < self.attempts && retryable(&e) => {
+                    let delay = self.delay(retry);
+                    log::warn!("State {operation} failed, retrying in {delay:?}: {e}");
+                    crate::metrics::STATE_RETRIES
+                        .with_label_values(&[operation])
+                        .inc();
+                    tokio::time::sleep(delay).await;
+                    retry += 1;
+                }
+                r => return r,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FAST: Backoff = Backoff {
+        initial: Duration::from_millis(1),
+        max: Duration::from_millis(4),
+        attempts: 3,
+    };
+
+    #[test]
+    fn delay_doubles_up_to_max() {
+        for (retry, max) in [(0, 1000), (1, 2000), (2, 4000), (5, 30000), (40, 30000)] {
+            let delay = STORAGE.delay(retry).as_millis();
+            assert!(delay <= max && delay >= max / 2, "{retry}: {delay}");
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_only_what_may_pass() {
+        let mut calls = 0;
+        let r: Result<(), &str> = FAST
+            .retry(
+                "test",
+                |e| *e == "transient",
+                || {
+                    calls += 1;
+                    async { Err("transient") }
+                },
+            )
+            .await;
+        assert_eq!(r, Err("transient"));
+        assert_eq!(calls, 3);
+
+        let mut calls = 0;
+        let r: Result<(), &str> = FAST
+            .retry(
+                "test",
+                |e| *e == "transient",
+                || {
+                    calls += 1;
+                    async { Err("permanent") }
+                },
+            )
+            .await;
+        assert_eq!(r, Err("permanent"));
+        assert_eq!(calls, 1);
+
+        let mut calls = 0;
+        let r = FAST
+            .retry(
+                "test",
+                |e| *e == "transient",
+                || {
+                    calls += 1;
+                    let n = calls;
+                    async move { if n < 2 { Err("transient") } else { Ok(n) } }
+                },
+            )
+            .await;
+        assert_eq!(r, Ok(2));
+    }
+}
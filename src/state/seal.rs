@@ -0,0 +1,1126 @@
+use chacha20poly1305::aead::generic_array::GenericArray;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32};
+use chacha20poly1305::aead::{Aead, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit};
+use futures::StreamExt;
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::AsyncReadExt;
+
+use super::SignalStateError;
+use super::chunk::{self, ChunkKey, ChunkReader, Chunker};
+use super::compression::{Compression, Decoder, FORMAT_GZIP};
+use super::keyring::Keyring;
+use super::kms::{Kms, KmsError};
+use super::passphrase::{Passphrase, SALT_LEN};
+use crate::store::{StateStore, StoreError};
+
+/// Starts versions of the state written by `Sealer::seal`. It is followed
+/// by one of the compression `FORMAT_*` bytes, one of the `KEY_*` bytes
+/// saying where the key comes from and what follows to identify it, then
+/// a nonce prefix and then the encrypted compressed tarball as a sequence
+/// of chunks using the STREAM construction, each authenticated along with
+/// `Sealer::associated_data`. Each chunk is preceded by its length as a
+/// big-endian u32 with `LAST_CHUNK` set on the final one.
+const STREAM_MAGIC: &[u8] = b"SPSTR3";
+/// Starts versions of the state written with `--state-chunked`, which
+/// are a manifest of chunks stored separately. It is followed by a key
+/// header as after the format byte of `STREAM_MAGIC`, then a nonce and
+/// the manifest encrypted with the same associated data: the chunk key
+/// and the names of the chunks of the tarball in order.
+const CHUNKED_MAGIC: &[u8] = b"SPCDC2";
+/// How many chunks are stored or fetched at once.
+const CHUNK_CONCURRENCY: usize = 8;
+/// The u32 ID of a key in the keyring follows.
+const KEY_KEYRING: u8 = 0;
+/// The length of a KMS-wrapped data key as a big-endian u16 and the
+/// wrapped key follow.
+const KEY_KMS: u8 = 1;
+/// The Argon2id salt of a passphrase-derived key follows.
+const KEY_PASSPHRASE: u8 = 2;
+const STREAM_NONCE_SIZE: usize = 7;
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+const LAST_CHUNK: u32 = 1 << 31;
+
+/// Like `STREAM_MAGIC` but with no associated data, and gzipped with no
+/// format byte for the first.
+const GZIP_STREAM_MAGIC: &[u8] = b"SPSTR1";
+const UNBOUND_STREAM_MAGIC: &[u8] = b"SPSTR2";
+/// Like `CHUNKED_MAGIC` but with no associated data.
+const UNBOUND_CHUNKED_MAGIC: &[u8] = b"SPCDC1";
+
+/// Older formats, which are still read but no longer written. These are
+/// a single nonce and ciphertext for the whole gzipped tarball, preceded
+/// by the magic for KMS and passphrase keys.
+const ENVELOPE_MAGIC: &[u8] = b"SPKMS1";
+const PASSPHRASE_MAGIC: &[u8] = b"SPPW01";
+const NONCE_SIZE: usize = 12; //<ChaCha20Poly1305 as AeadCore>::NonceSize;
+
+/// How versions of the state are encrypted. Without KMS they are
+/// encrypted with the newest key in the keyring. With KMS each version
+/// gets a new data key which is wrapped by KMS and stored with it, and
+/// the keyring is only needed for versions from before KMS was used.
+/// The same goes for a passphrase, except that there is no data key: the
+/// key is derived from the passphrase and the salt stored with the state.
+///
+/// The state is streamed through tar, gzip and encryption to and from the
+/// store so that it never has to be held in memory all at once. Chunked,
+/// only the manifest is encrypted that way and each chunk is compressed
+/// and encrypted by itself with the chunk key.
+#[derive(Clone)]
+pub struct Sealer {
+    keys: Keyring,
+    kms: Option<Arc<Kms>>,
+    passphrase: Option<Arc<Passphrase>>,
+    chunked: bool,
+    compression: Compression,
+    /// From `--state-deployment-id`.
+    deployment: String,
+    /// Refuse versions which are not bound, for `--state-require-bound`.
+    require_bound: bool,
+}
+
+/// How a version was sealed, as far as its header says: whether it is
+/// bound to its version and deployment, and what key it is encrypted
+/// with. A version which cannot be opened although one sealed the same way
+/// could be is corrupt, rather than needing other keys or another
+/// `--state-deployment-id`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Sealing {
+    bound: bool,
+    key: SealingKey,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum SealingKey {
+    Keyring(u32),
+    Kms,
+    Passphrase,
+}
+
+fn decryption_failed<E>(_: E) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, "state decryption failed")
+}
+
+/// Encrypts everything written to it as STREAM chunks.
+struct EncryptWriter<W: Write> {
+    inner: W,
+    encryptor: EncryptorBE32<ChaCha20Poly1305>,
+    aad: Vec<u8>,
+    buf: Vec<u8>,
+}
+
+/// Writes one STREAM chunk, after its length and `flags`.
+fn write_chunk(out: &mut impl Write, ciphertext: &[u8], flags: u32) -> std::io::Result<()> {
+    let len = u32::try_from(ciphertext.len()).map_err(std::io::Error::other)?;
+    out.write_all(&(len | flags).to_be_bytes())?;
+    out.write_all(ciphertext)
+}
+
+impl<W: Write> EncryptWriter<W> {
+    fn finish(mut self) -> std::io::Result<W> {
+        let ciphertext = self
+            .encryptor
+            .encrypt_last(Payload {
+                msg: &self.buf,
+                aad: &self.aad,
+            })
+            .map_err(std::io::Error::other)?;
+        write_chunk(&mut self.inner, &ciphertext, LAST_CHUNK)?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+        // A full chunk is only written once more data arrives, since
+        // until then it could turn out to be the last one.
+        if self.buf.len() == CHUNK_SIZE {
+            let ciphertext = self
+                .encryptor
+                .encrypt_next(Payload {
+                    msg: &self.buf,
+                    aad: &self.aad,
+                })
+                .map_err(std::io::Error::other)?;
+            write_chunk(&mut self.inner, &ciphertext, 0)?;
+            self.buf.clear();
+        }
+        let n = data.len().min(CHUNK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&data[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Decrypts STREAM chunks, failing if the stream is truncated.
+struct DecryptReader<R: Read> {
+    inner: R,
+    decryptor: Option<DecryptorBE32<ChaCha20Poly1305>>,
+    aad: Vec<u8>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos == self.buf.len() {
+            let Some(decryptor) = self.decryptor.as_mut() else {
+                return Ok(0);
+            };
+            let mut header = [0u8; 4];
+            self.inner.read_exact(&mut header)?;
+            let header = u32::from_be_bytes(header);
+            let len = (header & !LAST_CHUNK) as usize;
+            if len > CHUNK_SIZE + TAG_SIZE {
+                return Err(decryption_failed(()));
+            }
+            let mut ciphertext = vec![0u8; len];
+            self.inner.read_exact(&mut ciphertext)?;
+            let payload = Payload {
+                msg: &ciphertext,
+                aad: &self.aad,
+            };
+            self.buf = if header & LAST_CHUNK == 0 {
+                decryptor.decrypt_next(payload)
+            } else {
+                let decryptor = self.decryptor.take().expect("checked above");
+                decryptor.decrypt_last(payload)
+            }
+            .map_err(decryption_failed)?;
+            self.pos = 0;
+        }
+        let n = out.len().min(self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Feeds what is written to it to an async reader on the other side.
+struct ChannelWriter(tokio::sync::mpsc::Sender<std::io::Result<bytes::Bytes>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .blocking_send(Ok(bytes::Bytes::copy_from_slice(data)))
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn write_sealed<W: Write>(
+    path: &Path,
+    header: &[u8],
+    cipher: ChaCha20Poly1305,
+    compression: Compression,
+    aad: Vec<u8>,
+    out: W,
+) -> Result<(), SignalStateError> {
+    let mut out = std::io::BufWriter::with_capacity(CHUNK_SIZE, out);
+    let mut prefix = [0u8; STREAM_NONCE_SIZE];
+    OsRng.fill_bytes(&mut prefix);
+    out.write_all(header)?;
+    out.write_all(&prefix)?;
+    let enc = EncryptWriter {
+        inner: out,
+        encryptor: EncryptorBE32::from_aead(cipher, GenericArray::from_slice(&prefix)),
+        aad,
+        buf: Vec::with_capacity(CHUNK_SIZE),
+    };
+    let mut tar = tar::Builder::new(compression.encoder(enc)?);
+    tar.append_dir_all("", path)?;
+    let mut out = tar.into_inner()?.finish()?.finish()?;
+    out.flush()?;
+    Ok(())
+}
+
+fn read_sealed<R: Read>(
+    sealed: R,
+    cipher: ChaCha20Poly1305,
+    prefix: &[u8],
+    format: u8,
+    aad: Vec<u8>,
+    path: &Path,
+) -> Result<(), SignalStateError> {
+    let dec = DecryptReader {
+        inner: sealed,
+        decryptor: Some(DecryptorBE32::from_aead(
+            cipher,
+            GenericArray::from_slice(prefix),
+        )),
+        aad,
+        buf: Vec::new(),
+        pos: 0,
+    };
+    let mut archive = tar::Archive::new(Decoder::new(format, std::io::BufReader::new(dec))?);
+    archive.unpack(path)?;
+    // Read to the end so that a truncated stream is noticed.
+    let mut dec = archive.into_inner().into_inner();
+    std::io::copy(&mut dec, &mut std::io::sink())?;
+    Ok(())
+}
+
+/// Cuts the tarball of the state directory into chunks and sends their
+/// names along with those which are not `stored` yet, encrypted.
+fn write_chunks(
+    path: &Path,
+    key: &ChunkKey,
+    compression: Compression,
+    mut stored: HashSet<String>,
+    tx: tokio::sync::mpsc::Sender<(chunk::Name, Option<Vec<u8>>)>,
+) -> Result<(), SignalStateError> {
+    let chunker = Chunker::new(|data: Vec<u8>| {
+        let name = key.name(&data);
+        let sealed = if stored.insert(hex::encode(name)) {
+            Some(key.seal(&data, compression)?)
+        } else {
+            None
+        };
+        tx.blocking_send((name, sealed))
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))
+    });
+    let mut tar = tar::Builder::new(chunker);
+    // Without times, which change when the state is copied for saving.
+    tar.mode(tar::HeaderMode::Deterministic);
+    chunk::append_sorted(&mut tar, path, Path::new(""))?;
+    tar.into_inner()?.finish()?;
+    Ok(())
+}
+
+/// Stores the chunk if it is given, which it is if it is not stored yet.
+/// Returns its name and whether it was stored.
+async fn put_chunk(
+    store: &dyn StateStore,
+    name: chunk::Name,
+    sealed: Option<Vec<u8>>,
+) -> Result<(chunk::Name, bool), StoreError> {
+    if let Some(ref sealed) = sealed {
+        store.put_chunk(&hex::encode(name), sealed).await?;
+    }
+    Ok((name, sealed.is_some()))
+}
+
+fn split_nonce(s: &[u8]) -> Result<(&[u8], &[u8]), SignalStateError> {
+    if s.len() <= NONCE_SIZE {
+        return Err(SignalStateError::CiphertextTooShort);
+    }
+    Ok(s.split_at(NONCE_SIZE))
+}
+
+fn unpack(tar_gz: &[u8], path: &Path) -> Result<(), SignalStateError> {
+    let tar = flate2::read::GzDecoder::new(std::io::Cursor::new(tar_gz));
+    tar::Archive::new(tar).unpack(path)?;
+    Ok(())
+}
+
+impl Sealer {
+    pub fn new(keys: Keyring, kms: Option<Kms>, passphrase: Option<Passphrase>) -> Self {
+        Self {
+            keys,
+            kms: kms.map(Arc::new),
+            passphrase: passphrase.map(Arc::new),
+            chunked: false,
+            compression: Compression::default(),
+            deployment: String::new(),
+            require_bound: false,
+        }
+    }
+
+    /// Which deployment the versions belong to, for
+    /// `--state-deployment-id`.
+    pub fn deployment(mut self, deployment: String) -> Self {
+        self.deployment = deployment;
+        self
+    }
+
+    /// Whether to refuse versions which are not bound to their version
+    /// and deployment, for `--state-require-bound`.
+    pub fn require_bound(mut self, require_bound: bool) -> Self {
+        self.require_bound = require_bound;
+        self
+    }
+
+    /// Fails if an unbound version may not be opened.
+    fn check_unbound(&self) -> Result<(), SignalStateError> {
+        if self.require_bound {
+            return Err(SignalStateError::Unbound);
+        }
+        Ok(())
+    }
+
+    /// Authenticated along with each version, so that it is only opened
+    /// as the version that it was stored as, by the deployment that
+    /// stored it. The version number is last and always the same length.
+    fn associated_data(&self, version: u32) -> Vec<u8> {
+        [self.deployment.as_bytes(), &version.to_be_bytes()].concat()
+    }
+
+    /// How to compress versions, for `--state-compression`.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Whether to write versions as chunks, for `--state-chunked`.
+    pub fn chunked(mut self, chunked: bool) -> Self {
+        self.chunked = chunked;
+        self
+    }
+
+    /// Whether a version sealed this way is encrypted with an older key
+    /// from the keyring than the one that new versions would be.
+    pub fn outdated(&self, sealing: &Sealing) -> bool {
+        let SealingKey::Keyring(id) = sealing.key else {
+            return false;
+        };
+        self.kms.is_none()
+            && self.passphrase.is_none()
+            && self.keys.current().is_ok_and(|(current, _)| current > id)
+    }
+
+    /// The header identifying the key after `magic`, and the key itself.
+    async fn new_key(&self, magic: &[u8]) -> Result<(Vec<u8>, ChaCha20Poly1305), SignalStateError> {
+        let mut header = magic.to_vec();
+        if let Some(ref kms) = self.kms {
+            let key = ChaCha20Poly1305::generate_key(&mut OsRng);
+            let wrapped = kms.wrap(&key).await?;
+            let len = u16::try_from(wrapped.len())
+                .map_err(|_| KmsError::BadResponse(String::from("wrapped key too long")))?;
+            header.push(KEY_KMS);
+            header.extend_from_slice(&len.to_be_bytes());
+            header.extend_from_slice(&wrapped);
+            return Ok((header, ChaCha20Poly1305::new(&key)));
+        }
+        if let Some(ref passphrase) = self.passphrase {
+            let (salt, cipher) = passphrase.current();
+            header.push(KEY_PASSPHRASE);
+            header.extend_from_slice(salt);
+            return Ok((header, cipher.clone()));
+        }
+        let (id, cipher) = self.keys.current()?;
+        header.push(KEY_KEYRING);
+        header.extend_from_slice(&id.to_be_bytes());
+        Ok((header, cipher.clone()))
+    }
+
+    /// Reads the header written by `new_key` and finds the key.
+    async fn find_key<R: tokio::io::AsyncRead + Unpin>(
+        &self,
+        sealed: &mut R,
+    ) -> Result<ChaCha20Poly1305, SignalStateError> {
+        match sealed.read_u8().await? {
+            KEY_KEYRING => {
+                let id = sealed.read_u32().await?;
+                Ok(self.keys.get(id)?.clone())
+            }
+            KEY_KMS => {
+                let kms = self.kms.as_ref().ok_or(SignalStateError::KmsRequired)?;
+                let mut wrapped = vec![0u8; sealed.read_u16().await?.into()];
+                sealed.read_exact(&mut wrapped).await?;
+                Ok(ChaCha20Poly1305::new_from_slice(
+                    &kms.unwrap(&wrapped).await?,
+                )?)
+            }
+            KEY_PASSPHRASE => {
+                let passphrase = self
+                    .passphrase
+                    .as_ref()
+                    .ok_or(SignalStateError::PassphraseRequired)?;
+                let mut salt = [0u8; SALT_LEN];
+                sealed.read_exact(&mut salt).await?;
+                passphrase.for_salt(&salt)
+            }
+            _ => Err(SignalStateError::UnknownFormat),
+        }
+    }
+
+    /// Packs, encrypts and stores the state directory as `version`.
+    /// Chunked, it uses `chunk_key`, or a new one which is kept there.
+    pub async fn seal(
+        &self,
+        path: &Path,
+        store: &dyn StateStore,
+        version: u32,
+        chunk_key: &mut Option<ChunkKey>,
+    ) -> Result<(), SignalStateError> {
+        if self.chunked {
+            let key = chunk_key.get_or_insert_with(ChunkKey::generate).clone();
+            return self.seal_chunked(path, store, version, key).await;
+        }
+        let compression = self.compression;
+        let aad = self.associated_data(version);
+        let magic = [STREAM_MAGIC, &[compression.format()]].concat();
+        let (header, cipher) = self.new_key(&magic).await?;
+        let (tx, mut rx) = tokio::sync::mpsc::channel(4);
+        let path = path.to_owned();
+        let producer = tokio::task::spawn_blocking(move || {
+            let r = write_sealed(
+                &path,
+                &header,
+                cipher,
+                compression,
+                aad,
+                ChannelWriter(tx.clone()),
+            );
+            if let Err(ref e) = r {
+                // Make the upload fail instead of storing a truncated version.
+                let _ = tx.blocking_send(Err(std::io::Error::other(e.to_string())));
+            }
+            r
+        });
+        let mut reader =
+            tokio_util::io::StreamReader::new(futures::stream::poll_fn(move |cx| rx.poll_recv(cx)));
+        let (stored, produced) = tokio::join!(store.put_stream(version, &mut reader), producer);
+        stored?;
+        produced.map_err(std::io::Error::other)?
+    }
+
+    /// Stores the chunks which are not stored yet and then the manifest
+    /// as `version`.
+    async fn seal_chunked(
+        &self,
+        path: &Path,
+        store: &dyn StateStore,
+        version: u32,
+        key: ChunkKey,
+    ) -> Result<(), SignalStateError> {
+        let stored = store.list_chunks().await?.into_iter().collect();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(CHUNK_CONCURRENCY);
+        let path = path.to_owned();
+        let producer_key = key.clone();
+        let compression = self.compression;
+        let producer = tokio::task::spawn_blocking(move || {
+            write_chunks(&path, &producer_key, compression, stored, tx)
+        });
+        let upload = async {
+            let mut uploads = futures::stream::poll_fn(move |cx| rx.poll_recv(cx))
+                .map(|(name, sealed)| put_chunk(store, name, sealed))
+                .buffered(CHUNK_CONCURRENCY);
+            let mut manifest = key.as_bytes().to_vec();
+            let (mut chunks, mut new) = (0, 0);
+            while let Some(r) = uploads.next().await {
+                let (name, uploaded) = r?;
+                manifest.extend_from_slice(&name);
+                chunks += 1;
+                new += usize::from(uploaded);
+            }
+            log::info!("Stored {new} new of the {chunks} chunks of version {version}");
+            Ok::<_, StoreError>(manifest)
+        };
+        let (uploaded, produced) = tokio::join!(upload, producer);
+        // A failed upload makes the producer fail too, so it goes first.
+        let manifest = uploaded?;
+        produced.map_err(std::io::Error::other)??;
+        let (mut sealed, cipher) = self.new_key(CHUNKED_MAGIC).await?;
+        let mut nonce = [0u8; NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend(cipher.encrypt(
+            (&nonce).into(),
+            Payload {
+                msg: &manifest,
+                aad: &self.associated_data(version),
+            },
+        )?);
+        store.put(version, &sealed).await?;
+        Ok(())
+    }
+
+    /// Decrypts a manifest, which follows `CHUNKED_MAGIC`, into the chunk
+    /// key and chunk names.
+    async fn open_manifest(
+        &self,
+        mut sealed: &[u8],
+        aad: &[u8],
+    ) -> Result<(ChunkKey, Vec<chunk::Name>), SignalStateError> {
+        let cipher = self.find_key(&mut sealed).await?;
+        let (nonce, ciphertext) = split_nonce(sealed)?;
+        let manifest = cipher.decrypt(
+            nonce.into(),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )?;
+        let (key, names) = manifest
+            .split_first_chunk::<{ chunk::KEY_LEN }>()
+            .ok_or(SignalStateError::UnknownFormat)?;
+        let (names, rest) = names.as_chunks::<{ chunk::NAME_LEN }>();
+        if !rest.is_empty() {
+            return Err(SignalStateError::UnknownFormat);
+        }
+        Ok((ChunkKey::from_bytes(*key), names.to_vec()))
+    }
+
+    /// Fetches the chunks in a manifest and unpacks them into `path`.
+    async fn open_chunked(
+        &self,
+        store: &dyn StateStore,
+        sealed: &[u8],
+        aad: &[u8],
+        path: &Path,
+    ) -> Result<ChunkKey, SignalStateError> {
+        let (key, names) = self.open_manifest(sealed, aad).await?;
+        let hexes: Vec<_> = names.iter().map(hex::encode).collect();
+        let (tx, rx) = tokio::sync::mpsc::channel(CHUNK_CONCURRENCY);
+        let fetch = async move {
+            let fetches: Vec<_> = hexes.iter().map(|name| store.get_chunk(name)).collect();
+            let mut chunks = futures::stream::iter(fetches).buffered(CHUNK_CONCURRENCY);
+            while let Some(chunk) = chunks.next().await {
+                // The reader is gone once it has failed.
+                if tx.send(chunk.map_err(std::io::Error::other)).await.is_err() {
+                    break;
+                }
+            }
+        };
+        let reader = ChunkReader::new(rx, names, key.clone());
+        let path = path.to_owned();
+        let unpack = tokio::task::spawn_blocking(move || {
+            let mut archive = tar::Archive::new(reader);
+            archive.unpack(&path)?;
+            // Read to the end so that every chunk is checked.
+            std::io::copy(&mut archive.into_inner(), &mut std::io::sink())?;
+            Ok::<_, SignalStateError>(())
+        });
+        let ((), unpacked) = tokio::join!(fetch, unpack);
+        unpacked.map_err(std::io::Error::other)??;
+        Ok(key)
+    }
+
+    /// The names of the chunks which any of `versions` is made of.
+    pub async fn chunks_in_use(
+        &self,
+        store: &dyn StateStore,
+        versions: impl IntoIterator<Item = u32>,
+    ) -> Result<HashSet<String>, SignalStateError> {
+        let mut used = HashSet::new();
+        for version in versions {
+            let sealed = store.get(version).await?;
+            let (sealed, aad) = if let Some(sealed) = sealed.strip_prefix(CHUNKED_MAGIC) {
+                (sealed, self.associated_data(version))
+            } else if let Some(sealed) = sealed.strip_prefix(UNBOUND_CHUNKED_MAGIC) {
+                (sealed, Vec::new())
+            } else {
+                continue;
+            };
+            let (_, names) = self.open_manifest(sealed, &aad).await?;
+            used.extend(names.iter().map(hex::encode));
+        }
+        Ok(used)
+    }
+
+    /// Reads how `version` was sealed from its header, without opening
+    /// it. Versions in the older formats have none to read.
+    pub async fn sealing(
+        &self,
+        store: &dyn StateStore,
+        version: u32,
+    ) -> Result<Sealing, SignalStateError> {
+        let (mut reader, mut writer) = tokio::io::duplex(CHUNK_SIZE);
+        let fetch = async move { store.get_stream(version, &mut writer).await };
+        // Dropping the reader once the header is read stops the fetch.
+        let header = async move {
+            let mut magic = [0u8; STREAM_MAGIC.len()];
+            reader.read_exact(&mut magic).await?;
+            let bound = if magic == STREAM_MAGIC || magic == UNBOUND_STREAM_MAGIC {
+                reader.read_u8().await?;
+                magic == STREAM_MAGIC
+            } else if magic == CHUNKED_MAGIC || magic == UNBOUND_CHUNKED_MAGIC {
+                magic == CHUNKED_MAGIC
+            } else if magic == GZIP_STREAM_MAGIC {
+                false
+            } else {
+                return Err(SignalStateError::UnknownFormat);
+            };
+            let key = match reader.read_u8().await? {
+                KEY_KEYRING => SealingKey::Keyring(reader.read_u32().await?),
+                KEY_KMS => SealingKey::Kms,
+                KEY_PASSPHRASE => SealingKey::Passphrase,
+                _ => return Err(SignalStateError::UnknownFormat),
+            };
+            Ok(Sealing { bound, key })
+        };
+        match tokio::join!(fetch, header) {
+            (Err(e), Err(_)) => Err(e.into()),
+            (_, header) => header,
+        }
+    }
+
+    /// Fetches, decrypts and unpacks `version` into the state directory.
+    /// Returns the chunk key if it was chunked.
+    pub async fn open(
+        &self,
+        store: &dyn StateStore,
+        version: u32,
+        path: &Path,
+    ) -> Result<Option<ChunkKey>, SignalStateError> {
+        let (mut reader, mut writer) = tokio::io::duplex(CHUNK_SIZE);
+        let fetch = async move { store.get_stream(version, &mut writer).await };
+        let unseal = async move {
+            let mut magic = [0u8; STREAM_MAGIC.len()];
+            reader.read_exact(&mut magic).await?;
+            if magic == CHUNKED_MAGIC || magic == UNBOUND_CHUNKED_MAGIC {
+                let aad = if magic == CHUNKED_MAGIC {
+                    self.associated_data(version)
+                } else {
+                    self.check_unbound()?;
+                    Vec::new()
+                };
+                let mut sealed = Vec::new();
+                reader.read_to_end(&mut sealed).await?;
+                return self
+                    .open_chunked(store, &sealed, &aad, path)
+                    .await
+                    .map(Some);
+            }
+            let (format, aad) = if magic == STREAM_MAGIC {
+                (reader.read_u8().await?, self.associated_data(version))
+            } else if magic == UNBOUND_STREAM_MAGIC {
+                self.check_unbound()?;
+                (reader.read_u8().await?, Vec::new())
+            } else if magic == GZIP_STREAM_MAGIC {
+                self.check_unbound()?;
+                (FORMAT_GZIP, Vec::new())
+            } else {
+                self.check_unbound()?;
+                let mut sealed = magic.to_vec();
+                reader.read_to_end(&mut sealed).await?;
+                let tar_gz = self.open_legacy(&sealed).await?;
+                let path = path.to_owned();
+                tokio::task::spawn_blocking(move || unpack(&tar_gz, &path))
+                    .await
+                    .map_err(std::io::Error::other)??;
+                return Ok(None);
+            };
+            let cipher = self.find_key(&mut reader).await?;
+            let mut prefix = [0u8; STREAM_NONCE_SIZE];
+            reader.read_exact(&mut prefix).await?;
+            let reader = tokio_util::io::SyncIoBridge::new(reader);
+            let path = path.to_owned();
+            tokio::task::spawn_blocking(move || {
+                read_sealed(reader, cipher, &prefix, format, aad, &path)
+            })
+            .await
+            .map_err(std::io::Error::other)??;
+            Ok(None)
+        };
+        let (fetched, unsealed) = tokio::join!(fetch, unseal);
+        match (fetched, unsealed) {
+            (Err(e), Ok(_)) => Err(e.into()),
+            // Unsealing failed first and stopped reading.
+            (Err(StoreError::IOError(e)), Err(u)) if e.kind() == std::io::ErrorKind::BrokenPipe => {
+                log::error!("Fetching state version {version}: {e}");
+                Err(u)
+            }
+            // Fetching failed first and cut the version short, which is
+            // no reason to think it bad.
+            (Err(e), Err(u)) => {
+                log::error!("Unsealing state version {version}: {u}");
+                Err(e.into())
+            }
+            (Ok(()), unsealed) => unsealed,
+        }
+    }
+
+    /// Returns the gzipped tarball from a version in an older format.
+    async fn open_legacy(&self, sealed: &[u8]) -> Result<Vec<u8>, SignalStateError> {
+        if let Some(rest) = sealed.strip_prefix(PASSPHRASE_MAGIC) {
+            let passphrase = self
+                .passphrase
+                .as_ref()
+                .ok_or(SignalStateError::PassphraseRequired)?;
+            if rest.len() < SALT_LEN {
+                return Err(SignalStateError::CiphertextTooShort);
+            }
+            let (salt, rest) = rest.split_at(SALT_LEN);
+            let (nonce, ciphertext) = split_nonce(rest)?;
+            return Ok(passphrase
+                .for_salt(salt)?
+                .decrypt(nonce.into(), ciphertext)?);
+        }
+        let Some(rest) = sealed.strip_prefix(ENVELOPE_MAGIC) else {
+            let (nonce, ciphertext) = split_nonce(sealed)?;
+            return self.keys.decrypt(nonce, ciphertext);
+        };
+        let kms = self.kms.as_ref().ok_or(SignalStateError::KmsRequired)?;
+        if rest.len() < 2 {
+            return Err(SignalStateError::CiphertextTooShort);
+        }
+        let (len, rest) = rest.split_at(2);
+        let len = usize::from(u16::from_be_bytes([len[0], len[1]]));
+        if rest.len() < len {
+            return Err(SignalStateError::CiphertextTooShort);
+        }
+        let (wrapped, rest) = rest.split_at(len);
+        let cipher = ChaCha20Poly1305::new_from_slice(&kms.unwrap(wrapped).await?)?;
+        let (nonce, ciphertext) = split_nonce(rest)?;
+        Ok(cipher.decrypt(nonce.into(), ciphertext)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::store::mem::MemStore;
+
+    /// A state directory with enough in it to take several STREAM
+    /// chunks, which does not compress.
+    fn state() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let mut data = vec![0u8; 3 * CHUNK_SIZE];
+        OsRng.fill_bytes(&mut data);
+        std::fs::create_dir(dir.path().join("data")).unwrap();
+        std::fs::write(dir.path().join("data/account"), &data).unwrap();
+        std::fs::write(dir.path().join("small"), b"hello").unwrap();
+        dir
+    }
+
+    fn keyring(dir: &Path) -> Keyring {
+        Keyring::create_key_file(&dir.join("key")).unwrap()
+    }
+
+    fn passphrase(dir: &Path) -> Passphrase {
+        let path = dir.join("passphrase");
+        std::fs::write(&path, "correct horse").unwrap();
+        Passphrase::read(&path).unwrap()
+    }
+
+    async fn open(
+        sealer: &Sealer,
+        store: &MemStore,
+        version: u32,
+    ) -> Result<tempfile::TempDir, SignalStateError> {
+        let out = tempfile::tempdir().unwrap();
+        sealer.open(store, version, out.path()).await?;
+        Ok(out)
+    }
+
+    fn assert_same(a: &Path, b: &Path) {
+        for name in ["data/account", "small"] {
+            assert_eq!(
+                std::fs::read(a.join(name)).unwrap(),
+                std::fs::read(b.join(name)).unwrap(),
+                "{name}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn keyring_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state();
+        let sealer = Sealer::new(keyring(dir.path()), None, None);
+        let store = MemStore::default();
+        sealer
+            .seal(state.path(), &store, 7, &mut None)
+            .await
+            .unwrap();
+        assert!(store.versions.lock().unwrap()[&7].starts_with(STREAM_MAGIC));
+        let out = open(&sealer, &store, 7).await.unwrap();
+        assert_same(state.path(), out.path());
+
+        let other = Sealer::new(
+            Keyring::rotate(&dir.path().join("other")).unwrap(),
+            None,
+            None,
+        );
+        assert!(open(&other, &store, 7).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn zstd_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state();
+        let keys = keyring(dir.path());
+        let store = MemStore::default();
+        Sealer::new(keys.clone(), None, None)
+            .compression(Compression::zstd(3))
+            .seal(state.path(), &store, 1, &mut None)
+            .await
+            .unwrap();
+        assert!(store.versions.lock().unwrap()[&1].starts_with(STREAM_MAGIC));
+        // Whatever compression is chosen now.
+        let sealer = Sealer::new(keys, None, None);
+        assert_same(state.path(), open(&sealer, &store, 1).await.unwrap().path());
+    }
+
+    #[tokio::test]
+    async fn bound_to_version_and_deployment() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state();
+        let keys = keyring(dir.path());
+        let store = MemStore::default();
+        let sealer = Sealer::new(keys.clone(), None, None).deployment(String::from("prod"));
+        sealer
+            .seal(state.path(), &store, 1, &mut None)
+            .await
+            .unwrap();
+        sealer
+            .clone()
+            .chunked(true)
+            .seal(state.path(), &store, 3, &mut None)
+            .await
+            .unwrap();
+        for version in [1, 3] {
+            assert_same(
+                state.path(),
+                open(&sealer, &store, version).await.unwrap().path(),
+            );
+            let staging = Sealer::new(keys.clone(), None, None).deployment(String::from("staging"));
+            assert!(open(&staging, &store, version).await.is_err());
+            // Renamed to another version.
+            let sealed = store.versions.lock().unwrap()[&version].clone();
+            store.versions.lock().unwrap().insert(version + 1, sealed);
+            let e = open(&sealer, &store, version + 1).await.unwrap_err();
+            assert!(e.is_bad_version(), "{e}");
+        }
+    }
+
+    #[tokio::test]
+    async fn sealing() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state();
+        let keys = keyring(dir.path());
+        let store = MemStore::default();
+        let sealer = Sealer::new(keys.clone(), None, None).deployment(String::from("prod"));
+        sealer
+            .seal(state.path(), &store, 1, &mut None)
+            .await
+            .unwrap();
+        sealer
+            .clone()
+            .chunked(true)
+            .seal(state.path(), &store, 2, &mut None)
+            .await
+            .unwrap();
+        Sealer::new(keys.clone(), None, Some(passphrase(dir.path())))
+            .seal(state.path(), &store, 3, &mut None)
+            .await
+            .unwrap();
+        let rotated = Keyring::rotate(&dir.path().join("rotated")).unwrap();
+        Sealer::new(rotated, None, None)
+            .seal(state.path(), &store, 4, &mut None)
+            .await
+            .unwrap();
+        let magic = [UNBOUND_STREAM_MAGIC, &[Compression::default().format()]].concat();
+        let (header, cipher) = sealer.new_key(&magic).await.unwrap();
+        let mut unbound = Vec::new();
+        write_sealed(
+            state.path(),
+            &header,
+            cipher,
+            Compression::default(),
+            Vec::new(),
+            &mut unbound,
+        )
+        .unwrap();
+        store.versions.lock().unwrap().insert(5, unbound);
+
+        let sealing = sealer.sealing(&store, 1).await.unwrap();
+        // Even by a sealer which could not open it.
+        let staging = Sealer::new(keys, None, None).deployment(String::from("staging"));
+        assert_eq!(staging.sealing(&store, 1).await.unwrap(), sealing);
+        assert_eq!(sealer.sealing(&store, 2).await.unwrap(), sealing);
+        for other in [3, 4, 5] {
+            assert_ne!(sealer.sealing(&store, other).await.unwrap(), sealing);
+        }
+        store.versions.lock().unwrap().insert(6, b"junk".to_vec());
+        assert!(sealer.sealing(&store, 6).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn outdated() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state();
+        let path = dir.path().join("keyring");
+        let store = MemStore::default();
+        Sealer::new(Keyring::rotate(&path).unwrap(), None, None)
+            .seal(state.path(), &store, 1, &mut None)
+            .await
+            .unwrap();
+        let rotated = Sealer::new(Keyring::rotate(&path).unwrap(), None, None);
+        rotated
+            .seal(state.path(), &store, 2, &mut None)
+            .await
+            .unwrap();
+        assert!(rotated.outdated(&rotated.sealing(&store, 1).await.unwrap()));
+        assert!(!rotated.outdated(&rotated.sealing(&store, 2).await.unwrap()));
+        // New versions would not be encrypted with the keyring anyway.
+        let with_passphrase = Sealer::new(
+            Keyring::load(&path).unwrap(),
+            None,
+            Some(passphrase(dir.path())),
+        );
+        assert!(!with_passphrase.outdated(&rotated.sealing(&store, 1).await.unwrap()));
+    }
+
+    #[tokio::test]
+    async fn unbound_stream() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state();
+        let sealer = Sealer::new(keyring(dir.path()), None, None);
+        let (header, cipher) = sealer.new_key(GZIP_STREAM_MAGIC).await.unwrap();
+        let mut sealed = Vec::new();
+        write_sealed(
+            state.path(),
+            &header,
+            cipher,
+            Compression::default(),
+            Vec::new(),
+            &mut sealed,
+        )
+        .unwrap();
+        let store = MemStore::default();
+        store.versions.lock().unwrap().insert(4, sealed);
+        assert_same(state.path(), open(&sealer, &store, 4).await.unwrap().path());
+        let e = open(&sealer.require_bound(true), &store, 4)
+            .await
+            .unwrap_err();
+        assert!(matches!(e, SignalStateError::Unbound));
+        assert!(e.is_bad_version());
+    }
+
+    #[tokio::test]
+    async fn passphrase_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state();
+        let keys = keyring(dir.path());
+        let store = MemStore::default();
+        Sealer::new(keys.clone(), None, None)
+            .seal(state.path(), &store, 1, &mut None)
+            .await
+            .unwrap();
+        let sealer = Sealer::new(keys.clone(), None, Some(passphrase(dir.path())));
+        sealer
+            .seal(state.path(), &store, 2, &mut None)
+            .await
+            .unwrap();
+        assert_same(state.path(), open(&sealer, &store, 2).await.unwrap().path());
+        // Versions from before the passphrase was used still open.
+        assert_same(state.path(), open(&sealer, &store, 1).await.unwrap().path());
+        assert!(matches!(
+            open(&Sealer::new(keys, None, None), &store, 2).await,
+            Err(SignalStateError::PassphraseRequired)
+        ));
+    }
+
+    #[tokio::test]
+    async fn legacy_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let keys = keyring(dir.path());
+        let state = state();
+        let mut tar_gz = Vec::new();
+        let mut tar = tar::Builder::new(flate2::write::GzEncoder::new(
+            &mut tar_gz,
+            flate2::Compression::default(),
+        ));
+        tar.append_dir_all("", state.path()).unwrap();
+        tar.into_inner().unwrap().finish().unwrap();
+        let mut nonce = [0u8; NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        let ciphertext = keys
+            .current()
+            .unwrap()
+            .1
+            .encrypt((&nonce).into(), tar_gz.as_slice())
+            .unwrap();
+        let store = MemStore::default();
+        store
+            .versions
+            .lock()
+            .unwrap()
+            .insert(1, [&nonce[..], &ciphertext].concat());
+        let sealer = Sealer::new(keys, None, None);
+        assert_same(state.path(), open(&sealer, &store, 1).await.unwrap().path());
+    }
+
+    #[tokio::test]
+    async fn truncated() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state();
+        let sealer = Sealer::new(keyring(dir.path()), None, None);
+        let store = MemStore::default();
+        sealer
+            .seal(state.path(), &store, 7, &mut None)
+            .await
+            .unwrap();
+        let sealed = store.versions.lock().unwrap()[&7].clone();
+        // Magic, format, key type, key ID and nonce prefix.
+        let mut last = STREAM_MAGIC.len() + 1 + 1 + 4 + STREAM_NONCE_SIZE;
+        loop {
+            let header = u32::from_be_bytes(sealed[last..last + 4].try_into().unwrap());
+            if header & LAST_CHUNK != 0 {
+                break;
+            }
+            last += 4 + header as usize;
+        }
+        assert!(last > CHUNK_SIZE, "expected more than one chunk");
+        // Cut inside a chunk, and without the last chunk at all.
+        for len in [sealed.len() - 1, last] {
+            store
+                .versions
+                .lock()
+                .unwrap()
+                .insert(7, sealed[..len].to_vec());
+            // So that an older version is loaded in its place.
+            let e = open(&sealer, &store, 7).await.unwrap_err();
+            assert!(e.is_bad_version(), "{len}: {e}");
+        }
+    }
+
+    #[tokio::test]
+    async fn chunked_shares_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state();
+        // Big enough that it must be cut into several chunks.
+        let account = chunk::test_data(3 * chunk::MAX_CHUNK);
+        std::fs::write(state.path().join("data/account"), account).unwrap();
+        let sealer = Sealer::new(keyring(dir.path()), None, None).chunked(true);
+        let store = MemStore::default();
+        let mut chunk_key = None;
+        sealer
+            .seal(state.path(), &store, 1, &mut chunk_key)
+            .await
+            .unwrap();
+        assert!(store.versions.lock().unwrap()[&1].starts_with(CHUNKED_MAGIC));
+        let first: HashSet<_> = store.chunks.lock().unwrap().keys().cloned().collect();
+        assert!(first.len() > 1, "{}", first.len());
+        let out = open(&sealer, &store, 1).await.unwrap();
+        assert_same(state.path(), out.path());
+
+        std::fs::write(state.path().join("small"), b"changed").unwrap();
+        let mut chunk_key = sealer.open(&store, 1, out.path()).await.unwrap();
+        assert!(chunk_key.is_some());
+        sealer
+            .seal(state.path(), &store, 2, &mut chunk_key)
+            .await
+            .unwrap();
+        let second = sealer.chunks_in_use(&store, [2]).await.unwrap();
+        assert!(second.intersection(&first).count() > 0);
+        assert!(!second.is_subset(&first));
+        assert_same(state.path(), open(&sealer, &store, 2).await.unwrap().path());
+
+        // A chunk that is not the one named is refused.
+        let name = second.difference(&first).next().unwrap().clone();
+        let other = first.iter().next().unwrap().clone();
+        let replacement = store.chunks.lock().unwrap()[&other].clone();
+        store.chunks.lock().unwrap().insert(name, replacement);
+        assert!(open(&sealer, &store, 2).await.is_err());
+    }
+}
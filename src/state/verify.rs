@@ -0,0 +1,80 @@
+use chacha20poly1305::aead::OsRng;
+use chacha20poly1305::aead::rand_core::RngCore;
+use std::time::Duration;
+
+use crate::store::StoredVersion;
+
+#[derive(clap::Args)]
+pub struct VerifyArgs {
+    /// Every so often, download the newest version of the state and
+    /// check that it can be decrypted and unpacked, so that a damaged
+    /// version or a lost key is noticed before the state is needed.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    state_verify_interval: Option<Duration>,
+    /// Also check one of the older versions, chosen at random, each time.
+    #[arg(long, requires = "state_verify_interval")]
+    state_verify_older: bool,
+}
+
+impl VerifyArgs {
+    pub fn interval(&self) -> Option<Duration> {
+        self.state_verify_interval
+    }
+
+    /// Which of `versions` to check this time, and whether each is the
+    /// `newest` or an `older` one.
+    pub fn pick(&self, versions: &[StoredVersion]) -> Vec<(&'static str, u32)> {
+        let Some(newest) = versions.iter().map(|v| v.version).max() else {
+            return Vec::new();
+        };
+        let mut picked = vec![("newest", newest)];
+        let older = versions
+            .iter()
+            .map(|v| v.version)
+            .filter(|&v| v != newest)
+            .collect::<Vec<_>>();
+        if self.state_verify_older && !older.is_empty() {
+            let i = OsRng.next_u32() as usize % older.len();
+            picked.push(("older", older[i]));
+        }
+        picked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn versions(versions: &[u32]) -> Vec<StoredVersion> {
+        versions
+            .iter()
+            .map(|&version| StoredVersion {
+                version,
+                modified: None,
+            })
+            .collect()
+    }
+
+    fn args(state_verify_older: bool) -> VerifyArgs {
+        VerifyArgs {
+            state_verify_interval: Some(Duration::from_secs(3600)),
+            state_verify_older,
+        }
+    }
+
+    #[test]
+    fn newest_only() {
+        assert_eq!(args(false).pick(&versions(&[3, 5, 4])), [("newest", 5)]);
+        assert!(args(false).pick(&[]).is_empty());
+    }
+
+    #[test]
+    fn older() {
+        assert_eq!(args(true).pick(&versions(&[5])), [("newest", 5)]);
+        for _ in 0..20 {
+            let picked = args(true).pick(&versions(&[3, 5, 4]));
+            assert_eq!(picked[0], ("newest", 5));
+            assert!(matches!(picked[1], ("older", 3 | 4)), "{picked:?}");
+        }
+    }
+}
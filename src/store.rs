@@ -0,0 +1,245 @@
+use futures::future::BoxFuture;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+mod bucket;
+mod dir;
+mod gcs;
+#[cfg(test)]
+pub mod mem;
+mod mirror;
+
+#[derive(Debug, thiserror::Error)]
+pub enum StoreError {
+    #[error("{0}")]
+    S3Error(#[from] s3::error::S3Error),
+    #[error("{0}")]
+    IOError(#[from] std::io::Error),
+    #[error("{0}")]
+    HttpError(#[from] reqwest::Error),
+    #[error("Invalid state storage configuration: {0}")]
+    Config(String),
+    #[error("State version {0} already exists")]
+    Conflict(u32),
+    #[error("The lease was changed by another instance")]
+    LeaseConflict,
+}
+
+impl StoreError {
+    /// Whether trying again may work, as when the storage could not be
+    /// reached or was overloaded.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::S3Error(s3::error::S3Error::HttpFailWithBody(status, _)) => {
+                *status >= 500 || *status == 429
+            }
+            Self::S3Error(_) => true,
+            Self::HttpError(e) => e
+                .status()
+                .is_none_or(|s| s.is_server_error() || s == reqwest::StatusCode::TOO_MANY_REQUESTS),
+            Self::IOError(e) => !matches!(
+                e.kind(),
+                std::io::ErrorKind::NotFound
+                    | std::io::ErrorKind::InvalidData
+                    | std::io::ErrorKind::InvalidInput
+                    | std::io::ErrorKind::PermissionDenied
+            ),
+            Self::Config(_) | Self::Conflict(_) | Self::LeaseConflict => false,
+        }
+    }
+}
+
+/// Name of the object holding the lease for `--lease-duration`. It is
+/// kept with the state versions but is not a number so they ignore it.
+const LEASE: &str = "lease";
+/// Put before the names of chunks, which are not numbers either.
+const CHUNKS: &str = "chunks/";
+/// Put before the names of versions which could not be loaded, to keep
+/// them out of the way.
+const QUARANTINE: &str = "quarantine/";
+
+pub struct StoredVersion {
+    pub version: u32,
+    /// When it was stored, if the store knows.
+    pub modified: Option<SystemTime>,
+}
+
+/// Somewhere to keep the encrypted state. Each version of the state is
+/// an opaque blob stored under its version number. With
+/// `--state-chunked` versions also refer to chunks, which are opaque
+/// blobs named for what is in them and shared between versions.
+pub trait StateStore: Send + Sync {
+    fn list(&self) -> BoxFuture<'_, Result<Vec<StoredVersion>, StoreError>>;
+    fn get(&self, version: u32) -> BoxFuture<'_, Result<Vec<u8>, StoreError>>;
+    /// Fails with `StoreError::Conflict` rather than replace an existing
+    /// version, which means that another instance is using the store.
+    fn put<'a>(&'a self, version: u32, data: &'a [u8]) -> BoxFuture<'a, Result<(), StoreError>>;
+    fn delete(&self, version: u32) -> BoxFuture<'_, Result<(), StoreError>>;
+    /// Moves a version which cannot be loaded to where `list` does not
+    /// see it, keeping it for the operator to look at.
+    fn quarantine(&self, version: u32) -> BoxFuture<'_, Result<(), StoreError>>;
+
+    fn list_chunks(&self) -> BoxFuture<'_, Result<Vec<String>, StoreError>>;
+    fn get_chunk<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<u8>, StoreError>>;
+    /// Replaces any chunk of the same name, which has the same contents.
+    fn put_chunk<'a>(
+        &'a self,
+        name: &'a str,
+        data: &'a [u8],
+    ) -> BoxFuture<'a, Result<(), StoreError>>;
+    fn delete_chunk<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<(), StoreError>>;
+
+    fn get_lease(&self) -> BoxFuture<'_, Result<Option<Vec<u8>>, StoreError>>;
+    /// Replaces the lease with `data` if it is still `expected` (`None`
+    /// meaning that there is none) and otherwise fails with
+    /// `StoreError::LeaseConflict`.
+    fn swap_lease<'a>(
+        &'a self,
+        expected: Option<&'a [u8]>,
+        data: &'a [u8],
+    ) -> BoxFuture<'a, Result<(), StoreError>>;
+
+    /// Like `put` but reads the blob from `data` as it goes. Stores which
+    /// cannot stream buffer the whole blob.
+    fn put_stream<'a>(
+        &'a self,
+        version: u32,
+        data: &'a mut (dyn AsyncRead + Send + Unpin),
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            let mut buf = Vec::new();
+            data.read_to_end(&mut buf).await?;
+            self.put(version, &buf).await
+        })
+    }
+
+    /// Like `get` but writes the blob to `out` as it arrives.
+    fn get_stream<'a>(
+        &'a self,
+        version: u32,
+        out: &'a mut (dyn AsyncWrite + Send + Unpin),
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            out.write_all(&self.get(version).await?).await?;
+            out.shutdown().await?;
+            Ok(())
+        })
+    }
+}
+
+#[derive(clap::Args)]
+pub struct StateStoreArgs {
+    #[arg(long)]
+    s3_endpoint: Option<String>,
+    #[arg(long)]
+    s3_region_name: Option<String>,
+    #[arg(long, conflicts_with_all = ["state_dir", "gcs_bucket"])]
+    bucket_name: Option<String>,
+    #[arg(long)]
+    state_dir: Option<PathBuf>,
+    #[arg(long, conflicts_with = "state_dir")]
+    gcs_bucket: Option<String>,
+    /// Also write everything to this S3 bucket, preferably in another
+    /// region or with another provider, and read from it what cannot be
+    /// read from the state storage. It uses the same S3 credentials.
+    #[arg(long, conflicts_with_all = ["mirror_state_dir", "mirror_gcs_bucket"])]
+    mirror_bucket_name: Option<String>,
+    #[arg(long, requires = "mirror_bucket_name")]
+    mirror_s3_region_name: Option<String>,
+    #[arg(long, requires = "mirror_bucket_name")]
+    mirror_s3_endpoint: Option<String>,
+    /// Like `--mirror-bucket-name` but a directory.
+    #[arg(long)]
+    mirror_state_dir: Option<PathBuf>,
+    /// Like `--mirror-bucket-name` but a GCS bucket.
+    #[arg(long, conflicts_with = "mirror_state_dir")]
+    mirror_gcs_bucket: Option<String>,
+}
+
+/// Where one store is, from the flags for either the state storage or
+/// its mirror, which start with `flags`.
+struct Location<'a> {
+    flags: &'static str,
+    s3_endpoint: Option<&'a String>,
+    s3_region_name: Option<&'a String>,
+    bucket_name: Option<&'a String>,
+    state_dir: Option<&'a Path>,
+    gcs_bucket: Option<&'a String>,
+}
+
+impl Location<'_> {
+    /// `None` if no store is given.
+    fn store(&self, prefix: &str) -> Result<Option<Arc<dyn StateStore>>, StoreError> {
+        if let Some(path) = self.state_dir {
+            let path = path.join(prefix);
+            if !prefix.is_empty() {
+                std::fs::create_dir_all(&path)?;
+            }
+            return Ok(Some(Arc::new(dir::DirStore::new(path)?)));
+        }
+        if let Some(bucket) = self.gcs_bucket {
+            return Ok(Some(Arc::new(gcs::GcsStore::new(
+                bucket.clone(),
+                String::from(prefix),
+            ))));
+        }
+        match (self.bucket_name, self.s3_region_name) {
+            (Some(bucket_name), Some(region_name)) => Ok(Some(Arc::new(bucket::BucketStore::new(
+                bucket_name,
+                region_name,
+                self.s3_endpoint.cloned(),
+                String::from(prefix),
+            )?))),
+            (Some(_), None) => Err(StoreError::Config(format!(
+                "--{0}bucket-name requires --{0}s3-region-name",
+                self.flags
+            ))),
+            (None, _) => Ok(None),
+        }
+    }
+}
+
+impl StateStoreArgs {
+    /// The store with every object name starting with `prefix`, so that
+    /// more than one state can share a bucket. In `--state-dir` the
+    /// prefix is a subdirectory, which is created if need be.
+    pub fn store(&self, prefix: &str) -> Result<Arc<dyn StateStore>, StoreError> {
+        if !prefix
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_./".contains(&b))
+        {
+            return Err(StoreError::Config(format!(
+                "prefix {prefix:?} may only have letters, digits, '-', '_', '.' and '/'"
+            )));
+        }
+        let primary = Location {
+            flags: "",
+            s3_endpoint: self.s3_endpoint.as_ref(),
+            s3_region_name: self.s3_region_name.as_ref(),
+            bucket_name: self.bucket_name.as_ref(),
+            state_dir: self.state_dir.as_deref(),
+            gcs_bucket: self.gcs_bucket.as_ref(),
+        }
+        .store(prefix)?
+        .ok_or_else(|| {
+            StoreError::Config(String::from(
+                "one of --bucket-name, --gcs-bucket or --state-dir is required",
+            ))
+        })?;
+        let mirror = Location {
+            flags: "mirror-",
+            s3_endpoint: self.mirror_s3_endpoint.as_ref(),
+            s3_region_name: self.mirror_s3_region_name.as_ref(),
+            bucket_name: self.mirror_bucket_name.as_ref(),
+            state_dir: self.mirror_state_dir.as_deref(),
+            gcs_bucket: self.mirror_gcs_bucket.as_ref(),
+        }
+        .store(prefix)?;
+        Ok(match mirror {
+            Some(mirror) => Arc::new(mirror::MirrorStore::new(primary, mirror)),
+            None => primary,
+        })
+    }
+}
@@ -0,0 +1,314 @@
+use futures::future::BoxFuture;
+use http::{HeaderMap, HeaderValue, header};
+use s3::creds::Credentials;
+use s3::error::S3Error;
+use s3::{Bucket, Region};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::{CHUNKS, LEASE, QUARANTINE, StateStore, StoreError, StoredVersion};
+
+/// Versions at least this big are uploaded in parts of this size. S3
+/// wants parts of at least 5MiB.
+const PART_SIZE: usize = 8 << 20;
+const CONTENT_TYPE: &str = "application/octet-stream";
+
+pub struct BucketStore {
+    bucket: Box<Bucket>,
+    /// Put before every object name.
+    prefix: String,
+}
+
+impl BucketStore {
+    pub fn new(
+        bucket_name: &str,
+        region_name: &str,
+        endpoint: Option<String>,
+        prefix: String,
+    ) -> Result<Self, StoreError> {
+        let cred = Credentials::default().map_err(|e| StoreError::Config(e.to_string()))?;
+        let region = match endpoint {
+            Some(endpoint) => Region::Custom {
+                region: String::from(region_name),
+                endpoint,
+            },
+            None => region_name
+                .parse::<Region>()
+                .map_err(|e| StoreError::Config(e.to_string()))?,
+        };
+        Ok(Self {
+            bucket: Bucket::new(bucket_name, region, cred)?,
+            prefix,
+        })
+    }
+
+    fn key(&self, name: impl std::fmt::Display) -> String {
+        format!("{}{name}", self.prefix)
+    }
+}
+
+fn condition(name: header::HeaderName, value: &str) -> Result<HeaderMap, S3Error> {
+    Ok(HeaderMap::from_iter([(
+        name,
+        HeaderValue::from_str(value)?,
+    )]))
+}
+
+/// Writes with `condition` fail if it does not hold: with 412, or with
+/// 409 if another conditional write of the same object is under way.
+fn unless_conflict(e: S3Error, conflict: StoreError) -> StoreError {
+    match e {
+        S3Error::HttpFailWithBody(409 | 412, _) => conflict,
+        e => e.into(),
+    }
+}
+
+/// Reads up to `PART_SIZE`, less only at the end.
+async fn read_part(data: &mut (dyn AsyncRead + Send + Unpin)) -> std::io::Result<Vec<u8>> {
+    let mut part = Vec::with_capacity(PART_SIZE);
+    while part.len() < PART_SIZE {
+        let n = (&mut *data)
+            .take((PART_SIZE - part.len()) as u64)
+            .read_to_end(&mut part)
+            .await?;
+        if n == 0 {
+            break;
+        }
+    }
+    Ok(part)
+}
+
+impl BucketStore {
+    /// Uploads `part` and what follows it in `data` as `key` in parts,
+    /// completing the upload only if `key` does not exist yet.
+    async fn put_parts(
+        &self,
+        key: &str,
+        mut part: Vec<u8>,
+        data: &mut (dyn AsyncRead + Send + Unpin),
+    ) -> Result<(), S3Error> {
+        let upload = self
+            .bucket
+            .initiate_multipart_upload(key, CONTENT_TYPE)
+            .await?;
+        let r = async {
+            let mut parts = Vec::new();
+            while !part.is_empty() {
+                let number = parts.len() as u32 + 1;
+                parts.push(
+                    self.bucket
+                        .put_multipart_chunk(part, key, number, &upload.upload_id, CONTENT_TYPE)
+                        .await?,
+                );
+                part = read_part(data).await?;
+            }
+            // Only completing the upload creates the object.
+            self.bucket
+                .with_extra_headers(condition(header::IF_NONE_MATCH, "*")?)?
+                .complete_multipart_upload(key, &upload.upload_id, parts)
+                .await?;
+            Ok(())
+        }
+        .await;
+        if r.is_err()
+            && let Err(e) = self.bucket.abort_upload(key, &upload.upload_id).await
+        {
+            log::warn!("Aborting upload of {key}: {e}");
+        }
+        r
+    }
+}
+
+impl StateStore for BucketStore {
+    fn list(&self) -> BoxFuture<'_, Result<Vec<StoredVersion>, StoreError>> {
+        Box::pin(async move {
+            Ok(self
+                .bucket
+                .list(self.prefix.clone(), Some(String::from("")))
+                .await?
+                .into_iter()
+                .flat_map(|entry| {
+                    entry.contents.into_iter().filter_map(|obj| {
+                        Some(StoredVersion {
+                            version: obj.key.strip_prefix(&self.prefix)?.parse().ok()?,
+                            modified: humantime::parse_rfc3339_weak(&obj.last_modified).ok(),
+                        })
+                    })
+                })
+                .collect())
+        })
+    }
+
+    fn get(&self, version: u32) -> BoxFuture<'_, Result<Vec<u8>, StoreError>> {
+        Box::pin(async move {
+            Ok(self
+                .bucket
+                .get_object(self.key(version))
+                .await?
+                .as_slice()
+                .to_vec())
+        })
+    }
+
+    /// With `If-None-Match`, so that S3 refuses to replace a version.
+    fn put<'a>(&'a self, version: u32, data: &'a [u8]) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            self.bucket
+                .put_object_with_headers(
+                    self.key(version),
+                    data,
+                    Some(condition(header::IF_NONE_MATCH, "*")?),
+                )
+                .await
+                .map_err(|e| unless_conflict(e, StoreError::Conflict(version)))?;
+            Ok(())
+        })
+    }
+
+    fn delete(&self, version: u32) -> BoxFuture<'_, Result<(), StoreError>> {
+        Box::pin(async move {
+            self.bucket.delete_object(self.key(version)).await?;
+            Ok(())
+        })
+    }
+
+    fn quarantine(&self, version: u32) -> BoxFuture<'_, Result<(), StoreError>> {
+        Box::pin(async move {
+            let data = self.bucket.get_object(self.key(version)).await?;
+            self.bucket
+                .put_object(self.key(format!("{QUARANTINE}{version}")), data.as_slice())
+                .await?;
+            self.bucket.delete_object(self.key(version)).await?;
+            Ok(())
+        })
+    }
+
+    fn list_chunks(&self) -> BoxFuture<'_, Result<Vec<String>, StoreError>> {
+        Box::pin(async move {
+            let prefix = self.key(CHUNKS);
+            Ok(self
+                .bucket
+                .list(prefix.clone(), Some(String::from("")))
+                .await?
+                .into_iter()
+                .flat_map(|entry| {
+                    entry
+                        .contents
+                        .into_iter()
+                        .filter_map(|obj| Some(String::from(obj.key.strip_prefix(&prefix)?)))
+                })
+                .collect())
+        })
+    }
+
+    fn get_chunk<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<u8>, StoreError>> {
+        Box::pin(async move {
+            Ok(self
+                .bucket
+                .get_object(self.key(format!("{CHUNKS}{name}")))
+                .await?
+                .as_slice()
+                .to_vec())
+        })
+    }
+
+    fn put_chunk<'a>(
+        &'a self,
+        name: &'a str,
+        data: &'a [u8],
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            self.bucket
+                .put_object(self.key(format!("{CHUNKS}{name}")), data)
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn delete_chunk<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            self.bucket
+                .delete_object(self.key(format!("{CHUNKS}{name}")))
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn get_lease(&self) -> BoxFuture<'_, Result<Option<Vec<u8>>, StoreError>> {
+        Box::pin(async move {
+            match self.bucket.get_object(self.key(LEASE)).await {
+                Ok(r) if r.status_code() == 404 => Ok(None),
+                Ok(r) => Ok(Some(r.as_slice().to_vec())),
+                Err(s3::error::S3Error::HttpFailWithBody(404, _)) => Ok(None),
+                Err(e) => Err(e.into()),
+            }
+        })
+    }
+
+    /// With `If-Match` on the ETag of the lease that was expected, or
+    /// `If-None-Match` if there was to be none, so that of two instances
+    /// swapping the same lease only one succeeds.
+    fn swap_lease<'a>(
+        &'a self,
+        expected: Option<&'a [u8]>,
+        data: &'a [u8],
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            let current = match self.bucket.get_object(self.key(LEASE)).await {
+                Ok(r) if r.status_code() == 404 => None,
+                Ok(r) => Some(r),
+                Err(S3Error::HttpFailWithBody(404, _)) => None,
+                Err(e) => return Err(e.into()),
+            };
+            let condition = match (expected, current) {
+                (None, None) => condition(header::IF_NONE_MATCH, "*")?,
+                (Some(expected), Some(current)) if current.as_slice() == expected => {
+                    let headers = current.headers();
+                    let Some(etag) = headers.get("etag") else {
+                        return Err(StoreError::Config(String::from(
+                            "S3 gave the lease no ETag",
+                        )));
+                    };
+                    condition(header::IF_MATCH, etag)?
+                }
+                _ => return Err(StoreError::LeaseConflict),
+            };
+            self.bucket
+                .put_object_with_headers(self.key(LEASE), data, Some(condition))
+                .await
+                .map_err(|e| unless_conflict(e, StoreError::LeaseConflict))?;
+            Ok(())
+        })
+    }
+
+    /// Uses a multipart upload unless the version is small, completed
+    /// with `If-None-Match` like `put`.
+    fn put_stream<'a>(
+        &'a self,
+        version: u32,
+        data: &'a mut (dyn AsyncRead + Send + Unpin),
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            let part = read_part(data).await?;
+            if part.len() < PART_SIZE {
+                return self.put(version, &part).await;
+            }
+            self.put_parts(&self.key(version), part, data)
+                .await
+                .map_err(|e| unless_conflict(e, StoreError::Conflict(version)))
+        })
+    }
+
+    fn get_stream<'a>(
+        &'a self,
+        version: u32,
+        out: &'a mut (dyn AsyncWrite + Send + Unpin),
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            self.bucket
+                .get_object_to_writer(self.key(version), out)
+                .await?;
+            out.shutdown().await?;
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,358 @@
+use futures::future::BoxFuture;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+use super::{CHUNKS, LEASE, QUARANTINE, StateStore, StoreError, StoredVersion};
+
+/// Stores each version as a file in a local (or network mounted) directory.
+pub struct DirStore(Arc<PathBuf>);
+
+impl DirStore {
+    pub fn new(path: PathBuf) -> Result<Self, StoreError> {
+        if !std::fs::metadata(&path)?.is_dir() {
+            return Err(StoreError::Config(format!(
+                "{} is not a directory",
+                path.display()
+            )));
+        }
+        Ok(Self(Arc::new(path)))
+    }
+
+    async fn blocking<T, F>(&self, f: F) -> Result<T, StoreError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&PathBuf) -> Result<T, std::io::Error> + Send + 'static,
+    {
+        let path = Arc::clone(&self.0);
+        tokio::task::spawn_blocking(move || f(&path))
+            .await
+            .map_err(std::io::Error::other)?
+            .map_err(StoreError::from)
+    }
+}
+
+fn read_lease(path: &Path) -> Result<Option<Vec<u8>>, std::io::Error> {
+    match std::fs::read(path.join(LEASE)) {
+        Ok(data) => Ok(Some(data)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Moves the temporary file `tmp` into place as `version`, returning
+/// false if that version already exists.
+fn link_into_place(path: &Path, tmp: &Path, version: u32) -> Result<bool, std::io::Error> {
+    // Link instead of renaming because a rename would replace it.
+    let r = std::fs::hard_link(tmp, path.join(version.to_string()));
+    std::fs::remove_file(tmp)?;
+    match r {
+        Ok(()) => (),
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => return Ok(false),
+        Err(e) => return Err(e),
+    }
+    std::fs::File::open(path)?.sync_all()?;
+    Ok(true)
+}
+
+impl StateStore for DirStore {
+    fn list(&self) -> BoxFuture<'_, Result<Vec<StoredVersion>, StoreError>> {
+        Box::pin(self.blocking(|path| {
+            let mut versions = Vec::new();
+            for entry in std::fs::read_dir(path)? {
+                let entry = entry?;
+                if let Some(version) = entry.file_name().to_str().and_then(|n| n.parse().ok()) {
+                    versions.push(StoredVersion {
+                        version,
+                        modified: entry.metadata()?.modified().ok(),
+                    });
+                }
+            }
+            Ok(versions)
+        }))
+    }
+
+    fn get(&self, version: u32) -> BoxFuture<'_, Result<Vec<u8>, StoreError>> {
+        Box::pin(self.blocking(move |path| std::fs::read(path.join(version.to_string()))))
+    }
+
+    fn put<'a>(&'a self, version: u32, data: &'a [u8]) -> BoxFuture<'a, Result<(), StoreError>> {
+        let data = data.to_vec();
+        Box::pin(async move {
+            let linked = self
+                .blocking(move |path| {
+                    // Write under a name that list() ignores and then move it into
+                    // place so that a crash never leaves a truncated version behind.
+                    let tmp = path.join(format!(".{version}.tmp"));
+                    let mut f = std::fs::File::create(&tmp)?;
+                    f.write_all(&data)?;
+                    f.sync_all()?;
+                    drop(f);
+                    link_into_place(path, &tmp, version)
+                })
+                .await?;
+            if !linked {
+                return Err(StoreError::Conflict(version));
+            }
+            Ok(())
+        })
+    }
+
+    fn delete(&self, version: u32) -> BoxFuture<'_, Result<(), StoreError>> {
+        Box::pin(self.blocking(move |path| std::fs::remove_file(path.join(version.to_string()))))
+    }
+
+    fn quarantine(&self, version: u32) -> BoxFuture<'_, Result<(), StoreError>> {
+        Box::pin(self.blocking(move |path| {
+            let dir = path.join(QUARANTINE);
+            std::fs::create_dir_all(&dir)?;
+            std::fs::rename(
+                path.join(version.to_string()),
+                dir.join(version.to_string()),
+            )?;
+            std::fs::File::open(path)?.sync_all()
+        }))
+    }
+
+    fn list_chunks(&self) -> BoxFuture<'_, Result<Vec<String>, StoreError>> {
+        Box::pin(self.blocking(|path| {
+            let entries = match std::fs::read_dir(path.join(CHUNKS)) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+                Err(e) => return Err(e),
+            };
+            let mut chunks = Vec::new();
+            for entry in entries {
+                let entry = entry?;
+                let Some(name) = entry.file_name().to_str().map(String::from) else {
+                    continue;
+                };
+                if !name.starts_with('.') {
+                    chunks.push(name);
+                }
+            }
+            Ok(chunks)
+        }))
+    }
+
+    fn get_chunk<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<u8>, StoreError>> {
+        let name = String::from(name);
+        Box::pin(self.blocking(move |path| std::fs::read(path.join(CHUNKS).join(name))))
+    }
+
+    fn put_chunk<'a>(
+        &'a self,
+        name: &'a str,
+        data: &'a [u8],
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        let name = String::from(name);
+        let data = data.to_vec();
+        Box::pin(self.blocking(move |path| {
+            let dir = path.join(CHUNKS);
+            std::fs::create_dir_all(&dir)?;
+            // As in put(), but replacing a chunk loses nothing.
+            let tmp = dir.join(format!(".{name}.tmp"));
+            let mut f = std::fs::File::create(&tmp)?;
+            f.write_all(&data)?;
+            f.sync_all()?;
+            drop(f);
+            std::fs::rename(tmp, dir.join(name))?;
+            std::fs::File::open(dir)?.sync_all()
+        }))
+    }
+
+    fn delete_chunk<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<(), StoreError>> {
+        let name = String::from(name);
+        Box::pin(self.blocking(move |path| std::fs::remove_file(path.join(CHUNKS).join(name))))
+    }
+
+    fn get_lease(&self) -> BoxFuture<'_, Result<Option<Vec<u8>>, StoreError>> {
+        Box::pin(self.blocking(|path| read_lease(path)))
+    }
+
+    fn swap_lease<'a>(
+        &'a self,
+        expected: Option<&'a [u8]>,
+        data: &'a [u8],
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        let expected = expected.map(<[u8]>::to_vec);
+        let data = data.to_vec();
+        Box::pin(async move {
+            let swapped = self
+                .blocking(move |path| {
+                    let lock = std::fs::File::create(path.join(".lease.lock"))?;
+                    lock.lock()?;
+                    if read_lease(path)? != expected {
+                        return Ok(false);
+                    }
+                    let tmp = path.join(".lease.tmp");
+                    let mut f = std::fs::File::create(&tmp)?;
+                    f.write_all(&data)?;
+                    f.sync_all()?;
+                    drop(f);
+                    std::fs::rename(&tmp, path.join(LEASE))?;
+                    std::fs::File::open(path)?.sync_all()?;
+                    Ok(true)
+                })
+                .await?;
+            if !swapped {
+                return Err(StoreError::LeaseConflict);
+            }
+            Ok(())
+        })
+    }
+
+    fn put_stream<'a>(
+        &'a self,
+        version: u32,
+        data: &'a mut (dyn AsyncRead + Send + Unpin),
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            // Same as put().
+            let tmp = self.0.join(format!(".{version}.tmp"));
+            let mut f = tokio::fs::File::create(&tmp).await?;
+            tokio::io::copy(data, &mut f).await?;
+            f.sync_all().await?;
+            drop(f);
+            if !self
+                .blocking(move |path| link_into_place(path, &tmp, version))
+                .await?
+            {
+                return Err(StoreError::Conflict(version));
+            }
+            Ok(())
+        })
+    }
+
+    fn get_stream<'a>(
+        &'a self,
+        version: u32,
+        out: &'a mut (dyn AsyncWrite + Send + Unpin),
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            let mut f = tokio::fs::File::open(self.0.join(version.to_string())).await?;
+            tokio::io::copy(&mut f, out).await?;
+            out.shutdown().await?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn versions(store: &DirStore) -> Vec<u32> {
+        let listed = store.list().await.unwrap();
+        assert!(listed.iter().all(|v| v.modified.is_some()));
+        let mut versions = listed.into_iter().map(|v| v.version).collect::<Vec<_>>();
+        versions.sort();
+        versions
+    }
+
+    #[tokio::test]
+    async fn put_get_list_delete() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = DirStore::new(dir.path().to_owned()).unwrap();
+        assert!(versions(&store).await.is_empty());
+        store.put(1, b"one").await.unwrap();
+        store.put(2, b"two").await.unwrap();
+        assert_eq!(versions(&store).await, [1, 2]);
+        assert_eq!(store.get(2).await.unwrap(), b"two");
+        store.delete(1).await.unwrap();
+        assert_eq!(versions(&store).await, [2]);
+        assert!(store.get(1).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn list_ignores_other_files() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join(".3.tmp"), b"partial").unwrap();
+        std::fs::write(dir.path().join("notes"), b"").unwrap();
+        let store = DirStore::new(dir.path().to_owned()).unwrap();
+        store.put(4, b"four").await.unwrap();
+        assert_eq!(versions(&store).await, [4]);
+    }
+
+    #[tokio::test]
+    async fn never_overwrites() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = DirStore::new(dir.path().to_owned()).unwrap();
+        store.put(1, b"one").await.unwrap();
+        assert!(matches!(
+            store.put(1, b"other").await,
+            Err(StoreError::Conflict(1))
+        ));
+        let mut data: &[u8] = b"other";
+        assert!(matches!(
+            store.put_stream(1, &mut data).await,
+            Err(StoreError::Conflict(1))
+        ));
+        assert_eq!(store.get(1).await.unwrap(), b"one");
+        // The temporary files are cleaned up.
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn swap_lease() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = DirStore::new(dir.path().to_owned()).unwrap();
+        assert_eq!(store.get_lease().await.unwrap(), None);
+        store.swap_lease(None, b"a").await.unwrap();
+        assert!(matches!(
+            store.swap_lease(None, b"b").await,
+            Err(StoreError::LeaseConflict)
+        ));
+        assert!(matches!(
+            store.swap_lease(Some(b"x"), b"b").await,
+            Err(StoreError::LeaseConflict)
+        ));
+        store.swap_lease(Some(b"a"), b"b").await.unwrap();
+        assert_eq!(store.get_lease().await.unwrap().as_deref(), Some(&b"b"[..]));
+        // The lease is not mistaken for a version.
+        assert!(versions(&store).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn quarantine() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = DirStore::new(dir.path().to_owned()).unwrap();
+        store.put(1, b"one").await.unwrap();
+        store.put(2, b"bad").await.unwrap();
+        store.quarantine(2).await.unwrap();
+        assert_eq!(versions(&store).await, [1]);
+        assert_eq!(
+            std::fs::read(dir.path().join("quarantine/2")).unwrap(),
+            b"bad"
+        );
+        // The version number can be used again.
+        store.put(2, b"two").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = DirStore::new(dir.path().to_owned()).unwrap();
+        assert!(store.list_chunks().await.unwrap().is_empty());
+        store.put_chunk("abc", b"one").await.unwrap();
+        store.put_chunk("abc", b"one").await.unwrap();
+        store.put(1, b"manifest").await.unwrap();
+        let chunks = store.list_chunks().await.unwrap();
+        assert_eq!(chunks, ["abc"]);
+        assert_eq!(store.get_chunk("abc").await.unwrap(), b"one");
+        // Chunks are not mistaken for versions.
+        assert_eq!(versions(&store).await, [1]);
+        store.delete_chunk("abc").await.unwrap();
+        assert!(store.list_chunks().await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn not_a_directory() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        assert!(matches!(
+            DirStore::new(file.path().to_owned()),
+            Err(StoreError::Config(_))
+        ));
+    }
+}
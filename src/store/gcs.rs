@@ -0,0 +1,272 @@
+use futures::future::BoxFuture;
+use serde::Deserialize;
+use std::time::SystemTime;
+
+use super::{CHUNKS, LEASE, QUARANTINE, StateStore, StoreError, StoredVersion};
+use crate::gcp::MetadataToken;
+
+const API_BASE: &str = "https://storage.googleapis.com";
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListResponse {
+    #[serde(default)]
+    items: Vec<ListItem>,
+    next_page_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ListItem {
+    name: String,
+    updated: Option<String>,
+}
+
+/// Google Cloud Storage via its JSON API. Credentials come from the
+/// metadata server.
+pub struct GcsStore {
+    client: reqwest::Client,
+    bucket: String,
+    /// Put before every object name.
+    prefix: String,
+    token: MetadataToken,
+}
+
+impl GcsStore {
+    pub fn new(bucket: String, prefix: String) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            bucket,
+            prefix,
+            token: MetadataToken::default(),
+        }
+    }
+
+    async fn token(&self) -> Result<String, StoreError> {
+        Ok(self.token.get(&self.client).await?)
+    }
+
+    fn name(&self, name: impl std::fmt::Display) -> String {
+        format!("{}{name}", self.prefix)
+    }
+
+    /// The prefix only has characters which need no escaping but `/`.
+    fn object_url(&self, name: impl std::fmt::Display) -> String {
+        format!(
+            "{API_BASE}/storage/v1/b/{}/o/{}",
+            self.bucket,
+            self.name(name).replace('/', "%2F")
+        )
+    }
+
+    /// Returns the object and its generation.
+    async fn get_object(&self, name: &str) -> Result<Option<(Vec<u8>, String)>, StoreError> {
+        let r = self
+            .client
+            .get(self.object_url(name))
+            .query(&[("alt", "media")])
+            .bearer_auth(self.token().await?)
+            .send()
+            .await?;
+        if r.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let r = r.error_for_status()?;
+        let generation = r
+            .headers()
+            .get("x-goog-generation")
+            .and_then(|g| g.to_str().ok())
+            .unwrap_or("0")
+            .to_owned();
+        Ok(Some((r.bytes().await?.to_vec(), generation)))
+    }
+
+    /// Writes the object if its generation is still `generation` (0 for
+    /// none), returning false if it is not. Without a generation it
+    /// writes the object whatever is there.
+    async fn upload(
+        &self,
+        name: &str,
+        data: &[u8],
+        generation: Option<&str>,
+    ) -> Result<bool, StoreError> {
+        let name = self.name(name);
+        let mut req = self
+            .client
+            .post(format!("{API_BASE}/upload/storage/v1/b/{}/o", self.bucket))
+            .query(&[("uploadType", "media"), ("name", name.as_str())]);
+        if let Some(generation) = generation {
+            req = req.query(&[("ifGenerationMatch", generation)]);
+        }
+        let r = req
+            .bearer_auth(self.token().await?)
+            .header(http::header::CONTENT_TYPE, "application/octet-stream")
+            .body(data.to_vec())
+            .send()
+            .await?;
+        if r.status() == reqwest::StatusCode::PRECONDITION_FAILED {
+            return Ok(false);
+        }
+        r.error_for_status()?;
+        Ok(true)
+    }
+
+    async fn delete_object(&self, name: impl std::fmt::Display) -> Result<(), StoreError> {
+        self.client
+            .delete(self.object_url(name))
+            .bearer_auth(self.token().await?)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Returns the names after `prefix` of the objects under it.
+    async fn list_objects(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<(String, Option<SystemTime>)>, StoreError> {
+        let url = format!("{API_BASE}/storage/v1/b/{}/o", self.bucket);
+        let prefix = self.name(prefix);
+        let mut objects = Vec::new();
+        let mut page_token = None;
+        loop {
+            let mut req = self
+                .client
+                .get(&url)
+                .query(&[("prefix", &prefix)])
+                .bearer_auth(self.token().await?);
+            if let Some(ref t) = page_token {
+                req = req.query(&[("pageToken", t)]);
+            }
+            let r: ListResponse = req.send().await?.error_for_status()?.json().await?;
+            objects.extend(r.items.into_iter().filter_map(|i| {
+                Some((
+                    String::from(i.name.strip_prefix(&prefix)?),
+                    i.updated
+                        .and_then(|t| humantime::parse_rfc3339_weak(&t).ok()),
+                ))
+            }));
+            match r.next_page_token {
+                Some(t) => page_token = Some(t),
+                None => break,
+            }
+        }
+        Ok(objects)
+    }
+}
+
+impl StateStore for GcsStore {
+    fn list(&self) -> BoxFuture<'_, Result<Vec<StoredVersion>, StoreError>> {
+        Box::pin(async move {
+            Ok(self
+                .list_objects("")
+                .await?
+                .into_iter()
+                .filter_map(|(name, modified)| {
+                    Some(StoredVersion {
+                        version: name.parse().ok()?,
+                        modified,
+                    })
+                })
+                .collect())
+        })
+    }
+
+    fn get(&self, version: u32) -> BoxFuture<'_, Result<Vec<u8>, StoreError>> {
+        Box::pin(async move {
+            Ok(self
+                .client
+                .get(self.object_url(version))
+                .query(&[("alt", "media")])
+                .bearer_auth(self.token().await?)
+                .send()
+                .await?
+                .error_for_status()?
+                .bytes()
+                .await?
+                .to_vec())
+        })
+    }
+
+    fn put<'a>(&'a self, version: u32, data: &'a [u8]) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            // Only create, never replace.
+            if !self.upload(&version.to_string(), data, Some("0")).await? {
+                return Err(StoreError::Conflict(version));
+            }
+            Ok(())
+        })
+    }
+
+    fn get_lease(&self) -> BoxFuture<'_, Result<Option<Vec<u8>>, StoreError>> {
+        Box::pin(async move { Ok(self.get_object(LEASE).await?.map(|(data, _)| data)) })
+    }
+
+    fn swap_lease<'a>(
+        &'a self,
+        expected: Option<&'a [u8]>,
+        data: &'a [u8],
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            let current = self.get_object(LEASE).await?;
+            let (current, generation) = match current {
+                Some((ref data, ref generation)) => (Some(data.as_slice()), generation.as_str()),
+                None => (None, "0"),
+            };
+            if current != expected || !self.upload(LEASE, data, Some(generation)).await? {
+                return Err(StoreError::LeaseConflict);
+            }
+            Ok(())
+        })
+    }
+
+    fn delete(&self, version: u32) -> BoxFuture<'_, Result<(), StoreError>> {
+        Box::pin(self.delete_object(version))
+    }
+
+    fn quarantine(&self, version: u32) -> BoxFuture<'_, Result<(), StoreError>> {
+        Box::pin(async move {
+            let Some((data, _)) = self.get_object(&version.to_string()).await? else {
+                return Err(std::io::Error::from(std::io::ErrorKind::NotFound).into());
+            };
+            self.upload(&format!("{QUARANTINE}{version}"), &data, None)
+                .await?;
+            self.delete_object(version).await
+        })
+    }
+
+    fn list_chunks(&self) -> BoxFuture<'_, Result<Vec<String>, StoreError>> {
+        Box::pin(async move {
+            Ok(self
+                .list_objects(CHUNKS)
+                .await?
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect())
+        })
+    }
+
+    fn get_chunk<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<u8>, StoreError>> {
+        Box::pin(async move {
+            match self.get_object(&format!("{CHUNKS}{name}")).await? {
+                Some((data, _)) => Ok(data),
+                None => Err(std::io::Error::from(std::io::ErrorKind::NotFound).into()),
+            }
+        })
+    }
+
+    fn put_chunk<'a>(
+        &'a self,
+        name: &'a str,
+        data: &'a [u8],
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            self.upload(&format!("{CHUNKS}{name}"), data, None).await?;
+            Ok(())
+        })
+    }
+
+    fn delete_chunk<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(self.delete_object(format!("{CHUNKS}{name}")))
+    }
+}
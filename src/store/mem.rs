@@ -0,0 +1,121 @@
+//! A store which keeps everything in memory, for tests.
+
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use super::{StateStore, StoreError, StoredVersion};
+
+#[derive(Default)]
+pub struct MemStore {
+    pub versions: Mutex<HashMap<u32, Vec<u8>>>,
+    pub chunks: Mutex<HashMap<String, Vec<u8>>>,
+    pub quarantined: Mutex<HashMap<u32, Vec<u8>>>,
+    pub lease: Mutex<Option<Vec<u8>>>,
+    /// Makes `list` fail, like a store which cannot be reached.
+    pub unlistable: AtomicBool,
+}
+
+impl StateStore for MemStore {
+    fn list(&self) -> BoxFuture<'_, Result<Vec<StoredVersion>, StoreError>> {
+        if self.unlistable.load(Ordering::Acquire) {
+            return Box::pin(async {
+                Err(std::io::Error::from(std::io::ErrorKind::TimedOut).into())
+            });
+        }
+        let versions = self
+            .versions
+            .lock()
+            .unwrap()
+            .keys()
+            .map(|&version| StoredVersion {
+                version,
+                modified: None,
+            })
+            .collect();
+        Box::pin(async { Ok(versions) })
+    }
+
+    fn get(&self, version: u32) -> BoxFuture<'_, Result<Vec<u8>, StoreError>> {
+        let data = self.versions.lock().unwrap().get(&version).cloned();
+        Box::pin(async move {
+            data.ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotFound).into())
+        })
+    }
+
+    fn put<'a>(&'a self, version: u32, data: &'a [u8]) -> BoxFuture<'a, Result<(), StoreError>> {
+        let r = match self.versions.lock().unwrap().entry(version) {
+            Entry::Occupied(_) => Err(StoreError::Conflict(version)),
+            Entry::Vacant(v) => {
+                v.insert(data.to_vec());
+                Ok(())
+            }
+        };
+        Box::pin(async { r })
+    }
+
+    fn delete(&self, version: u32) -> BoxFuture<'_, Result<(), StoreError>> {
+        self.versions.lock().unwrap().remove(&version);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn quarantine(&self, version: u32) -> BoxFuture<'_, Result<(), StoreError>> {
+        let data = self.versions.lock().unwrap().remove(&version);
+        Box::pin(async move {
+            let data = data.ok_or(std::io::Error::from(std::io::ErrorKind::NotFound))?;
+            self.quarantined.lock().unwrap().insert(version, data);
+            Ok(())
+        })
+    }
+
+    fn list_chunks(&self) -> BoxFuture<'_, Result<Vec<String>, StoreError>> {
+        let chunks = self.chunks.lock().unwrap().keys().cloned().collect();
+        Box::pin(async { Ok(chunks) })
+    }
+
+    fn get_chunk<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<u8>, StoreError>> {
+        let data = self.chunks.lock().unwrap().get(name).cloned();
+        Box::pin(async move {
+            data.ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotFound).into())
+        })
+    }
+
+    fn put_chunk<'a>(
+        &'a self,
+        name: &'a str,
+        data: &'a [u8],
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        self.chunks
+            .lock()
+            .unwrap()
+            .insert(String::from(name), data.to_vec());
+        Box::pin(async { Ok(()) })
+    }
+
+    fn delete_chunk<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<(), StoreError>> {
+        self.chunks.lock().unwrap().remove(name);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn get_lease(&self) -> BoxFuture<'_, Result<Option<Vec<u8>>, StoreError>> {
+        let lease = self.lease.lock().unwrap().clone();
+        Box::pin(async { Ok(lease) })
+    }
+
+    fn swap_lease<'a>(
+        &'a self,
+        expected: Option<&'a [u8]>,
+        data: &'a [u8],
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        let mut lease = self.lease.lock().unwrap();
+        let r = if lease.as_deref() == expected {
+            *lease = Some(data.to_vec());
+            Ok(())
+        } else {
+            Err(StoreError::LeaseConflict)
+        };
+        Box::pin(async { r })
+    }
+}
@@ -0,0 +1,357 @@
+//! Writes everything to a second store as well, for `--mirror-*`, and
+//! reads from it whatever cannot be read from the first. The state can
+//! then be restored from the mirror if the primary store is lost.
+
+use futures::future::BoxFuture;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::{StateStore, StoreError, StoredVersion};
+
+const BUF_SIZE: usize = 64 * 1024;
+
+pub struct MirrorStore {
+    primary: Arc<dyn StateStore>,
+    mirror: Arc<dyn StateStore>,
+}
+
+impl MirrorStore {
+    pub fn new(primary: Arc<dyn StateStore>, mirror: Arc<dyn StateStore>) -> Self {
+        Self { primary, mirror }
+    }
+}
+
+/// The primary store's result. The mirror failing is only logged, so
+/// that the state is still persisted without it.
+fn primary_result<T>(
+    operation: &str,
+    primary: Result<T, StoreError>,
+    mirror: Result<(), StoreError>,
+) -> Result<T, StoreError> {
+    if let Err(e) = mirror {
+        log::error!("State storage mirror {operation}: {e}");
+        crate::metrics::STATE_MIRROR_ERRORS
+            .with_label_values(&[operation])
+            .inc();
+    }
+    primary
+}
+
+/// Deleting what is not there is fine, since either store may lack what
+/// was only written to the other.
+fn deleted(r: Result<(), StoreError>) -> Result<(), StoreError> {
+    match r {
+        Err(StoreError::IOError(e)) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(StoreError::HttpError(e)) if e.status() == Some(reqwest::StatusCode::NOT_FOUND) => {
+            Ok(())
+        }
+        Err(StoreError::S3Error(s3::error::S3Error::HttpFailWithBody(404, _))) => Ok(()),
+        r => r,
+    }
+}
+
+/// Counts what is written through it, to know whether a failed read can
+/// still be retried from the mirror.
+struct Counted<'a> {
+    out: &'a mut (dyn AsyncWrite + Send + Unpin),
+    written: usize,
+}
+
+impl AsyncWrite for Counted<'_> {
+    fn poll_write(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        let r = std::pin::Pin::new(&mut *self.out).poll_write(cx, buf);
+        if let std::task::Poll::Ready(Ok(n)) = r {
+            self.written += n;
+        }
+        r
+    }
+
+    fn poll_flush(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut *self.out).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut *self.out).poll_shutdown(cx)
+    }
+}
+
+impl StateStore for MirrorStore {
+    /// Versions in either store, so that those only in the mirror can
+    /// be loaded. The primary store must be listed, since the mirror may
+    /// be behind it and would make an older version look like the newest.
+    fn list(&self) -> BoxFuture<'_, Result<Vec<StoredVersion>, StoreError>> {
+        Box::pin(async move {
+            let (primary, mirror) = tokio::join!(self.primary.list(), self.mirror.list());
+            let primary = primary?;
+            let mirror = mirror.unwrap_or_else(|e| {
+                log::warn!("Listing state storage mirror: {e}");
+                Vec::new()
+            });
+            let mut versions = HashMap::new();
+            for v in mirror.into_iter().chain(primary) {
+                versions.insert(v.version, v);
+            }
+            Ok(versions.into_values().collect())
+        })
+    }
+
+    fn get(&self, version: u32) -> BoxFuture<'_, Result<Vec<u8>, StoreError>> {
+        Box::pin(async move {
+            match self.primary.get(version).await {
+                Ok(data) => Ok(data),
+                Err(e) => {
+                    log::warn!("State storage reading {version}: {e}; trying the mirror");
+                    self.mirror.get(version).await
+                }
+            }
+        })
+    }
+
+    fn put<'a>(&'a self, version: u32, data: &'a [u8]) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            let (primary, mirror) = tokio::join!(
+                self.primary.put(version, data),
+                self.mirror.put(version, data)
+            );
+            primary_result("put", primary, mirror)
+        })
+    }
+
+    fn delete(&self, version: u32) -> BoxFuture<'_, Result<(), StoreError>> {
+        Box::pin(async move {
+            let (primary, mirror) =
+                tokio::join!(self.primary.delete(version), self.mirror.delete(version));
+            primary_result("delete", deleted(primary), deleted(mirror))
+        })
+    }
+
+    /// In both stores. It may be in only one of them if it was read from
+    /// the mirror, which is enough.
+    fn quarantine(&self, version: u32) -> BoxFuture<'_, Result<(), StoreError>> {
+        Box::pin(async move {
+            let (primary, mirror) = tokio::join!(
+                self.primary.quarantine(version),
+                self.mirror.quarantine(version)
+            );
+            match primary {
+                Ok(()) => Ok(()),
+                Err(e) => mirror.map_err(|_| e),
+            }
+        })
+    }
+
+    fn list_chunks(&self) -> BoxFuture<'_, Result<Vec<String>, StoreError>> {
+        Box::pin(async move {
+            let (primary, mirror) =
+                tokio::join!(self.primary.list_chunks(), self.mirror.list_chunks());
+            let mut chunks = primary?.into_iter().collect::<HashSet<_>>();
+            match mirror {
+                Ok(mirror) => chunks.extend(mirror),
+                Err(e) => log::warn!("Listing state storage mirror chunks: {e}"),
+            }
+            Ok(chunks.into_iter().collect())
+        })
+    }
+
+    fn get_chunk<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<u8>, StoreError>> {
+        Box::pin(async move {
+            match self.primary.get_chunk(name).await {
+                Ok(data) => Ok(data),
+                Err(e) => {
+                    log::warn!("State storage reading chunk {name}: {e}; trying the mirror");
+                    self.mirror.get_chunk(name).await
+                }
+            }
+        })
+    }
+
+    fn put_chunk<'a>(
+        &'a self,
+        name: &'a str,
+        data: &'a [u8],
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            let (primary, mirror) = tokio::join!(
+                self.primary.put_chunk(name, data),
+                self.mirror.put_chunk(name, data)
+            );
+            primary_result("put chunk", primary, mirror)
+        })
+    }
+
+    fn delete_chunk<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            let (primary, mirror) = tokio::join!(
+                self.primary.delete_chunk(name),
+                self.mirror.delete_chunk(name)
+            );
+            primary_result("delete chunk", deleted(primary), deleted(mirror))
+        })
+    }
+
+    /// The lease is only kept in the primary store.
+    fn get_lease(&self) -> BoxFuture<'_, Result<Option<Vec<u8>>, StoreError>> {
+        self.primary.get_lease()
+    }
+
+    fn swap_lease<'a>(
+        &'a self,
+        expected: Option<&'a [u8]>,
+        data: &'a [u8],
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        self.primary.swap_lease(expected, data)
+    }
+
+    /// Streams `data` to both stores at once. If the mirror stops
+    /// reading, the primary store carries on without it.
+    fn put_stream<'a>(
+        &'a self,
+        version: u32,
+        data: &'a mut (dyn AsyncRead + Send + Unpin),
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            let (mut primary_in, mut primary_out) = tokio::io::duplex(BUF_SIZE);
+            let (mut mirror_in, mirror_out) = tokio::io::duplex(BUF_SIZE);
+            let tee = async move {
+                let mut mirror_out = Some(mirror_out);
+                let mut buf = vec![0u8; BUF_SIZE];
+                loop {
+                    let n = data.read(&mut buf).await?;
+                    if n == 0 {
+                        break;
+                    }
+                    primary_out.write_all(&buf[..n]).await?;
+                    if let Some(ref mut out) = mirror_out
+                        && out.write_all(&buf[..n]).await.is_err()
+                    {
+                        // Its put_stream has failed and says why.
+                        mirror_out = None;
+                    }
+                }
+                primary_out.shutdown().await?;
+                if let Some(mut out) = mirror_out {
+                    let _ = out.shutdown().await;
+                }
+                Ok::<_, StoreError>(())
+            };
+            // Each reader is dropped once its store is done with it, so
+            // that the tee does not wait for it.
+            let primary = async move { self.primary.put_stream(version, &mut primary_in).await };
+            let mirror = async move { self.mirror.put_stream(version, &mut mirror_in).await };
+            let (tee, primary, mirror) = tokio::join!(tee, primary, mirror);
+            primary_result("put", primary, mirror)?;
+            tee
+        })
+    }
+
+    /// From the mirror if the primary store fails before writing
+    /// anything to `out`.
+    fn get_stream<'a>(
+        &'a self,
+        version: u32,
+        out: &'a mut (dyn AsyncWrite + Send + Unpin),
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            let mut counted = Counted { out, written: 0 };
+            match self.primary.get_stream(version, &mut counted).await {
+                Err(e) if counted.written == 0 => {
+                    log::warn!("State storage reading {version}: {e}; trying the mirror");
+                    self.mirror.get_stream(version, counted.out).await
+                }
+                r => r,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::store::mem::MemStore;
+    use std::sync::atomic::Ordering;
+
+    fn stores() -> (Arc<MemStore>, Arc<MemStore>, MirrorStore) {
+        let primary = Arc::new(MemStore::default());
+        let mirror = Arc::new(MemStore::default());
+        let store = MirrorStore::new(primary.clone(), mirror.clone());
+        (primary, mirror, store)
+    }
+
+    #[tokio::test]
+    async fn writes_both() {
+        let (primary, mirror, store) = stores();
+        store.put(1, b"one").await.unwrap();
+        let mut data: &[u8] = b"two";
+        store.put_stream(2, &mut data).await.unwrap();
+        store.put_chunk("c", b"chunk").await.unwrap();
+        for s in [&primary, &mirror] {
+            assert_eq!(s.versions.lock().unwrap()[&1], b"one");
+            assert_eq!(s.versions.lock().unwrap()[&2], b"two");
+            assert_eq!(s.chunks.lock().unwrap()["c"], b"chunk");
+        }
+        store.delete(1).await.unwrap();
+        assert!(!primary.versions.lock().unwrap().contains_key(&1));
+        assert!(!mirror.versions.lock().unwrap().contains_key(&1));
+    }
+
+    #[tokio::test]
+    async fn mirror_failure_is_not_fatal() {
+        let (primary, mirror, store) = stores();
+        mirror.versions.lock().unwrap().insert(1, b"other".to_vec());
+        let mut data: &[u8] = b"one";
+        store.put_stream(1, &mut data).await.unwrap();
+        assert_eq!(primary.versions.lock().unwrap()[&1], b"one");
+        // But the primary failing is.
+        let mut data: &[u8] = b"again";
+        assert!(matches!(
+            store.put_stream(1, &mut data).await,
+            Err(StoreError::Conflict(1))
+        ));
+    }
+
+    #[tokio::test]
+    async fn reads_mirror_when_primary_is_lost() {
+        let (primary, _mirror, store) = stores();
+        store.put(1, b"one").await.unwrap();
+        store.put(2, b"two").await.unwrap();
+        primary.versions.lock().unwrap().clear();
+        let mut versions = store
+            .list()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|v| v.version)
+            .collect::<Vec<_>>();
+        versions.sort();
+        assert_eq!(versions, [1, 2]);
+        assert_eq!(store.get(2).await.unwrap(), b"two");
+        let mut out = Vec::new();
+        store.get_stream(1, &mut out).await.unwrap();
+        assert_eq!(out, b"one");
+    }
+
+    #[tokio::test]
+    async fn primary_must_be_listed() {
+        let (primary, mirror, store) = stores();
+        store.put(1, b"one").await.unwrap();
+        primary.put(2, b"two").await.unwrap();
+        primary.unlistable.store(true, Ordering::Release);
+        // Rather than make 1 look like the newest.
+        assert!(store.list().await.is_err());
+        primary.unlistable.store(false, Ordering::Release);
+        mirror.unlistable.store(true, Ordering::Release);
+        assert_eq!(store.list().await.unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,10 @@
+//! Fixtures shared by the tests of several modules.
+
+use std::io::Write;
+
+/// A temporary file holding `contents`, such as a token or config file.
+pub fn file(contents: &str) -> tempfile::NamedTempFile {
+    let mut f = tempfile::NamedTempFile::new().unwrap();
+    f.write_all(contents.as_bytes()).unwrap();
+    f
+}
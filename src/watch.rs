@@ -0,0 +1,49 @@
+use futures::StreamExt;
+use inotify::{Inotify, WatchMask};
+use std::path::Path;
+
+/// Calls `reload` whenever anything changes in the directory containing
+/// `path`. The whole directory is watched so that files replaced by
+/// renaming, including Kubernetes ConfigMap volumes, are noticed.
+pub async fn watch<F: Fn()>(path: &Path, reload: F) -> Result<(), std::io::Error> {
+    let dir = match path.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    };
+    let inotify = Inotify::init()?;
+    inotify.watches().add(
+        dir,
+        WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::CREATE | WatchMask::DELETE,
+    )?;
+    let mut events = inotify.into_event_stream([0u8; 4096])?;
+    while let Some(event) = events.next().await {
+        event?;
+        reload();
+    }
+    Ok(())
+}
+
+/// Calls `reload` whenever the directory containing `path` changes, if
+/// there is a path, and whenever `reloads` says that it is time to.
+pub async fn watch_and_reload<F: Fn()>(
+    path: Option<&Path>,
+    mut reloads: tokio::sync::watch::Receiver<()>,
+    reload: F,
+) -> Result<(), std::io::Error> {
+    let hangups = async {
+        while reloads.changed().await.is_ok() {
+            reload();
+        }
+        std::future::pending::<()>().await
+    };
+    match path {
+        Some(path) => tokio::select! {
+            r = watch(path, &reload) => r,
+            () = hangups => Ok(()),
+        },
+        None => {
+            hangups.await;
+            Ok(())
+        }
+    }
+}